@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use crate::parser::*;
+
+/// A user-defined name for a key expression, optionally with parameters.
+///
+/// For example, `HRM(mod,key)` -> `MT(mod,key,200)` lets a layout write `HRM(KC_LEFTMETA,KC_A)`.
+#[derive(Clone, Debug)]
+pub struct KeyAlias {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: ParsedKeyTree,
+}
+
+/// A collection of aliases (the `"aliases"` block of a layers file).
+/// Aliases are expanded into plain key trees before keys are created.
+#[derive(Clone, Debug)]
+pub struct AliasCollection {
+    aliases: HashMap<String, KeyAlias>,
+}
+
+impl AliasCollection {
+    /// Create an empty collection.
+    pub fn new() -> AliasCollection {
+        AliasCollection {
+            aliases: HashMap::new()
+        }
+    }
+
+    /// Read an `"aliases"` block that maps signatures (e.g. `HRM(mod,key)`) to key syntax.
+    pub fn from_json(aliases: &json::JsonValue) -> Result<AliasCollection, String> {
+        let mut ans = AliasCollection::new();
+        for (signature, body) in aliases.entries() {
+            let body = body.as_str()
+                .ok_or_else(|| format!("The alias {} must be a string of key syntax.", signature))?;
+            ans.add(signature, body)?;
+        }
+        Ok(ans)
+    }
+
+    /// Add an alias by its signature (e.g. `HRM_A` or `HRM(mod,key)`) and body (e.g. `MT(mod,key,200)`).
+    pub fn add(&mut self, signature: &str, body: &str) -> Result<(), String> {
+        let describe = |e: KeySyntaxError| format!("The alias {} has a syntax error: {}", signature, e);
+        let signature = ParsedKeyTree::create(signature).map_err(describe)?;
+        let body = ParsedKeyTree::create(body).map_err(describe)?;
+
+        // Parameters must be plain names, and can't repeat.
+        let mut params: Vec<String> = Vec::new();
+        for i in signature.args.iter() {
+            if !i.args.is_empty() || i.name.is_some() || i.literal.is_some() {
+                return Err(format!("The alias {} has a parameter that isn't a plain name: {}", signature.identifier, i.identifier));
+            }
+            if params.contains(&i.identifier) {
+                return Err(format!("The alias {} repeats the parameter {}", signature.identifier, i.identifier));
+            }
+            params.push(i.identifier.clone());
+        }
+
+        self.aliases.insert(signature.identifier.clone(), KeyAlias {
+            name: signature.identifier,
+            params,
+            body
+        });
+        Ok(())
+    }
+
+    /// Find an alias by name.
+    pub fn get(&self, name: &str) -> Option<&KeyAlias> {
+        self.aliases.get(name)
+    }
+
+    /// Check if there aren't any aliases.
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    /// Every alias name (sorted).
+    pub fn names(&self) -> Vec<String> {
+        let mut ans: Vec<String> = self.aliases.keys().cloned().collect();
+        ans.sort();
+        ans
+    }
+
+    /// Expand every alias in a key tree. Aliases can use other aliases, but not themselves.
+    pub fn expand(&self, tree: &ParsedKeyTree) -> Result<ParsedKeyTree, String> {
+        self.expand_recursive(tree, &mut Vec::new())
+    }
+
+    fn expand_recursive(&self, tree: &ParsedKeyTree, stack: &mut Vec<String>) -> Result<ParsedKeyTree, String> {
+        // Quoted strings are never aliases. Arguments of keys are expanded if they're keys
+        // (e.g. not the layer of `MO(nav)`), while arguments of aliases are expanded once
+        // they've been substituted into the alias.
+        let alias = match (&tree.literal, self.aliases.get(&tree.identifier)) {
+            (Some(Literal::String(_)), _) | (_, None) => {
+                let mut ans = tree.clone();
+                for (idx, i) in ans.args.iter_mut().enumerate() {
+                    if is_key_argument(&tree.identifier, idx, i) {
+                        *i = self.expand_recursive(i, stack)?;
+                    }
+                }
+                return Ok(ans);
+            }
+            (_, Some(alias)) => alias
+        };
+
+        if stack.contains(&alias.name) {
+            stack.push(alias.name.clone());
+            return Err(format!("The alias {} uses itself: {}", alias.name, stack.join(" -> ")));
+        }
+
+        // Match arguments to parameters by position or by name.
+        if tree.args.len() != alias.params.len() {
+            return Err(format!("{} expects {} argument{}, got {}",
+                alias.name, alias.params.len(), if alias.params.len() == 1 { "" } else { "s" }, tree.args.len()));
+        }
+        let mut bound: HashMap<&str, &ParsedKeyTree> = HashMap::new();
+        for (idx, arg) in tree.args.iter().enumerate() {
+            let param = match &arg.name {
+                None => alias.params[idx].as_str(),
+                Some(name) => alias.params.iter().find(|x| *x == name).map(|x| x.as_str())
+                    .ok_or_else(|| format!("{} doesn't have an argument named {}", alias.name, name))?
+            };
+            if bound.contains_key(param) {
+                return Err(format!("{} got the {} argument twice", alias.name, param));
+            }
+            bound.insert(param, arg);
+        }
+
+        // Substitute the arguments into the body, then expand any aliases the body uses.
+        let body = self.substitute(&alias.body, &bound, true, stack)?;
+        stack.push(alias.name.clone());
+        let mut ans = self.expand_recursive(&body, stack)?;
+        stack.pop();
+
+        // The expansion takes the place of the alias.
+        ans.name = tree.name.clone();
+        ans.column = tree.column;
+        Ok(ans)
+    }
+
+    /// Replace parameters in an alias body with their arguments. Arguments that end up
+    /// as keys are expanded first (with the caller's stack, so an alias can be given itself
+    /// as an argument); other arguments (e.g. layer names) are used as they're written.
+    fn substitute(&self, body: &ParsedKeyTree, bound: &HashMap<&str, &ParsedKeyTree>, is_key: bool, stack: &mut Vec<String>)
+        -> Result<ParsedKeyTree, String> {
+        let is_param = body.args.is_empty() && body.literal.is_none();
+        match bound.get(body.identifier.as_str()) {
+            Some(v) if is_param => {
+                let mut ans = if is_key { self.expand_recursive(v, stack)? } else { (*v).clone() };
+                ans.name = body.name.clone();
+                Ok(ans)
+            }
+            _ => {
+                let mut ans = body.clone();
+                for (idx, i) in ans.args.iter_mut().enumerate() {
+                    let is_key = is_key_argument(&body.identifier, idx, i);
+                    *i = self.substitute(i, bound, is_key, stack)?;
+                }
+                Ok(ans)
+            }
+        }
+    }
+}
+
+impl Default for AliasCollection {
+    fn default() -> AliasCollection {
+        AliasCollection::new()
+    }
+}
+
+/// Check if an argument of a built-in key is a key, rather than a layer name, a number, an option
+/// or a steno chord. Aliases are only expanded in keys, so `MO(NAV)` keeps the layer `NAV` even if
+/// there's an alias with that name. Arguments of other keys (e.g. custom keys) are treated as keys.
+fn is_key_argument(identifier: &str, idx: usize, arg: &ParsedKeyTree) -> bool {
+    let is_one_of = |positions: &[usize], names: &[&str]| match &arg.name {
+        Some(name) => names.contains(&name.as_str()),
+        None => positions.contains(&idx)
+    };
+    if arg.literal.is_some() {
+        return false;
+    }
+    match identifier {
+        "MO" | "TG" | "AL" | "OSL" | "STENO" => false,
+        "LT" => !is_one_of(&[0, 2], &["layer", "hold_ms"]),
+        "MT" => !is_one_of(&[2, 3], &["tapping_term", "permissive_hold"]),
+        "MACRO" => arg.name.is_none(),
+        _ => true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(aliases: &AliasCollection, v: &str) -> Result<ParsedKeyTree, String> {
+        aliases.expand(&ParsedKeyTree::create(v).unwrap()).map(without_columns)
+    }
+
+    // Expanded keys keep the columns of the alias body, so compare trees without them.
+    fn without_columns(mut tree: ParsedKeyTree) -> ParsedKeyTree {
+        tree.column = 0;
+        tree.args = tree.args.into_iter().map(without_columns).collect();
+        tree
+    }
+
+    #[test]
+    fn alias_expansion() {
+        let mut aliases = AliasCollection::new();
+        aliases.add("PAREN_L", "SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT)").unwrap();
+        aliases.add("HRM(mod,key)", "MT(mod,key,200)").unwrap();
+        aliases.add("HRM_A", "HRM(KC_LEFTMETA,KC_A)").unwrap();
+
+        let same = |a: &str, b: &str| {
+            assert_eq!(expand(&aliases, a).unwrap(), without_columns(ParsedKeyTree::create(b).unwrap()));
+        };
+        same("PAREN_L", "SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT)");
+        same("HRM_A", "MT(KC_LEFTMETA,KC_A,200)");
+        same("HRM(key=KC_S,mod=KC_LEFTALT)", "MT(KC_LEFTALT,KC_S,200)");
+        same("MACRO(PAREN_L,KC_A)", "MACRO(SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT),KC_A)");
+        same("\"HRM_A\"", "\"HRM_A\"");
+
+        assert_eq!(expand(&aliases, "HRM(KC_A)").unwrap_err(), "HRM expects 2 arguments, got 1");
+        assert_eq!(expand(&aliases, "HRM(KC_A,KC_B,mod=KC_C)").unwrap_err(), "HRM expects 2 arguments, got 3");
+    }
+
+    #[test]
+    fn alias_expansion_in_keys_only() {
+        let mut aliases = AliasCollection::new();
+        aliases.add("NAV", "KC_N").unwrap();
+        aliases.add("PAREN_L", "WRAP(KC_LEFTSHIFT,KC_9)").unwrap();
+        aliases.add("HOLD_NAV(layer,key)", "LT(layer,key,150)").unwrap();
+        aliases.add("SHIFTED(key)", "WRAP(KC_LEFTSHIFT,key)").unwrap();
+
+        let same = |a: &str, b: &str| {
+            assert_eq!(expand(&aliases, a).unwrap(), without_columns(ParsedKeyTree::create(b).unwrap()));
+        };
+        // Layer names, numbers and options are left alone, even if they're the names of aliases.
+        same("MO(NAV)", "MO(NAV)");
+        same("LT(NAV,NAV)", "LT(NAV,KC_N)");
+        same("LT(tap=PAREN_L,layer=NAV)", "LT(tap=WRAP(KC_LEFTSHIFT,KC_9),layer=NAV)");
+        same("MACRO(NAV,TG(NAV))", "MACRO(KC_N,TG(NAV))");
+        same("STENO(NAV)", "STENO(NAV)");
+        same("HOLD_NAV(NAV,PAREN_L)", "LT(NAV,WRAP(KC_LEFTSHIFT,KC_9),150)");
+
+        // An alias can be given itself as an argument.
+        same("SHIFTED(SHIFTED(NAV))", "WRAP(KC_LEFTSHIFT,WRAP(KC_LEFTSHIFT,KC_N))");
+    }
+
+    #[test]
+    fn alias_cycles() {
+        let mut aliases = AliasCollection::new();
+        aliases.add("A", "MACRO(KC_A,B)").unwrap();
+        aliases.add("B", "WRAP(KC_LEFTSHIFT,A)").unwrap();
+        aliases.add("SELF(x)", "SELF(x)").unwrap();
+        assert_eq!(expand(&aliases, "A").unwrap_err(), "The alias A uses itself: A -> B -> A");
+        assert!(expand(&aliases, "SELF(KC_A)").is_err());
+    }
+
+    #[test]
+    fn alias_bad_signatures() {
+        let mut aliases = AliasCollection::new();
+        assert!(aliases.add("HRM(mod,mod)", "KC_A").is_err());
+        assert!(aliases.add("HRM(mod(x))", "KC_A").is_err());
+        assert!(aliases.add("HRM(", "KC_A").is_err());
+        assert!(aliases.add("HRM", "MT(").is_err());
+        assert!(AliasCollection::from_json(&json::parse(r#"{ "HRM": 1 }"#).unwrap()).is_err());
+    }
+}
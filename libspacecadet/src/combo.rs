@@ -0,0 +1,268 @@
+use std::time::{Duration, Instant};
+use crate::key_registry::KeyRegistry;
+use crate::keys::{KeyCode, NormalKey};
+use crate::virtual_keyboard_matrix::{Index2D, KeyStateChange, VirtualKeyboardMatrix};
+
+/// Keys in the matrix that act as another key when they're pressed together
+/// (e.g. `"KC_J+KC_K": "KC_ESC"`).
+pub struct Combo {
+    /// The positions of the keys in the matrix, in the order they're written.
+    pub positions: Vec<Index2D>,
+    /// The key the combo acts as. It handles events at the first position.
+    pub code: Box<KeyCode>,
+}
+
+/// What the driver should handle after a combo key changes state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ComboEvent {
+    /// A key event at a position, as if there weren't any combos (and when it happened).
+    Key(Index2D, KeyStateChange, Instant),
+    /// A key event for the combo at an index in `ComboCollection::combos`.
+    Combo(usize, KeyStateChange),
+}
+
+/// The combo that's pressed, and its keys that are still down.
+struct ActiveCombo {
+    idx: usize,
+    down: Vec<Index2D>,
+}
+
+/// The layout's combos (the `"combos"` section of a layers file).
+///
+/// A key that's part of a combo isn't handled when it's pressed. If the other keys of the
+/// combo are pressed within `term`, the combo's key is pressed instead, and it's released
+/// when the first of them is released. Otherwise, the keys are handled as if there weren't
+/// any combos (with the time they were pressed).
+pub struct ComboCollection {
+    pub combos: Vec<Combo>,
+    /// How long after the first key of a combo the others can be pressed (`combo_term_ms`).
+    pub term: Duration,
+    /// The presses that could still become a combo, and when they happened.
+    pending: Vec<(Index2D, Instant)>,
+    active: Option<ActiveCombo>,
+}
+
+impl ComboCollection {
+    /// Create a collection without any combos.
+    pub fn new() -> ComboCollection {
+        ComboCollection {
+            combos: Vec::new(),
+            term: Duration::from_millis(50),
+            pending: Vec::new(),
+            active: None,
+        }
+    }
+
+    /// Read a `"combos"` section: an object from the keys of each combo (physical key names or
+    /// `row,col` positions, joined with `+`) to the key it acts as.
+    pub fn from_json(v: &json::JsonValue, matrix: &VirtualKeyboardMatrix, registry: &KeyRegistry) -> Result<ComboCollection, String> {
+        let mut ans = ComboCollection::new();
+        if v.is_null() {
+            return Ok(ans);
+        }
+        if !v.is_object() {
+            return Err("\"combos\" must be an object of combos (e.g. \"KC_J+KC_K\": \"KC_ESC\").".to_string());
+        }
+        for (keys, code) in v.entries() {
+            let error = |message: String| format!("The combo \"{}\" {}", keys, message);
+            let mut positions = Vec::new();
+            for position in keys.split('+') {
+                let idx = matrix.parse_position(position)
+                    .map_err(|e| error(format!("has a bad key position: {}", e)))?;
+                if positions.contains(&idx) {
+                    return Err(error(format!("has the key at {},{} twice.", idx.0, idx.1)));
+                }
+                positions.push(idx);
+            }
+            if positions.len() < 2 {
+                return Err(error("needs at least two keys.".to_string()));
+            }
+            let mut sorted = positions.clone();
+            sorted.sort();
+            if ans.combos.iter().any(|x| { let mut v = x.positions.clone(); v.sort(); v == sorted }) {
+                return Err(error("has the same keys as another combo.".to_string()));
+            }
+            let text = code.as_str().ok_or_else(|| error(format!("has a key that isn't a string: {}", code)))?;
+            let code = registry.parse(text).map_err(|e| error(format!("has a bad key: {}", e)))?;
+            ans.combos.push(Combo { positions, code });
+        }
+        Ok(ans)
+    }
+
+    /// Convert the combos into a `"combos"` section that `from_json` can read.
+    /// Fails if a combo's key can't be written in key syntax.
+    pub fn to_json(&self, matrix: &VirtualKeyboardMatrix) -> Result<json::JsonValue, String> {
+        let mut ans = json::JsonValue::new_object();
+        for combo in self.combos.iter() {
+            let keys: Vec<String> = combo.positions.iter().map(|idx| match matrix.key_at(*idx) {
+                Some(key) => NormalKey { value: key }.name(),
+                None => format!("{},{}", idx.0, idx.1)
+            }).collect();
+            let code = combo.code.to_key_syntax()
+                .ok_or_else(|| format!("The combo at {} can't be written in key syntax.", keys.join("+")))?;
+            ans[keys.join("+")] = code.into();
+        }
+        Ok(ans)
+    }
+
+    /// Check if there aren't any combos.
+    pub fn is_empty(&self) -> bool {
+        self.combos.is_empty()
+    }
+
+    /// Handle a key state change, and return the events the driver should handle instead.
+    pub fn update(&mut self, idx: Index2D, state: KeyStateChange, now: Instant) -> Vec<ComboEvent> {
+        match state {
+            KeyStateChange::Pressed if self.active.is_none() => {
+                let mut pressed: Vec<Index2D> = self.pending.iter().map(|x| x.0).collect();
+                pressed.push(idx);
+                let contains_pressed = |combo: &Combo| pressed.iter().all(|x| combo.positions.contains(x));
+
+                // Wait for the rest of a combo (even if the keys are a shorter combo already).
+                if self.combos.iter().any(|x| contains_pressed(x) && x.positions.len() > pressed.len()) {
+                    self.pending.push((idx, now));
+                    return Vec::new();
+                }
+                if self.combos.iter().any(contains_pressed) {
+                    self.pending.push((idx, now));
+                    return self.resolve();
+                }
+            }
+            KeyStateChange::Released => {
+                if let Some(active) = &mut self.active {
+                    if let Some(i) = active.down.iter().position(|x| *x == idx) {
+                        // The first key up releases the combo. The others are ignored.
+                        let released = active.down.len() == self.combos[active.idx].positions.len();
+                        let c = active.idx;
+                        active.down.remove(i);
+                        if active.down.is_empty() {
+                            self.active = None;
+                        }
+                        return if released { vec![ComboEvent::Combo(c, KeyStateChange::Released)] } else { Vec::new() };
+                    }
+                }
+            }
+            KeyStateChange::Held => {
+                if let Some(active) = &self.active {
+                    if active.down.contains(&idx) {
+                        // The combo is held at its first position, until it's released.
+                        let combo = &self.combos[active.idx];
+                        return if idx == combo.positions[0] && active.down.len() == combo.positions.len() {
+                            vec![ComboEvent::Combo(active.idx, KeyStateChange::Held)]
+                        } else {
+                            Vec::new()
+                        };
+                    }
+                }
+            }
+            KeyStateChange::Pressed => ()
+        }
+
+        // Anything else ends the wait for a combo, then the event is handled on its own.
+        if self.pending.is_empty() {
+            return vec![ComboEvent::Key(idx, state, now)];
+        }
+        let mut ans = self.resolve();
+        ans.extend(self.update(idx, state, now));
+        ans
+    }
+
+    /// Stop waiting for a combo if the term has passed since its first key was pressed.
+    pub fn check_timeout(&mut self, now: Instant) -> Vec<ComboEvent> {
+        match self.pending.first() {
+            Some((_, t)) if now >= *t + self.term => self.resolve(),
+            _ => Vec::new()
+        }
+    }
+
+    /// Stop waiting for a combo. If the keys that are waiting are a combo, it's pressed.
+    /// Otherwise, the keys are pressed on their own.
+    fn resolve(&mut self) -> Vec<ComboEvent> {
+        let pressed: Vec<Index2D> = self.pending.iter().map(|x| x.0).collect();
+        let combo = self.combos.iter().position(|x| x.positions.len() == pressed.len() && pressed.iter().all(|p| x.positions.contains(p)));
+        match combo {
+            Some(c) => {
+                self.pending.clear();
+                self.active = Some(ActiveCombo { idx: c, down: pressed });
+                vec![ComboEvent::Combo(c, KeyStateChange::Pressed)]
+            },
+            None => self.pending.drain(..).map(|(idx, t)| ComboEvent::Key(idx, KeyStateChange::Pressed, t)).collect()
+        }
+    }
+}
+
+impl Default for ComboCollection {
+    fn default() -> ComboCollection {
+        ComboCollection::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::SimpleKey;
+
+    fn combos() -> (ComboCollection, VirtualKeyboardMatrix) {
+        let matrix = VirtualKeyboardMatrix::new(vec![vec![Some(SimpleKey::KEY_J), Some(SimpleKey::KEY_K), Some(SimpleKey::KEY_L)]], None);
+        let combos = ComboCollection::from_json(&json::parse(r#"{ "KC_J+KC_K": "KC_ESC", "KC_J+KC_K+0,2": "KC_TAB" }"#).unwrap(),
+            &matrix, &KeyRegistry::new()).unwrap();
+        (combos, matrix)
+    }
+
+    #[test]
+    fn press_combos() {
+        use KeyStateChange::*;
+        let (mut combos, _) = combos();
+        let t = Instant::now();
+        let ms = |x| t + Duration::from_millis(x);
+        let (j, k, l) = ((0, 0), (0, 1), (0, 2));
+
+        // Pressing J and K together presses the combo once the term has passed (K could still
+        // be the start of J+K+L), and the first key up releases it.
+        assert_eq!(combos.update(j, Pressed, t), vec![]);
+        assert_eq!(combos.update(k, Pressed, ms(10)), vec![]);
+        assert_eq!(combos.check_timeout(ms(40)), vec![]);
+        assert_eq!(combos.check_timeout(ms(50)), vec![ComboEvent::Combo(0, Pressed)]);
+        assert_eq!(combos.update(j, Released, ms(100)), vec![ComboEvent::Combo(0, Released)]);
+        assert_eq!(combos.update(k, Released, ms(110)), vec![]);
+
+        // Pressing all three presses the longer combo straight away.
+        combos.update(j, Pressed, t);
+        combos.update(k, Pressed, t);
+        assert_eq!(combos.update(l, Pressed, ms(10)), vec![ComboEvent::Combo(1, Pressed)]);
+        assert_eq!(combos.update(j, Held, ms(300)), vec![ComboEvent::Combo(1, Held)]);
+        assert_eq!(combos.update(k, Held, ms(300)), vec![]);
+        assert_eq!(combos.update(k, Released, ms(400)), vec![ComboEvent::Combo(1, Released)]);
+        assert_eq!(combos.update(j, Released, ms(410)), vec![]);
+        assert_eq!(combos.update(l, Released, ms(420)), vec![]);
+
+        // A key that isn't part of a combo is handled straight away.
+        let mut other = ComboCollection::new();
+        assert_eq!(other.update(l, Pressed, t), vec![ComboEvent::Key(l, Pressed, t)]);
+
+        // Keys that aren't a combo in time are handled on their own, when they were pressed.
+        combos.update(j, Pressed, t);
+        assert_eq!(combos.check_timeout(ms(50)), vec![ComboEvent::Key(j, Pressed, t)]);
+        assert_eq!(combos.update(j, Released, ms(60)), vec![ComboEvent::Key(j, Released, ms(60))]);
+
+        // Releasing a key before the combo is complete handles it as a normal key.
+        combos.update(j, Pressed, t);
+        assert_eq!(combos.update(j, Released, ms(20)), vec![ComboEvent::Key(j, Pressed, t), ComboEvent::Key(j, Released, ms(20))]);
+    }
+
+    #[test]
+    fn read_and_write_combos() {
+        let (combos, matrix) = combos();
+        assert_eq!(combos.combos[1].positions, vec![(0, 0), (0, 1), (0, 2)]);
+        assert_eq!(combos.to_json(&matrix).unwrap(), json::parse(r#"{ "KC_J+KC_K": "KC_ESC", "KC_J+KC_K+KC_L": "KC_TAB" }"#).unwrap());
+
+        let error = |v: &str| ComboCollection::from_json(&json::parse(v).unwrap(), &matrix, &KeyRegistry::new()).err().unwrap();
+        assert_eq!(error(r#"{ "KC_J": "KC_A" }"#), "The combo \"KC_J\" needs at least two keys.");
+        assert_eq!(error(r#"{ "KC_J+KC_J": "KC_A" }"#), "The combo \"KC_J+KC_J\" has the key at 0,0 twice.");
+        assert_eq!(error(r#"{ "KC_J+KC_K": "KC_A", "KC_K+KC_J": "KC_B" }"#), "The combo \"KC_K+KC_J\" has the same keys as another combo.");
+        assert_eq!(error(r#"{ "KC_J+KC_Z": "KC_A" }"#), "The combo \"KC_J+KC_Z\" has a bad key position: KC_Z isn't in the matrix");
+        assert!(error(r#"{ "KC_J+KC_K": "NOPE" }"#).starts_with("The combo \"KC_J+KC_K\" has a bad key: "));
+        assert_eq!(error("[]"), "\"combos\" must be an object of combos (e.g. \"KC_J+KC_K\": \"KC_ESC\").");
+    }
+}
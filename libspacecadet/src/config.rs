@@ -0,0 +1,306 @@
+use std::path::Path;
+use std::time::Duration;
+use crate::document::*;
+use crate::error::SpaceCadetError;
+use crate::input_keyboard::InputKeyboard;
+use crate::key_registry::*;
+use crate::keyboard_driver::KeyboardDriver;
+use crate::output_keyboard::{OutputKeyboard, Autorepeat};
+use crate::virtual_keyboard_matrix::VirtualKeyboardMatrix;
+
+/// The fastest the driver can check for events (`poll_hz`). Its clock ticks at most once a millisecond.
+pub const MAX_POLL_HZ: u32 = 1000;
+
+/// The time between the driver's checks for events, at `poll_hz` checks a second.
+pub fn poll_interval(poll_hz: u32) -> Duration {
+    Duration::from_secs_f64(1.0 / f64::from(poll_hz.max(1)))
+}
+
+/// Settings for the virtual (uinput) keyboard that the driver types on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputSettings {
+    pub name: String,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub bus: Option<u16>,
+    pub version: Option<u16>,
+    /// Repeat held keys (off by default - desktops usually repeat keys themselves).
+    pub autorepeat: Option<Autorepeat>,
+}
+
+impl OutputSettings {
+    /// Create the default output settings.
+    pub fn new() -> OutputSettings {
+        OutputSettings {
+            name: "spacecadet".to_string(),
+            vendor: None,
+            product: None,
+            bus: None,
+            version: None,
+            autorepeat: None,
+        }
+    }
+}
+
+impl Default for OutputSettings {
+    fn default() -> OutputSettings {
+        OutputSettings::new()
+    }
+}
+
+/// Settings for the whole driver (the `"settings"` block of a config or layers file).
+#[derive(Clone, Debug, PartialEq)]
+pub struct DriverSettings {
+    /// Hold thresholds and defaults for optional key arguments.
+    pub keys: KeySettings,
+    /// How many times a second the driver checks for events (`poll_hz`).
+    pub poll_hz: u32,
+    pub output: OutputSettings,
+}
+
+impl DriverSettings {
+    /// Create the default settings.
+    pub fn new() -> DriverSettings {
+        DriverSettings {
+            keys: KeySettings::new(),
+            poll_hz: 200,
+            output: OutputSettings::new(),
+        }
+    }
+
+    /// Read a `"settings"` block. Missing settings keep their default value.
+    pub fn from_json(settings: &json::JsonValue) -> Result<DriverSettings, String> {
+        let mut ans = DriverSettings::new();
+        ans.keys.update_from_json(settings)?;
+
+        if !settings["poll_hz"].is_null() {
+            ans.poll_hz = settings["poll_hz"].as_u32().filter(|x| *x > 0 && *x <= MAX_POLL_HZ)
+                .ok_or_else(|| format!("The \"poll_hz\" setting must be a number from 1 to {}.", MAX_POLL_HZ))?;
+        }
+
+        // Autorepeat is true, false, or an object with a delay and interval.
+        let autorepeat = &settings["autorepeat"];
+        ans.output.autorepeat = match autorepeat.as_bool() {
+            Some(true) => Some(Autorepeat::new()),
+            Some(false) => None,
+            None if autorepeat.is_null() => None,
+            None if autorepeat.is_object() => {
+                let millis = |name: &str, default: Duration| match &autorepeat[name] {
+                    json::JsonValue::Null => Ok(default),
+                    v => v.as_u64().map(Duration::from_millis)
+                        .ok_or(format!("The \"autorepeat\" {} must be a number of milliseconds.", name))
+                };
+                let defaults = Autorepeat::new();
+                Some(Autorepeat {
+                    delay: millis("delay_ms", defaults.delay)?,
+                    interval: millis("interval_ms", defaults.interval)?,
+                })
+            }
+            None => return Err("The \"autorepeat\" setting must be true, false, or an object.".to_string())
+        };
+
+        // The output device's name and IDs.
+        let output = &settings["output"];
+        if !output["name"].is_null() {
+            ans.output.name = output["name"].as_str()
+                .ok_or("The output \"name\" setting must be a string.")?.to_string();
+        }
+        ans.output.vendor = read_device_id(output, "vendor")?;
+        ans.output.product = read_device_id(output, "product")?;
+        ans.output.bus = read_device_id(output, "bus")?;
+        ans.output.version = read_device_id(output, "version")?;
+        Ok(ans)
+    }
+}
+
+impl Default for DriverSettings {
+    fn default() -> DriverSettings {
+        DriverSettings::new()
+    }
+}
+
+/// Read a device ID, written as a number or a hex string (e.g. `"0x046d"`).
+fn read_device_id(output: &json::JsonValue, name: &str) -> Result<Option<u16>, String> {
+    let v = &output[name];
+    if v.is_null() {
+        return Ok(None);
+    }
+    let id = match v.as_str() {
+        Some(s) if s.starts_with("0x") => u16::from_str_radix(&s[2..], 16).ok(),
+        Some(s) => s.parse().ok(),
+        None => v.as_u16()
+    };
+    id.map(Some).ok_or_else(|| format!("The output \"{}\" setting must be a number from 0 to 0xffff.", name))
+}
+
+/// Everything needed to run the driver: the input device, settings, matrix, and layers.
+///
+/// A config can be one file with `device`, `matrix`, `layers`, `settings`, `aliases` and `combos` sections
+/// (`Config::load`), or separate matrix and layers files (`Config::from_files`).
+pub struct Config {
+    /// The path of the input keyboard (if the config names one).
+    pub device: Option<String>,
+    pub settings: DriverSettings,
+    pub matrix: VirtualKeyboardMatrix,
+    /// The layers document, including the config's `"settings"`, `"aliases"` and `"combos"`.
+    pub layers: LayoutDocument,
+}
+
+impl Config {
+    /// Load a config file. The matrix and layers can be written in the file, or be paths
+    /// (relative to the file) to separate matrix and layers files.
+    pub fn load(path: &str) -> Result<Config, SpaceCadetError> {
+        let document = LayoutDocument::load(path)?;
+        let config = &document.value;
+        let base_dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let relative = |v: &str| base_dir.join(v).to_string_lossy().to_string();
+
+        let device = match &config["device"] {
+            json::JsonValue::Null => None,
+            v => Some(v.as_str().ok_or_else(|| SpaceCadetError::json(path, "\"device\" must be a path."))?.to_string())
+        };
+
+        let matrix = match config["matrix"].as_str() {
+            Some(v) => VirtualKeyboardMatrix::load(&relative(v))?,
+            None if config["matrix"].is_array() => VirtualKeyboardMatrix::from_json(&config["matrix"], path)?,
+            None => return Err(SpaceCadetError::json(path, "\"matrix\" must be a path or a list of rows."))
+        };
+
+        let mut layers = match config["layers"].as_str() {
+            Some(v) => LayoutDocument::load(&relative(v))?,
+            None if config["layers"].is_object() => LayoutDocument {
+                value: config["layers"].clone(),
+                sources: document.sources.clone(),
+            },
+            None => return Err(SpaceCadetError::json(path, "\"layers\" must be a path or an object."))
+        };
+
+        // The config's settings apply unless the layers set them.
+        let mut value = json::JsonValue::new_object();
+        for i in ["settings", "aliases", "combos"].iter() {
+            if !config[*i].is_null() {
+                value[*i] = config[*i].clone();
+            }
+        }
+        merge_json(&mut value, layers.value);
+        layers.value = value;
+
+        Config::from_parts(device, matrix, layers)
+    }
+
+    /// Load separate matrix and layers files (the settings are read from the layers file).
+    pub fn from_files(matrix_path: &str, layers_path: &str) -> Result<Config, SpaceCadetError> {
+        let matrix = VirtualKeyboardMatrix::load(matrix_path)?;
+        let layers = LayoutDocument::load(layers_path)?;
+        Config::from_parts(None, matrix, layers)
+    }
+
+    fn from_parts(device: Option<String>, mut matrix: VirtualKeyboardMatrix, layers: LayoutDocument) -> Result<Config, SpaceCadetError> {
+        let settings = DriverSettings::from_json(&layers.value["settings"])
+            .map_err(|e| SpaceCadetError::json(layers.path(), e))?;
+
+        matrix.set_hold_duration(settings.keys.hold_threshold);
+        Ok(Config {
+            device,
+            settings,
+            matrix,
+            layers,
+        })
+    }
+
+    /// Create a driver with the config's matrix and layers, then verify the layers.
+    pub fn into_driver<I, O>(self, input: I, output: O, registry: &KeyRegistry) -> Result<KeyboardDriver<I, O>, SpaceCadetError>
+        where I: InputKeyboard, O: OutputKeyboard {
+        let driver = self.into_unverified_driver(input, output, registry)?;
+        driver.verify().map_err(SpaceCadetError::Constraint)?;
+        Ok(driver)
+    }
+
+    /// Create a driver with the config's matrix and layers, without verifying the layers
+    /// (e.g. to lint them).
+    pub fn into_unverified_driver<I, O>(self, input: I, output: O, registry: &KeyRegistry) -> Result<KeyboardDriver<I, O>, SpaceCadetError>
+        where I: InputKeyboard, O: OutputKeyboard {
+        let mut driver = KeyboardDriver::new(input, output, self.matrix);
+        driver.load_layout(&self.layers, registry)?;
+        Ok(driver)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_io_keyboard::*;
+
+    #[test]
+    fn driver_settings() {
+        let settings = DriverSettings::from_json(&json::parse(r#"{
+            "hold_ms": 150,
+            "poll_hz": 500,
+            "autorepeat": { "delay_ms": 300 },
+            "output": { "name": "my board", "vendor": "0x046d", "product": 49948 }
+        }"#).unwrap()).unwrap();
+        assert_eq!(settings.keys.hold_threshold, Duration::from_millis(150));
+        assert_eq!(settings.poll_hz, 500);
+        assert_eq!(settings.output.autorepeat, Some(Autorepeat { delay: Duration::from_millis(300), interval: Duration::from_millis(33) }));
+        assert_eq!(settings.output.name, "my board");
+        assert_eq!(settings.output.vendor, Some(0x046d));
+        assert_eq!(settings.output.product, Some(49948));
+        assert_eq!(settings.output.bus, None);
+        assert_eq!(DriverSettings::from_json(&json::JsonValue::Null).unwrap(), DriverSettings::new());
+
+        let bad = |v: &str| DriverSettings::from_json(&json::parse(v).unwrap()).is_err();
+        assert!(bad(r#"{ "poll_hz": 0 }"#));
+        assert!(bad(r#"{ "poll_hz": 2000 }"#));
+        assert_eq!(poll_interval(200), Duration::from_millis(5));
+        assert_eq!(poll_interval(MAX_POLL_HZ), Duration::from_millis(1));
+        assert!(poll_interval(300) > Duration::from_millis(3));
+        assert!(bad(r#"{ "autorepeat": "yes" }"#));
+        assert!(bad(r#"{ "output": { "vendor": 70000 } }"#));
+        assert!(bad(r#"{ "output": { "vendor": "0xnope" } }"#));
+    }
+
+    #[test]
+    fn load_config() {
+        let dir = std::env::temp_dir().join(format!("spacecadet_config_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let layers = r#"{ "layer_order": [ "base" ], "base": { "enabled": true, "keys": [ [ "HRM(KC_A)", "KC_B" ] ] } }"#;
+
+        // One file with every section.
+        write("config.json", &format!(r#"{{
+            "device": "/dev/input/event3",
+            "matrix": [ [ "KC_1", "KC_2" ] ],
+            "settings": {{ "hold_ms": 150, "output": {{ "name": "my board" }} }},
+            "aliases": {{ "HRM(key)": "MT(KC_LEFTCTRL,key)" }},
+            "layers": {}
+        }}"#, layers));
+        let config = Config::load(&path("config.json")).unwrap();
+        assert_eq!(config.device, Some("/dev/input/event3".to_string()));
+        assert_eq!(config.settings.output.name, "my board");
+        let driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new()).unwrap();
+        assert_eq!(driver.layered_codes[0].codes[0][0].to_key_syntax().unwrap(), "MT(KC_LEFTCTRL,KC_A,200,false)");
+
+        // The same config, split into separate files.
+        write("matrix.json", r#"{ "matrix": [ [ "KC_1", "KC_2" ] ] }"#);
+        write("layers.json", &layers.replace("HRM(KC_A)", "KC_A"));
+        write("split.json", r#"{ "matrix": "matrix.json", "layers": "layers.json", "settings": { "poll_hz": 100 } }"#);
+        let config = Config::load(&path("split.json")).unwrap();
+        assert_eq!(config.device, None);
+        assert_eq!(config.settings.poll_hz, 100);
+        assert!(config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new()).is_ok());
+
+        // The legacy two-file form.
+        let config = Config::from_files(&path("matrix.json"), &path("layers.json")).unwrap();
+        assert_eq!(config.settings, DriverSettings::new());
+        assert!(config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new()).is_ok());
+
+        // Combos apply to the layers, too.
+        write("combos.json", r#"{ "matrix": "matrix.json", "layers": "layers.json", "combos": { "KC_1+KC_2": "KC_ESC" } }"#);
+        let driver = Config::load(&path("combos.json")).unwrap()
+            .into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new()).unwrap();
+        assert_eq!(driver.combos.combos[0].positions, vec![(0, 0), (0, 1)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+use crate::input_keyboard::InputKeyboard;
+use crate::keyboard_driver::KeyboardDriver;
+use crate::keys::describe_key_code;
+use crate::output_keyboard::OutputKeyboard;
+use crate::parser::ParsedKeyTree;
+
+/// Short labels for keys with long names (e.g. `KC_BACKSPACE` is `Bspc`).
+const KEY_LABELS: &[(&str, &str)] = &[
+    ("KC_SPACE", "Spc"), ("KC_ENTER", "Ent"), ("KC_BACKSPACE", "Bspc"), ("KC_DELETE", "Del"), ("KC_INSERT", "Ins"),
+    ("KC_CAPSLOCK", "Caps"), ("KC_PAGEUP", "PgUp"), ("KC_PAGEDOWN", "PgDn"), ("KC_SYSRQ", "PrtSc"),
+    ("KC_LEFTSHIFT", "Shift"), ("KC_RIGHTSHIFT", "RShift"), ("KC_LEFTCTRL", "Ctrl"), ("KC_RIGHTCTRL", "RCtrl"),
+    ("KC_LEFTALT", "Alt"), ("KC_RIGHTALT", "RAlt"), ("KC_LEFTMETA", "Meta"), ("KC_RIGHTMETA", "RMeta"),
+    ("KC_MINUS", "-"), ("KC_EQUAL", "="), ("KC_LEFTBRACE", "["), ("KC_RIGHTBRACE", "]"), ("KC_BACKSLASH", "\\"),
+    ("KC_SEMICOLON", ";"), ("KC_APOSTROPHE", "'"), ("KC_GRAVE", "`"), ("KC_COMMA", ","), ("KC_DOT", "."),
+    ("KC_SLASH", "/"), ("KC_LEFT", "←"), ("KC_RIGHT", "→"), ("KC_UP", "↑"), ("KC_DOWN", "↓"),
+];
+
+/// Labels for shifted keys (e.g. shift + `KC_9` is `(`).
+const SHIFTED_LABELS: &[(&str, &str)] = &[
+    ("KC_GRAVE", "~"), ("KC_1", "!"), ("KC_2", "@"), ("KC_3", "#"), ("KC_4", "$"), ("KC_5", "%"), ("KC_6", "^"),
+    ("KC_7", "&"), ("KC_8", "*"), ("KC_9", "("), ("KC_0", ")"), ("KC_MINUS", "_"), ("KC_EQUAL", "+"),
+    ("KC_LEFTBRACE", "{"), ("KC_RIGHTBRACE", "}"), ("KC_BACKSLASH", "|"), ("KC_SEMICOLON", ":"),
+    ("KC_APOSTROPHE", "\""), ("KC_COMMA", "<"), ("KC_DOT", ">"), ("KC_SLASH", "?"),
+];
+
+/// Explains the legends, printed with every diagram.
+const LEGEND_HELP: &str = "Top: tapped, bottom: held. →name switches to a layer. Blank keys use the layer below.";
+
+fn lookup<'a>(table: &'a [(&str, &str)], name: &str) -> Option<&'a str> {
+    table.iter().find(|x| x.0 == name).map(|x| x.1)
+}
+
+
+/// What's printed on a key in a keymap diagram: what it does when tapped, and when held.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyLegend {
+    pub tap: String,
+    pub hold: String,
+    /// The layer the key switches to (if any).
+    pub layer: Option<String>,
+}
+
+impl KeyLegend {
+    /// Describe a key from its key syntax (e.g. `LT(nav,KC_SPACE)` is tapped as `Spc`, held as `→nav`).
+    pub fn from_key_syntax(v: &str) -> KeyLegend {
+        let tree = match ParsedKeyTree::create(v) {
+            Ok(v) => v,
+            Err(_) => return KeyLegend { tap: v.to_string(), hold: String::new(), layer: None }
+        };
+        let arg = |idx: usize| tree.args.get(idx).map(key_label).unwrap_or_default();
+        let layer = tree.args.first().map(|x| x.identifier.clone());
+        let legend = |tap: String, hold: String| KeyLegend { tap, hold, layer: None };
+        match tree.identifier.as_str() {
+            "MO" => KeyLegend { tap: String::new(), hold: format!("→{}", layer.clone().unwrap_or_default()), layer },
+            "TG" | "OSL" | "AL" => KeyLegend {
+                tap: format!("{}→{}", tree.identifier, layer.clone().unwrap_or_default()),
+                hold: String::new(),
+                layer
+            },
+            "LT" => KeyLegend { tap: arg(1), hold: format!("→{}", layer.clone().unwrap_or_default()), layer },
+            "MT" => legend(arg(1), arg(0)),
+            "SPACECADET" => legend(arg(0), arg(1)),
+            _ => legend(key_label(&tree), String::new()),
+        }
+    }
+}
+
+/// A short label for a key (e.g. `Bspc`, `Ctrl+C` or `(`).
+fn key_label(tree: &ParsedKeyTree) -> String {
+    let name = tree.identifier.as_str();
+    match name {
+        _ if tree.args.is_empty() && name.len() > 1 && name.chars().all(|x| x == '_') => String::new(),
+        "TRANSPARENT" => String::new(),
+        _ if tree.args.is_empty() && name.len() > 1 && name.chars().all(|x| x == 'X') => "XXX".to_string(),
+        "OPAQUE" => "XXX".to_string(),
+        "LLOCK" => "Lock".to_string(),
+        "MACRO" => "Macro".to_string(),
+        "STENO" => tree.args.first().map(|x| x.identifier.clone()).unwrap_or_default(),
+        "WRAP" if tree.args.len() == 2 => {
+            let (outside, inside) = (&tree.args[0].identifier, &tree.args[1]);
+            match lookup(SHIFTED_LABELS, &inside.identifier) {
+                Some(v) if outside == "KC_LEFTSHIFT" || outside == "KC_RIGHTSHIFT" => v.to_string(),
+                _ => format!("{}+{}", key_label(&tree.args[0]), key_label(inside))
+            }
+        }
+        _ if tree.args.is_empty() && (name.starts_with("KC_") || name.starts_with("KEY_")) => {
+            let canonical = name.replacen("KEY_", "KC_", 1);
+            match lookup(KEY_LABELS, &canonical) {
+                Some(v) => v.to_string(),
+                None => {
+                    // KC_HOME is Home, KC_F1 is F1.
+                    let rest = &canonical[3..];
+                    let mut chars = rest.chars();
+                    chars.next().map(|x| x.to_string() + &chars.as_str().to_lowercase()).unwrap_or_default()
+                }
+            }
+        }
+        _ => name.to_string()
+    }
+}
+
+/// The legends of every key in a layer, by matrix position. Positions without a key are `None`.
+pub fn layer_legends<I, O>(driver: &KeyboardDriver<I, O>, layer: usize) -> Vec<Vec<Option<KeyLegend>>>
+    where I: InputKeyboard, O: OutputKeyboard {
+    driver.layered_codes[layer].codes.iter().enumerate().map(|(r, row)| {
+        row.iter().enumerate().map(|(c, code)| {
+            driver.matrix.key_at((r, c)).map(|_| KeyLegend::from_key_syntax(&describe_key_code(code.as_ref())))
+        }).collect()
+    }).collect()
+}
+
+/// Draw every layer as ASCII art, laid out like the matrix.
+///
+/// ```text
+/// base
+/// +------+------+
+/// | Esc  | Spc  |
+/// |      | →nav |
+/// +------+------+
+/// ```
+pub fn keymap_ascii<I, O>(driver: &KeyboardDriver<I, O>) -> String
+    where I: InputKeyboard, O: OutputKeyboard {
+    let layers: Vec<_> = (0..driver.layered_codes.len()).map(|x| layer_legends(driver, x)).collect();
+    let width = 2 + layers.iter().flat_map(|x| x.iter()).flat_map(|x| x.iter()).flatten()
+        .map(|x| std::cmp::max(x.tap.chars().count(), x.hold.chars().count()))
+        .fold(3, std::cmp::max);
+
+    let mut ans = format!("{}\n", LEGEND_HELP);
+    for (attr, legends) in driver.layer_attributes.attributes.iter().zip(layers.iter()) {
+        ans += &format!("\n{}\n", attr.name);
+        let has_key = |r: usize, c: usize| -> bool {
+            matches!(legends.get(r).and_then(|x| x.get(c)), Some(Some(_)))
+        };
+        let cols = legends.iter().map(|x| x.len()).max().unwrap_or(0);
+        for r in 0..=legends.len() {
+            // The border above row r (and below row r - 1).
+            let above = |c: usize| r > 0 && has_key(r - 1, c);
+            let mut line = String::new();
+            for c in 0..=cols {
+                let left = c > 0 && (has_key(r, c - 1) || above(c - 1));
+                let right = has_key(r, c) || above(c);
+                line.push(if left || right { '+' } else { ' ' });
+                if c < cols {
+                    let fill = if right { "-" } else { " " };
+                    line += &fill.repeat(width);
+                }
+            }
+            ans += line.trim_end();
+            ans.push('\n');
+            if r == legends.len() {
+                break;
+            }
+
+            let texts: [fn(&KeyLegend) -> String; 2] = [|x| x.tap.clone(), |x| x.hold.clone()];
+            for text in texts.iter() {
+                let mut line = String::new();
+                for c in 0..=cols {
+                    let edge = has_key(r, c) || (c > 0 && has_key(r, c - 1));
+                    line.push(if edge { '|' } else { ' ' });
+                    if c < cols {
+                        let label = legends[r].get(c).cloned().flatten().map(|x| text(&x)).unwrap_or_default();
+                        line += &format!(" {}{}", label, " ".repeat(width - 1 - label.chars().count()));
+                    }
+                }
+                ans += line.trim_end();
+                ans.push('\n');
+            }
+        }
+    }
+    ans
+}
+
+/// Draw every layer as an SVG image, laid out like the matrix. Keys that switch layers are
+/// highlighted, and keys that use the layer below are drawn faded.
+pub fn keymap_svg<I, O>(driver: &KeyboardDriver<I, O>) -> String
+    where I: InputKeyboard, O: OutputKeyboard {
+    const KEY: usize = 56;
+    const GAP: usize = 4;
+    const TITLE: usize = 28;
+    let layers: Vec<_> = (0..driver.layered_codes.len()).map(|x| layer_legends(driver, x)).collect();
+    let (rows, cols) = driver.matrix.dim();
+    let layer_height = TITLE + rows * (KEY + GAP) + GAP;
+    // Leave room for the help text (about 6 pixels per character).
+    let width = std::cmp::max(cols * (KEY + GAP) + GAP, LEGEND_HELP.chars().count() * 6);
+    let height = TITLE + layers.len() * layer_height;
+
+    let mut ans = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"sans-serif\">\n", width, height);
+    ans += &format!("  <text x=\"{}\" y=\"18\" font-size=\"11\" fill=\"#555\">{}</text>\n", GAP, escape_xml(LEGEND_HELP));
+    for (idx, (attr, legends)) in driver.layer_attributes.attributes.iter().zip(layers.iter()).enumerate() {
+        let top = TITLE + idx * layer_height;
+        ans += &format!("  <g transform=\"translate(0,{})\">\n", top);
+        ans += &format!("    <text x=\"{}\" y=\"20\" font-size=\"16\" font-weight=\"bold\">{}</text>\n", GAP, escape_xml(&attr.name));
+        for (r, row) in legends.iter().enumerate() {
+            for (c, legend) in row.iter().enumerate() {
+                let legend = match legend {
+                    Some(v) => v,
+                    None => continue
+                };
+                let (x, y) = (GAP + c * (KEY + GAP), TITLE + r * (KEY + GAP));
+                let blank = legend.tap.is_empty() && legend.hold.is_empty();
+                let fill = if legend.layer.is_some() { "#dbe8ff" } else if blank { "#f7f7f7" } else { "#ffffff" };
+                ans += &format!("    <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"5\" fill=\"{}\" stroke=\"#888\"/>\n",
+                                x, y, KEY, KEY, fill);
+                ans += &format!("    <text x=\"{}\" y=\"{}\" font-size=\"13\" text-anchor=\"middle\">{}</text>\n",
+                                x + KEY / 2, y + 24, escape_xml(&legend.tap));
+                ans += &format!("    <text x=\"{}\" y=\"{}\" font-size=\"10\" text-anchor=\"middle\" fill=\"#2255aa\">{}</text>\n",
+                                x + KEY / 2, y + KEY - 10, escape_xml(&legend.hold));
+            }
+        }
+        ans += "  </g>\n";
+    }
+    ans += "</svg>\n";
+    ans
+}
+
+fn escape_xml(v: &str) -> String {
+    v.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_io_keyboard::*;
+    use crate::{KeyCodeMatrix, KeyRegistry, LayerAttributes, SimpleKey, VirtualKeyboardMatrix};
+
+    #[test]
+    fn key_legends() {
+        let legend = |v: &str| {
+            let ans = KeyLegend::from_key_syntax(v);
+            (ans.tap, ans.hold, ans.layer)
+        };
+        let s = |v: &str| v.to_string();
+        assert_eq!(legend("KC_BACKSPACE"), (s("Bspc"), s(""), None));
+        assert_eq!(legend("KC_HOME"), (s("Home"), s(""), None));
+        assert_eq!(legend("___"), (s(""), s(""), None));
+        assert_eq!(legend("LT(nav,KC_SPACE,200)"), (s("Spc"), s("→nav"), Some(s("nav"))));
+        assert_eq!(legend("MO(nav)"), (s(""), s("→nav"), Some(s("nav"))));
+        assert_eq!(legend("TG(nav)"), (s("TG→nav"), s(""), Some(s("nav"))));
+        assert_eq!(legend("MT(KC_LEFTCTRL,KC_A,180,false)"), (s("A"), s("Ctrl"), None));
+        assert_eq!(legend("SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT)"), (s("("), s("Shift"), None));
+        assert_eq!(legend("WRAP(KC_LEFTCTRL,KC_C)"), (s("Ctrl+C"), s(""), None));
+    }
+
+    #[test]
+    fn keymap_diagrams() {
+        let registry = KeyRegistry::new();
+        let mut driver = KeyboardDriver::new(TestInputKeyboard::new(), TestOutputKeyboard::new(),
+            VirtualKeyboardMatrix::new(vec![
+                vec![Some(SimpleKey::KEY_1), Some(SimpleKey::KEY_2), None],
+                vec![Some(SimpleKey::KEY_3), Some(SimpleKey::KEY_4), Some(SimpleKey::KEY_5)],
+            ], None));
+        let mut base = KeyCodeMatrix::new((2, 3));
+        base.codes[0][0] = registry.parse("KC_ESC").unwrap();
+        base.codes[0][1] = registry.parse("LT(nav,KC_SPACE)").unwrap();
+        base.codes[1][2] = registry.parse("MT(KC_LEFTCTRL,KC_A)").unwrap();
+        driver.add_layer(LayerAttributes { name: "base".to_string(), enabled: true, timeout: None }, base);
+
+        let expected = [
+            LEGEND_HELP,
+            "",
+            "base",
+            "+------+------+",
+            "| Esc  | Spc  |",
+            "|      | →nav |",
+            "+------+------+------+",
+            "|      |      | A    |",
+            "|      |      | Ctrl |",
+            "+------+------+------+",
+            "",
+        ].join("\n");
+        assert_eq!(keymap_ascii(&driver), expected);
+
+        let svg = keymap_svg(&driver);
+        assert!(svg.starts_with("<svg "));
+        assert_eq!(svg.matches("<rect ").count(), 5);
+        assert!(svg.contains(">→nav</text>"));
+    }
+
+    #[test]
+    fn example_keyboard_diagrams() {
+        // The docs show each example keyboard's keymap.svg, so they must match their layouts.
+        let keyboards = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../keyboards");
+        for dir in std::fs::read_dir(keyboards).unwrap() {
+            let dir = dir.unwrap().path();
+            let file = |name: &str| dir.join(name).to_string_lossy().to_string();
+            let config = crate::Config::from_files(&file("matrix.json"), &file("layers.json")).unwrap();
+            let driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new()).unwrap();
+            let committed = std::fs::read_to_string(file("keymap.svg")).unwrap();
+            assert!(committed == format!("{}\n", keymap_svg(&driver).trim_end()),
+                "{} is out of date; run `make keymaps` in docs/.", file("keymap.svg"));
+        }
+    }
+}
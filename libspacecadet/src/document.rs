@@ -0,0 +1,438 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use crate::error::SpaceCadetError;
+
+/// The text of a file that was read into a layout document.
+#[derive(Debug, Clone)]
+pub struct LayoutSource {
+    pub path: String,
+    pub contents: String,
+}
+
+impl LayoutSource {
+    /// Find where a key in a layer is written, as a (line, column) pair (both starting at 1) of a
+    /// character in the key (counted from 1, so escapes in the file don't move it).
+    ///
+    /// Only JSON files are walked, since the other formats don't keep their positions once parsed.
+    pub fn locate_key(&self, layer: &str, entry: &KeyEntry, character: usize) -> Option<(usize, usize)> {
+        if !is_json_path(&self.path) {
+            return None;
+        }
+        let mut steps = vec![JsonStep::Key(layer), JsonStep::Key("keys")];
+        match entry {
+            KeyEntry::Row(r, c) => steps.extend_from_slice(&[JsonStep::Index(*r), JsonStep::Index(*c)]),
+            KeyEntry::Named(position) => steps.push(JsonStep::Key(position)),
+        }
+        // The layer can also be in the "layers" of a config file.
+        let start = find_json_value(&self.contents, &steps).or_else(|| {
+            steps.insert(0, JsonStep::Key("layers"));
+            find_json_value(&self.contents, &steps)
+        })?;
+        if self.contents.as_bytes()[start] != b'"' {
+            return None;
+        }
+        let offset = json_string_offset(&self.contents, start, character)?;
+        let line_start = self.contents[..offset].rfind('\n').map(|x| x + 1).unwrap_or(0);
+        Some((self.contents[..offset].matches('\n').count() + 1, self.contents[line_start..offset].chars().count() + 1))
+    }
+}
+
+/// A layout (layers or matrix) file, merged with any files it includes.
+///
+/// Files are read as TOML (`.toml`), YAML (`.yaml` or `.yml`) or JSON (anything else),
+/// and are always converted to JSON - so every format has the same structure.
+///
+/// A file can `"include"` a path or a list of paths (relative to the file). Included files are
+/// merged in order, then the file itself is merged on top. Objects are merged key-by-key, while
+/// anything else (e.g. a list of rows) replaces what came before.
+#[derive(Debug)]
+pub struct LayoutDocument {
+    pub value: json::JsonValue,
+    /// Every file that was read, in the order they were merged (the loaded file is last).
+    pub sources: Vec<LayoutSource>,
+}
+
+impl LayoutDocument {
+    /// Read a layout file and everything it includes.
+    pub fn load(path: &str) -> Result<LayoutDocument, SpaceCadetError> {
+        let mut ans = LayoutDocument {
+            value: json::JsonValue::new_object(),
+            sources: Vec::new(),
+        };
+        ans.value = ans.read(path, &mut Vec::new())?;
+        Ok(ans)
+    }
+
+    /// The path of the loaded file (i.e. not an included file).
+    pub fn path(&self) -> &str {
+        &self.sources.last().unwrap().path
+    }
+
+    /// Find the file that wrote a key in a layer. Later files override earlier ones, so they're
+    /// searched first. If no file has the key (e.g. it's in a layer's `"extends"`), it's the loaded file.
+    pub fn source_of(&self, layer: &str, entry: &KeyEntry) -> &LayoutSource {
+        self.sources.iter().rev()
+            .find(|x| parse_layout(&x.path, &x.contents).map(|v| {
+                // The layer can also be in the "layers" of a config file.
+                let keys = if v[layer].is_null() { &v["layers"][layer]["keys"] } else { &v[layer]["keys"] };
+                match entry {
+                    KeyEntry::Row(r, c) => keys.is_array() && !keys[*r][*c].is_null(),
+                    KeyEntry::Named(position) => keys.is_object() && keys.has_key(position),
+                }
+            }).unwrap_or(false))
+            .unwrap_or_else(|| self.sources.last().unwrap())
+    }
+
+    fn read(&mut self, path: &str, stack: &mut Vec<PathBuf>) -> Result<json::JsonValue, SpaceCadetError> {
+        // Includes are compared by their canonical path, so "a/../b.json" and "b.json" are the same file.
+        let canonical = Path::new(path).canonicalize().map_err(|e| SpaceCadetError::io(path, e))?;
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack.iter().chain(Some(&canonical)).map(|x| x.display().to_string()).collect();
+            return Err(SpaceCadetError::json(path, format!("The file includes itself: {}", chain.join(" -> "))));
+        }
+
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut x| x.read_to_string(&mut contents))
+            .map_err(|e| SpaceCadetError::io(path, e))?;
+        let mut document = parse_layout(path, &contents).map_err(|e| SpaceCadetError::json(path, e))?;
+
+        // Merge the included files (relative to this one), then this file.
+        let includes = match document.remove("include") {
+            json::JsonValue::Null => Vec::new(),
+            v if v.is_array() => v.members().map(|x| x.as_str().map(|x| x.to_string())).collect::<Option<Vec<_>>>()
+                .ok_or_else(|| SpaceCadetError::json(path, "\"include\" must be a path or a list of paths."))?,
+            v => vec![v.as_str().map(|x| x.to_string())
+                .ok_or_else(|| SpaceCadetError::json(path, "\"include\" must be a path or a list of paths."))?]
+        };
+        let base_dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let mut ans = json::JsonValue::new_object();
+        stack.push(canonical);
+        for i in includes {
+            let included = base_dir.join(i);
+            let included = self.read(&included.to_string_lossy(), stack)?;
+            merge_json(&mut ans, included);
+        }
+        stack.pop();
+        merge_json(&mut ans, document);
+
+        self.sources.push(LayoutSource {
+            path: path.to_string(),
+            contents
+        });
+        Ok(ans)
+    }
+}
+
+/// Where a key is written in a layer's `"keys"`: a row and column in a list of rows, or a
+/// position in an object of keys (as written, e.g. `KC_H` or `2,3`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyEntry {
+    Row(usize, usize),
+    Named(String),
+}
+
+impl fmt::Display for KeyEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyEntry::Row(r, c) => write!(f, "{},{}", r, c),
+            KeyEntry::Named(position) => write!(f, "{}", position),
+        }
+    }
+}
+
+/// A step into a JSON value: a key in an object, or an index in a list.
+#[derive(Clone, Copy)]
+enum JsonStep<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// Find where a value is written in JSON text by following the steps from the top-level value.
+/// Returns the byte offset of the value's first character.
+fn find_json_value(contents: &str, steps: &[JsonStep]) -> Option<usize> {
+    let bytes = contents.as_bytes();
+    let skip_whitespace = |mut i: usize| {
+        while bytes.get(i).map(|x| x.is_ascii_whitespace()).unwrap_or(false) {
+            i += 1;
+        }
+        i
+    };
+    let mut pos = skip_whitespace(0);
+    for step in steps {
+        match (bytes.get(pos), step) {
+            (Some(b'{'), JsonStep::Key(key)) => {
+                // If a key is written twice, the last one is used (as it is when the file is parsed).
+                let mut found = None;
+                pos = skip_whitespace(pos + 1);
+                while bytes.get(pos) == Some(&b'"') {
+                    let end = skip_json_value(bytes, pos)?;
+                    let name = json::parse(&contents[pos..end]).ok()?;
+                    pos = skip_whitespace(end);
+                    if bytes.get(pos) != Some(&b':') {
+                        return None;
+                    }
+                    pos = skip_whitespace(pos + 1);
+                    if name.as_str() == Some(*key) {
+                        found = Some(pos);
+                    }
+                    pos = skip_whitespace(skip_json_value(bytes, pos)?);
+                    if bytes.get(pos) == Some(&b',') {
+                        pos = skip_whitespace(pos + 1);
+                    }
+                }
+                pos = found?;
+            }
+            (Some(b'['), JsonStep::Index(idx)) => {
+                pos = skip_whitespace(pos + 1);
+                for _ in 0..*idx {
+                    pos = skip_whitespace(skip_json_value(bytes, pos)?);
+                    if bytes.get(pos) != Some(&b',') {
+                        return None;
+                    }
+                    pos = skip_whitespace(pos + 1);
+                }
+                if bytes.get(pos) == Some(&b']') {
+                    return None;
+                }
+            }
+            _ => return None
+        }
+    }
+    Some(pos)
+}
+
+/// Skip over a JSON value that starts at `pos`. Returns the byte offset just past its end.
+fn skip_json_value(bytes: &[u8], pos: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = pos;
+    loop {
+        match *bytes.get(i)? {
+            b'"' => {
+                i += 1;
+                while *bytes.get(i)? != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth > 0 => depth -= 1,
+            // The end of a number, `true`, `false` or `null`.
+            c if depth == 0 && (c == b',' || c == b'}' || c == b']' || c.is_ascii_whitespace()) => return Some(i),
+            _ => ()
+        }
+        i += 1;
+        if depth == 0 && (bytes[i - 1] == b'"' || bytes[i - 1] == b'}' || bytes[i - 1] == b']') {
+            return Some(i);
+        }
+    }
+}
+
+/// Find the byte offset of a character (counted from 1) in a JSON string that starts at `pos`
+/// (i.e. at its opening quote). Escapes count as the one character they stand for.
+fn json_string_offset(contents: &str, pos: usize, character: usize) -> Option<usize> {
+    let bytes = contents.as_bytes();
+    let mut i = pos + 1;
+    for _ in 1..character {
+        i += match (*bytes.get(i)?, bytes.get(i + 1)) {
+            (b'"', _) => return None,
+            (b'\\', Some(b'u')) => {
+                // A surrogate pair is written as two escapes, but it's one character.
+                let unit = u16::from_str_radix(contents.get(i + 2..i + 6)?, 16).ok()?;
+                if (0xD800..0xDC00).contains(&unit) { 12 } else { 6 }
+            }
+            (b'\\', _) => 2,
+            _ => contents[i..].chars().next()?.len_utf8()
+        };
+    }
+    Some(i)
+}
+
+/// Whether a layout file is read as JSON (see `parse_layout`).
+fn is_json_path(path: &str) -> bool {
+    let extension = Path::new(path).extension().and_then(|x| x.to_str()).unwrap_or("").to_lowercase();
+    !matches!(extension.as_str(), "toml" | "yaml" | "yml")
+}
+
+/// Parse a layout file's text, choosing the format by the file's extension.
+fn parse_layout(path: &str, contents: &str) -> Result<json::JsonValue, String> {
+    let extension = Path::new(path).extension().and_then(|x| x.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "toml" => {
+            let value: toml::Value = contents.parse().map_err(|e: toml::de::Error| e.to_string())?;
+            Ok(toml_to_json(value))
+        }
+        "yaml" | "yml" => {
+            let documents = yaml_rust::YamlLoader::load_from_str(contents).map_err(|e| e.to_string())?;
+            match documents.into_iter().next() {
+                Some(v) => yaml_to_json(v),
+                None => Ok(json::JsonValue::new_object())
+            }
+        }
+        _ => json::parse(contents).map_err(|e| e.to_string())
+    }
+}
+
+fn toml_to_json(value: toml::Value) -> json::JsonValue {
+    match value {
+        toml::Value::String(v) => v.into(),
+        toml::Value::Integer(v) => v.into(),
+        toml::Value::Float(v) => v.into(),
+        toml::Value::Boolean(v) => v.into(),
+        toml::Value::Datetime(v) => v.to_string().into(),
+        toml::Value::Array(v) => json::JsonValue::Array(v.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(v) => {
+            let mut ans = json::JsonValue::new_object();
+            for (k, v) in v {
+                ans[k.as_str()] = toml_to_json(v);
+            }
+            ans
+        }
+    }
+}
+
+fn yaml_to_json(value: yaml_rust::Yaml) -> Result<json::JsonValue, String> {
+    use yaml_rust::Yaml;
+    Ok(match value {
+        Yaml::String(v) => v.into(),
+        Yaml::Integer(v) => v.into(),
+        Yaml::Real(v) => v.parse::<f64>().map_err(|_| format!("{} isn't a number", v))?.into(),
+        Yaml::Boolean(v) => v.into(),
+        Yaml::Null => json::JsonValue::Null,
+        Yaml::Array(v) => json::JsonValue::Array(v.into_iter().map(yaml_to_json).collect::<Result<_, _>>()?),
+        Yaml::Hash(v) => {
+            let mut ans = json::JsonValue::new_object();
+            for (k, v) in v {
+                // Keys like "2,3" are strings, but keys like 1 or true need converting.
+                let k = match k {
+                    Yaml::String(k) => k,
+                    Yaml::Integer(k) => k.to_string(),
+                    Yaml::Real(k) => k,
+                    Yaml::Boolean(k) => k.to_string(),
+                    k => return Err(format!("{:?} can't be used as a key", k))
+                };
+                ans[k.as_str()] = yaml_to_json(v)?;
+            }
+            ans
+        }
+        Yaml::Alias(_) | Yaml::BadValue => return Err("the document has an unsupported or invalid value".to_string())
+    })
+}
+
+/// Merge one JSON value on top of another. Objects are merged key-by-key; anything else is replaced.
+pub fn merge_json(base: &mut json::JsonValue, over: json::JsonValue) {
+    if base.is_object() && over.is_object() {
+        for (k, v) in over.entries() {
+            merge_json(&mut base[k], v.clone());
+        }
+    } else {
+        *base = over;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_document_formats() {
+        let json = r#"{
+            "layer_order": [ "base" ],
+            "settings": { "hold_ms": 150 },
+            "base": { "enabled": true, "keys": { "KC_1": "LT(nav,KC_A)", "0,1": "KC_B" } }
+        }"#;
+        let toml = r#"
+            layer_order = [ "base" ]
+            settings = { hold_ms = 150 }
+
+            # The base layer.
+            [base]
+            enabled = true
+            keys = { KC_1 = "LT(nav,KC_A)", "0,1" = "KC_B" }
+        "#;
+        let yaml = "
+layer_order: [ base ]
+settings: { hold_ms: 150 }
+base:
+  enabled: true
+  keys:
+    KC_1: LT(nav,KC_A)  # Space doubles as a layer key.
+    0,1: 'KC_B'
+";
+        let expected = parse_layout("layers.json", json).unwrap();
+        assert_eq!(parse_layout("layers.toml", toml).unwrap(), expected);
+        assert_eq!(parse_layout("layers.yaml", yaml).unwrap(), expected);
+        assert!(parse_layout("layers.yml", "base: [").is_err());
+        assert!(parse_layout("layers.toml", "base = ").is_err());
+    }
+
+    #[test]
+    fn layout_document_includes() {
+        let dir = std::env::temp_dir().join(format!("spacecadet_include_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+
+        // Later definitions override earlier ones; objects are merged.
+        write("shared/symbols.json", r#"{ "symbols": { "enabled": false, "keys": [ [ "KC_1" ] ] }, "idle_timeout_ms": 5 }"#);
+        write("shared/settings.json", r#"{ "settings": { "hold_ms": 100, "tapping_term_ms": 150 } }"#);
+        write("main.json", r#"{
+            "include": [ "shared/symbols.json", "shared/settings.json" ],
+            "symbols": { "enabled": true },
+            "settings": { "hold_ms": 120 }
+        }"#);
+        let document = LayoutDocument::load(&path("main.json")).unwrap();
+        let expected = json::parse(r#"{
+            "symbols": { "enabled": true, "keys": [ [ "KC_1" ] ] },
+            "idle_timeout_ms": 5,
+            "settings": { "hold_ms": 120, "tapping_term_ms": 150 }
+        }"#).unwrap();
+        assert_eq!(document.value, expected);
+        assert_eq!(document.sources.len(), 3);
+        assert_eq!(document.source_of("symbols", &KeyEntry::Row(0, 0)).path, path("shared/symbols.json"));
+        assert_eq!(document.source_of("symbols", &KeyEntry::Row(0, 1)).path, path("main.json"));
+
+        // Includes can't form a cycle.
+        write("a.json", r#"{ "include": "shared/../b.json" }"#);
+        write("b.json", r#"{ "include": "a.json" }"#);
+        let e = LayoutDocument::load(&path("a.json")).unwrap_err();
+        assert!(e.to_string().contains("The file includes itself"));
+
+        // Missing includes are reported by their path.
+        write("c.json", r#"{ "include": "missing.json" }"#);
+        let e = LayoutDocument::load(&path("c.json")).unwrap_err();
+        assert!(e.to_string().starts_with(&path("missing.json")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn locate_keys_in_json() {
+        let source = |path: &str, contents: &str| LayoutSource { path: path.to_string(), contents: contents.to_string() };
+        let layers = source("layers.json", r#"{
+  "base": { "keys": [ [ 1, "KC_A", "LT(nav, KC_B)" ],
+                      [ "\"", "\ud83d\ude00x" ] ] },
+  "nav": { "keys": { "KC_A": "KC_1" }, "keys": { "KC_A": "KC_2" } }
+}"#);
+        assert_eq!(layers.locate_key("base", &KeyEntry::Row(0, 1), 1), Some((2, 29)));
+        assert_eq!(layers.locate_key("base", &KeyEntry::Row(0, 2), 9), Some((2, 45)));
+        assert_eq!(layers.locate_key("base", &KeyEntry::Row(1, 0), 1), Some((3, 26)));
+        assert_eq!(layers.locate_key("base", &KeyEntry::Row(1, 1), 2), Some((3, 44)));
+
+        // The last of a repeated key is the one that's parsed.
+        assert_eq!(layers.locate_key("nav", &KeyEntry::Named("KC_A".to_string()), 1), Some((4, 59)));
+
+        // Keys that aren't strings, or aren't there, have no position.
+        assert_eq!(layers.locate_key("base", &KeyEntry::Row(0, 0), 1), None);
+        assert_eq!(layers.locate_key("base", &KeyEntry::Row(0, 3), 1), None);
+        assert_eq!(layers.locate_key("base", &KeyEntry::Row(2, 0), 1), None);
+        assert_eq!(layers.locate_key("other", &KeyEntry::Row(0, 0), 1), None);
+
+        // Layers can be in a config file, but other formats aren't walked.
+        let config = source("config.json", r#"{ "layers": { "base": { "keys": [ [ "KC_A" ] ] } } }"#);
+        assert_eq!(config.locate_key("base", &KeyEntry::Row(0, 0), 1), Some((1, 38)));
+        let yaml = source("layers.yaml", "base:\n  keys: [ [ KC_A ] ]\n");
+        assert_eq!(yaml.locate_key("base", &KeyEntry::Row(0, 0), 1), None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io;
+
+/// An error from loading a layout or running the driver.
+#[derive(Debug)]
+pub enum SpaceCadetError {
+    /// A file couldn't be read or written.
+    Io { path: String, error: io::Error },
+    /// A file isn't valid JSON, or is missing something the driver needs (e.g. a layer's `"enabled"`).
+    Json { path: String, message: String },
+    /// A key couldn't be parsed or converted. The position is the (line, column) in the file, if it's
+    /// known (i.e. JSON files). Otherwise, the location is where the key is in the layers (e.g. `layer "base",
+    /// key 0,1`). The excerpt (if any) shows the offending line or key with a caret.
+    Syntax { path: String, position: Option<(usize, usize)>, location: String, message: String, excerpt: Option<String> },
+    /// The layers break a key constraint (e.g. a key references a missing layer).
+    Constraint(String),
+    /// An input or output device failed.
+    Device(String),
+    /// The command line is wrong (e.g. an out-of-range option).
+    Usage(String),
+}
+
+impl SpaceCadetError {
+    /// Create an error for a file that couldn't be read or written.
+    pub fn io(path: &str, error: io::Error) -> SpaceCadetError {
+        SpaceCadetError::Io { path: path.to_string(), error }
+    }
+
+    /// Create an error for a file with bad or unexpected JSON.
+    pub fn json<S: ToString>(path: &str, message: S) -> SpaceCadetError {
+        SpaceCadetError::Json { path: path.to_string(), message: message.to_string() }
+    }
+
+    /// A sysexits-style exit code for the error (e.g. `EX_DATAERR` for a bad layout).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SpaceCadetError::Io { error, .. } if error.kind() == io::ErrorKind::NotFound => 66, // EX_NOINPUT
+            SpaceCadetError::Io { error, .. } if error.kind() == io::ErrorKind::PermissionDenied => 77, // EX_NOPERM
+            SpaceCadetError::Io { .. } => 74, // EX_IOERR
+            SpaceCadetError::Json { .. } | SpaceCadetError::Syntax { .. } => 65, // EX_DATAERR
+            SpaceCadetError::Constraint(_) => 78, // EX_CONFIG
+            SpaceCadetError::Device(_) => 69, // EX_UNAVAILABLE
+            SpaceCadetError::Usage(_) => 64, // EX_USAGE
+        }
+    }
+}
+
+impl fmt::Display for SpaceCadetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpaceCadetError::Io { path, error } => write!(f, "{}: {}", path, error),
+            SpaceCadetError::Json { path, message } => write!(f, "{}: {}", path, message),
+            SpaceCadetError::Syntax { path, position, location, message, excerpt } => {
+                match position {
+                    Some((line, column)) => write!(f, "{}:{}:{}: {}", path, line, column, message)?,
+                    None => write!(f, "{}: {}: {}", path, location, message)?
+                }
+                match excerpt {
+                    Some(v) => write!(f, "\n{}", v),
+                    None => Ok(())
+                }
+            }
+            SpaceCadetError::Constraint(message) => write!(f, "{}", message),
+            SpaceCadetError::Device(message) => write!(f, "{}", message),
+            SpaceCadetError::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for SpaceCadetError {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match self {
+            SpaceCadetError::Io { error, .. } => Some(error),
+            _ => None
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_messages_and_exit_codes() {
+        let e = SpaceCadetError::io("layers.json", io::Error::new(io::ErrorKind::NotFound, "No such file or directory"));
+        assert_eq!(e.to_string(), "layers.json: No such file or directory");
+        assert_eq!(e.exit_code(), 66);
+
+        let e = SpaceCadetError::Syntax {
+            path: "layers.json".to_string(),
+            position: Some((7, 28)),
+            location: "layer \"base\", key 0,1".to_string(),
+            message: "expected a key, found \",\"".to_string(),
+            excerpt: Some("      [ \"KC_A\", \"LT(cursor,,KC_SPACE)\" ]\n                           ^".to_string()),
+        };
+        assert_eq!(e.to_string(), "layers.json:7:28: expected a key, found \",\"\n      [ \"KC_A\", \"LT(cursor,,KC_SPACE)\" ]\n                           ^");
+        assert_eq!(e.exit_code(), 65);
+
+        // Without a position (e.g. in a YAML file), the error names the layer and key.
+        let e = SpaceCadetError::Syntax {
+            path: "layers.yaml".to_string(),
+            position: None,
+            location: "layer \"base\", key 0,1".to_string(),
+            message: "expected a key, found \",\"".to_string(),
+            excerpt: Some("  LT(cursor,,KC_SPACE)\n            ^".to_string()),
+        };
+        assert_eq!(e.to_string(), "layers.yaml: layer \"base\", key 0,1: expected a key, found \",\"\n  LT(cursor,,KC_SPACE)\n            ^");
+        assert_eq!(e.exit_code(), 65);
+
+        assert_eq!(SpaceCadetError::json("m.json", "bad").exit_code(), 65);
+        assert_eq!(SpaceCadetError::Constraint("x".to_string()).exit_code(), 78);
+        assert_eq!(SpaceCadetError::Device("x".to_string()).exit_code(), 69);
+        assert_eq!(SpaceCadetError::Usage("x".to_string()).exit_code(), 64);
+    }
+}
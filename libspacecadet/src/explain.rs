@@ -0,0 +1,220 @@
+use std::fmt;
+use crate::input_keyboard::InputKeyboard;
+use crate::keyboard_driver::KeyboardDriver;
+use crate::keys::{describe_key_code, NormalKey};
+use crate::output_keyboard::OutputKeyboard;
+use crate::parser::ParsedKeyTree;
+use crate::virtual_keyboard_matrix::Index2D;
+
+/// What happened to an event on one layer, as the driver walks down the layers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerVerdict {
+    /// Skipped, because the layer is disabled.
+    Disabled,
+    /// Skipped, because the layer's key is transparent.
+    Transparent,
+    /// The layer's key handled the event.
+    Handled,
+    /// A higher layer already handled the event.
+    NotReached,
+}
+
+/// One layer in an `Explanation`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplainStep {
+    pub layer: String,
+    pub enabled: bool,
+    /// The layer's key at the position (key syntax).
+    pub key: String,
+    pub verdict: LayerVerdict,
+}
+
+/// How an event at a matrix position resolves through the layers: every layer from the
+/// highest to the lowest, and the key that ends up handling the event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    pub position: Index2D,
+    /// The physical key at the position (e.g. `KC_H`), if the matrix has one.
+    pub physical_key: Option<String>,
+    pub steps: Vec<ExplainStep>,
+}
+
+impl Explanation {
+    /// The step that handled the event (if any key did).
+    pub fn handled_by(&self) -> Option<&ExplainStep> {
+        self.steps.iter().find(|x| x.verdict == LayerVerdict::Handled)
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let physical_key = self.physical_key.clone().unwrap_or_else(|| "no key in the matrix".to_string());
+        writeln!(f, "Position {},{} ({}):", self.position.0, self.position.1, physical_key)?;
+        let layers: Vec<String> = self.steps.iter().map(|x| format!("layer \"{}\"", x.layer)).collect();
+        let layer_width = layers.iter().map(|x| x.chars().count()).max().unwrap_or(0);
+        let key_width = self.steps.iter().map(|x| x.key.chars().count()).max().unwrap_or(0);
+        for (step, layer) in self.steps.iter().zip(layers.iter()) {
+            let state = if step.enabled { "(enabled)" } else { "(disabled)" };
+            let verdict = match step.verdict {
+                LayerVerdict::Disabled => "skipped because the layer is disabled",
+                LayerVerdict::Transparent => "skipped because the key is transparent",
+                LayerVerdict::Handled => "handles the event",
+                LayerVerdict::NotReached => "not reached",
+            };
+            writeln!(f, "  {:<10} {:<lw$}  {:<kw$}  {}", state, layer, step.key, verdict, lw = layer_width, kw = key_width)?;
+        }
+        match self.handled_by() {
+            Some(step) => write!(f, "{} {}.", step.key, describe_key(&step.key)),
+            None => write!(f, "Nothing handles the event: no enabled layer has a key here that isn't transparent."),
+        }
+    }
+}
+
+/// Trace the walk the driver makes when a key at a position (a physical key name like `KC_H`,
+/// or `row,col`) is pressed or released: starting at the highest layer, disabled layers and
+/// transparent keys are skipped, and the first other key handles the event.
+///
+/// `enabled` names the layers to treat as enabled. If it's `None`, the driver's current layer
+/// states are used (the startup layers, for a driver that hasn't handled any events).
+pub fn explain_position<I, O>(driver: &KeyboardDriver<I, O>, position: &str, enabled: Option<&[String]>) -> Result<Explanation, String>
+    where I: InputKeyboard, O: OutputKeyboard {
+    let idx = driver.matrix.parse_position(position)?;
+    let layers = &driver.layer_attributes;
+    if let Some(names) = enabled {
+        if let Some(name) = names.iter().find(|x| !layers.name_to_idx.contains_key(*x)) {
+            return Err(format!("There's no layer named \"{}\".", name));
+        }
+    }
+    let is_enabled = |i: usize| match enabled {
+        Some(names) => names.contains(&layers.attributes[i].name),
+        None => layers.is_enabled(i)
+    };
+
+    let mut steps = Vec::new();
+    let mut handled = false;
+    for i in (0..driver.layered_codes.len()).rev() {
+        let code = driver.layered_codes[i].codes.get(idx.0).and_then(|row| row.get(idx.1))
+            .ok_or_else(|| format!("Layer \"{}\" doesn't have a key at {},{}.", layers.attributes[i].name, idx.0, idx.1))?;
+        let verdict = if handled {
+            LayerVerdict::NotReached
+        } else if !is_enabled(i) {
+            LayerVerdict::Disabled
+        } else if code.is_transparent() {
+            LayerVerdict::Transparent
+        } else {
+            handled = true;
+            LayerVerdict::Handled
+        };
+        steps.push(ExplainStep {
+            layer: layers.attributes[i].name.clone(),
+            enabled: is_enabled(i),
+            key: describe_key_code(code.as_ref()),
+            verdict,
+        });
+    }
+    Ok(Explanation {
+        position: idx,
+        physical_key: driver.matrix.key_at(idx).map(|x| NormalKey { value: x }.name()),
+        steps,
+    })
+}
+
+/// Describe what a key does, from its key syntax (e.g. `MO(nav)` "turns on layer "nav" while it's held").
+pub fn describe_key(v: &str) -> String {
+    match ParsedKeyTree::create(v) {
+        Ok(tree) => describe_tree(&tree),
+        Err(_) => "isn't valid key syntax".to_string()
+    }
+}
+
+fn describe_tree(tree: &ParsedKeyTree) -> String {
+    let arg = |idx: usize| tree.args.get(idx).map(|x| x.to_key_syntax()).unwrap_or_default();
+    let layer = || format!("layer \"{}\"", tree.args.first().map(|x| x.identifier.clone()).unwrap_or_default());
+    let name = tree.identifier.as_str();
+    match name {
+        _ if tree.args.is_empty() && (name.starts_with("KC_") || name.starts_with("KEY_")) => format!("sends {}", name),
+        _ if tree.args.is_empty() && name.len() > 1 && name.chars().all(|x| x == '_') =>
+            "is transparent, so the layer below handles the event".to_string(),
+        "TRANSPARENT" => "is transparent, so the layer below handles the event".to_string(),
+        _ if tree.args.is_empty() && name.len() > 1 && name.chars().all(|x| x == 'X') => "does nothing".to_string(),
+        "OPAQUE" => "does nothing".to_string(),
+        "MO" => format!("turns on {} while it's held", layer()),
+        "TG" => format!("toggles {} on or off", layer()),
+        "AL" => format!("turns on {}", layer()),
+        "OSL" => format!("turns on {} for the next key", layer()),
+        "LT" => format!("sends {} when tapped, or turns on {} when it's held", arg(1), layer()),
+        "LLOCK" => "locks the active momentary layer on, or unlocks it if it's already locked".to_string(),
+        "MT" => format!("sends {} when tapped, or holds {} while it's held", arg(1), arg(0)),
+        "SPACECADET" => format!("sends {} when tapped, or holds {} when used with another key", arg(0), arg(1)),
+        "WRAP" => format!("sends {} while holding {}", arg(1), arg(0)),
+        "MACRO" => {
+            let keys: Vec<String> = tree.args.iter().filter(|x| x.name.is_none()).map(|x| x.to_key_syntax()).collect();
+            match tree.args.iter().find(|x| x.name.as_deref() == Some("when")) {
+                Some(when) => format!("taps {} in order on {}", keys.join(", "), when.identifier),
+                None => format!("taps {} in order", keys.join(", "))
+            }
+        },
+        "STENO" => format!("adds {} to the steno chord", arg(0)),
+        _ => "is a custom key".to_string()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_registry::KeyRegistry;
+    use crate::layer::{KeyCodeMatrix, LayerAttributes};
+    use crate::test_io_keyboard::*;
+    use crate::virtual_keyboard_matrix::VirtualKeyboardMatrix;
+    use evdev_rs::enums::EV_KEY;
+
+    #[test]
+    fn explain_layer_walk() {
+        let registry = KeyRegistry::new();
+        let mut driver = KeyboardDriver::new(TestInputKeyboard::new(), TestOutputKeyboard::new(),
+            VirtualKeyboardMatrix::new(vec![vec![Some(EV_KEY::KEY_H), Some(EV_KEY::KEY_SPACE)]], None));
+        for (name, enabled, keys) in [("base", true, ["KC_A", "LT(nav,KC_SPACE)"]), ("sym", true, ["___", "___"]), ("nav", false, ["KC_LEFT", "___"])].iter() {
+            let mut codes = KeyCodeMatrix::new((1, 2));
+            for (idx, key) in keys.iter().enumerate() {
+                codes.codes[0][idx] = registry.parse(key).unwrap();
+            }
+            driver.add_layer(LayerAttributes { name: name.to_string(), enabled: *enabled, timeout: None }, codes);
+        }
+
+        // The walk from the docs: disabled, transparent, then handled.
+        let explained = explain_position(&driver, "KC_H", None).unwrap();
+        let verdicts: Vec<LayerVerdict> = explained.steps.iter().map(|x| x.verdict).collect();
+        assert_eq!(verdicts, vec![LayerVerdict::Disabled, LayerVerdict::Transparent, LayerVerdict::Handled]);
+        assert_eq!(explained.to_string(), [
+            "Position 0,0 (KC_H):",
+            "  (disabled) layer \"nav\"   KC_LEFT  skipped because the layer is disabled",
+            "  (enabled)  layer \"sym\"   ___      skipped because the key is transparent",
+            "  (enabled)  layer \"base\"  KC_A     handles the event",
+            "KC_A sends KC_A.",
+        ].join("\n"));
+
+        // Layers can be enabled by name, and lower layers aren't reached.
+        let explained = explain_position(&driver, "0,0", Some(&["base".to_string(), "nav".to_string()])).unwrap();
+        assert_eq!(explained.handled_by().unwrap().layer, "nav");
+        assert_eq!(explained.steps[2].verdict, LayerVerdict::NotReached);
+
+        let explained = explain_position(&driver, "KC_SPACE", None).unwrap();
+        assert!(explained.to_string().ends_with("sends KC_SPACE when tapped, or turns on layer \"nav\" when it's held."));
+        let explained = explain_position(&driver, "KC_SPACE", Some(&[])).unwrap();
+        assert_eq!(explained.handled_by(), None);
+
+        assert_eq!(explain_position(&driver, "KC_B", None).unwrap_err(), "KC_B isn't in the matrix");
+        assert_eq!(explain_position(&driver, "0,0", Some(&["nope".to_string()])).unwrap_err(), "There's no layer named \"nope\".");
+    }
+
+    #[test]
+    fn key_descriptions() {
+        assert_eq!(describe_key("MO(nav)"), "turns on layer \"nav\" while it's held");
+        assert_eq!(describe_key("MT(KC_LEFTCTRL,KC_A,tapping_term=200)"), "sends KC_A when tapped, or holds KC_LEFTCTRL while it's held");
+        assert_eq!(describe_key("MACRO(KC_A,WRAP(KC_LEFTSHIFT,KC_B))"), "taps KC_A, WRAP(KC_LEFTSHIFT,KC_B) in order");
+        assert_eq!(describe_key("MACRO(KC_A,KC_B,when=release)"), "taps KC_A, KC_B in order on release");
+        assert_eq!(describe_key("XXX"), "does nothing");
+        assert_eq!(describe_key("CUSTOM"), "is a custom key");
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::Read;
+
+use crate::error::SpaceCadetError;
+use crate::keys::NormalKey;
+use crate::parser::ParsedKeyTree;
+use crate::kbd::import_kbd;
+use crate::qmk::{import_qmk_c, import_qmk_json};
+
+/// A layer imported from another keyboard tool, as rows of key syntax (e.g. `LT(nav,KC_SPACE)`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedLayer {
+    pub name: String,
+    pub keys: Vec<Vec<String>>,
+}
+
+/// A layout imported from another keyboard tool (e.g. a QMK keymap).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedLayout {
+    /// The layers, lowest first. The first layer is enabled at startup.
+    pub layers: Vec<ImportedLayer>,
+    /// The physical keys (e.g. `KC_A`, or `___` for positions without a key), if the tool defines them.
+    pub matrix: Option<Vec<Vec<String>>>,
+    /// Keys that couldn't be imported exactly. They're imported as `XXX` or as a close equivalent.
+    pub warnings: Vec<String>,
+}
+
+impl ImportedLayout {
+    /// Create an empty layout.
+    pub fn new() -> ImportedLayout {
+        ImportedLayout {
+            layers: Vec::new(),
+            matrix: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// The number of rows and columns needed to fit every layer.
+    fn dim(&self) -> (usize, usize) {
+        let rows = self.layers.iter().map(|x| x.keys.len()).max().unwrap_or(0);
+        let cols = self.layers.iter().flat_map(|x| x.keys.iter()).map(|x| x.len()).max().unwrap_or(0);
+        (rows, cols)
+    }
+
+    /// Create a layers document that `KeyboardDriver::load_layers` can read.
+    /// Short rows are padded with transparent keys, so every layer has the same size.
+    pub fn layers_to_json(&self) -> json::JsonValue {
+        let dim = self.dim();
+        let mut ans = json::JsonValue::new_object();
+        ans["layer_order"] = json::JsonValue::new_array();
+        for (idx, layer) in self.layers.iter().enumerate() {
+            ans["layer_order"].push(layer.name.clone()).unwrap();
+            let mut value = json::JsonValue::new_object();
+            value["enabled"] = (idx == 0).into();
+            value["keys"] = pad_rows(&layer.keys, dim, "___").into();
+            ans[layer.name.as_str()] = value;
+        }
+        ans
+    }
+
+    /// Create a matrix document that `VirtualKeyboardMatrix::load` can read.
+    ///
+    /// If the tool doesn't define physical keys, they're guessed from the first layer: a position
+    /// that types a simple key (e.g. `KC_A`) is assumed to be that key. Other positions are left
+    /// empty (`___`) and need to be filled in by hand.
+    pub fn matrix_to_json(&self) -> json::JsonValue {
+        let dim = self.dim();
+        let matrix = match &self.matrix {
+            Some(v) => pad_rows(v, dim, "___"),
+            None => {
+                let base = self.layers.first().map(|x| x.keys.clone()).unwrap_or_default();
+                let guess: Vec<Vec<String>> = base.iter()
+                    .map(|row| row.iter().map(|x| if is_simple_key(x) { x.clone() } else { "___".to_string() }).collect())
+                    .collect();
+                pad_rows(&guess, dim, "___")
+            }
+        };
+        let mut ans = json::JsonValue::new_object();
+        ans["matrix"] = matrix.into();
+        ans
+    }
+}
+
+impl Default for ImportedLayout {
+    fn default() -> ImportedLayout {
+        ImportedLayout::new()
+    }
+}
+
+/// Import a layout from another keyboard tool, choosing the format by the file's extension:
+/// a QMK `keymap.c` (`.c`), a QMK Configurator `keymap.json` (`.json`), or a KMonad or Kanata
+/// config (`.kbd`).
+pub fn import_layout(path: &str) -> Result<ImportedLayout, SpaceCadetError> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| SpaceCadetError::io(path, e))?;
+    let ans = if path.ends_with(".c") {
+        import_qmk_c(&contents)
+    } else if path.ends_with(".json") {
+        import_qmk_json(&contents)
+    } else if path.ends_with(".kbd") {
+        import_kbd(&contents)
+    } else {
+        Err("Unknown keymap format (expected a QMK keymap.c or keymap.json, or a .kbd file).".to_string())
+    };
+    ans.map_err(|e| SpaceCadetError::json(path, e))
+}
+
+/// Check if key syntax is a simple key (e.g. `KC_A`).
+pub fn is_simple_key(v: &str) -> bool {
+    match ParsedKeyTree::create(v) {
+        Ok(tree) => v.starts_with("KC_") && NormalKey::from_tokens(&tree).is_ok(),
+        Err(_) => false
+    }
+}
+
+/// Pad rows with a key so they're all the same size.
+fn pad_rows(rows: &[Vec<String>], dim: (usize, usize), key: &str) -> Vec<Vec<String>> {
+    let mut ans = rows.to_vec();
+    ans.resize(dim.0, Vec::new());
+    for row in ans.iter_mut() {
+        row.resize(dim.1, key.to_string());
+    }
+    ans
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imported_layout_documents() {
+        let mut layout = ImportedLayout::new();
+        layout.layers.push(ImportedLayer {
+            name: "base".to_string(),
+            keys: vec![vec!["KC_A".to_string(), "MO(nav)".to_string()], vec!["KC_B".to_string()]],
+        });
+        layout.layers.push(ImportedLayer {
+            name: "nav".to_string(),
+            keys: vec![vec!["KC_LEFT".to_string()]],
+        });
+
+        let layers = json::parse(r#"{
+            "layer_order": [ "base", "nav" ],
+            "base": { "enabled": true, "keys": [ [ "KC_A", "MO(nav)" ], [ "KC_B", "___" ] ] },
+            "nav": { "enabled": false, "keys": [ [ "KC_LEFT", "___" ], [ "___", "___" ] ] }
+        }"#).unwrap();
+        assert_eq!(layout.layers_to_json(), layers);
+
+        // Physical keys are guessed from the first layer.
+        let matrix = json::parse(r#"{ "matrix": [ [ "KC_A", "___" ], [ "KC_B", "___" ] ] }"#).unwrap();
+        assert_eq!(layout.matrix_to_json(), matrix);
+    }
+}
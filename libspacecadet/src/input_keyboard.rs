@@ -1,5 +1,9 @@
 use evdev_rs as evdev;
+use std::fs::File;
+use std::io::Write;
 use crate::KeyStats;
+use crate::error::SpaceCadetError;
+use crate::recording::{format_recorded_event, RECORDING_HEADER};
 
 /// An interface for keyboards that receive input events.
 pub trait InputKeyboard {
@@ -12,7 +16,9 @@ pub trait InputKeyboard {
 /// A wrapper around an input keyboard device (e.g. `/dev/input/event4`).
 pub struct EvdevKeyboard {
     device: evdev::Device,
-    stats: KeyStats
+    stats: KeyStats,
+    recording: Option<(String, File)>,
+    error: Option<SpaceCadetError>
 }
 
 impl EvdevKeyboard {
@@ -28,15 +34,19 @@ impl EvdevKeyboard {
 
         match file_descriptor {
             Ok(fd) => {
-                let mut device = evdev::Device::new_from_fd(fd).unwrap();
+                let mut device = evdev::Device::new_from_fd(fd)
+                    .map_err(|e| format!("Couldn't open \"{}\" as an evdev device: {}", path, e))?;
 
                 // Check that the device supports keys.
                 if device.has(&evdev::enums::EventType::EV_KEY) {
-                    device.grab(evdev::GrabMode::Grab).unwrap();
+                    device.grab(evdev::GrabMode::Grab)
+                        .map_err(|e| format!("Couldn't grab \"{}\" (is another program using it?): {}", path, e))?;
 
                     Ok(EvdevKeyboard {
                         device,
                         stats: KeyStats::new(),
+                        recording: None,
+                        error: None,
                     })
                 } else {
                     Err(format!("Device isn't a keyboard: \"{}\" doesn't support EV_KEY events.", path))
@@ -48,6 +58,35 @@ impl EvdevKeyboard {
         }
     }
 
+    /// Write every event that's read to a recording (with its kernel timestamp), so it can be
+    /// replayed later (see `parse_recording`).
+    pub fn record(&mut self, path: &str) -> Result<(), SpaceCadetError> {
+        let mut file = File::create(path).map_err(|e| SpaceCadetError::io(path, e))?;
+        writeln!(file, "{}", RECORDING_HEADER).map_err(|e| SpaceCadetError::io(path, e))?;
+        self.recording = Some((path.to_string(), file));
+        Ok(())
+    }
+
+    /// Take the first error from writing the recording (if any). Recording stops after an
+    /// error, so the caller should check this regularly (e.g. after every clock tick).
+    pub fn take_error(&mut self) -> Option<SpaceCadetError> {
+        self.error.take()
+    }
+
+    /// Append events to the recording (if there is one).
+    fn record_events(&mut self, events: &[evdev::InputEvent]) {
+        let written = match &mut self.recording {
+            Some((path, file)) => {
+                let lines: String = events.iter().filter_map(format_recorded_event).map(|x| x + "\n").collect();
+                file.write_all(lines.as_bytes()).map_err(|e| SpaceCadetError::io(path, e))
+            }
+            None => return
+        };
+        if let Err(e) = written {
+            self.recording = None;
+            self.error = Some(e);
+        }
+    }
 }
 
 impl InputKeyboard for EvdevKeyboard {
@@ -78,6 +117,9 @@ impl InputKeyboard for EvdevKeyboard {
         for i in ans.iter() {
             self.stats.increment(i.value.into());
         }
+        if !ans.is_empty() {
+            self.record_events(&ans);
+        }
 
         ans
     }
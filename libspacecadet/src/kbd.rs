@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+
+use crate::import::{is_simple_key, ImportedLayer, ImportedLayout};
+use crate::parser::format_argument;
+
+/// KMonad and Kanata key names and the names used here (e.g. `bspc` is `KC_BACKSPACE`).
+/// Names that only differ by case (e.g. `a` and `KC_A`) aren't listed.
+const KBD_KEY_NAMES: &[(&str, &str)] = &[
+    ("-", "KC_MINUS"), ("min", "KC_MINUS"), ("=", "KC_EQUAL"), ("eql", "KC_EQUAL"),
+    ("[", "KC_LEFTBRACE"), ("lbrc", "KC_LEFTBRACE"), ("]", "KC_RIGHTBRACE"), ("rbrc", "KC_RIGHTBRACE"),
+    ("\\", "KC_BACKSLASH"), ("bksl", "KC_BACKSLASH"), (";", "KC_SEMICOLON"), ("scln", "KC_SEMICOLON"),
+    ("'", "KC_APOSTROPHE"), ("apos", "KC_APOSTROPHE"), ("quot", "KC_APOSTROPHE"),
+    (",", "KC_COMMA"), ("comm", "KC_COMMA"), (".", "KC_DOT"), ("/", "KC_SLASH"), ("slsh", "KC_SLASH"),
+    ("`", "KC_GRAVE"), ("grv", "KC_GRAVE"), ("spc", "KC_SPACE"), ("ret", "KC_ENTER"), ("ent", "KC_ENTER"),
+    ("bspc", "KC_BACKSPACE"), ("bks", "KC_BACKSPACE"), ("del", "KC_DELETE"), ("ins", "KC_INSERT"),
+    ("caps", "KC_CAPSLOCK"), ("pgup", "KC_PAGEUP"), ("pgdn", "KC_PAGEDOWN"),
+    ("lsft", "KC_LEFTSHIFT"), ("lshift", "KC_LEFTSHIFT"), ("rsft", "KC_RIGHTSHIFT"), ("rshift", "KC_RIGHTSHIFT"),
+    ("lctl", "KC_LEFTCTRL"), ("lctrl", "KC_LEFTCTRL"), ("rctl", "KC_RIGHTCTRL"), ("rctrl", "KC_RIGHTCTRL"),
+    ("lmet", "KC_LEFTMETA"), ("lgui", "KC_LEFTMETA"), ("lwin", "KC_LEFTMETA"), ("lcmd", "KC_LEFTMETA"),
+    ("rmet", "KC_RIGHTMETA"), ("rgui", "KC_RIGHTMETA"), ("rwin", "KC_RIGHTMETA"), ("rcmd", "KC_RIGHTMETA"),
+    ("lalt", "KC_LEFTALT"), ("ralt", "KC_RIGHTALT"), ("comp", "KC_COMPOSE"), ("menu", "KC_COMPOSE"),
+    ("prnt", "KC_SYSRQ"), ("sys", "KC_SYSRQ"), ("slck", "KC_SCROLLLOCK"), ("scrlck", "KC_SCROLLLOCK"),
+    ("nlck", "KC_NUMLOCK"), ("102d", "KC_102ND"), ("volu", "KC_VOLUMEUP"), ("vold", "KC_VOLUMEDOWN"),
+    ("voldwn", "KC_VOLUMEDOWN"), ("pp", "KC_PLAYPAUSE"), ("next", "KC_NEXTSONG"), ("prev", "KC_PREVIOUSSONG"),
+];
+
+/// Shifted symbols and the key they shift (e.g. `!` is shift + `KC_1`).
+const KBD_SHIFTED_KEYS: &[(&str, &str)] = &[
+    ("~", "KC_GRAVE"), ("!", "KC_1"), ("#", "KC_3"), ("$", "KC_4"), ("%", "KC_5"), ("^", "KC_6"), ("&", "KC_7"),
+    ("*", "KC_8"), ("+", "KC_EQUAL"), ("{", "KC_LEFTBRACE"), ("}", "KC_RIGHTBRACE"), ("|", "KC_BACKSLASH"),
+    (":", "KC_SEMICOLON"), ("\"", "KC_APOSTROPHE"), ("<", "KC_COMMA"), (">", "KC_DOT"), ("?", "KC_SLASH"),
+];
+
+/// Modifier prefixes (e.g. `C-a` is control + `a`) and the modifier they hold.
+const KBD_MODIFIER_PREFIXES: &[(&str, &str)] = &[
+    ("C", "KC_LEFTCTRL"), ("S", "KC_LEFTSHIFT"), ("A", "KC_LEFTALT"), ("M", "KC_LEFTMETA"),
+    ("RC", "KC_RIGHTCTRL"), ("RS", "KC_RIGHTSHIFT"), ("RA", "KC_RIGHTALT"), ("AG", "KC_RIGHTALT"), ("RM", "KC_RIGHTMETA"),
+];
+
+fn lookup<'a>(table: &'a [(&str, &str)], name: &str) -> Option<&'a str> {
+    table.iter().find(|x| x.0 == name).map(|x| x.1)
+}
+
+
+/// An s-expression, with the line (starting at 0) where it starts.
+#[derive(Debug, Clone, PartialEq)]
+enum Sexp {
+    Atom(String, usize),
+    List(Vec<Sexp>, usize),
+}
+
+impl Sexp {
+    fn line(&self) -> usize {
+        match self {
+            Sexp::Atom(_, line) | Sexp::List(_, line) => *line
+        }
+    }
+
+    fn as_atom(&self) -> Option<&str> {
+        match self {
+            Sexp::Atom(v, _) => Some(v),
+            Sexp::List(..) => None
+        }
+    }
+
+    /// Write the expression back out (for warnings).
+    fn describe(&self) -> String {
+        match self {
+            Sexp::Atom(v, _) => v.clone(),
+            Sexp::List(v, _) => format!("({})", v.iter().map(|x| x.describe()).collect::<Vec<String>>().join(" "))
+        }
+    }
+}
+
+/// Read every top-level s-expression, skipping `;;` and `#| |#` comments.
+/// KMonad's `#(a b)` macros are read as `(macro a b)`.
+fn parse_sexps(contents: &str) -> Result<Vec<Sexp>, String> {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut stack: Vec<(Vec<Sexp>, usize)> = vec![(Vec::new(), 0)];
+    let mut line = 0;
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        let next = chars.get(idx + 1).cloned();
+        if c == '\n' {
+            line += 1;
+            idx += 1;
+        } else if c.is_whitespace() {
+            idx += 1;
+        } else if c == ';' && next == Some(';') {
+            while idx < chars.len() && chars[idx] != '\n' {
+                idx += 1;
+            }
+        } else if c == '#' && next == Some('|') {
+            idx += 2;
+            while idx < chars.len() && !(chars[idx] == '|' && chars.get(idx + 1) == Some(&'#')) {
+                if chars[idx] == '\n' {
+                    line += 1;
+                }
+                idx += 1;
+            }
+            idx += 2;
+        } else if c == '(' || (c == '#' && next == Some('(')) {
+            let mut items = Vec::new();
+            if c == '#' {
+                items.push(Sexp::Atom("macro".to_string(), line));
+                idx += 1;
+            }
+            stack.push((items, line));
+            idx += 1;
+        } else if c == ')' {
+            if stack.len() == 1 {
+                return Err(format!("line {}: unexpected \")\"", line + 1));
+            }
+            let (items, start) = stack.pop().unwrap();
+            stack.last_mut().unwrap().0.push(Sexp::List(items, start));
+            idx += 1;
+        } else if c == '"' && idx + 1 < chars.len() && chars[idx + 1..].contains(&'"') {
+            // A quoted string (e.g. a command), unless it's the `"` key on its own.
+            let end = (idx + 1..chars.len()).find(|x| chars[*x] == '"').unwrap();
+            if end == idx + 1 || chars[idx + 1].is_whitespace() {
+                stack.last_mut().unwrap().0.push(Sexp::Atom("\"".to_string(), line));
+                idx += 1;
+            } else {
+                let value: String = chars[idx..=end].iter().collect();
+                let lines = value.matches('\n').count();
+                stack.last_mut().unwrap().0.push(Sexp::Atom(value, line));
+                line += lines;
+                idx = end + 1;
+            }
+        } else {
+            let start = idx;
+            while idx < chars.len() && !chars[idx].is_whitespace() && chars[idx] != '(' && chars[idx] != ')' {
+                idx += 1;
+            }
+            stack.last_mut().unwrap().0.push(Sexp::Atom(chars[start..idx].iter().collect(), line));
+        }
+    }
+    if stack.len() != 1 {
+        return Err(format!("line {}: \"(\" isn't closed", stack.last().unwrap().1 + 1));
+    }
+    Ok(stack.pop().unwrap().0)
+}
+
+/// Group items into rows by the line they're written on.
+fn rows_by_line(items: &[Sexp]) -> Vec<Vec<Sexp>> {
+    let mut ans: Vec<Vec<Sexp>> = Vec::new();
+    let mut last_line = None;
+    for item in items.iter() {
+        if last_line != Some(item.line()) {
+            ans.push(Vec::new());
+            last_line = Some(item.line());
+        }
+        ans.last_mut().unwrap().push(item.clone());
+    }
+    ans
+}
+
+
+/// Translates KMonad and Kanata actions (e.g. `(tap-hold 200 spc (layer-toggle nav))`) into key
+/// syntax (e.g. `LT(nav,KC_SPACE,200ms)`).
+struct KbdTranslator {
+    aliases: HashMap<String, Sexp>,
+}
+
+impl KbdTranslator {
+    /// Translate an action, noting anything that's imported as a close equivalent.
+    fn translate(&self, item: &Sexp, notes: &mut Vec<String>, depth: usize) -> Result<String, String> {
+        match item {
+            Sexp::Atom(v, _) => self.translate_atom(v, depth),
+            Sexp::List(items, _) => {
+                let name = items.first().and_then(|x| x.as_atom()).unwrap_or("");
+                let args = &items[std::cmp::min(1, items.len())..];
+                let arg = |idx: usize| -> Result<&Sexp, String> {
+                    args.get(idx).ok_or_else(|| format!("{} is missing an argument", name))
+                };
+                match name {
+                    "layer-toggle" | "layer-while-held" => Ok(format!("MO({})", self.layer(arg(0)?)?)),
+                    "layer-add" => Ok(format!("AL({})", self.layer(arg(0)?)?)),
+                    "layer-switch" => {
+                        notes.push("layer-switch is imported as AL, which doesn't disable other layers".to_string());
+                        Ok(format!("AL({})", self.layer(arg(0)?)?))
+                    }
+                    "layer-next" => Ok(format!("OSL({})", self.layer(arg(0)?)?)),
+                    "one-shot" | "one-shot-press" | "one-shot-release" => {
+                        match self.layer_action(arg(1)?) {
+                            Some(layer) => {
+                                notes.push(format!("{} is imported as OSL, which doesn't time out", name));
+                                Ok(format!("OSL({})", self.layer(layer)?))
+                            }
+                            None => Err(format!("{} is only supported for layers", name))
+                        }
+                    }
+                    "tap-hold" | "tap-hold-next" | "tap-hold-next-release" | "tap-hold-press" | "tap-hold-release" => {
+                        // KMonad: (tap-hold delay tap hold), Kanata: (tap-hold tap-timeout hold-timeout tap hold).
+                        let offset = if args.len() == 4 { 1 } else { 0 };
+                        let delay = arg(offset)?.as_atom().and_then(|x| x.parse::<u64>().ok())
+                            .ok_or_else(|| format!("{} expects a delay in milliseconds", name))?;
+                        let tap = self.translate(arg(offset + 1)?, notes, depth)?;
+                        let hold = arg(offset + 2)?;
+                        if let Some(layer) = self.layer_action(hold) {
+                            return Ok(format!("LT({},{},{}ms)", self.layer(layer)?, tap, delay));
+                        }
+                        let hold = self.translate(hold, notes, depth)?;
+                        if !is_simple_key(&hold) {
+                            return Err(format!("{} can only hold a layer or a modifier", name));
+                        }
+                        let permissive = if name.ends_with("-release") { ",permissive_hold=true" } else { "" };
+                        Ok(format!("MT({},{},tapping_term={}ms{})", hold, tap, delay, permissive))
+                    }
+                    "around" | "multi" => {
+                        // Every key but the last is held around the last (e.g. `(around lsft a)`).
+                        let last = args.last().ok_or_else(|| format!("{} is missing an argument", name))?;
+                        let mut ans = self.translate(last, notes, depth)?;
+                        for item in args[..args.len() - 1].iter().rev() {
+                            let key = self.translate(item, notes, depth)?;
+                            if !is_simple_key(&key) {
+                                return Err(format!("{} can only hold simple keys", name));
+                            }
+                            ans = format!("WRAP({},{})", key, ans);
+                        }
+                        Ok(ans)
+                    }
+                    "macro" => {
+                        let keys = args.iter().map(|x| self.translate(x, notes, depth)).collect::<Result<Vec<String>, String>>()?;
+                        Ok(format!("MACRO({})", keys.join(",")))
+                    }
+                    "" => Err(format!("{} isn't an action", item.describe())),
+                    _ => Err(format!("{} isn't supported", name))
+                }
+            }
+        }
+    }
+
+    fn translate_atom(&self, v: &str, depth: usize) -> Result<String, String> {
+        if let Some(name) = v.strip_prefix('@') {
+            let item = self.aliases.get(name).ok_or_else(|| format!("unknown alias @{}", name))?;
+            if depth > 16 {
+                return Err(format!("the alias @{} uses itself", name));
+            }
+            // Problems with the alias are reported where it's defined.
+            return self.translate(item, &mut Vec::new(), depth + 1);
+        }
+        match v {
+            "_" => return Ok("___".to_string()),
+            "XX" | "✗" | "∅" | "•" => return Ok("XXX".to_string()),
+            _ => ()
+        }
+        if let Some(v) = lookup(KBD_SHIFTED_KEYS, v) {
+            return Ok(format!("WRAP(KC_LEFTSHIFT,{})", v));
+        }
+        if let Some(v) = lookup(KBD_KEY_NAMES, v) {
+            return Ok(v.to_string());
+        }
+        // Modifier prefixes (e.g. `C-S-a`).
+        if let Some(idx) = v.find('-') {
+            if let Some(m) = lookup(KBD_MODIFIER_PREFIXES, &v[..idx]) {
+                if idx + 1 < v.len() {
+                    return Ok(format!("WRAP({},{})", m, self.translate_atom(&v[idx + 1..], depth)?));
+                }
+            }
+        }
+        let name = format!("KC_{}", v.to_uppercase());
+        if is_simple_key(&name) {
+            Ok(name)
+        } else {
+            Err(format!("{} isn't supported", v))
+        }
+    }
+
+    /// The layer argument of a layer action (e.g. `(layer-toggle nav)` or an alias of one).
+    fn layer_action<'a>(&'a self, item: &'a Sexp) -> Option<&'a Sexp> {
+        match item {
+            Sexp::Atom(v, _) => v.strip_prefix('@').and_then(|x| self.aliases.get(x)).and_then(|x| match x {
+                Sexp::List(..) => self.layer_action(x),
+                Sexp::Atom(..) => None
+            }),
+            Sexp::List(items, _) => match items.first().and_then(|x| x.as_atom()) {
+                Some("layer-toggle") | Some("layer-while-held") => items.get(1),
+                _ => None
+            }
+        }
+    }
+
+    fn layer(&self, item: &Sexp) -> Result<String, String> {
+        item.as_atom().map(format_argument).ok_or_else(|| format!("{} isn't a layer name", item.describe()))
+    }
+}
+
+/// Import a KMonad or Kanata config (`.kbd`).
+///
+/// `defsrc` becomes the matrix and each `deflayer` a layer, with rows taken from the lines of
+/// `defsrc`. The first layer is the base layer. `defalias` actions are imported wherever they're
+/// used (e.g. `@nav`). Other blocks (e.g. `defcfg`) are skipped.
+pub fn import_kbd(contents: &str) -> Result<ImportedLayout, String> {
+    let forms = parse_sexps(contents)?;
+    let mut translator = KbdTranslator { aliases: HashMap::new() };
+    let mut ans = ImportedLayout::new();
+    let mut source: Option<Vec<Vec<Sexp>>> = None;
+    let mut layers: Vec<(String, Vec<Sexp>)> = Vec::new();
+    let mut alias_order = Vec::new();
+    for form in forms.iter() {
+        let items = match form {
+            Sexp::List(items, _) => items,
+            Sexp::Atom(v, line) => return Err(format!("line {}: expected a block, found {}", line + 1, v))
+        };
+        let name = items.first().and_then(|x| x.as_atom()).unwrap_or("");
+        match name {
+            "defsrc" => source = Some(rows_by_line(&items[1..])),
+            "deflayer" => {
+                let layer = items.get(1).and_then(|x| x.as_atom())
+                    .ok_or_else(|| format!("line {}: deflayer is missing a name", form.line() + 1))?;
+                layers.push((layer.to_string(), items[2..].to_vec()));
+            }
+            "defalias" => {
+                for pair in items[1..].chunks(2) {
+                    match (pair[0].as_atom(), pair.get(1)) {
+                        (Some(alias), Some(action)) => {
+                            translator.aliases.insert(alias.to_string(), action.clone());
+                            alias_order.push(alias.to_string());
+                        }
+                        _ => return Err(format!("line {}: defalias expects names and actions", pair[0].line() + 1))
+                    }
+                }
+            }
+            "defcfg" | "defvar" => (),
+            _ => ans.warnings.push(format!("line {}: {} isn't supported, skipped it", form.line() + 1, name))
+        }
+    }
+    let source = source.ok_or_else(|| "Couldn't find defsrc.".to_string())?;
+
+    for alias in alias_order.iter() {
+        let mut notes = Vec::new();
+        if let Err(e) = translator.translate(&translator.aliases[alias], &mut notes, 0) {
+            notes.push(format!("{}, imported as XXX", e));
+        }
+        for note in notes {
+            ans.warnings.push(format!("alias @{}: {}", alias, note));
+        }
+    }
+
+    let mut matrix = Vec::new();
+    for (idx, key) in source.iter().flat_map(|x| x.iter()).enumerate() {
+        let v = match translator.translate(key, &mut Vec::new(), 0) {
+            Ok(ref v) if is_simple_key(v) => v.clone(),
+            _ => {
+                ans.warnings.push(format!("defsrc, key {} ({}): not a simple key, imported as ___", idx + 1, key.describe()));
+                "___".to_string()
+            }
+        };
+        matrix.push(v);
+    }
+    ans.matrix = Some(reshape(&source, matrix));
+
+    let count: usize = source.iter().map(|x| x.len()).sum();
+    for (name, items) in layers.iter() {
+        if items.len() != count {
+            return Err(format!("The layer {} has {} keys, but defsrc has {}.", name, items.len(), count));
+        }
+        let mut keys = Vec::new();
+        for (idx, item) in items.iter().enumerate() {
+            let mut notes = Vec::new();
+            let key = match translator.translate(item, &mut notes, 0) {
+                Ok(v) => v,
+                Err(e) => {
+                    // Aliases report their own problems.
+                    if !matches!(item.as_atom(), Some(x) if x.starts_with('@')) {
+                        notes.push(format!("{}, imported as XXX", e));
+                    }
+                    "XXX".to_string()
+                }
+            };
+            for note in notes {
+                ans.warnings.push(format!("layer {}, key {} ({}): {}", name, idx + 1, item.describe(), note));
+            }
+            keys.push(key);
+        }
+        ans.layers.push(ImportedLayer { name: name.clone(), keys: reshape(&source, keys) });
+    }
+    Ok(ans)
+}
+
+/// Split keys into rows the same size as the rows of `defsrc`.
+fn reshape(source: &[Vec<Sexp>], keys: Vec<String>) -> Vec<Vec<String>> {
+    let mut keys = keys.into_iter();
+    source.iter().map(|row| keys.by_ref().take(row.len()).collect()).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyRegistry, ParsedKeyTree};
+
+    #[test]
+    fn import_kbd_config() {
+        let layout = import_kbd(r#"
+            ;; A small KMonad config.
+            (defcfg
+              input (device-file "/dev/input/by-id/usb-keyboard-event-kbd")
+              output (uinput-sink "kmonad"))
+
+            #| The physical keys. |#
+            (defsrc
+              esc  q    w    e
+              caps a    s    spc)
+
+            (defalias
+              nav (tap-hold 200 spc (layer-toggle nav))
+              ctl_a (tap-hold-next-release 180 a lctl)
+              osm (one-shot 500 lsft)
+              mac #(h i))
+
+            (deflayer base
+              _    q    w    e
+              @ctl_a  S-a  @osm  @nav)
+
+            (deflayer nav
+              XX   left (around lctl c) C-S-v
+              !    #(a b)  (layer-switch base) @mac)
+
+            (defchords foo 50)
+        "#).unwrap();
+
+        assert_eq!(layout.matrix, Some(vec![
+            vec!["KC_ESC".to_string(), "KC_Q".to_string(), "KC_W".to_string(), "KC_E".to_string()],
+            vec!["KC_CAPSLOCK".to_string(), "KC_A".to_string(), "KC_S".to_string(), "KC_SPACE".to_string()],
+        ]));
+        let names: Vec<&str> = layout.layers.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["base", "nav"]);
+        assert_eq!(layout.layers[0].keys, vec![
+            vec!["___", "KC_Q", "KC_W", "KC_E"],
+            vec!["MT(KC_LEFTCTRL,KC_A,tapping_term=180ms,permissive_hold=true)", "WRAP(KC_LEFTSHIFT,KC_A)", "XXX",
+                 "LT(nav,KC_SPACE,200ms)"],
+        ]);
+        assert_eq!(layout.layers[1].keys, vec![
+            vec!["XXX", "KC_LEFT", "WRAP(KC_LEFTCTRL,KC_C)", "WRAP(KC_LEFTCTRL,WRAP(KC_LEFTSHIFT,KC_V))"],
+            vec!["WRAP(KC_LEFTSHIFT,KC_1)", "MACRO(KC_A,KC_B)", "AL(base)", "MACRO(KC_H,KC_I)"],
+        ]);
+        assert_eq!(layout.warnings, vec![
+            "line 26: defchords isn't supported, skipped it".to_string(),
+            "alias @osm: one-shot is only supported for layers, imported as XXX".to_string(),
+            "layer nav, key 7 ((layer-switch base)): layer-switch is imported as AL, which doesn't disable other layers".to_string(),
+        ]);
+
+        let registry = KeyRegistry::new();
+        for key in layout.layers.iter().flat_map(|x| x.keys.iter()).flat_map(|x| x.iter()) {
+            let tree = ParsedKeyTree::create(key).unwrap();
+            assert!(registry.convert(&tree).is_ok(), "{} doesn't load", key);
+        }
+    }
+
+    #[test]
+    fn import_kbd_errors() {
+        assert!(import_kbd("(deflayer base a b)").is_err());
+        assert!(import_kbd("(defsrc a b) (deflayer base a)").is_err());
+        assert!(import_kbd("(defsrc a b").is_err());
+        assert!(import_kbd("(defsrc a b))").is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::alias::*;
+use crate::keys::*;
+use crate::parser::*;
+
+/// A function that converts a parsed key tree into a key.
+/// The registry is passed along so keys with nested keys (e.g. `MACRO`) can convert their arguments.
+pub type KeyConstructor = fn(&ParsedKeyTree, &KeyRegistry) -> Result<Box<KeyCode>, String>;
+
+/// Layout-wide defaults for optional key arguments (the `"settings"` block of a layers file).
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeySettings {
+    /// How long `LT` must be held to enable its layer (`hold_ms`).
+    pub hold_threshold: Duration,
+    /// How long `MT` can be held and still count as a tap (`tapping_term_ms`).
+    pub tapping_term: Duration,
+    /// Whether `MT` uses a permissive hold by default (`permissive_hold`).
+    pub permissive_hold: bool,
+    /// How long after the first key of a combo the others can be pressed (`combo_term_ms`).
+    pub combo_term: Duration,
+}
+
+impl KeySettings {
+    /// Create the default settings.
+    pub fn new() -> KeySettings {
+        KeySettings {
+            hold_threshold: Duration::from_millis(200),
+            tapping_term: Duration::from_millis(200),
+            permissive_hold: false,
+            combo_term: Duration::from_millis(50),
+        }
+    }
+
+    /// Read a `"settings"` block. Missing settings keep their current value.
+    pub fn update_from_json(&mut self, settings: &json::JsonValue) -> Result<(), String> {
+        let millis = |name: &str| -> Result<Option<Duration>, String> {
+            let v = &settings[name];
+            if v.is_null() {
+                Ok(None)
+            } else {
+                v.as_u64().map(|x| Some(Duration::from_millis(x)))
+                    .ok_or_else(|| format!("The \"{}\" setting must be a number of milliseconds.", name))
+            }
+        };
+        if let Some(v) = millis("hold_ms")? {
+            self.hold_threshold = v;
+        }
+        if let Some(v) = millis("tapping_term_ms")? {
+            self.tapping_term = v;
+        }
+        if let Some(v) = millis("combo_term_ms")? {
+            self.combo_term = v;
+        }
+        if !settings["permissive_hold"].is_null() {
+            self.permissive_hold = settings["permissive_hold"].as_bool()
+                .ok_or("The \"permissive_hold\" setting must be true or false.")?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for KeySettings {
+    fn default() -> KeySettings {
+        KeySettings::new()
+    }
+}
+
+/// A map from key identifiers (e.g. `MO`) to the functions that construct them.
+///
+/// Library users can register their own keys before loading a layout:
+///
+/// ```ignore
+/// let mut registry = KeyRegistry::new();
+/// registry.register("MY_KEY", |tree, _| Ok(Box::new(MyKey::from_tokens(tree)?)));
+/// driver.load_layers("layers.json", &registry);
+/// ```
+#[derive(Clone)]
+pub struct KeyRegistry {
+    /// Defaults for optional key arguments.
+    pub settings: KeySettings,
+    /// User-defined aliases, expanded before keys are converted.
+    aliases: AliasCollection,
+    /// Keys matched by their whole identifier (e.g. `MO`).
+    exact: HashMap<String, KeyConstructor>,
+    /// Keys matched by the start of their identifier (e.g. `KC_`).
+    prefixes: Vec<(String, KeyConstructor)>,
+    /// Keys written as a single repeated character (e.g. `___`).
+    repeated: Vec<(char, KeyConstructor)>,
+}
+
+impl KeyRegistry {
+    /// Create a registry with every built-in key.
+    pub fn new() -> KeyRegistry {
+        let mut ans = KeyRegistry::empty();
+        ans.register("TRANSPARENT", |x, _| Ok(Box::new(TransparentKey::from_tokens(x)?)));
+        ans.register("OPAQUE", |x, _| Ok(Box::new(OpaqueKey::from_tokens(x)?)));
+        ans.register("MACRO", |x, r| Ok(Box::new(MacroKey::from_tokens(x, r)?)));
+        ans.register("TG", |x, _| Ok(Box::new(ToggleLayerKey::from_tokens(x)?)));
+        ans.register("MO", |x, _| Ok(Box::new(MomentarilyEnableLayerKey::from_tokens(x)?)));
+        ans.register("AL", |x, _| Ok(Box::new(ActivateLayerKey::from_tokens(x)?)));
+        ans.register("LT", |x, r| Ok(Box::new(HoldEnableLayerPressKey::from_tokens(x, &r.settings)?)));
+        ans.register("MT", |x, r| Ok(Box::new(ModTapKey::from_tokens(x, r)?)));
+        ans.register("OSL", |x, _| Ok(Box::new(OneShotLayer::from_tokens(x)?)));
+        ans.register("LLOCK", |x, _| Ok(Box::new(LayerLockKey::from_tokens(x)?)));
+        ans.register("WRAP", |x, r| Ok(Box::new(WrappedKey::from_tokens(x, r)?)));
+        ans.register("SPACECADET", |x, r| Ok(Box::new(SpaceCadet::from_tokens(x, r)?)));
+        ans.register("STENO", |x, _| Ok(Box::new(StenoChordKey::from_tokens(x)?)));
+        ans.register_repeated('_', |x, _| Ok(Box::new(TransparentKey::from_tokens(x)?)));
+        ans.register_repeated('X', |x, _| Ok(Box::new(OpaqueKey::from_tokens(x)?)));
+        ans.register_prefix("KC_", |x, _| Ok(Box::new(NormalKey::from_tokens(x)?)));
+        ans.register_prefix("KEY_", |x, _| Ok(Box::new(NormalKey::from_tokens(x)?)));
+        ans
+    }
+
+    /// Create a registry without any keys.
+    pub fn empty() -> KeyRegistry {
+        KeyRegistry {
+            settings: KeySettings::new(),
+            aliases: AliasCollection::new(),
+            exact: HashMap::new(),
+            prefixes: Vec::new(),
+            repeated: Vec::new(),
+        }
+    }
+
+    /// Register a key by its identifier (e.g. `MO`). Replaces any key with the same identifier.
+    pub fn register(&mut self, identifier: &str, constructor: KeyConstructor) {
+        self.exact.insert(identifier.to_string(), constructor);
+    }
+
+    /// Register a key by the start of its identifier (e.g. `KC_`).
+    /// Prefixes registered later are checked first.
+    pub fn register_prefix(&mut self, prefix: &str, constructor: KeyConstructor) {
+        self.prefixes.insert(0, (prefix.to_string(), constructor));
+    }
+
+    /// Register a key written as a single character repeated any number of times (e.g. `___`).
+    pub fn register_repeated(&mut self, c: char, constructor: KeyConstructor) {
+        self.repeated.insert(0, (c, constructor));
+    }
+
+    /// Use a layout's aliases. Aliases can't replace a key registered by `register`, or have
+    /// a name that's already a key (e.g. `KC_A` or `___`).
+    pub fn set_aliases(&mut self, aliases: AliasCollection) -> Result<(), String> {
+        let is_key = |name: &str| self.exact.contains_key(name)
+            || ParsedKeyTree::create(name).map(|x| self.convert_expanded(&x).is_ok()).unwrap_or(false);
+        if let Some(name) = aliases.names().into_iter().find(|x| is_key(x)) {
+            return Err(format!("The alias {} has the same name as a key.", name));
+        }
+        self.aliases = aliases;
+        Ok(())
+    }
+
+    /// The aliases expanded by `convert`.
+    pub fn aliases(&self) -> &AliasCollection {
+        &self.aliases
+    }
+
+    /// Every identifier registered by `register` (sorted).
+    pub fn identifiers(&self) -> Vec<String> {
+        let mut ans: Vec<String> = self.exact.keys().cloned().collect();
+        ans.sort();
+        ans
+    }
+
+    /// Find the constructor for an identifier.
+    /// Exact matches are preferred over repeated characters, which are preferred over prefixes.
+    pub fn lookup(&self, identifier: &str) -> Option<KeyConstructor> {
+        if let Some(v) = self.exact.get(identifier) {
+            return Some(*v);
+        }
+        let repeated = self.repeated.iter()
+            .find(|x| !identifier.is_empty() && identifier.chars().all(|c| c == x.0));
+        if let Some(v) = repeated {
+            return Some(v.1);
+        }
+        self.prefixes.iter()
+            .find(|x| identifier.starts_with(&x.0))
+            .map(|x| x.1)
+    }
+
+    /// Convert a parsed key tree into a key.
+    pub fn convert(&self, tree: &ParsedKeyTree) -> Result<Box<KeyCode>, String> {
+        // Expanding an expanded tree doesn't change it, so nested keys converting their
+        // arguments won't expand anything twice.
+        if !self.aliases.is_empty() {
+            let tree = self.aliases.expand(tree)?;
+            return self.convert_expanded(&tree);
+        }
+        self.convert_expanded(tree)
+    }
+
+    fn convert_expanded(&self, tree: &ParsedKeyTree) -> Result<Box<KeyCode>, String> {
+        match self.lookup(&tree.identifier) {
+            Some(constructor) => constructor(tree, self),
+            None => {
+                let candidates = self.identifiers().into_iter()
+                    .chain(self.aliases.names())
+                    .chain(simple_key_names());
+                Err(unknown_key_message(&tree.identifier, candidates))
+            }
+        }
+    }
+
+    /// Parse key syntax (e.g. `MO(nav)`) into a key.
+    pub fn parse(&self, v: &str) -> Result<Box<KeyCode>, String> {
+        let tree = ParsedKeyTree::create(v)?;
+        self.convert(&tree)
+    }
+}
+
+impl Default for KeyRegistry {
+    fn default() -> KeyRegistry {
+        KeyRegistry::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct CustomKey;
+    impl KeyCode for CustomKey {
+        fn to_key_syntax(&self) -> Option<String> { Some("CUSTOM".to_string()) }
+    }
+
+    #[test]
+    fn key_registry_builtin_lookups() {
+        let registry = KeyRegistry::new();
+        assert!(registry.parse("MO(nav)").is_ok());
+        assert!(registry.parse("____").is_ok());
+        assert!(registry.parse("XXX").is_ok());
+        assert!(registry.parse("KC_A").is_ok());
+        assert!(registry.parse("KEY_A").is_ok());
+        assert!(registry.parse("NOPE").is_err());
+        assert!(KeyRegistry::empty().parse("KC_A").is_err());
+    }
+
+    #[test]
+    fn key_registry_settings() {
+        let mut registry = KeyRegistry::new();
+        registry.settings.update_from_json(&json::parse(r#"{ "hold_ms": 150, "tapping_term_ms": 180 }"#).unwrap()).unwrap();
+        assert_eq!(registry.settings.hold_threshold, Duration::from_millis(150));
+        assert_eq!(registry.settings.tapping_term, Duration::from_millis(180));
+        assert!(!registry.settings.permissive_hold);
+        assert!(registry.settings.update_from_json(&json::parse(r#"{ "permissive_hold": 1 }"#).unwrap()).is_err());
+
+        // Optional arguments default to the settings.
+        assert_eq!(registry.parse("LT(nav,KC_SPACE)").unwrap().to_key_syntax().unwrap(), "LT(nav,KC_SPACE,150)");
+        assert_eq!(registry.parse("MT(KC_LEFTCTRL,KC_A)").unwrap().to_key_syntax().unwrap(), "MT(KC_LEFTCTRL,KC_A,180,false)");
+        assert_eq!(
+            registry.parse("MT(KC_LEFTCTRL, KC_A, permissive_hold=true)").unwrap().to_key_syntax().unwrap(),
+            "MT(KC_LEFTCTRL,KC_A,180,true)");
+        assert_eq!(
+            registry.parse("LT(layer=nav, tap=KC_SPACE, hold_ms=100ms)").unwrap().to_key_syntax().unwrap(),
+            "LT(nav,KC_SPACE,100)");
+
+        // The examples from the docs, using QMK's short modifier names.
+        assert_eq!(
+            registry.parse("LT(layer=nav, tap=KC_SPACE, hold_ms=150)").unwrap().to_key_syntax().unwrap(),
+            "LT(nav,KC_SPACE,150)");
+        assert_eq!(
+            registry.parse("MT(KC_LCTRL, KC_A, tapping_term=180, permissive_hold=true)").unwrap().to_key_syntax().unwrap(),
+            "MT(KC_LEFTCTRL,KC_A,180,true)");
+        assert_eq!(registry.parse("KC_RSFT").unwrap().to_key_syntax().unwrap(), "KC_RIGHTSHIFT");
+    }
+
+    #[test]
+    fn key_registry_custom_key() {
+        let mut registry = KeyRegistry::new();
+        registry.register("CUSTOM", |_, _| Ok(Box::new(CustomKey)));
+
+        // Custom keys can be nested inside built-in keys.
+        assert!(registry.parse("CUSTOM").is_ok());
+        assert!(registry.parse("MACRO(KC_A,CUSTOM)").is_ok());
+        assert!(KeyRegistry::new().parse("MACRO(KC_A,CUSTOM)").is_err());
+
+        // Custom keys are suggested when misspelled.
+        assert_eq!(registry.parse("CUSTOMM").err().unwrap(), "unknown key CUSTOMM, did you mean CUSTOM?");
+    }
+
+    #[test]
+    fn key_registry_aliases() {
+        let mut aliases = AliasCollection::new();
+        aliases.add("HRM(mod,key)", "MT(mod,key,150)").unwrap();
+        aliases.add("PAREN_L", "SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT)").unwrap();
+        let mut registry = KeyRegistry::new();
+        registry.set_aliases(aliases).unwrap();
+
+        // Aliases expand into the keys they stand for.
+        assert_eq!(registry.parse("HRM(KC_LEFTCTRL,KC_A)").unwrap().to_key_syntax().unwrap(), "MT(KC_LEFTCTRL,KC_A,150,false)");
+        assert_eq!(
+            registry.parse("MACRO(PAREN_L,KC_A)").unwrap().to_key_syntax().unwrap(),
+            "MACRO(SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT),KC_A)");
+        assert_eq!(registry.parse("PAREN_LL").err().unwrap(), "unknown key PAREN_LL, did you mean PAREN_L?");
+
+        // Aliases can't replace keys.
+        for name in &["MO(x)", "KC_A", "KC_LCTRL", "___", "XXX"] {
+            let mut aliases = AliasCollection::new();
+            aliases.add(name, "TG(x)").unwrap();
+            assert!(registry.set_aliases(aliases).unwrap_err().ends_with("has the same name as a key."), "{}", name);
+        }
+    }
+}
@@ -1,12 +1,21 @@
 use json;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::Read;
-use std::time::{Instant};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use crate::input_keyboard::*;
 use crate::output_keyboard::*;
 use crate::virtual_keyboard_matrix::*;
 use crate::layer::*;
 use crate::keys::*;
+use crate::parser::{format_argument, KeySyntaxError, ParsedKeyTree};
+use crate::key_registry::KeyRegistry;
+use crate::alias::AliasCollection;
+use crate::steno::StenoEngine;
+use crate::combo::{ComboCollection, ComboEvent};
+use crate::error::SpaceCadetError;
+use crate::document::{KeyEntry, LayoutDocument, LayoutSource};
 
 /// A driver that includes in/out devices, a matrix, and key layers.
 pub struct KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
@@ -14,11 +23,29 @@ pub struct KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
     pub output: O,
     pub matrix: VirtualKeyboardMatrix,
     pub layered_codes: Vec<KeyCodeMatrix>,
-    pub layer_attributes: LayerCollection
+    pub layer_attributes: LayerCollection,
+    /// The steno engine from the layout's `"steno"` section. If there isn't one, an engine that
+    /// types raw strokes is created the first time a steno key is pressed.
+    pub steno: Option<StenoEngine>,
+    /// The combos from the layout's `"combos"` section.
+    pub combos: ComboCollection,
 }
 
 impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
 
+    /// Create a driver without any layers (add them with `add_layer` or `load_layout`).
+    pub fn new(input: I, output: O, matrix: VirtualKeyboardMatrix) -> KeyboardDriver<I, O> {
+        KeyboardDriver {
+            input,
+            output,
+            matrix,
+            layered_codes: Vec::new(),
+            layer_attributes: LayerCollection::new(),
+            steno: None,
+            combos: ComboCollection::new(),
+        }
+    }
+
     /// Add a layer to the driver by specify its attributes and code matrix.
     pub fn add_layer(&mut self, attr: LayerAttributes, codes: KeyCodeMatrix) {
         self.layer_attributes.add(attr);
@@ -27,26 +54,63 @@ impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
 
     pub fn clock_tick(&mut self, now: Instant) {
 
+        // Handle any combo keys that weren't pressed together in time.
+        let events = self.combos.check_timeout(now);
+        self.handle_combo_events(events, now);
+
         // Check for any keys that have been held down and oppressed by the user.
         for idx in self.matrix.detect_held_keys(now) {
-            self.matrix_state_changed(idx, KeyStateChange::Held, now);
+            let events = self.combos.update(idx, KeyStateChange::Held, now);
+            self.handle_combo_events(events, now);
         }
 
-        // Handle every event coming in from the input device.
+        // Handle every event coming in from the input device. Any input counts
+        // as activity, even if the matrix doesn't pass it on to a key.
         for i in self.input.read_events() {
+            self.layer_attributes.record_activity(now);
             match self.matrix.update(i.clone(), now) {
                 // The key was within the matrix - store the update for later.
                 MatrixUpdateResult::Bypass => { self.output.send_bypass_buffer(i); },
                 MatrixUpdateResult::Redundant(_idx) => {},
-                MatrixUpdateResult::StateChanged(idx, state) => { self.matrix_state_changed(idx, state, now); },
+                MatrixUpdateResult::StateChanged(idx, state) => {
+                    let events = self.combos.update(idx, state, now);
+                    self.handle_combo_events(events, now);
+                },
                 MatrixUpdateResult::Blocked => {}
             }
         }
 
+        // Keys that are still being held count as activity (e.g. a held MO key
+        // shouldn't let its layer time out). Then check for idle layers.
+        if self.matrix.is_any_key_pressed() {
+            self.layer_attributes.record_activity(now);
+        }
+        self.layer_attributes.check_timeouts(now);
+
         // Check if any layer event callbacks need to be processed.
         self.layer_attributes.check_event_callbacks(self.output.get_stats());
     }
 
+    fn handle_combo_events(&mut self, events: Vec<ComboEvent>, now: Instant) {
+        for event in events {
+            match event {
+                ComboEvent::Key(idx, state, t) => self.matrix_state_changed(idx, state, t),
+                ComboEvent::Combo(c, state) => {
+                    let combo = &mut self.combos.combos[c];
+                    let mut context = KeyEventContext {
+                        output_device: &mut self.output,
+                        virtual_matrix: &mut self.matrix,
+                        layers: &mut self.layer_attributes,
+                        steno: &mut self.steno,
+                        location: combo.positions[0],
+                        now
+                    };
+                    combo.code.handle_event(&mut context, state);
+                }
+            }
+        }
+    }
+
     fn matrix_state_changed(&mut self, idx: Index2D, state: KeyStateChange, now: Instant) {
         // Starting at the highest enabled layer, find the first key that's
         // not transparent.
@@ -62,6 +126,7 @@ impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
                         output_device: &mut self.output,
                         virtual_matrix: &mut self.matrix,
                         layers: &mut self.layer_attributes,
+                        steno: &mut self.steno,
                         location: idx,
                         now
                     };
@@ -79,6 +144,142 @@ impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
         Ok(())
     }
 
+    /// Check the layout for mistakes. Errors include everything `verify` rejects, plus layouts
+    /// that can lock you out of the keyboard. Warnings are layouts that work, but probably
+    /// not as intended.
+    pub fn lint(&self) -> Vec<LintFinding> {
+        // The other checks assume every layer is the same size as the matrix.
+        if let Err(e) = self.verify_dims() {
+            return vec![LintFinding::error(e)];
+        }
+        let mut ans: Vec<LintFinding> = self.key_constraint_errors().into_iter().map(LintFinding::error).collect();
+        let names: Vec<&str> = self.layer_attributes.attributes.iter().map(|x| x.name.as_str()).collect();
+        let dim = self.matrix.dim();
+        // Rows can be different lengths, so only check positions that every layer has.
+        let positions: Vec<Index2D> = (0..dim.0).flat_map(|r| (0..dim.1).map(move |c| (r, c)))
+            .filter(|p| self.layered_codes.iter().all(|x| x.codes.get(p.0).and_then(|row| row.get(p.1)).is_some()))
+            .collect();
+        let key = |layer: usize, idx: Index2D| &self.layered_codes[layer].codes[idx.0][idx.1];
+        // Every key in the layout, with its layer and position.
+        let all_keys = || (0..names.len()).flat_map(|i| positions.iter().map(move |p| (i, *p)));
+
+        // Keys in the matrix twice.
+        for (key, idx) in self.matrix.duplicate_keys() {
+            let used = self.matrix.index_of(key).unwrap();
+            ans.push(LintFinding::error(format!(
+                "{} is in the matrix at {}x{} and {}x{}, so the key at {}x{} can't be pressed.",
+                NormalKey { value: key.clone() }.name(), idx.0, idx.1, used.0, used.1, idx.0, idx.1)));
+        }
+
+        // Keys at positions without a physical key (duplicates are already reported).
+        let duplicates: Vec<Index2D> = self.matrix.duplicate_keys().iter().map(|x| x.1).collect();
+        for &p in positions.iter().filter(|x| self.matrix.key_at(**x).is_none() && !duplicates.contains(x)) {
+            for (i, name) in names.iter().enumerate() {
+                let code = key(i, p);
+                if !code.is_transparent() && !code.is_opaque() {
+                    ans.push(LintFinding::warning(format!(
+                        "The key at {}x{} on layer \"{}\" ({}) can't be pressed: the matrix doesn't have a key there.",
+                        p.0, p.1, name, describe_key_code(code.as_ref()))));
+                }
+            }
+        }
+
+        // Layers that are never enabled. Start with the layers enabled at startup, then
+        // add the layers that keys on them enable.
+        let mut reachable: Vec<bool> = self.layer_attributes.attributes.iter().map(|x| x.enabled).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in 0..reachable.len() {
+                if !reachable[i] {
+                    continue;
+                }
+                for action in positions.iter().flat_map(|p| key(i, *p).layer_actions()) {
+                    let target = match action {
+                        LayerAction::Momentary(name) | LayerAction::Activate(name) | LayerAction::Toggle(name) => name,
+                        LayerAction::Lock => continue
+                    };
+                    if let Some(&j) = self.layer_attributes.name_to_idx.get(&target) {
+                        changed |= !reachable[j];
+                        reachable[j] = true;
+                    }
+                }
+            }
+        }
+        for (i, _) in reachable.iter().enumerate().filter(|x| !*x.1) {
+            ans.push(LintFinding::warning(format!(
+                "Layer \"{}\" is never enabled: it's disabled at startup, and no key on an enabled layer enables it.", names[i])));
+        }
+
+        // The top key at a position, starting from a layer and looking down.
+        let top_key = |layer: usize, idx: Index2D| {
+            (0..=layer).rev().find(|x| !key(*x, idx).is_transparent()).map(|x| (x, key(x, idx)))
+        };
+        // Whether a key on a layer (or a key below it that it's transparent to) does any of the actions.
+        let can_reach = |layer: usize, actions: &[LayerAction]| positions.iter()
+            .filter_map(|p| top_key(layer, *p))
+            .any(|(_, code)| code.layer_actions().iter().any(|x| actions.contains(x)));
+        // The first key in the layout that does any of the actions.
+        let any_key = |actions: &[LayerAction]| all_keys()
+            .find(|(i, p)| key(*i, *p).layer_actions().iter().any(|x| actions.contains(x)));
+
+        // Trap states: a layer that's turned on by AL, TG or LLOCK, with no way to turn it off.
+        let has_timeout = self.layer_attributes.idle_timeout.is_some();
+        for (j, name) in names.iter().enumerate() {
+            if has_timeout || self.layer_attributes.attributes[j].timeout.is_some() {
+                continue;
+            }
+            let toggle = LayerAction::Toggle(name.to_string());
+            if let Some((i, p)) = any_key(&[LayerAction::Activate(name.to_string()), toggle.clone()]) {
+                if !can_reach(j, &[toggle]) {
+                    ans.push(LintFinding::error(format!(
+                        "{} at {}x{} on layer \"{}\" turns on layer \"{}\", but nothing on it or below it turns it off \
+                         (add TG({}) or a timeout_ms).",
+                        describe_key_code(key(i, p).as_ref()), p.0, p.1, names[i], name, format_argument(name))));
+                }
+                continue;
+            }
+
+            // A momentary layer can be locked on by an LLOCK on a layer above it, and is unlocked
+            // by LLOCK (or turned off by TG). An LLOCK on or below the layer can unlock it, too.
+            let momentary = any_key(&[LayerAction::Momentary(name.to_string())]).is_some();
+            let locked_by = all_keys().find(|(i, p)| *i > j && key(*i, *p).layer_actions().contains(&LayerAction::Lock));
+            if let (true, Some((i, p))) = (momentary, locked_by) {
+                if !can_reach(j, &[LayerAction::Lock, toggle]) {
+                    ans.push(LintFinding::error(format!(
+                        "{} at {}x{} on layer \"{}\" can lock layer \"{}\" on, but nothing on it or below it unlocks it \
+                         (add LLOCK, TG({}) or a timeout_ms).",
+                        describe_key_code(key(i, p).as_ref()), p.0, p.1, names[i], name, format_argument(name))));
+                }
+            }
+        }
+
+        // Modifiers that can't be released: if a layer is turned off while one of its modifiers
+        // is held, the release goes to the key below it instead.
+        for (j, name) in names.iter().enumerate() {
+            if any_key(&[LayerAction::Momentary(name.to_string()), LayerAction::Toggle(name.to_string())]).is_none() {
+                continue;
+            }
+            for p in positions.iter() {
+                let below = if j == 0 { None } else { top_key(j - 1, *p) };
+                let held_below = below.map(|(_, code)| code.held_keys()).unwrap_or_default();
+                for modifier in key(j, *p).held_keys().into_iter().filter(is_modifier) {
+                    if held_below.contains(&modifier) {
+                        continue;
+                    }
+                    let receiver = match below {
+                        Some((i, code)) => format!("{} on layer \"{}\"", describe_key_code(code.as_ref()), names[i]),
+                        None => "nothing".to_string()
+                    };
+                    ans.push(LintFinding::warning(format!(
+                        "{} at {}x{} on layer \"{}\" stays held if the layer is turned off first: the release goes to {}.",
+                        NormalKey { value: modifier }.name(), p.0, p.1, name, receiver)));
+                }
+            }
+        }
+        ans
+    }
+
     /// Verify that matrix and layers share the same dimensions.
     fn verify_dims(&self) -> Result<(), String> {
         let dim = self.matrix.dim();
@@ -96,19 +297,43 @@ impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
 
     /// Verify that per-key constraints are satisfied.
     fn verify_key_constraints(&self) -> Result<(), String> {
-        // Loop through every key in every layer.
-        // Verify every constraint- quit early if a constraint is violated.
+        match self.key_constraint_errors().into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+
+    /// Describe every per-key constraint that isn't satisfied.
+    fn key_constraint_errors(&self) -> Vec<String> {
+        // Loop through every key in every layer and check every constraint.
+        let mut ans = Vec::new();
         for i in self.layered_codes.iter().enumerate() {
             for r in i.1.codes.iter().enumerate() {
                 for c in r.1.iter().enumerate() {
                     let idx = (r.0, c.0);
                     for rule in c.1.get_constraints() {
-                        self.verify_key_constraint(rule, idx, &self.layer_attributes.attributes[i.0].name)?;
+                        if let Err(e) = self.verify_key_constraint(rule, idx, &self.layer_attributes.attributes[i.0].name) {
+                            ans.push(e);
+                        }
                     }
                 }
             }
         }
-        Ok(())
+
+        // Combos aren't on a layer, and get their own releases (so only missing layers matter).
+        for combo in self.combos.combos.iter() {
+            for rule in combo.code.get_constraints() {
+                if let KeyConstraint::LayerExists(name) = rule {
+                    if !self.layer_attributes.name_to_idx.contains_key(&name) {
+                        let keys: Vec<String> = combo.positions.iter().map(|x| format!("{}x{}", x.0, x.1)).collect();
+                        ans.push(format!(
+                            "Key constraint violated: the combo at {} references \"{}\", but no layer exists with that name.",
+                            keys.join("+"), name));
+                    }
+                }
+            }
+        }
+        ans
     }
 
     /// Verify that a single key constraint is met.
@@ -117,7 +342,7 @@ impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
             KeyConstraint::LayerExists(name) => {
                 if !self.layer_attributes.name_to_idx.get(&name).is_some() {
                     Err(format!(
-                        "Key constraint violated: the key at {}x{} on layer \"{}\" references \"{}\",\
+                        "Key constraint violated: the key at {}x{} on layer \"{}\" references \"{}\", \
                          but no layer exists with that name.",
                         idx.0, idx.1, parent_layer, name))
                 } else {
@@ -125,8 +350,11 @@ impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
                 }
             }
             KeyConstraint::KeyOnOtherLayerIsTransparent(name) => {
-                let layer_idx = *self.layer_attributes.name_to_idx.get(&name).unwrap();
-                let other_key = &self.layered_codes[layer_idx].codes[idx.0][idx.1];
+                // A missing layer is reported by its LayerExists constraint.
+                let other_key = match self.layer_attributes.name_to_idx.get(&name) {
+                    Some(&layer_idx) => &self.layered_codes[layer_idx].codes[idx.0][idx.1],
+                    None => return Ok(())
+                };
                 if !other_key.is_transparent() {
                     Err(format!(
                         "Key constraint violated: the key at {}x{} on layer \"{}\" requires the key \
@@ -140,41 +368,684 @@ impl<I, O> KeyboardDriver<I, O> where I: InputKeyboard, O: OutputKeyboard {
         }
     }
 
-    pub fn load_layers(&mut self, path: &str) {
-        // Load a json document.
-        let document = {
-            let mut file = File::open(path).unwrap();
-            let mut contents = String::new();
-            file.read_to_string(&mut contents).unwrap();
-            json::parse(&contents).unwrap()
-        };
+    /// Load layers from a JSON file. Keys are converted using the given registry,
+    /// so custom keys must be registered before loading.
+    pub fn load_layers(&mut self, path: &str, registry: &KeyRegistry) -> Result<(), SpaceCadetError> {
+        // Load a json document (merged with any files it includes).
+        self.load_layout(&LayoutDocument::load(path)?, registry)
+    }
+
+    /// Load layers from a document that's already been read (e.g. the `"layers"` of a config file).
+    pub fn load_layout(&mut self, layout: &LayoutDocument, registry: &KeyRegistry) -> Result<(), SpaceCadetError> {
+        let path = layout.path();
+        let document = &layout.value;
+
+        // Optional key arguments default to the layout's settings.
+        let mut registry = registry.clone();
+        registry.settings.update_from_json(&document["settings"])
+            .map_err(|e| SpaceCadetError::json(path, e))?;
+
+        // Aliases are expanded as keys are converted.
+        AliasCollection::from_json(&document["aliases"])
+            .and_then(|x| registry.set_aliases(x))
+            .map_err(|e| SpaceCadetError::json(path, e))?;
 
         // Loop through every layer, populate the attributes and init
         // the key matrix.
         for l in document["layer_order"].members() {
 
             // Load the layer attributes.
-            let name = l.as_str().unwrap();
+            let name = l.as_str()
+                .ok_or_else(|| SpaceCadetError::json(path, "\"layer_order\" must only contain layer names."))?;
             let layer = &document[name];
+            let enabled = layer["enabled"].as_bool()
+                .ok_or_else(|| SpaceCadetError::json(path, format!("The layer \"{}\" needs \"enabled\" to be true or false.", name)))?;
             self.layer_attributes.add(LayerAttributes {
                 name: name.to_string(),
-                enabled: layer["enabled"].as_bool().unwrap(),
+                enabled,
+                timeout: read_millis(&layer["timeout_ms"]).ok_or_else(|| SpaceCadetError::json(path,
+                    format!("The layer \"{}\" needs \"timeout_ms\" to be a number of milliseconds.", name)))?,
             });
 
-            // Load the key matrix for the layer.
-            let matrix = {
-                let mut ans = KeyCodeMatrix::new((0, 0));
-                for row in layer["keys"].members() {
-                    ans.codes.push(Vec::new());
-                    for col in row.members() {
-                        let code: Box<KeyCode> = str::parse(col.as_str().unwrap()).unwrap();
-                        ans.codes.last_mut().unwrap().push(code);
-                    }
+            // Load the key matrix for the layer (including any keys it extends).
+            let key_strings = read_layer_keys(document, &self.matrix, path, name, &mut Vec::new())?;
+            let mut matrix = KeyCodeMatrix::new((0, 0));
+            for row in key_strings {
+                let mut codes = Vec::with_capacity(row.len());
+                for key in row {
+                    codes.push(convert_key_string(&registry, layout, &key)?);
                 }
-                ans
-            };
+                matrix.codes.push(codes);
+            }
             self.layered_codes.push(matrix)
 
         }
+
+        // Optionally reset every layer after a period of inactivity.
+        self.layer_attributes.idle_timeout = read_millis(&document["idle_timeout_ms"])
+            .ok_or_else(|| SpaceCadetError::json(path, "\"idle_timeout_ms\" must be a number of milliseconds."))?;
+
+        // Combos are global, rather than on a layer.
+        self.combos = ComboCollection::from_json(&document["combos"], &self.matrix, &registry)
+            .map_err(|e| SpaceCadetError::json(path, e))?;
+        self.combos.term = registry.settings.combo_term;
+
+        // Configure the steno engine (paths are relative to the layers file).
+        if !document["steno"].is_null() {
+            let base_dir = Path::new(path).parent().unwrap_or(Path::new(""));
+            self.steno = Some(StenoEngine::from_json(&document["steno"], base_dir)
+                .map_err(|e| SpaceCadetError::json(path, e))?);
+        }
+        Ok(())
+    }
+
+    /// Convert the layers into a JSON document that `load_layers` can read.
+    /// Fails if a key can't be written in key syntax (e.g. a custom key that doesn't support it).
+    pub fn layers_to_json(&self) -> Result<json::JsonValue, String> {
+        let mut ans = json::JsonValue::new_object();
+        ans["layer_order"] = json::JsonValue::new_array();
+        for (attr, codes) in self.layer_attributes.attributes.iter().zip(self.layered_codes.iter()) {
+            ans["layer_order"].push(attr.name.clone()).unwrap();
+
+            let mut layer = json::JsonValue::new_object();
+            layer["enabled"] = attr.enabled.into();
+            if let Some(timeout) = attr.timeout {
+                layer["timeout_ms"] = (timeout.as_millis() as u64).into();
+            }
+            layer["keys"] = json::JsonValue::new_array();
+            for (r, row) in codes.codes.iter().enumerate() {
+                let row = row.iter().enumerate().map(|(c, x)| x.to_key_syntax().ok_or_else(|| format!(
+                    "The key at {}x{} on layer \"{}\" can't be written in key syntax.", r, c, attr.name)))
+                    .collect::<Result<Vec<String>, String>>()?;
+                layer["keys"].push(row).unwrap();
+            }
+            ans[attr.name.as_str()] = layer;
+        }
+
+        if let Some(timeout) = self.layer_attributes.idle_timeout {
+            ans["idle_timeout_ms"] = (timeout.as_millis() as u64).into();
+        }
+        if !self.combos.is_empty() {
+            ans["combos"] = self.combos.to_json(&self.matrix)?;
+        }
+        if let Some(steno) = &self.steno {
+            if !steno.config().is_null() {
+                ans["steno"] = steno.config().clone();
+            }
+        }
+        Ok(ans)
+    }
+
+    /// Write the layers to a JSON file that `load_layers` can read.
+    /// Keys are written in their canonical form, so aliases are written expanded
+    /// and extended layers are written with every key.
+    pub fn save_layers(&self, path: &str) -> std::io::Result<()> {
+        let document = self.layers_to_json()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let mut file = File::create(path)?;
+        document.write_pretty(&mut file, 2)?;
+        writeln!(file)
+    }
+}
+
+
+/// A key string from the layers file, and where it was written (used to locate errors).
+#[derive(Clone)]
+struct KeyString {
+    text: String,
+    layer: String,
+    entry: KeyEntry,
+}
+
+/// Read an optional number of milliseconds. Returns `None` if the value is set
+/// to something else (e.g. a string, a negative number or a fraction).
+fn read_millis(value: &json::JsonValue) -> Option<Option<Duration>> {
+    if value.is_null() {
+        return Some(None);
+    }
+    // `as_u64` truncates fractions, so check the number is whole first.
+    match (value.as_u64(), value.as_f64()) {
+        (Some(x), Some(f)) if x as f64 == f => Some(Some(Duration::from_millis(x))),
+        _ => None
+    }
+}
+
+/// Read a layer's key strings. A list of rows sets every key, while an object only sets some keys.
+/// Layers can start from the keys of another layer (`"extends"`), optionally transformed (`"transform"`).
+fn read_layer_keys(document: &json::JsonValue, matrix: &VirtualKeyboardMatrix, path: &str, name: &str, stack: &mut Vec<String>)
+    -> Result<Vec<Vec<KeyString>>, SpaceCadetError> {
+    let layer_error = |message: String| SpaceCadetError::json(path, format!("The layer \"{}\" {}", name, message));
+    let layer = &document[name];
+    if !layer.is_object() {
+        return Err(layer_error("isn't defined.".to_string()));
+    }
+
+    // Start with the keys of the extended layer (if any).
+    let mut keys = match &layer["extends"] {
+        json::JsonValue::Null => Vec::new(),
+        parent => {
+            let parent = parent.as_str()
+                .ok_or_else(|| layer_error("must name the layer it extends with a string.".to_string()))?;
+            stack.push(name.to_string());
+            if stack.iter().any(|x| x == parent) {
+                stack.push(parent.to_string());
+                return Err(layer_error(format!("extends itself: {}", stack.join(" -> "))));
+            }
+            let ans = read_layer_keys(document, matrix, path, parent, stack)?;
+            stack.pop();
+            ans
+        }
+    };
+    if !layer["transform"].is_null() {
+        keys = transform_layer_keys(keys, &layer["transform"], matrix).map_err(layer_error)?;
+    }
+
+    // Then apply the layer's own keys.
+    let key_text = |v: &json::JsonValue, entry: KeyEntry| v.as_str()
+        .map(|x| KeyString { text: x.to_string(), layer: name.to_string(), entry })
+        .ok_or_else(|| layer_error(format!("has a key that isn't a string: {}", v)));
+    if layer["keys"].is_object() {
+        if keys.is_empty() {
+            let dim = matrix.dim();
+            keys = (0..dim.0).map(|r| (0..dim.1).map(|c| {
+                KeyString { text: "___".to_string(), layer: name.to_string(), entry: KeyEntry::Row(r, c) }
+            }).collect()).collect();
+        }
+        let mut assigned = HashSet::new();
+        for (position, v) in layer["keys"].entries() {
+            let idx = matrix.parse_position(position)
+                .map_err(|e| layer_error(format!("has a bad key position: {}", e)))?;
+            if !assigned.insert(idx) {
+                return Err(layer_error(format!("sets the key at {},{} more than once.", idx.0, idx.1)));
+            }
+            match keys.get_mut(idx.0).and_then(|x| x.get_mut(idx.1)) {
+                Some(key) => *key = key_text(v, KeyEntry::Named(position.to_string()))?,
+                None => return Err(layer_error(format!("sets the key at {},{}, which isn't in the layer it extends.", idx.0, idx.1)))
+            }
+        }
+    } else if layer["keys"].is_array() {
+        // A list of rows would replace every extended key, so it must be an object instead.
+        if !layer["extends"].is_null() || !layer["transform"].is_null() {
+            return Err(layer_error("extends another layer, so its \"keys\" must be an object that replaces some keys.".to_string()));
+        }
+        for (r, row) in layer["keys"].members().enumerate() {
+            keys.push(row.members().enumerate().map(|(c, v)| key_text(v, KeyEntry::Row(r, c))).collect::<Result<Vec<_>, _>>()?);
+        }
+    } else if !layer["keys"].is_null() {
+        return Err(layer_error("needs \"keys\" to be a list of rows or an object.".to_string()));
+    }
+    Ok(keys)
+}
+
+/// Transform a layer's keys. A transform is `"mirror"` (swap left and right), `{ "move": { "from": "to", ... } }`
+/// (move keys between positions), or a list of transforms applied in order.
+fn transform_layer_keys(keys: Vec<Vec<KeyString>>, transform: &json::JsonValue, matrix: &VirtualKeyboardMatrix)
+    -> Result<Vec<Vec<KeyString>>, String> {
+    if transform.is_array() {
+        let mut keys = keys;
+        for i in transform.members() {
+            keys = transform_layer_keys(keys, i, matrix)?;
+        }
+        Ok(keys)
+    } else if transform.as_str() == Some("mirror") {
+        Ok(keys.into_iter().map(|x| x.into_iter().rev().collect()).collect())
+    } else if transform["move"].is_object() {
+        // Keys are moved from the original positions, so two keys can swap places.
+        let mut ans = keys.clone();
+        for (from, to) in transform["move"].entries() {
+            let to = to.as_str().ok_or_else(|| format!("moves {} to a position that isn't a string: {}", from, to))?;
+            let from_idx = matrix.parse_position(from).map_err(|e| format!("has a bad key position: {}", e))?;
+            let to_idx = matrix.parse_position(to).map_err(|e| format!("has a bad key position: {}", e))?;
+            let key = keys.get(from_idx.0).and_then(|x| x.get(from_idx.1))
+                .ok_or_else(|| format!("moves {}, which isn't in the layer it extends.", from))?;
+            match ans.get_mut(to_idx.0).and_then(|x| x.get_mut(to_idx.1)) {
+                Some(v) => *v = key.clone(),
+                None => return Err(format!("moves a key to {}, which isn't in the layer it extends.", to))
+            }
+        }
+        Ok(ans)
+    } else {
+        Err(format!("has an unknown transform: {}", transform))
+    }
+}
+
+/// Convert a key string from the layers file, describing any error by where it was written.
+fn convert_key_string(registry: &KeyRegistry, layout: &LayoutDocument, key: &KeyString) -> Result<Box<KeyCode>, SpaceCadetError> {
+    let source = layout.source_of(&key.layer, &key.entry);
+    let tree = ParsedKeyTree::create(&key.text)
+        .map_err(|e| describe_key_syntax_error(source, key, &e))?;
+    registry.convert(&tree).map_err(|e| SpaceCadetError::Syntax {
+        path: source.path.clone(),
+        position: source.locate_key(&key.layer, &key.entry, 1),
+        location: describe_key_location(key),
+        message: e,
+        excerpt: None
+    })
+}
+
+/// Describe where a key is in the layers (e.g. `layer "base", key 0,1`).
+fn describe_key_location(key: &KeyString) -> String {
+    format!("layer \"{}\", key {}", key.layer, key.entry)
+}
+
+/// Describe a key syntax error, with a caret under the offending character. If the key's line and
+/// column in the file are known, the excerpt is that line of the file; otherwise, it's the key.
+fn describe_key_syntax_error(source: &LayoutSource, key: &KeyString, e: &KeySyntaxError) -> SpaceCadetError {
+    let position = source.locate_key(&key.layer, &key.entry, e.column);
+    let excerpt = match position {
+        Some((line, column)) => format!("{}\n{}", source.contents.lines().nth(line - 1).unwrap_or(""), e.caret(column)),
+        None => format!("  {}\n  {}", e.source, e.caret(e.column))
+    };
+    SpaceCadetError::Syntax {
+        path: source.path.clone(),
+        position,
+        location: describe_key_location(key),
+        message: e.message(),
+        excerpt: Some(excerpt),
+    }
+}
+
+/// How serious a problem found by `KeyboardDriver::lint` is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintLevel {
+    /// The layout works, but probably not as intended.
+    Warning,
+    /// The layout is rejected by `verify`, or can lock you out of the keyboard.
+    Error,
+}
+
+/// A problem found by `KeyboardDriver::lint`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintFinding {
+    pub level: LintLevel,
+    pub message: String,
+}
+
+impl LintFinding {
+    pub fn warning<S: ToString>(message: S) -> LintFinding {
+        LintFinding { level: LintLevel::Warning, message: message.to_string() }
+    }
+
+    pub fn error<S: ToString>(message: S) -> LintFinding {
+        LintFinding { level: LintLevel::Error, message: message.to_string() }
+    }
+}
+
+impl std::fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.level {
+            LintLevel::Warning => write!(f, "warning: {}", self.message),
+            LintLevel::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Check if a key is a modifier (e.g. `KEY_LEFTSHIFT`).
+fn is_modifier(key: &SimpleKey) -> bool {
+    matches!(key,
+        SimpleKey::KEY_LEFTSHIFT | SimpleKey::KEY_RIGHTSHIFT | SimpleKey::KEY_LEFTCTRL | SimpleKey::KEY_RIGHTCTRL |
+        SimpleKey::KEY_LEFTALT | SimpleKey::KEY_RIGHTALT | SimpleKey::KEY_LEFTMETA | SimpleKey::KEY_RIGHTMETA)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_io_keyboard::*;
+
+    fn get_test_driver() -> KeyboardDriver<TestInputKeyboard, TestOutputKeyboard> {
+        KeyboardDriver::new(TestInputKeyboard::new(), TestOutputKeyboard::new(),
+            VirtualKeyboardMatrix::new(vec![vec![Some(SimpleKey::KEY_1), Some(SimpleKey::KEY_2)]], None))
+    }
+
+    #[test]
+    fn save_and_load_layers() {
+        let path = std::env::temp_dir().join(format!("spacecadet_layers_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let registry = KeyRegistry::new();
+
+        // Build a driver with two layers, then write it to a file.
+        let mut original = get_test_driver();
+        let mut base = KeyCodeMatrix::new((1, 2));
+        base.codes[0][0] = registry.parse("LT(\"nav layer\",KC_SPACE,150)").unwrap();
+        base.codes[0][1] = registry.parse("SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT)").unwrap();
+        original.add_layer(LayerAttributes { name: "base".to_string(), enabled: true, timeout: None }, base);
+        let mut nav = KeyCodeMatrix::new((1, 2));
+        nav.codes[0][1] = registry.parse("MACRO(KC_A,TG(base),STENO(-Z))").unwrap();
+        original.add_layer(LayerAttributes {
+            name: "nav layer".to_string(),
+            enabled: false,
+            timeout: Some(Duration::from_secs(5))
+        }, nav);
+        original.layer_attributes.idle_timeout = Some(Duration::from_secs(30));
+        original.save_layers(path).unwrap();
+
+        // Load the file and check it renders the same document.
+        let mut loaded = get_test_driver();
+        loaded.load_layers(path, &registry).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(loaded.verify().is_ok());
+        assert_eq!(loaded.layers_to_json(), original.layers_to_json());
+        assert_eq!(loaded.layered_codes[0].codes[0][0].to_key_syntax().unwrap(), "LT(\"nav layer\",KC_SPACE,150)");
+        assert_eq!(loaded.layered_codes[1].codes[0][0].to_key_syntax().unwrap(), "___");
+        assert_eq!(loaded.layer_attributes.attributes[1].timeout, Some(Duration::from_secs(5)));
+
+        // A key that can't be written fails the save, rather than being saved as another key.
+        struct Custom;
+        impl KeyCode for Custom {}
+        loaded.layered_codes[1].codes[0][1] = Box::new(Custom);
+        assert_eq!(loaded.layers_to_json().unwrap_err(), "The key at 0x1 on layer \"nav layer\" can't be written in key syntax.");
+        let e = loaded.save_layers(path).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!Path::new(path).exists());
+    }
+
+    #[test]
+    fn load_sparse_layers() {
+        let path = std::env::temp_dir().join(format!("spacecadet_sparse_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let write = |contents: &str| std::fs::write(path, contents).unwrap();
+
+        // Keys can be set by physical key name or by row,col; the rest are transparent.
+        write(r#"{
+            "layer_order": [ "base", "nav" ],
+            "base": { "enabled": true, "keys": [ [ "KC_A", "MO(nav)" ] ] },
+            "nav": { "enabled": false, "keys": { "KC_1": "KC_LEFT" } }
+        }"#);
+        let mut driver = get_test_driver();
+        driver.load_layers(path, &KeyRegistry::new()).unwrap();
+        assert!(driver.verify().is_ok());
+        assert_eq!(driver.layered_codes[1].codes[0][0].to_key_syntax().unwrap(), "KC_LEFT");
+        assert_eq!(driver.layered_codes[1].codes[0][1].to_key_syntax().unwrap(), "___");
+
+        // Positions must be in the matrix, and can't be set twice.
+        write(r#"{ "layer_order": [ "base" ], "base": { "enabled": true, "keys": { "0,5": "KC_A" } } }"#);
+        assert!(get_test_driver().load_layers(path, &KeyRegistry::new()).is_err());
+        write(r#"{ "layer_order": [ "base" ], "base": { "enabled": true, "keys": { "KC_2": "KC_A", "0,1": "KC_B" } } }"#);
+        let e = get_test_driver().load_layers(path, &KeyRegistry::new()).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert!(e.to_string().ends_with("The layer \"base\" sets the key at 0,1 more than once."));
+    }
+
+    #[test]
+    fn load_extended_layers() {
+        let path = std::env::temp_dir().join(format!("spacecadet_extends_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let load = |contents: &str| {
+            std::fs::write(path, contents).unwrap();
+            let mut driver = get_test_driver();
+            let ans = driver.load_layers(path, &KeyRegistry::new()).map(|_| driver);
+            std::fs::remove_file(path).unwrap();
+            ans
+        };
+        let keys = |driver: &KeyboardDriver<TestInputKeyboard, TestOutputKeyboard>, layer: usize| -> Vec<String> {
+            driver.layered_codes[layer].codes[0].iter().map(|x| x.to_key_syntax().unwrap()).collect()
+        };
+
+        // Layers can extend (and override) other layers, including layers that aren't in the layer order.
+        let driver = load(r#"{
+            "layer_order": [ "base", "mirror", "swapped", "numbers" ],
+            "base": { "enabled": true, "keys": [ [ "KC_A", "KC_B" ] ] },
+            "mirror": { "enabled": false, "extends": "base", "transform": "mirror" },
+            "swapped": { "enabled": false, "extends": "base", "transform": { "move": { "KC_1": "KC_2", "KC_2": "KC_1" } } },
+            "numbers": { "enabled": false, "extends": "template", "keys": { "0,1": "KC_2" } },
+            "template": { "keys": [ [ "KC_1", "KC_9" ] ] }
+        }"#).unwrap();
+        assert_eq!(keys(&driver, 1), vec!["KC_B", "KC_A"]);
+        assert_eq!(keys(&driver, 2), vec!["KC_B", "KC_A"]);
+        assert_eq!(keys(&driver, 3), vec!["KC_1", "KC_2"]);
+
+        // Errors in extended keys point at the layer that wrote them.
+        let e = load("{\n\"layer_order\": [ \"top\" ],\n\"top\": { \"enabled\": true, \"extends\": \"bottom\" },\n\"bottom\": { \"keys\": [ [ \"KC_A\", \"NOPE\" ] ] }\n}")
+            .err().unwrap();
+        assert!(e.to_string().starts_with(&format!("{}:4:", path)));
+
+        // Layers can't extend themselves.
+        let e = load(r#"{
+            "layer_order": [ "a" ],
+            "a": { "enabled": true, "extends": "b" },
+            "b": { "extends": "a" }
+        }"#).err().unwrap();
+        assert!(e.to_string().ends_with("The layer \"b\" extends itself: a -> b -> a"));
+
+        // A list of rows would throw away the extended keys.
+        let e = load(r#"{
+            "layer_order": [ "base", "numbers" ],
+            "base": { "enabled": true, "keys": [ [ "KC_A", "KC_B" ] ] },
+            "numbers": { "enabled": false, "extends": "base", "transform": "mirror", "keys": [ [ "KC_1", "KC_2" ] ] }
+        }"#).err().unwrap();
+        assert!(e.to_string().ends_with(
+            "The layer \"numbers\" extends another layer, so its \"keys\" must be an object that replaces some keys."));
+    }
+
+    #[test]
+    fn combo_keys() {
+        let path = std::env::temp_dir().join(format!("spacecadet_combos_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, r#"{
+            "layer_order": [ "base" ],
+            "base": { "enabled": true, "keys": [ [ "KC_A", "KC_B" ] ] },
+            "combos": { "KC_1+KC_2": "KC_ESC" },
+            "settings": { "combo_term_ms": 30 }
+        }"#).unwrap();
+        let mut driver = get_test_driver();
+        driver.load_layers(path, &KeyRegistry::new()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(driver.combos.term, Duration::from_millis(30));
+        assert_eq!(driver.layers_to_json().unwrap()["combos"], json::parse(r#"{ "KC_1+KC_2": "KC_ESC" }"#).unwrap());
+        assert!(driver.verify().is_ok());
+
+        let t = Instant::now();
+        let ms = |x| t + Duration::from_millis(x);
+        let mut tick = |event: Option<(SimpleKey, KeyStateChange)>, now: Instant| -> Vec<String> {
+            if let Some((key, state)) = event {
+                driver.input.events.push(KeyState(key, state).into());
+            }
+            driver.clock_tick(now);
+            driver.output.events.drain(..).map(|x| format!("{:?} {}", x.event_code, x.value)).collect()
+        };
+        let press = |key| Some((key, KeyStateChange::Pressed));
+        let release = |key| Some((key, KeyStateChange::Released));
+
+        // Both keys together send the combo's key.
+        assert!(tick(press(SimpleKey::KEY_1), t).is_empty());
+        assert_eq!(tick(press(SimpleKey::KEY_2), ms(10)), vec!["EV_KEY(KEY_ESC) 1"]);
+        assert_eq!(tick(release(SimpleKey::KEY_2), ms(50)), vec!["EV_KEY(KEY_ESC) 0"]);
+        assert!(tick(release(SimpleKey::KEY_1), ms(60)).is_empty());
+
+        // One key on its own is sent once the combo term has passed, or when it's released.
+        assert!(tick(press(SimpleKey::KEY_1), ms(100)).is_empty());
+        assert_eq!(tick(None, ms(130)), vec!["EV_KEY(KEY_A) 1"]);
+        assert_eq!(tick(release(SimpleKey::KEY_1), ms(140)), vec!["EV_KEY(KEY_A) 0"]);
+        assert!(tick(press(SimpleKey::KEY_2), ms(200)).is_empty());
+        assert_eq!(tick(release(SimpleKey::KEY_2), ms(210)), vec!["EV_KEY(KEY_B) 1", "EV_KEY(KEY_B) 0"]);
+
+        // A combo's key must only use layers that exist.
+        driver.combos.combos[0].code = "TG(nav)".parse().unwrap();
+        assert_eq!(driver.verify().unwrap_err(),
+            "Key constraint violated: the combo at 0x0+0x1 references \"nav\", but no layer exists with that name.");
+    }
+
+    #[test]
+    fn idle_timeouts() {
+        let path = std::env::temp_dir().join(format!("spacecadet_idle_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let load = |document: &str| {
+            std::fs::write(path, document).unwrap();
+            let mut driver = get_test_driver();
+            let result = driver.load_layers(path, &KeyRegistry::new()).map(|_| driver);
+            std::fs::remove_file(path).unwrap();
+            result
+        };
+
+        // Timeouts must be a whole number of milliseconds.
+        for bad in &[ r#""50""#, "-50", "50.5" ] {
+            let err = load(&format!(r#"{{
+                "layer_order": [ "base" ],
+                "base": {{ "enabled": true, "keys": [ [ "KC_A", "KC_B" ] ], "timeout_ms": {} }}
+            }}"#, bad)).err().unwrap();
+            assert!(err.to_string().ends_with("The layer \"base\" needs \"timeout_ms\" to be a number of milliseconds."), "{}", err);
+            let err = load(&format!(r#"{{
+                "layer_order": [ "base" ],
+                "base": {{ "enabled": true, "keys": [ [ "KC_A", "KC_B" ] ] }},
+                "idle_timeout_ms": {}
+            }}"#, bad)).err().unwrap();
+            assert!(err.to_string().ends_with("\"idle_timeout_ms\" must be a number of milliseconds."), "{}", err);
+        }
+
+        let mut driver = load(r#"{
+            "layer_order": [ "base", "nav" ],
+            "base": { "enabled": true, "keys": [ [ "KC_A", "KC_B" ] ] },
+            "nav": { "enabled": false, "keys": [ [ "KC_1", "KC_2" ] ] },
+            "idle_timeout_ms": 50
+        }"#).unwrap();
+        assert_eq!(driver.layer_attributes.idle_timeout, Some(Duration::from_millis(50)));
+
+        // Every input event counts as activity, including keys outside the
+        // matrix and repeated releases, so the layer stays on.
+        let t = Instant::now();
+        let ms = |x| t + Duration::from_millis(x);
+        driver.layer_attributes.set("nav", true);
+        driver.clock_tick(t);
+        driver.input.events.push(KeyState(SimpleKey::KEY_9, KeyStateChange::Pressed).into());
+        driver.clock_tick(ms(40));
+        assert!(driver.layer_attributes.is_enabled(1));
+        driver.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Released).into());
+        driver.clock_tick(ms(80));
+        assert!(driver.layer_attributes.is_enabled(1));
+        driver.clock_tick(ms(110));
+        assert!(driver.layer_attributes.is_enabled(1));
+        driver.clock_tick(ms(130));
+        assert!(!driver.layer_attributes.is_enabled(1));
+    }
+
+    #[test]
+    fn key_syntax_error_location() {
+        let dir = std::env::temp_dir().join(format!("spacecadet_error_location_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let load = |name: &str| {
+            let mut driver = get_test_driver();
+            driver.load_layers(&path(name), &KeyRegistry::new()).err().unwrap().to_string()
+        };
+
+        // A bad key that's written twice is reported at the key that's loaded first, with a caret
+        // under the offending character (escapes in the file are skipped over).
+        let nav = r#"            "nav": { "enabled": false, "keys": { "KC_2": "TG(\"a\",,KC_A)", "KC_1": "TG(\"a\",,KC_A)" } }"#;
+        write("layers.json", &format!(r#"{{
+            "layer_order": [ "base", "nav" ],
+            "base": {{ "enabled": true, "keys": [ [ "KC_A", "KC_B" ] ] }},
+{}
+        }}"#, nav));
+        assert_eq!(load("layers.json"), format!(
+            "{}:4:95: expected a key, found \",\"\n{}\n{}^", path("layers.json"), nav, " ".repeat(94)));
+
+        // Keys from an included file are reported in that file, even if the text is in both.
+        write("shared.json", r#"{ "base": { "enabled": true, "keys": [ [ "KC_A", "NOPE" ] ] } }"#);
+        write("main.json", r#"{ "include": "shared.json", "layer_order": [ "base" ], "nav": { "keys": [ [ "NOPE" ] ] } }"#);
+        assert!(load("main.json").starts_with(&format!("{}:1:51: ", path("shared.json"))));
+
+        // Other formats don't keep positions, so errors name the layer and key instead.
+        write("layers.yaml", "layer_order: [ base ]\nbase:\n  enabled: true\n  keys:\n    - [ KC_A, 'LT(nav,,KC_A)' ]\n");
+        assert_eq!(load("layers.yaml"), format!(
+            "{}: layer \"base\", key 0,1: expected a key, found \",\"\n  LT(nav,,KC_A)\n         ^", path("layers.yaml")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lint_layouts() {
+        // Keys are checked by what they do, so aliases and custom keys are checked too.
+        let mut registry = KeyRegistry::new();
+        let mut aliases = AliasCollection::new();
+        aliases.add("PLAY", "AL(game)").unwrap();
+        registry.set_aliases(aliases).unwrap();
+        registry.register("NAV", |_, _| Ok(Box::new(MomentarilyEnableLayerKey { layer_name: "nav".to_string() })));
+        let driver = |layers: &[(&str, bool, [&str; 3])]| {
+            let mut driver = get_test_driver();
+            driver.matrix = VirtualKeyboardMatrix::new(vec![vec![Some(SimpleKey::KEY_1), Some(SimpleKey::KEY_2), None]], None);
+            for (name, enabled, keys) in layers.iter() {
+                let mut codes = KeyCodeMatrix::new((1, 3));
+                for (idx, key) in keys.iter().enumerate() {
+                    codes.codes[0][idx] = registry.parse(key).unwrap();
+                }
+                driver.add_layer(LayerAttributes { name: name.to_string(), enabled: *enabled, timeout: None }, codes);
+            }
+            driver
+        };
+        let messages = |driver: &KeyboardDriver<TestInputKeyboard, TestOutputKeyboard>| -> Vec<String> {
+            driver.lint().iter().map(|x| x.to_string()).collect()
+        };
+
+        // A layout with nothing wrong.
+        let good = driver(&[("base", true, ["MO(nav)", "KC_A", "XXX"]), ("nav", false, ["___", "KC_LEFT", "___"])]);
+        assert_eq!(good.lint(), vec![]);
+
+        // Verify errors are lint errors, and every one is reported.
+        let bad = driver(&[("base", true, ["MO(nav)", "MO(nope)", "___"]), ("nav", false, ["KC_A", "___", "___"])]);
+        assert_eq!(messages(&bad), vec![
+            "error: Key constraint violated: the key at 0x0 on layer \"base\" requires the key at 0x0 on \"nav\" to be transparent.",
+            "error: Key constraint violated: the key at 0x1 on layer \"base\" references \"nope\", but no layer exists with that name.",
+        ]);
+
+        // Trap states, unreachable layers and keys, and stuck modifiers.
+        let trapped = driver(&[
+            ("base", true, ["AL(game)", "MO(nav)", "KC_A"]),
+            ("nav", false, ["KC_LEFTSHIFT", "___", "___"]),
+            ("game", false, ["KC_W", "XXX", "___"]),
+            ("unused", false, ["___", "___", "___"]),
+        ]);
+        assert_eq!(messages(&trapped), vec![
+            "warning: The key at 0x2 on layer \"base\" (KC_A) can't be pressed: the matrix doesn't have a key there.",
+            "warning: Layer \"unused\" is never enabled: it's disabled at startup, and no key on an enabled layer enables it.",
+            "error: AL(game) at 0x0 on layer \"base\" turns on layer \"game\", but nothing on it or below it turns it off \
+             (add TG(game) or a timeout_ms).",
+            "warning: KC_LEFTSHIFT at 0x0 on layer \"nav\" stays held if the layer is turned off first: the release goes to \
+             AL(game) on layer \"base\".",
+        ]);
+
+        // A TG on the layer (or a timeout) gets you out of it.
+        let escapable = driver(&[("base", true, ["AL(game)", "KC_A", "XXX"]), ("game", false, ["KC_W", "TG(game)", "___"])]);
+        assert_eq!(escapable.lint(), vec![]);
+
+        // Keys in the matrix twice.
+        let mut duplicated = driver(&[("base", true, ["KC_A", "KC_B", "KC_C"])]);
+        duplicated.matrix = VirtualKeyboardMatrix::new(vec![vec![Some(SimpleKey::KEY_1), Some(SimpleKey::KEY_2), Some(SimpleKey::KEY_1)]], None);
+        assert_eq!(messages(&duplicated), vec!["error: KC_1 is in the matrix at 0x0 and 0x2, so the key at 0x0 can't be pressed."]);
+        assert_eq!(duplicated.lint()[0].level, LintLevel::Error);
+
+        // Layer keys inside other keys, and keys from aliases.
+        let nested = driver(&[("base", true, ["MACRO(KC_A,AL(game))", "PLAY", "XXX"]), ("game", false, ["KC_W", "KC_S", "___"])]);
+        assert_eq!(messages(&nested), vec![
+            "error: MACRO(KC_A,AL(game)) at 0x0 on layer \"base\" turns on layer \"game\", but nothing on it or below it turns it off \
+             (add TG(game) or a timeout_ms).",
+        ]);
+
+        // A layer locked by an LLOCK above it, with no LLOCK on or below it to unlock it.
+        let locked = driver(&[
+            ("base", true, ["NAV", "KC_A", "XXX"]),
+            ("nav", false, ["___", "MO(sym)", "___"]),
+            ("sym", false, ["LLOCK", "___", "___"]),
+        ]);
+        assert_eq!(messages(&locked), vec![
+            "error: LLOCK at 0x0 on layer \"sym\" can lock layer \"nav\" on, but nothing on it or below it unlocks it \
+             (add LLOCK, TG(nav) or a timeout_ms).",
+        ]);
+        let unlockable = driver(&[("base", true, ["NAV", "LLOCK", "XXX"]), ("nav", false, ["___", "___", "___"])]);
+        assert_eq!(unlockable.lint(), vec![]);
+
+        // Mod-tap modifiers, and modifiers released by the same modifier below them.
+        let modifiers = driver(&[
+            ("base", true, ["MO(nav)", "KC_B", "XXX"]),
+            ("nav", false, ["___", "MT(KC_LEFTCTRL,KC_A)", "___"]),
+        ]);
+        assert_eq!(messages(&modifiers), vec![
+            "warning: KC_LEFTCTRL at 0x1 on layer \"nav\" stays held if the layer is turned off first: the release goes to \
+             KC_B on layer \"base\".",
+        ]);
+        let released = driver(&[
+            ("base", true, ["MO(nav)", "KC_LEFTCTRL", "XXX"]),
+            ("nav", false, ["___", "MT(KC_LEFTCTRL,KC_A)", "___"]),
+        ]);
+        assert_eq!(released.lint(), vec![]);
     }
 }
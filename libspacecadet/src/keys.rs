@@ -2,7 +2,7 @@ use evdev_rs as evdev;
 use crate::layer::LayerCollection;
 use crate::virtual_keyboard_matrix::{KeyStateChange, VirtualKeyboardMatrix};
 use crate::virtual_keyboard_matrix::Index2D;
-use crate::layer::ScheduledLayerEvent;
+use crate::layer::{LayerActivation, ScheduledLayerEvent};
 
 use std::str::FromStr;
 use std::time::{Duration, Instant};
@@ -10,12 +10,16 @@ pub use evdev::enums::EV_KEY as SimpleKey;
 use crate::virtual_keyboard_matrix::BlockedKeyStates;
 use crate::output_keyboard::{EventBuffer, OutputKeyboard};
 use crate::parser::*;
+use crate::key_registry::{KeyRegistry, KeySettings};
+use crate::steno::{StenoEngine, StenoKey};
 
 /// The context/state surrounding a key event (e.g. press).
 pub struct KeyEventContext<'a> {
     pub output_device: &'a mut OutputKeyboard,
     pub virtual_matrix: &'a mut VirtualKeyboardMatrix,
     pub layers: &'a mut LayerCollection,
+    /// The driver's steno engine (if it has one yet).
+    pub steno: &'a mut Option<StenoEngine>,
     pub location: Index2D,
     pub now: Instant,
 }
@@ -46,6 +50,19 @@ pub enum KeyConstraint {
     LayerExists(String)
 }
 
+/// Something a key can do to the layers. Used to check layouts (see `KeyboardDriver::lint`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum LayerAction {
+    /// Enables the layer while the key is held (e.g. `MO` or `OSL`).
+    Momentary(String),
+    /// Enables the layer until it's toggled off (`AL`, or a held `LT`).
+    Activate(String),
+    /// Enables the layer, or disables it if it's already enabled (`TG`).
+    Toggle(String),
+    /// Locks the highest momentary layer on, or unlocks the highest locked layer (`LLOCK`).
+    Lock,
+}
+
 
 /// The primary interface for custom keys (e.g. macros or layer toggles).
 pub trait KeyCode {
@@ -55,17 +72,39 @@ pub trait KeyCode {
     /// Check if the key is transparent (i.e. a pass-through to the key in the next lower layer).
     fn is_transparent(&self) -> bool { false }
 
+    /// Check if the key is opaque (i.e. a placeholder that ignores every event).
+    fn is_opaque(&self) -> bool { false }
+
     /// Get any constraints the key may have.
     fn get_constraints(&self) -> Vec<KeyConstraint> { vec![] }
+
+    /// Get what the key can do to the layers, including any keys inside it (e.g. in a macro).
+    fn layer_actions(&self) -> Vec<LayerAction> { vec![] }
+
+    /// Get the keys the key can leave pressed until it's released (e.g. the modifier of `MT`).
+    fn held_keys(&self) -> Vec<SimpleKey> { vec![] }
+
+    /// Render the key in its canonical key syntax (e.g. `LT(nav,KC_SPACE,150)`).
+    /// Parsing the result must produce an equivalent key.
+    ///
+    /// Custom keys should return the identifier they're registered under. By default, the key
+    /// can't be rendered (`None`), so saving a layout that uses it fails instead of losing the key.
+    fn to_key_syntax(&self) -> Option<String> { None }
+}
+
+/// Describe a key in messages (e.g. lint findings). Keys that can't be rendered in key syntax
+/// are described as `<custom key>`.
+pub fn describe_key_code(code: &KeyCode) -> String {
+    code.to_key_syntax().unwrap_or_else(|| "<custom key>".to_string())
 }
 
 
-// The FromStr trait lets str::parse() be used to create a key code.
+// The FromStr trait lets str::parse() be used to create a key code from the built-in keys.
+// Use `KeyRegistry::parse` to include custom keys.
 impl FromStr for Box<KeyCode> {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parsed = ParsedKeyTree::create(s)?;
-        convert_tokens_to_key(&parsed)
+        KeyRegistry::new().parse(s)
     }
 }
 
@@ -87,6 +126,7 @@ impl TransparentKey {
 }
 impl KeyCode for TransparentKey {
     fn is_transparent(&self) -> bool { true }
+    fn to_key_syntax(&self) -> Option<String> { Some("___".to_string()) }
 }
 
 /// A key that's the opposite of transparent; a no-op key that doesn't act on any events.
@@ -104,7 +144,10 @@ impl OpaqueKey {
         }
     }
 }
-impl KeyCode for OpaqueKey {}
+impl KeyCode for OpaqueKey {
+    fn is_opaque(&self) -> bool { true }
+    fn to_key_syntax(&self) -> Option<String> { Some("XXX".to_string()) }
+}
 
 #[derive(Clone)]
 pub struct NormalKey {
@@ -114,18 +157,34 @@ impl KeyCode for NormalKey {
     fn handle_event(&mut self, ctx: &mut KeyEventContext, state: KeyStateChange) {
         ctx.output_device.send(KeyState(self.value.clone(), state).into());
     }
+    fn held_keys(&self) -> Vec<SimpleKey> { vec![self.value.clone()] }
+    fn to_key_syntax(&self) -> Option<String> { Some(self.name()) }
 }
+/// QMK's short names for the modifiers (e.g. `KC_LCTL`), and the key they're written as.
+const MODIFIER_SHORT_NAMES: &[(&str, &str)] = &[
+    ("KC_LCTL", "KC_LEFTCTRL"), ("KC_LCTRL", "KC_LEFTCTRL"), ("KC_LSFT", "KC_LEFTSHIFT"), ("KC_LSHIFT", "KC_LEFTSHIFT"),
+    ("KC_LALT", "KC_LEFTALT"), ("KC_LGUI", "KC_LEFTMETA"), ("KC_RCTL", "KC_RIGHTCTRL"), ("KC_RCTRL", "KC_RIGHTCTRL"),
+    ("KC_RSFT", "KC_RIGHTSHIFT"), ("KC_RSHIFT", "KC_RIGHTSHIFT"), ("KC_RALT", "KC_RIGHTALT"), ("KC_RGUI", "KC_RIGHTMETA"),
+];
+
 impl NormalKey {
+    /// The name of the key in key syntax (e.g. `KC_Q`).
+    pub fn name(&self) -> String {
+        // KEY_Q is written as KC_Q.
+        format!("{:?}", self.value).replacen("KEY_", "KC_", 1)
+    }
+
     pub fn from_tokens(item: &ParsedKeyTree) -> Result<NormalKey, String> {
         if !item.args.is_empty() {
             Err("Simple keys don't have arguments".to_string())
         } else {
-            // Transform KC_Q into KEY_Q.
-            let split : Vec<&str> = item.identifier.split('_').collect();
+            // Modifiers can also be written with QMK's short names (e.g. KC_LCTL).
+            let name = MODIFIER_SHORT_NAMES.iter().find(|x| x.0 == item.identifier).map(|x| x.1).unwrap_or(&item.identifier);
+
+            // Transform KC_Q into KEY_Q (the rest of the name may contain more underscores).
+            let split : Vec<&str> = name.splitn(2, '_').collect();
             if split.len() == 1 {
                 Err("Missing an \"_\" character.".to_string())
-            } else if split.len() > 2 {
-                Err("Too many \"_\" characters.".to_string())
             } else {
                 let trial = ("KEY_".to_string() + split[1]).to_uppercase();
                 let v = evdev::enums::EventCode::from_str(&evdev::enums::EventType::EV_KEY, &trial);
@@ -134,7 +193,7 @@ impl NormalKey {
                         evdev::enums::EventCode::EV_KEY(k) => Ok(NormalKey { value : k }),
                         _ => Err("Key code was converted to an event, but it wasn't an EV_KEY.".to_string())
                     },
-                    None => Err("Couldn't convert \"".to_string() + &trial + "\" to an evdev key.")
+                    None => Err(unknown_key_message(&item.identifier, simple_key_names()))
                 }
             }
         }
@@ -142,12 +201,13 @@ impl NormalKey {
 }
 
 
-/// A key that's a collection of other simple keys that are quickly pressed sequentially.
+/// A key that's a collection of other keys that are quickly pressed and released sequentially.
 pub struct MacroKey {
     /// When to play the macro (e.g. when the key is pressed or released).
+    /// In key syntax, this is the `when` argument (e.g. `MACRO(KC_H,KC_I,when=release)`).
     pub play_macro_when: KeyStateChange,
-    /// The collection of keys to play.
-    pub keys: Vec<NormalKey>,
+    /// The collection of keys to play (any key, including nested macros).
+    pub keys: Vec<Box<KeyCode>>,
 }
 
 impl KeyCode for MacroKey {
@@ -159,9 +219,25 @@ impl KeyCode for MacroKey {
             }
         }
     }
+    fn get_constraints(&self) -> Vec<KeyConstraint> {
+        // A macro inherits the constraints of every key it plays.
+        self.keys.iter().flat_map(|x| x.get_constraints()).collect()
+    }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        self.keys.iter().flat_map(|x| x.layer_actions()).collect()
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        let mut args = self.keys.iter().map(|x| x.to_key_syntax()).collect::<Option<Vec<String>>>()?;
+        match self.play_macro_when {
+            KeyStateChange::Pressed => (),
+            KeyStateChange::Released => args.push("when=release".to_string()),
+            KeyStateChange::Held => args.push("when=hold".to_string()),
+        }
+        Some(format!("MACRO({})", args.join(",")))
+    }
 }
 impl MacroKey {
-    pub fn from_tokens(item: &ParsedKeyTree) -> Result<MacroKey, String> {
+    pub fn from_tokens(item: &ParsedKeyTree, registry: &KeyRegistry) -> Result<MacroKey, String> {
         if item.identifier != "MACRO" {
             Err("Wrong identifier.".to_string())
         } else {
@@ -171,8 +247,18 @@ impl MacroKey {
             };
 
             for i in item.args.iter() {
-                let converted = NormalKey::from_tokens(i)?;
-                ans.keys.push(converted)
+                match i.name.as_deref() {
+                    None => ans.keys.push(registry.convert(i)?),
+                    Some("when") => {
+                        ans.play_macro_when = match i.identifier.as_str() {
+                            "press" => KeyStateChange::Pressed,
+                            "release" => KeyStateChange::Released,
+                            "hold" => KeyStateChange::Held,
+                            v => return Err(format!("MACRO expects when to be press, release or hold, got \"{}\"", v))
+                        };
+                    }
+                    Some(name) => return Err(format!("MACRO doesn't have an argument named {}", name))
+                }
             }
             Ok(ans)
         }
@@ -180,19 +266,65 @@ impl MacroKey {
 }
 
 
-/// Shorthand for keys whose only argument is a layer name.
-fn expecting_just_layer_arg(item: &ParsedKeyTree) -> Result<String, String> {
-    if item.args.is_empty() {
-        Err("Missing layer name.".to_string())
-    } else if item.args.len() > 2 {
-        Err("Too many arguments.".to_string())
-    } else if item.args[0].args.is_empty() {
-        Err("Layer name doesn't have arguments".to_string())
+/// Match a key's arguments to its parameters, either by position or by name (e.g. `hold_ms=150`).
+/// The first `required` parameters must be given; the rest are optional.
+///
+/// Returns the argument for each parameter (in the order of `names`).
+fn bind_args<'a>(item: &'a ParsedKeyTree, names: &[&str], required: usize) -> Result<Vec<Option<&'a ParsedKeyTree>>, String> {
+    let count_error = || {
+        let expected = if required == names.len() { required.to_string() } else { format!("{} to {}", required, names.len()) };
+        let plural = if names.len() == 1 { "" } else { "s" };
+        format!("{} expects {} argument{}, got {}", item.identifier, expected, plural, item.args.len())
+    };
+
+    // The parser guarantees positional arguments come before named arguments.
+    let mut ans = vec![None; names.len()];
+    for (i, arg) in item.args.iter().enumerate() {
+        let idx = match &arg.name {
+            None if i < names.len() => i,
+            None => return Err(count_error()),
+            Some(name) => names.iter().position(|x| x == name)
+                .ok_or_else(|| format!("{} doesn't have an argument named {}", item.identifier, name))?
+        };
+        if ans[idx].is_some() {
+            return Err(format!("{} got the {} argument twice", item.identifier, names[idx]));
+        }
+        ans[idx] = Some(arg);
+    }
+
+    if let Some(missing) = (0..required).find(|x| ans[*x].is_none()) {
+        if item.args.iter().all(|x| x.name.is_none()) {
+            return Err(count_error());
+        }
+        return Err(format!("{} is missing the {} argument", item.identifier, names[missing]));
+    }
+    Ok(ans)
+}
+
+/// Shorthand for checking how many (positional) arguments a key was given.
+fn expecting_arg_count(item: &ParsedKeyTree, count: usize) -> Result<(), String> {
+    let names = vec![""; count];
+    match item.args.iter().find(|x| x.name.is_some()) {
+        Some(x) => Err(format!("{} doesn't have an argument named {}", item.identifier, x.name.as_ref().unwrap())),
+        None => bind_args(item, &names, count).map(|_| ())
+    }
+}
+
+/// Shorthand for an argument that's a plain name (e.g. a layer name).
+fn expecting_name(item: &ParsedKeyTree, arg: &ParsedKeyTree) -> Result<String, String> {
+    if !arg.args.is_empty() {
+        Err(format!("{} expects a layer name, but \"{}\" has arguments", item.identifier, arg.identifier))
     } else {
-        Ok(item.args[0].identifier.to_string())
+        Ok(arg.identifier.clone())
     }
 }
 
+/// Shorthand for keys whose only argument is a layer name.
+fn expecting_just_layer_arg(item: &ParsedKeyTree) -> Result<String, String> {
+    let args = bind_args(item, &["layer"], 1)?;
+    expecting_name(item, args[0].unwrap())
+}
+
 
 /// A key that toggles a layer on or off.
 pub struct ToggleLayerKey {
@@ -221,6 +353,12 @@ impl KeyCode for ToggleLayerKey {
     fn get_constraints(&self) -> Vec<KeyConstraint> {
         vec![KeyConstraint::LayerExists(self.layer_name.clone())]
     }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        vec![LayerAction::Toggle(self.layer_name.clone())]
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("TG({})", format_argument(&self.layer_name)))
+    }
 }
 
 
@@ -244,8 +382,8 @@ impl KeyCode for MomentarilyEnableLayerKey {
     fn handle_event(&mut self, ctx: &mut KeyEventContext, state: KeyStateChange) {
         match state {
             KeyStateChange::Held =>  { }
-            KeyStateChange::Released => { ctx.layers.set(&self.layer_name, false); }
-            KeyStateChange::Pressed => { ctx.layers.set(&self.layer_name, true); }
+            KeyStateChange::Released => { ctx.layers.deactivate(&self.layer_name, LayerActivation::Momentary(ctx.location)); }
+            KeyStateChange::Pressed => { ctx.layers.activate(&self.layer_name, LayerActivation::Momentary(ctx.location)); }
         }
     }
     fn get_constraints(&self) -> Vec<KeyConstraint> {
@@ -254,6 +392,12 @@ impl KeyCode for MomentarilyEnableLayerKey {
             KeyConstraint::KeyOnOtherLayerIsTransparent(self.layer_name.clone())
         ]
     }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        vec![LayerAction::Momentary(self.layer_name.clone())]
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("MO({})", format_argument(&self.layer_name)))
+    }
 }
 
 
@@ -278,7 +422,7 @@ impl KeyCode for ActivateLayerKey {
         match state {
             KeyStateChange::Pressed => {
                 // Enable the layer.
-                ctx.layers.set(&self.layer_name, true);
+                ctx.layers.activate(&self.layer_name, LayerActivation::Toggled);
 
                 // This was a PRESSED, but a RELEASED event will soon follow.
                 // We don't want that event to hit the layer we just switched to.
@@ -291,6 +435,12 @@ impl KeyCode for ActivateLayerKey {
     fn get_constraints(&self) -> Vec<KeyConstraint> {
         vec![KeyConstraint::LayerExists(self.layer_name.clone())]
     }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        vec![LayerAction::Activate(self.layer_name.clone())]
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("AL({})", format_argument(&self.layer_name)))
+    }
 }
 
 
@@ -318,18 +468,20 @@ impl HoldEnableLayerPressKey {
         delta > self.hold_threshold
     }
 
-    pub fn from_tokens(item: &ParsedKeyTree) -> Result<HoldEnableLayerPressKey, String> {
+    /// Create the key from `LT(layer, tap, hold_ms)`. The hold duration defaults to the layout's settings.
+    pub fn from_tokens(item: &ParsedKeyTree, settings: &KeySettings) -> Result<HoldEnableLayerPressKey, String> {
         if item.identifier != "LT" {
             Err("Wrong identifier.".to_string())
-        } else if item.args.len() != 3 {
-            Err("Wrong number of arguments.".to_string())
         } else {
-            // Get a layer name.
-            let layer_name = item.args[0].identifier;
-            let key = NormalKey::from_tokens(&item.args[1])?;
-            let duration_ms = item.args[2].identifier.parse();
-            let duration_ms = duration_ms.or(Err("Couldn't convert duration to milliseconds".to_string()))?;
-            Ok(HoldEnableLayerPressKey::new(layer_name, key, Duration::from_millis(duration_ms)))
+            let args = bind_args(item, &["layer", "tap", "hold_ms"], 2)?;
+            let layer_name = expecting_name(item, args[0].unwrap())?;
+            let key = NormalKey::from_tokens(args[1].unwrap())?;
+            let duration = match args[2] {
+                Some(v) => v.as_duration_ms()
+                    .ok_or_else(|| format!("LT expects a hold duration (e.g. 150 or 150ms), got \"{}\"", v.identifier))?,
+                None => settings.hold_threshold
+            };
+            Ok(HoldEnableLayerPressKey::new(&layer_name, key, duration))
         }
     }
 }
@@ -364,6 +516,12 @@ impl KeyCode for HoldEnableLayerPressKey {
     fn get_constraints(&self) -> Vec<KeyConstraint> {
         vec![KeyConstraint::LayerExists(self.layer_name.clone())]
     }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        vec![LayerAction::Activate(self.layer_name.clone())]
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("LT({},{},{})", format_argument(&self.layer_name), self.key.name(), self.hold_threshold.as_millis()))
+    }
 }
 
 
@@ -388,7 +546,7 @@ impl KeyCode for OneShotLayer {
             KeyStateChange::Held => { }
             KeyStateChange::Pressed => {
                 // Enable the target layer.
-                ctx.layers.set(&self.layer_name, true);
+                ctx.layers.activate(&self.layer_name, LayerActivation::Momentary(ctx.location));
 
                 // Inject a counter based call-back that disables the layer
                 // after another key has been released (this position doesn't count).
@@ -398,6 +556,7 @@ impl KeyCode for OneShotLayer {
                     event_type: t,
                     event_count: ctx.output_device.get_stats().get(t) + 1,
                     enable_layer_at_event: false,
+                    activation: LayerActivation::Momentary(ctx.location),
                 };
                 ctx.layers.schedule_event_count_callback(e);
 
@@ -416,6 +575,39 @@ impl KeyCode for OneShotLayer {
     fn get_constraints(&self) -> Vec<KeyConstraint> {
         vec![KeyConstraint::LayerExists(self.layer_name.clone())]
     }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        vec![LayerAction::Momentary(self.layer_name.clone())]
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("OSL({})", format_argument(&self.layer_name)))
+    }
+}
+
+
+/// A key that locks the active momentary layer on, or unlocks it if it's already locked.
+pub struct LayerLockKey;
+
+impl LayerLockKey {
+    pub fn from_tokens(item: &ParsedKeyTree) -> Result<LayerLockKey, String> {
+        if item.identifier != "LLOCK" {
+            Err("Wrong identifier.".to_string())
+        } else {
+            expecting_arg_count(item, 0)?;
+            Ok(LayerLockKey)
+        }
+    }
+}
+
+impl KeyCode for LayerLockKey {
+    fn handle_event(&mut self, ctx: &mut KeyEventContext, state: KeyStateChange) {
+        if state == KeyStateChange::Pressed {
+            // Lock (or unlock) a layer, and mask the RELEASED event that'll be processed soon.
+            ctx.layers.toggle_lock();
+            ctx.virtual_matrix.set_block(BlockedKeyStates::new_block_release_and_hold(), ctx.location);
+        }
+    }
+    fn layer_actions(&self) -> Vec<LayerAction> { vec![LayerAction::Lock] }
+    fn to_key_syntax(&self) -> Option<String> { Some("LLOCK".to_string()) }
 }
 
 
@@ -427,15 +619,14 @@ pub struct WrappedKey {
 }
 
 impl WrappedKey {
-    pub fn from_tokens(item: &ParsedKeyTree) -> Result<WrappedKey, String> {
+    pub fn from_tokens(item: &ParsedKeyTree, registry: &KeyRegistry) -> Result<WrappedKey, String> {
         if item.identifier != "WRAP" {
             Err("Wrong identifier.".to_string())
-        } else if item.args.len() != 2 {
-            Err("Wrong number of arguments.".to_string())
         } else {
+            expecting_arg_count(item, 2)?;
             Ok(WrappedKey {
                 outside: NormalKey::from_tokens(&item.args[0])?,
-                inside: convert_tokens_to_key(&item.args[1])?
+                inside: registry.convert(&item.args[1])?
             })
         }
     }
@@ -457,6 +648,20 @@ impl KeyCode for WrappedKey {
             }
         }
     }
+    fn get_constraints(&self) -> Vec<KeyConstraint> {
+        self.inside.get_constraints()
+    }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        self.inside.layer_actions()
+    }
+    fn held_keys(&self) -> Vec<SimpleKey> {
+        let mut ans = vec![self.outside.value.clone()];
+        ans.extend(self.inside.held_keys());
+        ans
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("WRAP({},{})", self.outside.name(), self.inside.to_key_syntax()?))
+    }
 }
 
 
@@ -483,14 +688,13 @@ impl SpaceCadet {
         }
     }
 
-    pub fn from_tokens(item: &ParsedKeyTree) -> Result<SpaceCadet, String> {
+    pub fn from_tokens(item: &ParsedKeyTree, registry: &KeyRegistry) -> Result<SpaceCadet, String> {
         if item.identifier != "SPACECADET" {
             Err("Wrong identifier.".to_string())
-        } else if item.args.len() != 2 {
-            Err("Wrong number of arguments.".to_string())
         } else {
+            expecting_arg_count(item, 2)?;
             Ok(SpaceCadet::new(
-                convert_tokens_to_key(&item.args[0])?,
+                registry.convert(&item.args[0])?,
                 NormalKey::from_tokens(&item.args[1])?
             ))
         }
@@ -540,6 +744,188 @@ impl KeyCode for SpaceCadet {
             KeyStateChange::Held => {}
         }
     }
+    fn get_constraints(&self) -> Vec<KeyConstraint> {
+        self.when_tapped.get_constraints()
+    }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        self.when_tapped.layer_actions()
+    }
+    fn held_keys(&self) -> Vec<SimpleKey> { vec![self.when_held.value.clone()] }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("SPACECADET({},{})", self.when_tapped.to_key_syntax()?, self.when_held.name()))
+    }
+}
+
+
+/// A key that acts like a modifier when held, but emits a key when tapped (QMK's mod-tap).
+///
+/// The key is a tap if it's released within the tapping term. With a permissive hold,
+/// any key pressed while this key is down gets the modifier, even within the tapping term.
+pub struct ModTapKey {
+    modifier: NormalKey,
+    when_tapped: Box<KeyCode>,
+    tapping_term: Duration,
+    permissive_hold: bool,
+    pressed_at: Instant,
+    number_of_keys_pressed: u32,
+    holding: bool,
+}
+
+impl ModTapKey {
+    pub fn new(modifier: NormalKey, when_tapped: Box<KeyCode>, tapping_term: Duration, permissive_hold: bool) -> ModTapKey {
+        ModTapKey {
+            modifier,
+            when_tapped,
+            tapping_term,
+            permissive_hold,
+            pressed_at: Instant::now(),
+            number_of_keys_pressed: 0,
+            holding: false,
+        }
+    }
+
+    /// Create the key from `MT(mod, key, tapping_term, permissive_hold)`.
+    /// The optional arguments default to the layout's settings.
+    pub fn from_tokens(item: &ParsedKeyTree, registry: &KeyRegistry) -> Result<ModTapKey, String> {
+        if item.identifier != "MT" {
+            Err("Wrong identifier.".to_string())
+        } else {
+            let args = bind_args(item, &["mod", "key", "tapping_term", "permissive_hold"], 2)?;
+            let tapping_term = match args[2] {
+                Some(v) => v.as_duration_ms()
+                    .ok_or_else(|| format!("MT expects a tapping term (e.g. 180 or 180ms), got \"{}\"", v.identifier))?,
+                None => registry.settings.tapping_term
+            };
+            let permissive_hold = match args[3] {
+                Some(v) => v.as_bool()
+                    .ok_or_else(|| format!("MT expects permissive_hold to be true or false, got \"{}\"", v.identifier))?,
+                None => registry.settings.permissive_hold
+            };
+            Ok(ModTapKey::new(
+                NormalKey::from_tokens(args[0].unwrap())?,
+                registry.convert(args[1].unwrap())?,
+                tapping_term,
+                permissive_hold
+            ))
+        }
+    }
+
+    fn is_past_tapping_term(&self, now: Instant) -> bool {
+        now.duration_since(self.pressed_at) > self.tapping_term
+    }
+
+    fn other_keys_pressed(&self, ctx: &KeyEventContext) -> bool {
+        ctx.output_device.get_stats().get(KeyStateChange::Pressed) != self.number_of_keys_pressed
+    }
+
+    /// Commit to acting like a modifier.
+    fn start_holding(&mut self, ctx: &mut KeyEventContext) {
+        if self.permissive_hold && !self.other_keys_pressed(ctx) {
+            // The modifier press is still waiting in the buffer; replace it with a real press.
+            ctx.output_device.set_buffer(EventBuffer::new());
+            self.modifier.handle_event(ctx, KeyStateChange::Pressed);
+        } else if !self.permissive_hold {
+            self.modifier.handle_event(ctx, KeyStateChange::Pressed);
+        }
+        self.holding = true;
+    }
+}
+
+impl KeyCode for ModTapKey {
+    fn handle_event(&mut self, ctx: &mut KeyEventContext, state: KeyStateChange) {
+        match state {
+            KeyStateChange::Pressed => {
+                self.pressed_at = ctx.now;
+                self.holding = false;
+
+                // A permissive hold works like a space cadet key - the modifier press waits in a
+                // two event buffer, and is sent if another key is pressed before this key is released.
+                if self.permissive_hold {
+                    ctx.output_device.set_buffer(EventBuffer::new_spacecadet());
+                    self.number_of_keys_pressed = ctx.output_device.get_stats().get(KeyStateChange::Pressed);
+                    self.modifier.handle_event(ctx, KeyStateChange::Pressed);
+                }
+            }
+            KeyStateChange::Held => {
+                if !self.holding && self.is_past_tapping_term(ctx.now) {
+                    self.start_holding(ctx);
+                }
+            }
+            KeyStateChange::Released => {
+                if !self.holding && self.permissive_hold && self.other_keys_pressed(ctx) {
+                    // The modifier was sent along with another key.
+                    self.holding = true;
+                }
+                if !self.holding && self.is_past_tapping_term(ctx.now) {
+                    self.start_holding(ctx);
+                }
+
+                if self.holding {
+                    self.modifier.handle_event(ctx, KeyStateChange::Released);
+                } else {
+                    if self.permissive_hold {
+                        // Drop the modifier press that's waiting in the buffer.
+                        ctx.output_device.set_buffer(EventBuffer::new());
+                    }
+                    self.when_tapped.handle_event(ctx, KeyStateChange::Pressed);
+                    self.when_tapped.handle_event(ctx, KeyStateChange::Released);
+                }
+            }
+        }
+    }
+    fn get_constraints(&self) -> Vec<KeyConstraint> {
+        self.when_tapped.get_constraints()
+    }
+    fn layer_actions(&self) -> Vec<LayerAction> {
+        self.when_tapped.layer_actions()
+    }
+    fn held_keys(&self) -> Vec<SimpleKey> { vec![self.modifier.value.clone()] }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("MT({},{},{},{})",
+            self.modifier.name(),
+            self.when_tapped.to_key_syntax()?,
+            self.tapping_term.as_millis(),
+            self.permissive_hold))
+    }
+}
+
+
+/// A key on a stenotype machine. Steno keys are collected into a chord until
+/// every key in the chord is released, then the stroke is sent to the steno engine.
+pub struct StenoChordKey {
+    pub key: StenoKey
+}
+
+impl StenoChordKey {
+    pub fn from_tokens(item: &ParsedKeyTree) -> Result<StenoChordKey, String> {
+        if item.identifier != "STENO" {
+            Err("Wrong identifier.".to_string())
+        } else if item.args.len() != 1 || !item.args[0].args.is_empty() {
+            Err("STENO expects a single steno key (e.g. S- or -Z)".to_string())
+        } else {
+            match StenoKey::from_name(&item.args[0].identifier) {
+                Some(key) => Ok(StenoChordKey { key }),
+                None => Err(format!("Unknown steno key \"{}\".", item.args[0].identifier))
+            }
+        }
+    }
+}
+
+impl KeyCode for StenoChordKey {
+    fn handle_event(&mut self, ctx: &mut KeyEventContext, state: KeyStateChange) {
+        match state {
+            KeyStateChange::Pressed => { ctx.steno.get_or_insert_with(StenoEngine::new).press(self.key); }
+            KeyStateChange::Released => {
+                if let Some(steno) = ctx.steno {
+                    steno.release(self.key, ctx.output_device);
+                }
+            }
+            KeyStateChange::Held => {}
+        }
+    }
+    fn to_key_syntax(&self) -> Option<String> {
+        Some(format!("STENO({})", self.key.name()))
+    }
 }
 
 
@@ -549,6 +935,7 @@ mod tests {
     use crate::test_io_keyboard::*;
     use crate::layer::{LayerAttributes, KeyCodeMatrix};
     use crate::keyboard_driver::*;
+    use crate::combo::ComboCollection;
 
     type TestDriver = KeyboardDriver<TestInputKeyboard, TestOutputKeyboard>;
 
@@ -564,7 +951,8 @@ mod tests {
         for i in keys.into_iter().enumerate() {
             layers.add(LayerAttributes {
                 name: format!("layer_{}", i.0),
-                enabled: i.0 == 0
+                enabled: i.0 == 0,
+                timeout: None
             });
 
             let mut codes = KeyCodeMatrix::new((1, 2));
@@ -578,9 +966,45 @@ mod tests {
             layer_attributes: layers,
             layered_codes: layer_codes,
             matrix: VirtualKeyboardMatrix::new(vec![vec![Some(SimpleKey::KEY_1), Some(SimpleKey::KEY_2)]], None),
+            steno: None,
+            combos: ComboCollection::new(),
         }
     }
 
+    #[test]
+    fn key_syntax_round_trip() {
+        // Every key renders in its canonical form, which parses back to the same key.
+        let keys = [
+            "___", "XXX", "KC_A", "KC_BRIGHTNESS_CYCLE", "MACRO(KC_H,WRAP(KC_LEFTSHIFT,KC_I))",
+            "TG(nav)", "MO(nav)", "AL(nav)", "OSL(nav)", "LLOCK", "LT(nav,KC_SPACE,150)",
+            "WRAP(KC_LEFTSHIFT,KC_9)", "SPACECADET(KC_9,KC_LEFTSHIFT)", "STENO(S-)", "TG(\"my layer\")",
+            "MT(KC_LEFTCTRL,KC_A,180,true)", "MACRO(KC_H,KC_I,when=release)"
+        ];
+        for i in keys.iter() {
+            let key: Box<KeyCode> = i.parse().unwrap();
+            assert_eq!(key.to_key_syntax().unwrap(), *i);
+        }
+
+        // Aliases render in canonical form.
+        let key: Box<KeyCode> = "LT( nav, KEY_SPACE, 2s )".parse().unwrap();
+        assert_eq!(key.to_key_syntax().unwrap(), "LT(nav,KC_SPACE,2000)");
+        let key: Box<KeyCode> = "TRANSPARENT".parse().unwrap();
+        assert_eq!(key.to_key_syntax().unwrap(), "___");
+        let key: Box<KeyCode> = "MACRO(KC_H,when=press)".parse().unwrap();
+        assert_eq!(key.to_key_syntax().unwrap(), "MACRO(KC_H)");
+        assert!("MACRO(KC_H,when=later)".parse::<Box<KeyCode>>().is_err());
+
+        // Keys that don't render themselves can't be rendered, even inside another key.
+        struct Custom;
+        impl KeyCode for Custom {
+            fn handle_event(&mut self, _ctx: &mut KeyEventContext, _state: KeyStateChange) {}
+        }
+        assert_eq!(Custom.to_key_syntax(), None);
+        let key = MacroKey { play_macro_when: KeyStateChange::Pressed, keys: vec![Box::new(NormalKey { value: SimpleKey::KEY_H }), Box::new(Custom)] };
+        assert_eq!(key.to_key_syntax(), None);
+        assert_eq!(describe_key_code(&key), "<custom key>");
+    }
+
     #[test]
     fn normal_key() {
         let mut fx = get_test_driver(Box::new(NormalKey { value: SimpleKey::KEY_A }));
@@ -619,7 +1043,7 @@ mod tests {
         // Construct a driver with a single macro key.
         let key = MacroKey {
             play_macro_when: KeyStateChange::Released,
-            keys: vec![NormalKey { value: SimpleKey::KEY_H }, NormalKey { value: SimpleKey::KEY_I }]
+            keys: vec![Box::new(NormalKey { value: SimpleKey::KEY_H }), Box::new(NormalKey { value: SimpleKey::KEY_I })]
         };
         let mut fx = get_test_driver(Box::new(key));
 
@@ -641,6 +1065,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn macro_key_nested() {
+        // A macro that plays a wrapped key, then toggles a layer.
+        let key: Box<KeyCode> = "MACRO(WRAP(KC_LEFTSHIFT,KC_9),TG(layer_1))".parse().unwrap();
+        let mut fx = get_test_driver_multilayer(vec![key, Box::new(TransparentKey{})]);
+        assert!(fx.verify().is_ok());
+
+        // Pressing the key should emit SHIFT + 9, then toggle the layer.
+        fx.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Pressed).into());
+        fx.clock_tick(Instant::now());
+        let codes = [
+            SimpleKey::KEY_LEFTSHIFT,
+            SimpleKey::KEY_9,
+            SimpleKey::KEY_9,
+            SimpleKey::KEY_LEFTSHIFT];
+        assert_eq!(fx.output.events.len(), codes.len());
+        for i in fx.output.events.iter().enumerate() {
+            assert_eq!(i.1.event_code, evdev::enums::EventCode::EV_KEY(codes[i.0].clone()));
+        }
+        assert!(fx.layer_attributes.is_enabled(1));
+    }
+
+    #[test]
+    fn macro_key_nested_constraints() {
+        // Layers referenced inside a macro must still exist.
+        let key: Box<KeyCode> = "MACRO(KC_A,WRAP(KC_LEFTSHIFT,TG(missing)))".parse().unwrap();
+        let fx = get_test_driver(key);
+        assert!(fx.verify().is_err());
+    }
+
     #[test]
     fn toggle_layer_key() {
         // Create a driver with a toggle layer key on layer_0, and a simple key on layer_1
@@ -672,6 +1126,32 @@ mod tests {
         assert_eq!(fx.output.events[0].event_code, evdev::enums::EventCode::EV_KEY(plain_key.value));
     }
 
+    #[test]
+    fn toggle_layer_key_timeout() {
+        // Toggle a layer that times out after a second of inactivity.
+        let key = ToggleLayerKey { layer_name: "layer_1".to_string() };
+        let mut fx = get_test_driver_multilayer(vec![Box::new(key), Box::new(TransparentKey{})]);
+        fx.layer_attributes.attributes[1].timeout = Some(Duration::from_secs(1));
+
+        let t = Instant::now();
+        fx.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Pressed).into());
+        fx.clock_tick(t);
+        assert!(fx.layer_attributes.is_enabled(1));
+
+        // Holding the key counts as activity.
+        fx.clock_tick(t + Duration::from_secs(2));
+        assert!(fx.layer_attributes.is_enabled(1));
+
+        // Once the key is released, the layer turns off after the timeout.
+        fx.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Released).into());
+        let t = t + Duration::from_secs(3);
+        fx.clock_tick(t);
+        fx.clock_tick(t + Duration::from_millis(500));
+        assert!(fx.layer_attributes.is_enabled(1));
+        fx.clock_tick(t + Duration::from_secs(1));
+        assert!(!fx.layer_attributes.is_enabled(1));
+    }
+
     #[test]
     fn momentarily_enable_layer_key() {
         // Setup the test driver.
@@ -694,6 +1174,29 @@ mod tests {
         assert!(!fx.layer_attributes.is_enabled(1));
     }
 
+    #[test]
+    fn overlapping_momentary_layer_keys() {
+        // Two MO keys for the same layer at KEY_1 and KEY_2.
+        let test_key = MomentarilyEnableLayerKey { layer_name: "layer_1".to_string() };
+        let mut fx = get_test_driver_multilayer(
+            vec![Box::new(test_key), Box::new(TransparentKey{})]
+        );
+        fx.layered_codes[0].codes[0][1] = "MO(layer_1)".parse().unwrap();
+        let t = Instant::now();
+
+        // Press both, then release the first. The second still holds the layer on.
+        fx.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Pressed).into());
+        fx.input.events.push(KeyState(SimpleKey::KEY_2, KeyStateChange::Pressed).into());
+        fx.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Released).into());
+        fx.clock_tick(t);
+        assert!(fx.layer_attributes.is_enabled(1));
+
+        // Releasing the last holder disables the layer.
+        fx.input.events.push(KeyState(SimpleKey::KEY_2, KeyStateChange::Released).into());
+        fx.clock_tick(t);
+        assert!(!fx.layer_attributes.is_enabled(1));
+    }
+
     #[test]
     fn activate_layer_key() {
         // Setup the test driver.
@@ -768,6 +1271,104 @@ mod tests {
         assert!(fx.output.events.is_empty());
     }
 
+    #[test]
+    fn layer_lock_key() {
+        // MO(layer_1) at KEY_1 and LLOCK at KEY_2 on both layers.
+        let mo = MomentarilyEnableLayerKey { layer_name: "layer_1".to_string() };
+        let mut fx = get_test_driver_multilayer(vec![Box::new(mo), Box::new(TransparentKey{})]);
+        fx.layered_codes[0].codes[0][1] = "LLOCK".parse().unwrap();
+        fx.layered_codes[1].codes[0][1] = Box::new(LayerLockKey);
+        assert!(fx.verify().is_ok());
+
+        let t = Instant::now();
+        let tap = |fx: &mut TestDriver, key: SimpleKey| {
+            fx.input.events.push(KeyState(key.clone(), KeyStateChange::Pressed).into());
+            fx.clock_tick(t);
+            fx.input.events.push(KeyState(key, KeyStateChange::Released).into());
+            fx.clock_tick(t);
+        };
+
+        // Hold MO, lock the layer, then release MO. The layer stays on.
+        fx.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Pressed).into());
+        fx.clock_tick(t);
+        tap(&mut fx, SimpleKey::KEY_2);
+        fx.input.events.push(KeyState(SimpleKey::KEY_1, KeyStateChange::Released).into());
+        fx.clock_tick(t);
+        assert!(fx.layer_attributes.is_enabled(1));
+        assert!(fx.layer_attributes.state(1).locked);
+
+        // Pressing the lock key again unlocks the layer.
+        tap(&mut fx, SimpleKey::KEY_2);
+        assert!(!fx.layer_attributes.is_enabled(1));
+        assert!(fx.output.events.is_empty());
+    }
+
+    #[test]
+    fn mod_tap_key() {
+        let key = |permissive_hold| -> Box<KeyCode> {
+            Box::new(ModTapKey::new(
+                NormalKey { value: SimpleKey::KEY_LEFTCTRL },
+                Box::new(NormalKey { value: SimpleKey::KEY_A }),
+                Duration::from_millis(180),
+                permissive_hold))
+        };
+        let event = |key: SimpleKey, state| -> evdev::InputEvent { KeyState(key, state).into() };
+        let codes = |fx: &TestDriver| -> Vec<evdev::enums::EventCode> {
+            fx.output.events.iter().map(|x| x.event_code.clone()).collect()
+        };
+        let ctrl = evdev::enums::EventCode::EV_KEY(SimpleKey::KEY_LEFTCTRL);
+        let a = evdev::enums::EventCode::EV_KEY(SimpleKey::KEY_A);
+        let b = evdev::enums::EventCode::EV_KEY(SimpleKey::KEY_B);
+        let t = Instant::now();
+
+        // A quick tap emits the key.
+        let mut fx = get_test_driver(key(false));
+        fx.layered_codes[0].codes[0][1] = Box::new(NormalKey { value: SimpleKey::KEY_B });
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Pressed));
+        fx.clock_tick(t);
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Released));
+        fx.clock_tick(t + Duration::from_millis(50));
+        assert_eq!(codes(&fx), vec![a.clone(), a.clone()]);
+
+        // Holding past the tapping term acts like the modifier.
+        fx.output.events.clear();
+        let held = t + Duration::from_secs(1);
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Pressed));
+        fx.clock_tick(held);
+        fx.clock_tick(held + VirtualKeyboardMatrix::default_hold_duration() + Duration::from_millis(1));
+        fx.input.events.push(event(SimpleKey::KEY_2, KeyStateChange::Pressed));
+        fx.input.events.push(event(SimpleKey::KEY_2, KeyStateChange::Released));
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Released));
+        fx.clock_tick(held + Duration::from_millis(300));
+        assert_eq!(codes(&fx), vec![ctrl.clone(), b.clone(), b.clone(), ctrl.clone()]);
+
+        // Without a permissive hold, rolling into another key within the tapping term stays a tap.
+        fx.output.events.clear();
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Pressed));
+        fx.input.events.push(event(SimpleKey::KEY_2, KeyStateChange::Pressed));
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Released));
+        fx.input.events.push(event(SimpleKey::KEY_2, KeyStateChange::Released));
+        fx.clock_tick(held + Duration::from_secs(1));
+        assert_eq!(codes(&fx), vec![b.clone(), a.clone(), a.clone(), b.clone()]);
+
+        // With a permissive hold, a key pressed while held gets the modifier.
+        let mut fx = get_test_driver(key(true));
+        fx.layered_codes[0].codes[0][1] = Box::new(NormalKey { value: SimpleKey::KEY_B });
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Pressed));
+        fx.input.events.push(event(SimpleKey::KEY_2, KeyStateChange::Pressed));
+        fx.input.events.push(event(SimpleKey::KEY_2, KeyStateChange::Released));
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Released));
+        fx.clock_tick(t);
+        assert_eq!(codes(&fx), vec![ctrl.clone(), b.clone(), b, ctrl]);
+
+        // ... but a quick tap still emits the key.
+        fx.output.events.clear();
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Pressed));
+        fx.input.events.push(event(SimpleKey::KEY_1, KeyStateChange::Released));
+        fx.clock_tick(t + Duration::from_secs(1));
+        assert_eq!(codes(&fx), vec![a.clone(), a]);
+    }
+
     #[test]
     fn one_shot_layer() {
         let test_key = OneShotLayer {
@@ -941,4 +1542,32 @@ mod tests {
             assert_eq!(i.1.event_code, evdev::enums::EventCode::EV_KEY(codes[i.0].clone()));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn steno_chord_key() {
+        // Two steno keys that form the stroke "KAT".
+        let mut fx = get_test_driver("STENO(K-)".parse().unwrap());
+        fx.layered_codes[0].codes[0][1] = "STENO(-T)".parse().unwrap();
+
+        let press1 : evdev::InputEvent = KeyState(SimpleKey::KEY_1, KeyStateChange::Pressed).into();
+        let release1 : evdev::InputEvent = KeyState( SimpleKey::KEY_1, KeyStateChange::Released).into();
+        let press2 : evdev::InputEvent = KeyState(SimpleKey::KEY_2, KeyStateChange::Pressed).into();
+        let release2 : evdev::InputEvent = KeyState( SimpleKey::KEY_2, KeyStateChange::Released).into();
+
+        // Nothing is typed until every key in the chord is released. The layout doesn't
+        // configure steno, so the engine is created by the first steno key.
+        assert!(fx.steno.is_none());
+        let t = Instant::now();
+        for i in [press1, press2, release1].iter() {
+            fx.input.events.push(i.clone());
+            fx.clock_tick(t);
+        }
+        assert!(fx.output.events.is_empty());
+        assert!(fx.steno.is_some());
+
+        // Without a dictionary, the raw stroke "K-T" is typed (shift + K, -, shift + T).
+        fx.input.events.push(release2);
+        fx.clock_tick(t);
+        assert_eq!(fx.output.events.len(), 10);
+    }
+}
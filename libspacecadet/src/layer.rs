@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use crate::virtual_keyboard_matrix::Index2D;
 use crate::keys::*;
 use crate::virtual_keyboard_matrix::{KeyStats, KeyStateChange};
@@ -39,13 +40,77 @@ impl KeyCodeMatrix {
 /// Attributes of a layer (e.g. name).
 pub struct LayerAttributes {
     pub name: String,
-    pub enabled: bool
+    /// Whether the layer is enabled at startup.
+    pub enabled: bool,
+    /// Return the layer to its startup state after this long without any key activity.
+    pub timeout: Option<Duration>
+}
+
+/// The reasons a layer can be enabled.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LayerActivation {
+    /// Enabled while the layer key at a position is held (e.g. `MO` or `OSL`).
+    Momentary(Index2D),
+    /// Enabled until it's toggled off (e.g. `TG`, `AL`, or enabled at startup).
+    Toggled,
+    /// A momentary layer that was locked on by `LLOCK`.
+    Locked,
+}
+
+/// The runtime state of a layer - every reason the layer is currently enabled.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LayerState {
+    /// Positions of the layer keys holding the layer on.
+    /// The layer stays on until every holder has been released.
+    pub momentary: Vec<Index2D>,
+    pub toggled: bool,
+    pub locked: bool,
+}
+
+impl LayerState {
+    /// Create the state of a layer at startup.
+    pub fn new(enabled: bool) -> LayerState {
+        LayerState {
+            momentary: Vec::new(),
+            toggled: enabled,
+            locked: false,
+        }
+    }
+
+    /// A layer is enabled if there's at least one reason for it to be enabled.
+    pub fn is_enabled(&self) -> bool {
+        !self.momentary.is_empty() || self.toggled || self.locked
+    }
+
+    fn activate(&mut self, why: LayerActivation) {
+        match why {
+            LayerActivation::Momentary(owner) => {
+                if !self.momentary.contains(&owner) {
+                    self.momentary.push(owner);
+                }
+            }
+            LayerActivation::Toggled => self.toggled = true,
+            LayerActivation::Locked => self.locked = true,
+        }
+    }
+
+    fn deactivate(&mut self, why: LayerActivation) {
+        match why {
+            LayerActivation::Momentary(owner) => self.momentary.retain(|x| *x != owner),
+            LayerActivation::Toggled => self.toggled = false,
+            LayerActivation::Locked => self.locked = false,
+        }
+    }
 }
 
 /// A collection of layer attributes.
 pub struct LayerCollection {
     pub attributes: Vec<LayerAttributes>,
     pub name_to_idx: HashMap<String, usize>,
+    /// Reset every layer to its startup state after this long without any key activity.
+    pub idle_timeout: Option<Duration>,
+    states: Vec<LayerState>,
+    last_activity: Option<Instant>,
     event_layer_callbacks: Vec<ScheduledLayerEvent>
 }
 
@@ -54,7 +119,9 @@ pub struct ScheduledLayerEvent {
     pub layer_name: String,
     pub event_type: KeyStateChange,
     pub event_count: u32,
-    pub enable_layer_at_event: bool
+    pub enable_layer_at_event: bool,
+    /// The reason to add (or remove) when the event occurs.
+    pub activation: LayerActivation
 }
 
 impl LayerCollection {
@@ -64,6 +131,9 @@ impl LayerCollection {
         LayerCollection {
             attributes: Vec::new(),
             name_to_idx: HashMap::new(),
+            idle_timeout: None,
+            states: Vec::new(),
+            last_activity: None,
             event_layer_callbacks: Vec::new()
         }
     }
@@ -71,6 +141,7 @@ impl LayerCollection {
     /// Add a layer to the collection.
     pub fn add(&mut self, attr: LayerAttributes) {
         self.name_to_idx.insert(attr.name.clone(), self.attributes.len());
+        self.states.push(LayerState::new(attr.enabled));
         self.attributes.push(attr);
     }
 
@@ -81,18 +152,54 @@ impl LayerCollection {
 
     /// Determine if a layer is enabled.
     pub fn is_enabled(&self, idx: usize) -> bool {
-        self.attributes[idx].enabled
+        self.states[idx].is_enabled()
     }
 
-    /// Toggle a layer.
+    /// Get the reasons a layer is enabled.
+    pub fn state(&self, idx: usize) -> &LayerState {
+        &self.states[idx]
+    }
+
+    /// Toggle a layer. Toggling an enabled layer turns it off, no matter why it was enabled.
     pub fn toggle(&mut self, name: &str) {
-        let v = &mut self.attributes[self.name_to_idx[name]].enabled;
-        *v = !*v;
+        let enabled = self.is_enabled(self.name_to_idx[name]);
+        self.set(name, !enabled);
     }
 
-    /// Set a layer state by name.
+    /// Set a layer state by name. Enabling a layer toggles it on; disabling
+    /// a layer clears every reason it was enabled.
     pub fn set(&mut self, name: &str, val: bool) {
-        self.attributes[self.name_to_idx[name]].enabled = val;
+        let idx = self.name_to_idx[name];
+        if val {
+            self.states[idx].toggled = true;
+        } else {
+            self.states[idx] = LayerState::new(false);
+        }
+    }
+
+    /// Add a reason for a layer to be enabled.
+    pub fn activate(&mut self, name: &str, why: LayerActivation) {
+        let idx = self.name_to_idx[name];
+        self.states[idx].activate(why);
+    }
+
+    /// Remove a reason for a layer to be enabled. The layer stays enabled if
+    /// there are other reasons (e.g. it was locked, or another key is holding it).
+    pub fn deactivate(&mut self, name: &str, why: LayerActivation) {
+        let idx = self.name_to_idx[name];
+        self.states[idx].deactivate(why);
+    }
+
+    /// Lock or unlock the highest layer that's held by a layer key or locked.
+    /// A locked layer stays on after its layer key is released.
+    ///
+    /// Returns the index of the layer that was locked or unlocked.
+    pub fn toggle_lock(&mut self) -> Option<usize> {
+        let idx = (0..self.len()).rev().find(|x| !self.states[*x].momentary.is_empty() || self.states[*x].locked);
+        if let Some(idx) = idx {
+            self.states[idx].locked = !self.states[idx].locked;
+        }
+        idx
     }
 
     /// Schedule a layer related event.
@@ -104,17 +211,60 @@ impl LayerCollection {
     pub fn check_event_callbacks(&mut self, state: KeyStats) {
 
         // This function could be replaced by drain_filter, but it's a nightly-only experiment.
-        let mut to_change: Vec<(String, bool)> = Vec::new();
+        let mut to_change: Vec<(String, bool, LayerActivation)> = Vec::new();
         self.event_layer_callbacks.retain(|x| {
             let ready = x.event_count <= state.get(x.event_type);
             if ready {
-                to_change.push((x.layer_name.clone(), x.enable_layer_at_event));
+                to_change.push((x.layer_name.clone(), x.enable_layer_at_event, x.activation));
             }
             !ready
         });
 
-        for (name, state) in to_change {
-            self.set(&name, state);
+        for (name, state, why) in to_change {
+            if state {
+                self.activate(&name, why);
+            } else {
+                self.deactivate(&name, why);
+            }
+        }
+    }
+
+    /// Record that a key was pressed, released, or is being held.
+    pub fn record_activity(&mut self, now: Instant) {
+        self.last_activity = Some(now);
+    }
+
+    /// Reset every layer to its startup state and drop any scheduled layer events.
+    pub fn reset(&mut self) {
+        for (attr, state) in self.attributes.iter().zip(self.states.iter_mut()) {
+            *state = LayerState::new(attr.enabled);
+        }
+        self.event_layer_callbacks.clear();
+    }
+
+    /// Check if any layer (or the whole collection) has been idle long enough to be reset.
+    pub fn check_timeouts(&mut self, now: Instant) {
+        let idle = match self.last_activity {
+            Some(t) => now.duration_since(t),
+            None => return
+        };
+
+        // Layers with their own timeout return to their startup state.
+        for (attr, state) in self.attributes.iter().zip(self.states.iter_mut()) {
+            if let Some(timeout) = attr.timeout {
+                if idle >= timeout {
+                    *state = LayerState::new(attr.enabled);
+                }
+            }
+        }
+
+        // The global timeout resets everything. There's nothing left to reset
+        // until the next key activity, so forget the last activity.
+        if let Some(timeout) = self.idle_timeout {
+            if idle >= timeout {
+                self.reset();
+                self.last_activity = None;
+            }
         }
     }
 }
@@ -136,7 +286,8 @@ mod tests {
         let mut item = LayerCollection::new();
         let to_add = LayerAttributes {
             name: "foo".to_string(),
-            enabled: true
+            enabled: true,
+            timeout: None
         };
         item.add(to_add);
         assert_eq!(item.len(), 1);
@@ -152,7 +303,8 @@ mod tests {
         let mut item = LayerCollection::new();
         let to_add = LayerAttributes {
             name: "foo".to_string(),
-            enabled: true
+            enabled: true,
+            timeout: None
         };
         item.add(to_add);
 
@@ -161,13 +313,15 @@ mod tests {
             layer_name: "foo".to_string(),
             event_type: KeyStateChange::Pressed,
             event_count: 10,
-            enable_layer_at_event: false
+            enable_layer_at_event: false,
+            activation: LayerActivation::Toggled
         });
         item.schedule_event_count_callback(ScheduledLayerEvent {
             layer_name: "foo".to_string(),
             event_type: KeyStateChange::Released,
             event_count: 5,
-            enable_layer_at_event: true
+            enable_layer_at_event: true,
+            activation: LayerActivation::Toggled
         });
 
         // Manipulate the stats and check that layers turn off and back on.
@@ -181,4 +335,109 @@ mod tests {
         item.check_event_callbacks(s);
         assert!(item.is_enabled(0));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn layer_collection_activation_reasons() {
+        let mut item = LayerCollection::new();
+        item.add(LayerAttributes { name: "nav".to_string(), enabled: false, timeout: None });
+
+        let owner = LayerActivation::Momentary((0, 0));
+
+        // A momentary layer turns off when the momentary reason is removed.
+        item.activate("nav", owner);
+        assert!(item.is_enabled(0));
+        item.deactivate("nav", owner);
+        assert!(!item.is_enabled(0));
+
+        // Locking a momentary layer keeps it on after the momentary reason is removed.
+        item.activate("nav", owner);
+        assert_eq!(item.toggle_lock(), Some(0));
+        item.deactivate("nav", owner);
+        assert!(item.is_enabled(0));
+        assert!(item.state(0).locked);
+
+        // Toggling the lock again unlocks the layer.
+        assert_eq!(item.toggle_lock(), Some(0));
+        assert!(!item.is_enabled(0));
+        assert_eq!(item.toggle_lock(), None);
+
+        // Toggling an enabled layer clears every reason.
+        item.activate("nav", owner);
+        item.toggle_lock();
+        item.toggle("nav");
+        assert!(!item.is_enabled(0));
+    }
+
+    #[test]
+    fn layer_collection_lock_highest_layer() {
+        let mut item = LayerCollection::new();
+        item.add(LayerAttributes { name: "nav".to_string(), enabled: false, timeout: None });
+        item.add(LayerAttributes { name: "sym".to_string(), enabled: false, timeout: None });
+        let first = LayerActivation::Momentary((0, 0));
+        let second = LayerActivation::Momentary((0, 1));
+
+        // With two layers held and the top one locked, the top one is unlocked.
+        item.activate("nav", first);
+        item.activate("sym", second);
+        assert_eq!(item.toggle_lock(), Some(1));
+        assert_eq!(item.toggle_lock(), Some(1));
+        assert!(!item.state(0).locked);
+        assert!(!item.state(1).locked);
+
+        // Once the top layer is released, the lower held layer is next.
+        assert_eq!(item.toggle_lock(), Some(1));
+        item.deactivate("sym", second);
+        assert_eq!(item.toggle_lock(), Some(1));
+        assert_eq!(item.toggle_lock(), Some(0));
+        assert!(item.state(0).locked);
+        assert!(!item.is_enabled(1));
+    }
+
+    #[test]
+    fn layer_collection_momentary_owners() {
+        let mut item = LayerCollection::new();
+        item.add(LayerAttributes { name: "nav".to_string(), enabled: false, timeout: None });
+        let first = LayerActivation::Momentary((0, 0));
+        let second = LayerActivation::Momentary((0, 1));
+
+        // The layer stays on until every holder has released it.
+        item.activate("nav", first);
+        item.activate("nav", second);
+        item.activate("nav", first);
+        item.deactivate("nav", first);
+        assert!(item.is_enabled(0));
+        item.deactivate("nav", second);
+        assert!(!item.is_enabled(0));
+    }
+
+    #[test]
+    fn layer_collection_timeouts() {
+        let mut item = LayerCollection::new();
+        item.add(LayerAttributes { name: "base".to_string(), enabled: true, timeout: None });
+        item.add(LayerAttributes { name: "nav".to_string(), enabled: false, timeout: Some(Duration::from_secs(5)) });
+        item.add(LayerAttributes { name: "sym".to_string(), enabled: false, timeout: None });
+        item.idle_timeout = Some(Duration::from_secs(60));
+
+        // Nothing times out before there's been any activity.
+        let t = Instant::now();
+        item.set("nav", true);
+        item.set("sym", true);
+        item.check_timeouts(t + Duration::from_secs(120));
+        assert!(item.is_enabled(1));
+
+        // The layer with a timeout turns off after 5 seconds of inactivity.
+        item.record_activity(t);
+        item.check_timeouts(t + Duration::from_secs(4));
+        assert!(item.is_enabled(1));
+        item.check_timeouts(t + Duration::from_secs(5));
+        assert!(!item.is_enabled(1));
+        assert!(item.is_enabled(2));
+
+        // The global timeout resets every layer to its startup state.
+        item.set("base", false);
+        item.check_timeouts(t + Duration::from_secs(60));
+        assert!(item.is_enabled(0));
+        assert!(!item.is_enabled(1));
+        assert!(!item.is_enabled(2));
+    }
+}
@@ -0,0 +1,227 @@
+use std::path::Path;
+use std::time::Duration;
+use crate::config::Config;
+use crate::document::LayoutDocument;
+use crate::error::SpaceCadetError;
+use crate::keys::NormalKey;
+use crate::parser::ParsedKeyTree;
+use crate::simulate::{key_state_name, parse_script, simulate, SimulatedDriver, SimulatedEventKind};
+
+/// A layout test: key events to feed the driver, and what it should do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutTest {
+    pub name: String,
+    /// Script lines (e.g. `150ms press KC_SPACE`), as read by `parse_script`.
+    pub events: Vec<String>,
+    /// The key events the driver should send, in order (e.g. `press KC_A`). An event can start
+    /// with a time (e.g. `200ms press KC_A`) to check when it's sent, too.
+    pub output: Vec<String>,
+    /// The layers that should be enabled after the last event (if the test checks them).
+    pub layers: Option<Vec<String>>,
+}
+
+/// A file of layout tests, and the layout they test.
+///
+/// ```yaml
+/// config: config.json
+/// tests:
+///   - name: hold space for the cursor layer
+///     events: [ "0 press KC_SPACE", "300 press KC_H", "350 release KC_H" ]
+///     output: [ "press KC_LEFT", "release KC_LEFT" ]
+///     layers: [ base, cursor ]
+/// ```
+///
+/// The layout is a `config` file, or `matrix` and `layers` files (paths are relative to the
+/// test file). Test files can be JSON, TOML or YAML, and can include other files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutTestFile {
+    pub path: String,
+    pub config_path: Option<String>,
+    pub matrix_path: Option<String>,
+    pub layers_path: Option<String>,
+    pub tests: Vec<LayoutTest>,
+}
+
+impl LayoutTestFile {
+    /// Read a test file.
+    pub fn load(path: &str) -> Result<LayoutTestFile, SpaceCadetError> {
+        let document = LayoutDocument::load(path)?;
+        let value = &document.value;
+        let error = |message: String| SpaceCadetError::json(path, message);
+        let base_dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let relative = |name: &str| -> Result<Option<String>, SpaceCadetError> {
+            match &value[name] {
+                json::JsonValue::Null => Ok(None),
+                v => v.as_str().map(|x| Some(base_dir.join(x).to_string_lossy().to_string()))
+                    .ok_or_else(|| error(format!("\"{}\" must be a path.", name)))
+            }
+        };
+
+        let mut tests = Vec::new();
+        if !value["tests"].is_array() {
+            return Err(error("\"tests\" must be a list of tests.".to_string()));
+        }
+        for (idx, test) in value["tests"].members().enumerate() {
+            let name = match test["name"].as_str() {
+                Some(v) => v.to_string(),
+                None => format!("test #{}", idx + 1)
+            };
+            let strings = |key: &str| -> Result<Vec<String>, SpaceCadetError> {
+                test[key].members().map(|x| x.as_str().map(|x| x.to_string())).collect::<Option<Vec<String>>>()
+                    .filter(|_| test[key].is_array())
+                    .ok_or_else(|| error(format!("The \"{}\" of \"{}\" must be a list of strings.", key, name)))
+            };
+            tests.push(LayoutTest {
+                events: strings("events")?,
+                output: strings("output")?,
+                layers: if test["layers"].is_null() { None } else { Some(strings("layers")?) },
+                name,
+            });
+        }
+        Ok(LayoutTestFile {
+            path: path.to_string(),
+            config_path: relative("config")?,
+            matrix_path: relative("matrix")?,
+            layers_path: relative("layers")?,
+            tests,
+        })
+    }
+
+    /// Load the layout the file names (if it names one).
+    pub fn load_config(&self) -> Result<Option<Config>, SpaceCadetError> {
+        match (&self.config_path, &self.matrix_path, &self.layers_path) {
+            (Some(config), None, None) => Config::load(config).map(Some),
+            (None, Some(matrix), Some(layers)) => Config::from_files(matrix, layers).map(Some),
+            (None, None, None) => Ok(None),
+            _ => Err(SpaceCadetError::json(&self.path, "A test file needs a \"config\", or a \"matrix\" and \"layers\" (not both)."))
+        }
+    }
+}
+
+impl LayoutTest {
+    /// Run the test through a driver that hasn't handled any events yet, with the clock ticking
+    /// every `tick`. Returns what didn't match (nothing, if the test passed).
+    pub fn run(&self, driver: &mut SimulatedDriver, tick: Duration) -> Vec<String> {
+        let script = match parse_script(&self.events.join("\n"), &driver.matrix) {
+            Ok(v) => v,
+            Err(e) => return vec![format!("events {}", e)]
+        };
+        let mut output = Vec::new();
+        for event in simulate(driver, &script, tick) {
+            if let SimulatedEventKind::Output(key, state) = event.kind {
+                output.push((event.time, format!("{} {}", key_state_name(state), NormalKey { value: key }.name())));
+            }
+        }
+
+        // Compare the events in order, and stop at the first difference.
+        let mut ans = Vec::new();
+        for (idx, expected) in self.output.iter().enumerate() {
+            let words: Vec<&str> = expected.split_whitespace().collect();
+            let time = match words.len() {
+                3 => Some(ParsedKeyTree::create(words[0]).ok().and_then(|x| x.as_duration_ms())),
+                _ => None
+            };
+            let (actual_time, actual) = match output.get(idx) {
+                Some(v) => v,
+                None => {
+                    ans.push(format!("output #{}: expected \"{}\", but the driver only sent {} events", idx + 1, expected, output.len()));
+                    break;
+                }
+            };
+            let (matches, actual) = match time {
+                Some(t) => (t == Some(*actual_time) && actual == &words[1..].join(" "), format!("{}ms {}", actual_time.as_millis(), actual)),
+                None => (actual == &words.join(" "), actual.clone())
+            };
+            if !matches {
+                ans.push(format!("output #{}: expected \"{}\", got \"{}\"", idx + 1, expected, actual));
+                break;
+            }
+        }
+        if ans.is_empty() && output.len() > self.output.len() {
+            let extra: Vec<String> = output[self.output.len()..].iter().map(|x| x.1.clone()).collect();
+            ans.push(format!("output: the driver sent more events than expected: {}", extra.join(", ")));
+        }
+
+        if let Some(layers) = &self.layers {
+            let attributes = &driver.layer_attributes;
+            let enabled: Vec<String> = (0..attributes.len()).filter(|x| attributes.is_enabled(*x))
+                .map(|x| attributes.attributes[x].name.clone()).collect();
+            let mut expected = layers.clone();
+            expected.sort_by_key(|x| attributes.name_to_idx.get(x).cloned());
+            if expected != enabled {
+                ans.push(format!("layers: expected [{}], got [{}]", expected.join(", "), enabled.join(", ")));
+            }
+        }
+        ans
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_registry::KeyRegistry;
+    use crate::test_io_keyboard::*;
+
+    #[test]
+    fn run_layout_tests() {
+        let dir = std::env::temp_dir().join(format!("spacecadet_layout_tests_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        write("matrix.json", r#"{ "matrix": [ [ "KC_H", "KC_SPACE" ] ] }"#);
+        write("layers.json", r#"{
+            "layer_order": [ "base", "cursor" ],
+            "base": { "enabled": true, "keys": [ [ "KC_H", "LT(cursor,KC_SPACE,150)" ] ] },
+            "cursor": { "enabled": false, "keys": [ [ "KC_LEFT", "___" ] ] }
+        }"#);
+        write("tests.yaml", "
+matrix: matrix.json
+layers: layers.json
+tests:
+  - name: tap
+    events: [ 0 press KC_SPACE, 50 release KC_SPACE ]
+    output: [ press KC_SPACE, 50ms release KC_SPACE ]
+    layers: [ base ]
+  - name: hold
+    events: [ 0 press KC_SPACE, 300 press KC_H, 350 release KC_H ]
+    output: [ press KC_LEFT, release KC_LEFT ]
+    layers: [ cursor, base ]
+  - events: [ 0 press KC_H, 10 release KC_H, 20 press KC_SPACE ]
+    output: [ press KC_H, 20ms release KC_H ]
+    layers: [ cursor ]
+  - name: bad script
+    events: [ 0 push KC_H ]
+    output: []
+");
+        let file = LayoutTestFile::load(dir.join("tests.yaml").to_str().unwrap()).unwrap();
+        assert_eq!(file.tests.len(), 4);
+        assert_eq!(file.tests[2].name, "test #3");
+
+        let results: Vec<Vec<String>> = file.tests.iter().map(|test| {
+            let config = file.load_config().unwrap().unwrap();
+            let mut driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new()).unwrap();
+            test.run(&mut driver, Duration::from_millis(5))
+        }).collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(results[0], Vec::<String>::new());
+        assert_eq!(results[1], Vec::<String>::new());
+        assert_eq!(results[2], vec![
+            "output #2: expected \"20ms release KC_H\", got \"10ms release KC_H\"",
+            "layers: expected [cursor], got [base]",
+        ]);
+        assert_eq!(results[3], vec![
+            "events line 1: expected a time, then \"press KEY\", \"release KEY\" or \"wait\".",
+        ]);
+    }
+
+    #[test]
+    fn example_keyboard_tests() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../keyboards/vim_cursor/tests.json");
+        let file = LayoutTestFile::load(path.to_str().unwrap()).unwrap();
+        for test in file.tests.iter() {
+            let config = file.load_config().unwrap().unwrap();
+            let mut driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new()).unwrap();
+            assert_eq!(test.run(&mut driver, Duration::from_millis(5)), Vec::<String>::new(), "{}", test.name);
+        }
+    }
+}
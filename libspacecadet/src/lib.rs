@@ -1,3 +1,12 @@
+mod error;
+pub use error::*;
+
+mod document;
+pub use document::*;
+
+mod config;
+pub use config::*;
+
 mod input_keyboard;
 pub use input_keyboard::*;
 
@@ -11,7 +20,7 @@ mod layer;
 pub use layer::*;
 
 mod output_keyboard;
-pub use output_keyboard::UInputKeyboard;
+pub use output_keyboard::{UInputKeyboard, Autorepeat};
 
 mod test_io_keyboard;
 pub use test_io_keyboard::*;
@@ -20,4 +29,39 @@ mod virtual_keyboard_matrix;
 pub use virtual_keyboard_matrix::*;
 
 mod parser;
-pub use parser::*;
\ No newline at end of file
+pub use parser::*;
+
+mod key_registry;
+pub use key_registry::*;
+
+mod alias;
+pub use alias::*;
+
+mod steno;
+pub use steno::*;
+
+mod combo;
+pub use combo::*;
+
+mod import;
+pub use import::*;
+
+mod qmk;
+pub use qmk::*;
+
+mod kbd;
+pub use kbd::*;
+
+mod diagram;
+pub use diagram::*;
+mod explain;
+pub use explain::*;
+
+mod simulate;
+pub use simulate::*;
+
+mod layout_test;
+pub use layout_test::*;
+
+mod recording;
+pub use recording::*;
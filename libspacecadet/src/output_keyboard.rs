@@ -2,6 +2,9 @@ use evdev_rs as evdev;
 use std::collections::HashMap;
 use std::os::raw::c_int;
 use crate::KeyStats;
+use crate::error::SpaceCadetError;
+use crate::config::OutputSettings;
+use std::time::{Duration, Instant};
 
 /// An interface for output keyboards (e.g. sending events to OS).
 pub trait OutputKeyboard {
@@ -19,6 +22,9 @@ pub trait OutputKeyboard {
     fn send(&mut self, e: evdev::InputEvent) {
         self.send_override(e, false);
     }
+    /// Report an error from output that isn't a key event (e.g. a steno stroke), so it's
+    /// returned by the keyboard's `take_error` like its own send failures. Ignored by default.
+    fn report_error(&mut self, _error: SpaceCadetError) {}
 }
 
 /// A wrapper around a uinput device.
@@ -26,7 +32,36 @@ pub struct UInputKeyboard {
     device: uinput::Device,
     evdev_to_uinput: EvdevToUinput,
     event_buffer: EventBuffer,
-    stats: KeyStats
+    stats: KeyStats,
+    error: Option<SpaceCadetError>,
+    autorepeat: Option<Autorepeat>,
+    /// The last key that was pressed (and not released), and when it should next repeat.
+    repeating: Option<(evdev::InputEvent, Instant)>,
+}
+
+/// Repeat the last pressed key while it's held, like the kernel's autorepeat.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Autorepeat {
+    /// How long a key is held before it starts repeating.
+    pub delay: Duration,
+    /// How long to wait between repeats.
+    pub interval: Duration,
+}
+
+impl Autorepeat {
+    /// Create the default autorepeat (the same as the kernel's defaults).
+    pub fn new() -> Autorepeat {
+        Autorepeat {
+            delay: Duration::from_millis(250),
+            interval: Duration::from_millis(33),
+        }
+    }
+}
+
+impl Default for Autorepeat {
+    fn default() -> Autorepeat {
+        Autorepeat::new()
+    }
 }
 
 impl UInputKeyboard {
@@ -45,18 +80,91 @@ impl UInputKeyboard {
             evdev_to_uinput: EvdevToUinput::new(),
             event_buffer: EventBuffer::new(),
             stats: KeyStats::new(),
+            error: None,
+            autorepeat: None,
+            repeating: None,
         })
     }
 
+    /// Create a new uinput device from the output settings of a config.
+    pub fn from_settings(settings: &OutputSettings) -> Result<UInputKeyboard, uinput::Error> {
+        let mut builder = uinput::default()?
+            .name(&settings.name)?
+            .event(uinput::event::Keyboard::All)?;
+        if let Some(v) = settings.vendor { builder = builder.vendor(v); }
+        if let Some(v) = settings.product { builder = builder.product(v); }
+        if let Some(v) = settings.bus { builder = builder.bus(v); }
+        if let Some(v) = settings.version { builder = builder.version(v); }
+        Ok(UInputKeyboard {
+            device: builder.create()?,
+            evdev_to_uinput: EvdevToUinput::new(),
+            event_buffer: EventBuffer::new(),
+            stats: KeyStats::new(),
+            error: None,
+            autorepeat: settings.autorepeat,
+            repeating: None,
+        })
+    }
+
+    /// Send repeat events for the last pressed key if it's been held long enough.
+    /// Does nothing unless autorepeat is enabled.
+    pub fn send_repeats(&mut self, now: Instant) {
+        let (autorepeat, (e, next)) = match (self.autorepeat, self.repeating.clone()) {
+            (Some(a), Some(r)) => (a, r),
+            _ => return
+        };
+        if now >= next {
+            let mut repeat = e.clone();
+            repeat.value = 2;
+            self.send_unbuffered(repeat);
+            self.repeating = Some((e, now + autorepeat.interval));
+        }
+    }
+
+    /// Take the first error from sending events (if any). Events that fail to send are
+    /// dropped, so the caller should check this regularly (e.g. after every clock tick).
+    pub fn take_error(&mut self) -> Option<SpaceCadetError> {
+        self.error.take()
+    }
+
     /// Send an event to the output keyboard without buffering.
     fn send_unbuffered(&mut self, e: evdev::InputEvent) {
         // evdev event -> uinput event -> device command.
+        self.track_repeats(&e);
         let code = e.value;
         self.stats.increment(code.into());
-        let e = self.evdev_to_uinput.convert(e).unwrap();
+        let e = match self.evdev_to_uinput.convert(e.clone()) {
+            Some(v) => v,
+            None => return self.record_error(format!("The output device doesn't support {:?}.", e.event_code))
+        };
         println!("sending {:?} (val = {})", e, code);
-        self.device.send(e, code).unwrap();
-        self.device.synchronize().unwrap();
+        let sent = self.device.send(e, code).and_then(|_| self.device.synchronize());
+        if let Err(e) = sent {
+            self.record_error(format!("Couldn't send an event to the output device: {}", e));
+        }
+    }
+
+    /// Track the last pressed key for autorepeat.
+    fn track_repeats(&mut self, e: &evdev::InputEvent) {
+        let autorepeat = match self.autorepeat {
+            Some(v) => v,
+            None => return
+        };
+        match e.value {
+            1 => self.repeating = Some((e.clone(), Instant::now() + autorepeat.delay)),
+            0 => {
+                if let Some((held, _)) = &self.repeating {
+                    if held.event_code == e.event_code {
+                        self.repeating = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn record_error(&mut self, message: String) {
+        self.report_error(SpaceCadetError::Device(message));
     }
 }
 
@@ -77,6 +185,13 @@ impl OutputKeyboard for UInputKeyboard {
     }
 
     fn get_stats(&self) -> KeyStats { self.stats }
+
+    /// Keep the first error until it's taken.
+    fn report_error(&mut self, error: SpaceCadetError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
 }
 
 
@@ -1,7 +1,3 @@
-/// Having never written a compiler or taken a course on compilers,
-/// this is a rough first pass at compiling key-syntax into something
-/// that's even marginally friendly to work with.
-///
 /// Key's are specified via the following syntax:
 ///
 ///   identifier()
@@ -19,291 +15,417 @@
 ///
 ///   identifier(arg1, identifier2(arg2))
 ///
-/// All the "expected" syntax rules apply, for example:
+/// Arguments can also be literals:
+///
+///   "a string"    a quoted string (supports \" and \\ escapes)
+///   150           an integer
+///   150ms, 2s     a duration
+///
+/// The grammar is small enough to parse by recursive descent:
+///
+///   key       := atom [ "(" [ arg { "," arg } ] ")" ]
+///   arg       := [ word "=" ] key
+///   atom      := word | string
 ///
-/// identifier(,arg1) // illegal due to misplaced comma
+/// Named arguments (e.g. `LT(nav, KC_SPACE, hold_ms=150)`) must come
+/// after any positional arguments.
+///
+/// Syntax errors record the column of the offending character
+/// and the token that was expected, for example:
+///
+///   identifier(,arg1) // expected a key, found ","
 
+use std::fmt;
+use std::time::Duration;
 use crate::keys::*;
+use crate::key_registry::KeyRegistry;
+
+
+/// A literal value in key syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    /// A quoted string (e.g. `"hello, world"`).
+    String(String),
+    /// An unsigned integer (e.g. `150`).
+    Integer(u64),
+    /// An integer with a `ms` or `s` suffix (e.g. `150ms`).
+    Duration(Duration),
+}
+
+impl Literal {
+    /// Interpret an unquoted word as a literal (if it looks like one).
+    fn from_word(v: &str) -> Option<Literal> {
+        let digits = v.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        let value: u64 = v[..digits].parse().ok()?;
+        match &v[digits..] {
+            "" => Some(Literal::Integer(value)),
+            "ms" => Some(Literal::Duration(Duration::from_millis(value))),
+            "s" => Some(Literal::Duration(Duration::from_secs(value))),
+            _ => None
+        }
+    }
+}
 
 
-/// Raw token represent the basic building blocks of key syntax.
-#[derive(Debug)]
-enum RawToken<'a> {
-    Identifier(&'a str),
-    StartArgumentCollection,
-    EndArgumentCollection,
-    Comma,
+/// An error found while parsing key syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySyntaxError {
+    /// The key syntax that failed to parse.
+    pub source: String,
+    /// The column (starting at 1) of the offending character.
+    pub column: usize,
+    /// A description of what was expected (e.g. `")"`).
+    pub expected: String,
+    /// A description of what was found instead.
+    pub found: String,
 }
 
-impl<'a> RawToken<'a> {
-    /// Create raw tokens given a string constant. Parsing errors are
-    /// returned via the Result.
-    pub fn create(v: &str) -> Result<Vec<RawToken>, String> {
-        let ans = RawToken::parse(v);
-        RawToken::verify_first_is_identifier(&ans)?;
-        RawToken::verify_ordering(&ans)?;
-        RawToken::verify_collection_starts_and_ends(&ans)?;
-        Ok(ans)
+impl KeySyntaxError {
+    /// A one line description of the error (without the location).
+    pub fn message(&self) -> String {
+        format!("expected {}, found {}", self.expected, self.found)
     }
 
-    fn parse(v: &str) -> Vec<RawToken> {
-        assert!(!v.is_empty());
-
-        let mut ans = Vec::new();
-        let delimited : Vec<(usize, &str)> = v.match_indices(|c| "(),".contains(c)).collect();
-        match delimited.len() {
-            0 => {
-                // Just a token - no commas or parens.
-                // Add a pair of synthetic () tokens.
-                // Later code expects tokens to start like "identifier(optional_args)".
-                ans.push(RawToken::Identifier(v));
-                ans.push(RawToken::StartArgumentCollection);
-                ans.push(RawToken::EndArgumentCollection);
-            }
-            _ => {
-                // Check if the first delimiter is also the first character.
-                // If not, then we have an identifier to add.
-                let first_position = delimited[0].0;
-                if first_position != 0 {
-                    ans.push(RawToken::Identifier(&v[..first_position]));
-                }
+    /// A line of whitespace with a caret under the offending column.
+    pub fn caret(&self, column: usize) -> String {
+        format!("{}^", " ".repeat(column.saturating_sub(1)))
+    }
+}
 
-                // Loop though each token and the string on its left.
-                for i in 0..delimited.len() {
-                    ans.push(match delimited[i].1 {
-                        "(" => RawToken::StartArgumentCollection,
-                        ")" => RawToken::EndArgumentCollection,
-                        "," => RawToken::Comma,
-                        _ => panic!("Logic error")
-                    });
-                    let start_idx = delimited[i].0 + 1;
-                    let stop_idx = if i < delimited.len() - 1 { delimited[i + 1].0 } else { delimited.len() };
-                    if start_idx < stop_idx {
-                        ans.push(RawToken::Identifier(&v[start_idx..stop_idx].trim()));
-                    }
-                }
-            }
-        }
-        return ans;
+impl fmt::Display for KeySyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} at column {}", self.message(), self.column)?;
+        writeln!(f, "  {}", self.source)?;
+        write!(f, "  {}", self.caret(self.column))
     }
+}
 
-    fn verify_first_is_identifier(tokens: &Vec<RawToken>) -> Result<(), String> {
+impl From<KeySyntaxError> for String {
+    fn from(e: KeySyntaxError) -> String {
+        e.to_string()
+    }
+}
 
-        // First element must be an identifier.
-        match tokens.get(0) {
-            Some(t) => match t {
-                RawToken::Identifier(_) => Ok(()),
-                _ => Err("Expected an identifier at the beginning.".to_string())
-            }
-            None => Err("Collection of tokens is empty.".to_string())
-        }?;
-
-        // The second element must be a "(".
-        match tokens.get(1) {
-            Some(t) => match t {
-                RawToken::StartArgumentCollection => Ok(()),
-                _ => Err("The second token can only be \"(\" or \"\".".to_string())
-            }
-            None => Err("Missing a token after the first identifier.".to_string())
+
+/// Tokens are the basic building blocks of key syntax.
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    /// An unquoted identifier or literal (e.g. `KC_A`, `S-`, or `150ms`).
+    Word(String),
+    /// A quoted string (with escapes removed).
+    Quoted(String),
+    StartArgumentCollection,
+    EndArgumentCollection,
+    Comma,
+    Equals,
+    End,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            TokenKind::Word(v) => format!("\"{}\"", v),
+            TokenKind::Quoted(_) => "a string".to_string(),
+            TokenKind::StartArgumentCollection => "\"(\"".to_string(),
+            TokenKind::EndArgumentCollection => "\")\"".to_string(),
+            TokenKind::Comma => "\",\"".to_string(),
+            TokenKind::Equals => "\"=\"".to_string(),
+            TokenKind::End => "the end of the key".to_string(),
         }
     }
+}
 
-    fn verify_ordering(tokens: &Vec<RawToken>) -> Result<(), String> {
-        // This is the complete list of allowed syntax:
-        //   identifier
-        //   identifier + (
-        //   identifier + ,
-        //   identifier + )identifier
-        //   )
-        //   ) + ,
-        //   ) + )
-        //   ( + )
-        //   ( + identifier
-        //   , + identifier
-        for i in 0..tokens.len() {
-            let last = i == tokens.len() - 1;
-            match tokens[i] {
-                RawToken::Identifier(_) => {
-                    // There's nothing really to verify here.
-                    // An identifier can be before any of the other tokens.
-                },
-                RawToken::StartArgumentCollection => {
-                    // Argument start can be followed by any other token except
-                    // nothing or another argument start.
-                    if last {
-                        return Err("The \"(\" character must be followed by another token.".to_string());
-                    }
-                    match tokens[i + 1] {
-                        RawToken::StartArgumentCollection => {
-                            return Err("The \"(\" token can't be followed by another \"(\".".to_string());
-                        },
-                        RawToken::Comma => {
-                            return Err("The \"(\" token can't be followed by a \",\".".to_string());
+/// A token and the column (starting at 1) where it starts.
+#[derive(Debug)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+/// Split key syntax into tokens. Whitespace between tokens is ignored.
+fn tokenize(source: &str) -> Result<Vec<Token>, KeySyntaxError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut ans = Vec::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        let column = idx + 1;
+        let kind = match c {
+            _ if c.is_whitespace() => { idx += 1; continue; }
+            '(' => { idx += 1; TokenKind::StartArgumentCollection }
+            ')' => { idx += 1; TokenKind::EndArgumentCollection }
+            ',' => { idx += 1; TokenKind::Comma }
+            '=' => { idx += 1; TokenKind::Equals }
+            '"' => {
+                // Quoted strings run until the next unescaped quote.
+                let mut value = String::new();
+                idx += 1;
+                loop {
+                    match chars.get(idx) {
+                        None => return Err(KeySyntaxError {
+                            source: source.to_string(),
+                            column: idx + 1,
+                            expected: "a closing \"\\\"\"".to_string(),
+                            found: TokenKind::End.describe(),
+                        }),
+                        Some('"') => { idx += 1; break; }
+                        Some('\\') => match chars.get(idx + 1) {
+                            Some(e) if *e == '"' || *e == '\\' => { value.push(*e); idx += 2; }
+                            _ => return Err(KeySyntaxError {
+                                source: source.to_string(),
+                                column: idx + 2,
+                                expected: "an escaped \"\\\"\" or \"\\\\\"".to_string(),
+                                found: match chars.get(idx + 1) {
+                                    Some(e) => format!("\"{}\"", e),
+                                    None => TokenKind::End.describe()
+                                },
+                            })
                         },
-                        _ => ()
-                    }
-                },
-                RawToken::EndArgumentCollection => {
-                    if !last {
-                        match tokens[i + 1] {
-                            RawToken::Identifier(_) => {
-                                return Err("The \")\" token can't be followed immediately by an identifier.".to_string());
-                            },
-                            _ => ()
-                        }
+                        Some(v) => { value.push(*v); idx += 1; }
                     }
                 }
-                RawToken::Comma => {
-                    if !last {
-                        match tokens[i + 1] {
-                            RawToken::Identifier(_) => (),
-                            _ => return Err("The \",\" token can only be followed by an identifier.".to_string())
-                        }
-                    }
+                TokenKind::Quoted(value)
+            }
+            _ => {
+                // Words run until whitespace or a delimiter.
+                let start = idx;
+                while idx < chars.len() && !chars[idx].is_whitespace() && !"(),=\"".contains(chars[idx]) {
+                    idx += 1;
                 }
+                TokenKind::Word(chars[start..idx].iter().collect())
             }
-        }
-        Ok(())
+        };
+        ans.push(Token { kind, column });
     }
+    ans.push(Token { kind: TokenKind::End, column: chars.len() + 1 });
+    Ok(ans)
+}
 
-    fn verify_collection_starts_and_ends(tokens: &Vec<RawToken>) -> Result<(), String> {
-        // Scan from left to right counting the number of start and stop tokens.
-        // Start and stop need to cancel each other out.
-        let mut start_count = 0;
-        for i in tokens {
-            match i {
-                RawToken::StartArgumentCollection => start_count += 1,
-                RawToken::EndArgumentCollection => {
-                    start_count -= 1;
-                    if start_count < 0  {
-                        return Err("Missing a \"(\" token.".to_string())
-                    }
-                }
-                _ => ()
-            }
+
+/// Precursor to keys - hierarchical collection of identifiers and optional arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedKeyTree {
+    /// The identifier as written (for quoted strings, the unescaped contents).
+    pub identifier: String,
+    /// The identifier interpreted as a literal (if it is one).
+    pub literal: Option<Literal>,
+    pub args: Vec<ParsedKeyTree>,
+    /// The argument name, if this tree is a named argument (e.g. `hold_ms` in `hold_ms=150`).
+    pub name: Option<String>,
+    /// The column (starting at 1) where the identifier starts.
+    pub column: usize,
+}
+
+impl ParsedKeyTree {
+    pub fn create(v: &str) -> Result<ParsedKeyTree, KeySyntaxError> {
+        let mut parser = Parser {
+            source: v,
+            tokens: tokenize(v)?,
+            idx: 0
+        };
+        let ans = parser.parse_key()?;
+        parser.expect_end()?;
+        Ok(ans)
+    }
+
+    /// Get the literal duration, treating a bare integer as milliseconds.
+    pub fn as_duration_ms(&self) -> Option<Duration> {
+        match self.literal {
+            Some(Literal::Duration(d)) => Some(d),
+            Some(Literal::Integer(v)) => Some(Duration::from_millis(v)),
+            _ => None
         }
+    }
 
-        if start_count > 0 {
-            Err("Missing a \")\" token.".to_string())
+    /// Write the tree back out as key syntax (e.g. for messages).
+    pub fn to_key_syntax(&self) -> String {
+        let name = match &self.name {
+            Some(v) => format!("{}=", v),
+            None => String::new()
+        };
+        if self.args.is_empty() {
+            format!("{}{}", name, format_argument(&self.identifier))
         } else {
-            Ok(())
+            let args: Vec<String> = self.args.iter().map(|x| x.to_key_syntax()).collect();
+            format!("{}{}({})", name, self.identifier, args.join(","))
         }
     }
-}
-
-/// Simplified tokens are one step up from raw tokens - they form
-/// higher level concepts like functions.
-#[derive(Debug)]
-enum SimplifiedToken<'a> {
-    Function(&'a str),
-    Argument(&'a str),
-    EndFunction,
-}
 
-impl<'a> SimplifiedToken<'a> {
-    pub fn simplify(items: Vec<RawToken>) -> Vec<SimplifiedToken> {
-        let mut ans = vec![];
-        for i in 0..items.len() {
-            let v = &items[i];
-            let next = items.get(i + 1);
-
-            match v {
-                RawToken::Comma => (),
-                RawToken::StartArgumentCollection => (),
-                RawToken::EndArgumentCollection => ans.push(SimplifiedToken::EndFunction),
-                RawToken::Identifier(v) => {
-                    ans.push(match next.unwrap_or(&RawToken::Comma) {
-                        RawToken::StartArgumentCollection => SimplifiedToken::Function(v),
-                        _ => SimplifiedToken::Argument(v)
-                    });
-                }
-            }
+    /// Get the identifier as a boolean (`true` or `false`).
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.identifier.as_str() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None
         }
-        ans
     }
 }
 
-
-/// Precursor to keys - hierarchical collection of identifiers and optional arguments (all strings).
-#[derive(Debug)]
-pub struct ParsedKeyTree<'a> {
-    pub identifier: &'a str,
-    pub args: Vec<ParsedKeyTree<'a>>
+/// A recursive descent parser over a list of tokens.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    idx: usize,
 }
 
-impl<'a> ParsedKeyTree<'a> {
-    pub fn create(v: &str) -> Result<ParsedKeyTree, String> {
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.idx]
+    }
 
-        // Convert the string into a set of tokens, then simplify.
-        let raw = RawToken::create(v)?;
-        let tokens = SimplifiedToken::simplify(raw);
+    fn next(&mut self) -> &Token {
+        // The last token is always End; never step past it.
+        let ans = &self.tokens[self.idx];
+        if self.idx < self.tokens.len() - 1 {
+            self.idx += 1;
+        }
+        ans
+    }
 
-        // Take those tokens and assemble them into a key tree.
-        let mut idx = 0;
-        let args = ParsedKeyTree::convert_to_tree_recursive(&tokens, &mut idx);
-        Ok(args)
+    fn error(&self, token: &Token, expected: &str) -> KeySyntaxError {
+        KeySyntaxError {
+            source: self.source.to_string(),
+            column: token.column,
+            expected: expected.to_string(),
+            found: token.kind.describe(),
+        }
     }
 
-    fn convert_to_tree_recursive(vals: &Vec<SimplifiedToken<'a>>, idx: &mut usize) -> ParsedKeyTree<'a> {
+    fn expect_end(&mut self) -> Result<(), KeySyntaxError> {
+        let token = self.next();
+        if token.kind == TokenKind::End {
+            Ok(())
+        } else {
+            let token = &self.tokens[self.idx - 1];
+            Err(self.error(token, &TokenKind::End.describe()))
+        }
+    }
 
-        // Setup a default answer.
-        let mut ans = ParsedKeyTree {
-            identifier: "",
-            args: vec![]
+    /// key := atom [ "(" [ key { "," key } ] ")" ]
+    fn parse_key(&mut self) -> Result<ParsedKeyTree, KeySyntaxError> {
+        let column = self.peek().column;
+        let (identifier, literal) = match &self.peek().kind {
+            TokenKind::Word(v) => (v.clone(), Literal::from_word(v)),
+            TokenKind::Quoted(v) => (v.clone(), Some(Literal::String(v.clone()))),
+            _ => return Err(self.error(self.peek(), "a key"))
         };
+        let quoted = matches!(self.next().kind, TokenKind::Quoted(_));
+        let mut ans = ParsedKeyTree { identifier, literal, args: vec![], name: None, column };
+
+        // Quoted strings never have arguments.
+        if quoted || self.peek().kind != TokenKind::StartArgumentCollection {
+            return Ok(ans);
+        }
+        self.next();
 
-        // The first item will always be a function.
-        match vals[*idx] {
-            SimplifiedToken::Function(a) => ans.identifier = a,
-            _ => unreachable!()
+        // Empty argument list.
+        if self.peek().kind == TokenKind::EndArgumentCollection {
+            self.next();
+            return Ok(ans);
         }
-        *idx += 1;
-
-        // Loop until we find an EndFunction token.
-        while *idx < vals.len() {
-            match vals[*idx] {
-                SimplifiedToken::EndFunction => break,
-                SimplifiedToken::Function(_) => ans.args.push(ParsedKeyTree::convert_to_tree_recursive(vals, idx)),
-                SimplifiedToken::Argument(v) => ans.args.push(ParsedKeyTree { identifier: v, args: vec![] })
+
+        loop {
+            ans.args.push(self.parse_argument()?);
+            let idx = self.idx;
+            match self.next().kind {
+                TokenKind::Comma => (),
+                TokenKind::EndArgumentCollection => return Ok(ans),
+                _ => return Err(self.error(&self.tokens[idx], "\",\" or \")\""))
+            }
+
+            // Positional arguments can't follow named arguments.
+            let named = ans.args.iter().any(|x| x.name.is_some());
+            if named && !self.is_named_argument() {
+                return Err(self.error(self.peek(), "a named argument (e.g. name=value)"));
             }
-            *idx += 1;
         }
-        ans
+    }
+
+    /// Check if the next tokens start a named argument (i.e. `word =`).
+    fn is_named_argument(&self) -> bool {
+        let is_word = matches!(self.peek().kind, TokenKind::Word(_));
+        is_word && self.tokens[self.idx + 1].kind == TokenKind::Equals
+    }
+
+    /// arg := [ word "=" ] key
+    fn parse_argument(&mut self) -> Result<ParsedKeyTree, KeySyntaxError> {
+        let name = if self.is_named_argument() {
+            let name = match &self.next().kind { TokenKind::Word(v) => v.clone(), _ => unreachable!() };
+            self.next();
+            Some(name)
+        } else {
+            None
+        };
+        let mut ans = self.parse_key()?;
+        ans.name = name;
+        Ok(ans)
     }
 }
 
 
+/// Format a string argument (e.g. a layer name) so it parses back to the same string.
+/// Plain words are left alone; anything else is quoted.
+pub fn format_argument(v: &str) -> String {
+    let is_word = !v.is_empty() && v.chars().all(|c| !c.is_whitespace() && !"(),=\"".contains(c));
+    if is_word {
+        v.to_string()
+    } else {
+        format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Convert a parsed key tree into a key using the built-in keys (see `KeyRegistry`).
 pub fn convert_tokens_to_key(v: &ParsedKeyTree) -> Result<Box<KeyCode>, String> {
+    KeyRegistry::new().convert(v)
+}
 
-    // Try to parse the key tree using every known key.
-    // Wish there was a reflection based alternative for listing
-    // every possible key.
-    type Converter = fn(&ParsedKeyTree) -> Result<Box<KeyCode>, String>;
-    let converters: [Converter; 11] = [
-        |x| { Ok(Box::new(NormalKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(TransparentKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(OpaqueKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(MacroKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(ToggleLayerKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(MomentarilyEnableLayerKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(ActivateLayerKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(HoldEnableLayerPressKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(OneShotLayer::from_tokens(x)?)) },
-        |x| { Ok(Box::new(WrappedKey::from_tokens(x)?)) },
-        |x| { Ok(Box::new(SpaceCadet::from_tokens(x)?)) },
-    ];
-
-    for i in converters.into_iter() {
-        let v = i(&v);
-        match v {
-            Err(_) => (),
-            Ok(v) => {
-                return Ok(v)
-            }
-        }
+/// Describe an unknown key, suggesting the closest candidate (if any are close).
+pub fn unknown_key_message<I>(name: &str, candidates: I) -> String where I: IntoIterator<Item=String> {
+    match closest_match(name, candidates) {
+        Some(v) => format!("unknown key {}, did you mean {}?", name, v),
+        None => format!("unknown key {}", name)
     }
+}
 
-    Err("Failed to convert key tree into a key.".to_string())
+/// Names of every simple key, written the way layouts spell them (e.g. `KC_ENTER`).
+pub fn simple_key_names() -> Vec<String> {
+    (0..=767).filter_map(evdev_rs::enums::int_to_ev_key)
+        .map(|x| format!("{:?}", x))
+        .filter(|x| x.starts_with("KEY_"))
+        .map(|x| x.replacen("KEY_", "KC_", 1))
+        .collect()
+}
+
+/// Find the candidate that's closest to a (probably misspelled) name.
+/// Candidates that are too different aren't suggested.
+pub fn closest_match<I>(name: &str, candidates: I) -> Option<String> where I: IntoIterator<Item=String> {
+    let name = name.to_uppercase();
+    let max_distance = std::cmp::max(2, name.len() / 3);
+    candidates.into_iter()
+        .map(|x| (edit_distance(&name, &x.to_uppercase()), x))
+        .filter(|x| x.0 <= max_distance)
+        .min_by_key(|x| x.0)
+        .map(|x| x.1)
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let above = row[j + 1];
+            let cost = if ca == b[j] { 0 } else { 1 };
+            row[j + 1] = std::cmp::min(std::cmp::min(row[j] + 1, above + 1), diagonal + cost);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
 }
 
 #[cfg(test)]
@@ -311,16 +433,19 @@ mod tests {
     use super::*;
 
     #[test]
-    fn raw_token_failures() {
+    fn parsed_key_tree_failures() {
         // These are random assortment of cases that shouldn't be parsed.
-        assert!(!RawToken::create(",key").is_ok());
-        assert!(!RawToken::create("key(,)").is_ok());
-        assert!(!RawToken::create("()").is_ok());
-        assert!(!RawToken::create("key(arg1,, arg2)").is_ok());
-        assert!(!RawToken::create("key(arg1(arg2)()").is_ok());
-        assert!(!RawToken::create("key(arg1(arg2, arg3(arg4),))").is_ok());
-        assert!(!RawToken::create("key(,arg1)").is_ok());
-        assert!(!RawToken::create("key(arg1))").is_ok());
+        assert!(!ParsedKeyTree::create("").is_ok());
+        assert!(!ParsedKeyTree::create(",key").is_ok());
+        assert!(!ParsedKeyTree::create("key(,)").is_ok());
+        assert!(!ParsedKeyTree::create("()").is_ok());
+        assert!(!ParsedKeyTree::create("key(arg1,, arg2)").is_ok());
+        assert!(!ParsedKeyTree::create("key(arg1(arg2)()").is_ok());
+        assert!(!ParsedKeyTree::create("key(arg1(arg2, arg3(arg4),))").is_ok());
+        assert!(!ParsedKeyTree::create("key(,arg1)").is_ok());
+        assert!(!ParsedKeyTree::create("key(arg1))").is_ok());
+        assert!(!ParsedKeyTree::create("key(arg1)arg2").is_ok());
+        assert!(!ParsedKeyTree::create("key(\"unterminated)").is_ok());
     }
 
     #[test]
@@ -332,7 +457,8 @@ mod tests {
         assert!(ParsedKeyTree::create("key(arg1, arg2)").is_ok());
         assert!(ParsedKeyTree::create("key(arg1(arg2))").is_ok());
         assert!(ParsedKeyTree::create("key(arg1(arg2, arg3(arg4)))").is_ok());
-        assert!(ParsedKeyTree::create("key(arg1)").is_ok());
+        assert!(ParsedKeyTree::create("  key ( arg1 )  ").is_ok());
+        assert!(ParsedKeyTree::create("key(\"a, (string)\")").is_ok());
     }
 
     #[test]
@@ -362,4 +488,66 @@ mod tests {
         assert_eq!(v.args[3].args[0].args[0].args[0].identifier, "i");
         assert_eq!(v.args[3].args[0].args[0].args[0].args.len(), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parsed_key_tree_literals() {
+        let v = ParsedKeyTree::create("key(150, 150ms, 2s, \"say \\\"hi\\\"\", KC_A)").unwrap();
+        assert_eq!(v.args[0].literal, Some(Literal::Integer(150)));
+        assert_eq!(v.args[1].literal, Some(Literal::Duration(Duration::from_millis(150))));
+        assert_eq!(v.args[2].literal, Some(Literal::Duration(Duration::from_secs(2))));
+        assert_eq!(v.args[3].literal, Some(Literal::String("say \"hi\"".to_string())));
+        assert_eq!(v.args[4].literal, None);
+        assert_eq!(v.args[0].as_duration_ms(), Some(Duration::from_millis(150)));
+        assert_eq!(v.args[4].column, 35);
+    }
+
+    #[test]
+    fn convert_tokens_to_key_errors() {
+        let convert = |x: &str| convert_tokens_to_key(&ParsedKeyTree::create(x).unwrap()).err().unwrap();
+        assert_eq!(convert("LT(nav)"), "LT expects 2 to 3 arguments, got 1");
+        assert_eq!(convert("LT(nav,KC_A,hold=150)"), "LT doesn't have an argument named hold");
+        assert_eq!(convert("LT(nav,KC_A,layer=nav)"), "LT got the layer argument twice");
+        assert_eq!(convert("LT(tap=KC_A)"), "LT is missing the layer argument");
+        assert_eq!(convert("KC_ENTR"), "unknown key KC_ENTR, did you mean KC_ENTER?");
+        assert_eq!(convert("MO(nav,KC_ENTR)"), "MO expects 1 argument, got 2");
+        assert_eq!(convert("WRAP(KC_LEFTSHIFT,KC_ENTR)"), "unknown key KC_ENTR, did you mean KC_ENTER?");
+        assert_eq!(convert("SPACECADT(KC_9,KC_LEFTSHIFT)"), "unknown key SPACECADT, did you mean SPACECADET?");
+        assert_eq!(convert("QWERTYUIOP"), "unknown key QWERTYUIOP");
+    }
+
+    #[test]
+    fn convert_tokens_to_key_underscores() {
+        // Key names can contain underscores after the prefix.
+        assert!(convert_tokens_to_key(&ParsedKeyTree::create("KC_BRIGHTNESS_CYCLE").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn parsed_key_tree_named_arguments() {
+        let v = ParsedKeyTree::create("LT(nav, tap=KC_SPACE, hold_ms = 150)").unwrap();
+        assert_eq!(v.args.len(), 3);
+        assert_eq!(v.args[0].name, None);
+        assert_eq!(v.args[1].name, Some("tap".to_string()));
+        assert_eq!(v.args[1].identifier, "KC_SPACE");
+        assert_eq!(v.args[2].name, Some("hold_ms".to_string()));
+        assert_eq!(v.args[2].literal, Some(Literal::Integer(150)));
+
+        // Positional arguments can't follow named arguments, and keys can't be named.
+        let e = ParsedKeyTree::create("LT(layer=nav, KC_SPACE)").unwrap_err();
+        assert_eq!(e.column, 15);
+        assert!(ParsedKeyTree::create("name=KC_A").is_err());
+        assert!(ParsedKeyTree::create("LT(=nav)").is_err());
+    }
+
+    #[test]
+    fn parsed_key_tree_error_positions() {
+        let e = ParsedKeyTree::create("MO(nav").unwrap_err();
+        assert_eq!(e.column, 7);
+        assert_eq!(e.expected, "\",\" or \")\"");
+        assert_eq!(e.found, "the end of the key");
+
+        let e = ParsedKeyTree::create("LT(nav,,KC_A)").unwrap_err();
+        assert_eq!(e.column, 8);
+        assert_eq!(e.expected, "a key");
+        assert_eq!(e.to_string(), "expected a key, found \",\" at column 8\n  LT(nav,,KC_A)\n         ^");
+    }
+}
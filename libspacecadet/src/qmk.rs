@@ -0,0 +1,785 @@
+use std::collections::HashMap;
+
+use crate::import::{ImportedLayer, ImportedLayout};
+use crate::input_keyboard::InputKeyboard;
+use crate::keyboard_driver::KeyboardDriver;
+use crate::keys::{describe_key_code, NormalKey};
+use crate::output_keyboard::OutputKeyboard;
+use crate::parser::{format_argument, ParsedKeyTree};
+
+/// QMK's short key names and the names used here (e.g. `KC_BSPC` is `KC_BACKSPACE`).
+const QMK_KEY_NAMES: &[(&str, &str)] = &[
+    ("KC_ENT", "KC_ENTER"), ("KC_ESCAPE", "KC_ESC"), ("KC_BSPC", "KC_BACKSPACE"), ("KC_SPC", "KC_SPACE"),
+    ("KC_MINS", "KC_MINUS"), ("KC_EQL", "KC_EQUAL"), ("KC_LBRC", "KC_LEFTBRACE"), ("KC_RBRC", "KC_RIGHTBRACE"),
+    ("KC_LEFT_BRACKET", "KC_LEFTBRACE"), ("KC_RIGHT_BRACKET", "KC_RIGHTBRACE"),
+    ("KC_BSLS", "KC_BACKSLASH"), ("KC_NUHS", "KC_BACKSLASH"), ("KC_NUBS", "KC_102ND"),
+    ("KC_SCLN", "KC_SEMICOLON"), ("KC_QUOT", "KC_APOSTROPHE"), ("KC_QUOTE", "KC_APOSTROPHE"),
+    ("KC_GRV", "KC_GRAVE"), ("KC_COMM", "KC_COMMA"), ("KC_SLSH", "KC_SLASH"),
+    ("KC_CAPS", "KC_CAPSLOCK"), ("KC_CAPS_LOCK", "KC_CAPSLOCK"), ("KC_PSCR", "KC_SYSRQ"), ("KC_PRINT_SCREEN", "KC_SYSRQ"),
+    ("KC_SCRL", "KC_SCROLLLOCK"), ("KC_SCROLL_LOCK", "KC_SCROLLLOCK"), ("KC_PAUS", "KC_PAUSE"), ("KC_BRK", "KC_PAUSE"),
+    ("KC_INS", "KC_INSERT"), ("KC_DEL", "KC_DELETE"), ("KC_PGUP", "KC_PAGEUP"), ("KC_PAGE_UP", "KC_PAGEUP"),
+    ("KC_PGDN", "KC_PAGEDOWN"), ("KC_PAGE_DOWN", "KC_PAGEDOWN"), ("KC_RGHT", "KC_RIGHT"), ("KC_APP", "KC_COMPOSE"),
+    ("KC_NUM", "KC_NUMLOCK"), ("KC_NLCK", "KC_NUMLOCK"), ("KC_NUM_LOCK", "KC_NUMLOCK"),
+    ("KC_PSLS", "KC_KPSLASH"), ("KC_PAST", "KC_KPASTERISK"), ("KC_PMNS", "KC_KPMINUS"), ("KC_PPLS", "KC_KPPLUS"),
+    ("KC_PENT", "KC_KPENTER"), ("KC_PDOT", "KC_KPDOT"), ("KC_PEQL", "KC_KPEQUAL"),
+    ("KC_P1", "KC_KP1"), ("KC_P2", "KC_KP2"), ("KC_P3", "KC_KP3"), ("KC_P4", "KC_KP4"), ("KC_P5", "KC_KP5"),
+    ("KC_P6", "KC_KP6"), ("KC_P7", "KC_KP7"), ("KC_P8", "KC_KP8"), ("KC_P9", "KC_KP9"), ("KC_P0", "KC_KP0"),
+    ("KC_LCTL", "KC_LEFTCTRL"), ("KC_LEFT_CTRL", "KC_LEFTCTRL"), ("KC_LSFT", "KC_LEFTSHIFT"), ("KC_LEFT_SHIFT", "KC_LEFTSHIFT"),
+    ("KC_LALT", "KC_LEFTALT"), ("KC_LOPT", "KC_LEFTALT"), ("KC_LEFT_ALT", "KC_LEFTALT"),
+    ("KC_LGUI", "KC_LEFTMETA"), ("KC_LCMD", "KC_LEFTMETA"), ("KC_LWIN", "KC_LEFTMETA"), ("KC_LEFT_GUI", "KC_LEFTMETA"),
+    ("KC_RCTL", "KC_RIGHTCTRL"), ("KC_RIGHT_CTRL", "KC_RIGHTCTRL"), ("KC_RSFT", "KC_RIGHTSHIFT"), ("KC_RIGHT_SHIFT", "KC_RIGHTSHIFT"),
+    ("KC_RALT", "KC_RIGHTALT"), ("KC_ROPT", "KC_RIGHTALT"), ("KC_ALGR", "KC_RIGHTALT"), ("KC_RIGHT_ALT", "KC_RIGHTALT"),
+    ("KC_RGUI", "KC_RIGHTMETA"), ("KC_RCMD", "KC_RIGHTMETA"), ("KC_RWIN", "KC_RIGHTMETA"), ("KC_RIGHT_GUI", "KC_RIGHTMETA"),
+    ("KC_VOLU", "KC_VOLUMEUP"), ("KC_VOLD", "KC_VOLUMEDOWN"), ("KC_MPLY", "KC_PLAYPAUSE"),
+    ("KC_MNXT", "KC_NEXTSONG"), ("KC_MPRV", "KC_PREVIOUSSONG"), ("KC_MSTP", "KC_STOPCD"),
+    ("KC_BRIU", "KC_BRIGHTNESSUP"), ("KC_BRID", "KC_BRIGHTNESSDOWN"),
+];
+
+/// QMK's shifted key names and the key they shift (e.g. `KC_EXLM` is shift + `KC_1`).
+const QMK_SHIFTED_KEYS: &[(&str, &str)] = &[
+    ("KC_TILD", "KC_GRAVE"), ("KC_EXLM", "KC_1"), ("KC_AT", "KC_2"), ("KC_HASH", "KC_3"), ("KC_DLR", "KC_4"),
+    ("KC_PERC", "KC_5"), ("KC_CIRC", "KC_6"), ("KC_AMPR", "KC_7"), ("KC_ASTR", "KC_8"), ("KC_LPRN", "KC_9"),
+    ("KC_RPRN", "KC_0"), ("KC_UNDS", "KC_MINUS"), ("KC_PLUS", "KC_EQUAL"), ("KC_LCBR", "KC_LEFTBRACE"),
+    ("KC_RCBR", "KC_RIGHTBRACE"), ("KC_PIPE", "KC_BACKSLASH"), ("KC_COLN", "KC_SEMICOLON"),
+    ("KC_DQUO", "KC_APOSTROPHE"), ("KC_DQT", "KC_APOSTROPHE"), ("KC_LABK", "KC_COMMA"), ("KC_LT", "KC_COMMA"),
+    ("KC_RABK", "KC_DOT"), ("KC_GT", "KC_DOT"), ("KC_QUES", "KC_SLASH"),
+];
+
+/// QMK's modifier functions (e.g. `LCTL(kc)`) and the modifiers they hold.
+const QMK_MODIFIER_FUNCTIONS: &[(&str, &[&str])] = &[
+    ("LCTL", &["KC_LEFTCTRL"]), ("C", &["KC_LEFTCTRL"]), ("LSFT", &["KC_LEFTSHIFT"]), ("S", &["KC_LEFTSHIFT"]),
+    ("LALT", &["KC_LEFTALT"]), ("A", &["KC_LEFTALT"]), ("LOPT", &["KC_LEFTALT"]),
+    ("LGUI", &["KC_LEFTMETA"]), ("G", &["KC_LEFTMETA"]), ("LCMD", &["KC_LEFTMETA"]), ("LWIN", &["KC_LEFTMETA"]),
+    ("RCTL", &["KC_RIGHTCTRL"]), ("RSFT", &["KC_RIGHTSHIFT"]), ("RALT", &["KC_RIGHTALT"]), ("ROPT", &["KC_RIGHTALT"]),
+    ("ALGR", &["KC_RIGHTALT"]), ("RGUI", &["KC_RIGHTMETA"]), ("RCMD", &["KC_RIGHTMETA"]), ("RWIN", &["KC_RIGHTMETA"]),
+    ("LCS", &["KC_LEFTCTRL", "KC_LEFTSHIFT"]), ("LCA", &["KC_LEFTCTRL", "KC_LEFTALT"]),
+    ("LSA", &["KC_LEFTSHIFT", "KC_LEFTALT"]), ("LCG", &["KC_LEFTCTRL", "KC_LEFTMETA"]),
+    ("SGUI", &["KC_LEFTSHIFT", "KC_LEFTMETA"]), ("SCMD", &["KC_LEFTSHIFT", "KC_LEFTMETA"]), ("SWIN", &["KC_LEFTSHIFT", "KC_LEFTMETA"]),
+    ("MEH", &["KC_LEFTCTRL", "KC_LEFTSHIFT", "KC_LEFTALT"]),
+    ("HYPR", &["KC_LEFTCTRL", "KC_LEFTSHIFT", "KC_LEFTALT", "KC_LEFTMETA"]),
+];
+
+/// QMK's modifier masks (used by `MT(mod, kc)`) and the modifiers they hold.
+const QMK_MODIFIER_MASKS: &[(&str, &[&str])] = &[
+    ("MOD_LCTL", &["KC_LEFTCTRL"]), ("MOD_LSFT", &["KC_LEFTSHIFT"]), ("MOD_LALT", &["KC_LEFTALT"]), ("MOD_LGUI", &["KC_LEFTMETA"]),
+    ("MOD_RCTL", &["KC_RIGHTCTRL"]), ("MOD_RSFT", &["KC_RIGHTSHIFT"]), ("MOD_RALT", &["KC_RIGHTALT"]), ("MOD_RGUI", &["KC_RIGHTMETA"]),
+    ("MOD_MEH", &["KC_LEFTCTRL", "KC_LEFTSHIFT", "KC_LEFTALT"]),
+    ("MOD_HYPR", &["KC_LEFTCTRL", "KC_LEFTSHIFT", "KC_LEFTALT", "KC_LEFTMETA"]),
+];
+
+/// QMK's mod-tap shortcuts (e.g. `LCTL_T(kc)`) and the modifier functions they hold.
+const QMK_MOD_TAPS: &[(&str, &str)] = &[
+    ("LCTL_T", "LCTL"), ("CTL_T", "LCTL"), ("LSFT_T", "LSFT"), ("SFT_T", "LSFT"), ("LALT_T", "LALT"), ("ALT_T", "LALT"),
+    ("LOPT_T", "LALT"), ("OPT_T", "LALT"), ("LGUI_T", "LGUI"), ("GUI_T", "LGUI"), ("LCMD_T", "LGUI"), ("CMD_T", "LGUI"),
+    ("LWIN_T", "LGUI"), ("WIN_T", "LGUI"), ("RCTL_T", "RCTL"), ("RSFT_T", "RSFT"), ("RALT_T", "RALT"), ("ROPT_T", "RALT"),
+    ("ALGR_T", "RALT"), ("RGUI_T", "RGUI"), ("RCMD_T", "RGUI"), ("RWIN_T", "RGUI"),
+    ("C_S_T", "LCS"), ("LCS_T", "LCS"), ("LCA_T", "LCA"), ("LSA_T", "LSA"), ("LCG_T", "LCG"),
+    ("SGUI_T", "SGUI"), ("SCMD_T", "SGUI"), ("SWIN_T", "SGUI"), ("MEH_T", "MEH"), ("HYPR_T", "HYPR"), ("ALL_T", "HYPR"),
+];
+
+fn lookup<'a, T>(table: &'a [(&str, T)], name: &str) -> Option<&'a T> {
+    table.iter().find(|x| x.0 == name).map(|x| &x.1)
+}
+
+/// Find the first QMK name for a value (e.g. `KC_ENT` for `KC_ENTER`).
+fn reverse_lookup<'a, T: PartialEq>(table: &'a [(&'a str, T)], value: &T) -> Option<&'a str> {
+    table.iter().find(|x| x.1 == *value).map(|x| x.0)
+}
+
+
+/// Translates QMK keycodes (e.g. `LT(_NAV, KC_SPC)`) into key syntax (e.g. `LT(nav,KC_SPACE)`).
+struct QmkTranslator {
+    /// Layer names by QMK layer number.
+    layers: HashMap<usize, String>,
+    /// Layer names by the symbol the keymap uses (e.g. `_NAV`).
+    symbols: HashMap<String, String>,
+    /// Values of `#define` and `enum` entries.
+    defines: HashMap<String, String>,
+}
+
+impl QmkTranslator {
+    /// Translate a keycode, noting anything that's imported as a close equivalent.
+    fn translate(&self, code: &str, notes: &mut Vec<String>) -> Result<String, String> {
+        // QMK allows whitespace anywhere (e.g. `MOD_LCTL | MOD_LSFT`), key syntax doesn't.
+        let code: String = code.chars().filter(|x| !x.is_whitespace()).collect();
+        let tree = ParsedKeyTree::create(&code).map_err(|e| format!("can't read {}: {}", code, e.message()))?;
+        self.translate_tree(&tree, notes, 0)
+    }
+
+    fn translate_tree(&self, tree: &ParsedKeyTree, notes: &mut Vec<String>, depth: usize) -> Result<String, String> {
+        let name = tree.identifier.as_str();
+        if tree.args.is_empty() {
+            if let Some(v) = self.defines.get(name) {
+                if depth > 16 {
+                    return Err(format!("{} is defined in terms of itself", name));
+                }
+                let code: String = v.chars().filter(|x| !x.is_whitespace()).collect();
+                let tree = ParsedKeyTree::create(&code).map_err(|e| format!("can't read {}: {}", code, e.message()))?;
+                return self.translate_tree(&tree, notes, depth + 1);
+            }
+            return self.translate_keycode(name);
+        }
+
+        let arg = |idx: usize| -> Result<&ParsedKeyTree, String> {
+            tree.args.get(idx).ok_or_else(|| format!("{} is missing an argument", name))
+        };
+        match name {
+            "MO" | "TG" | "OSL" => Ok(format!("{}({})", name, self.layer(arg(0)?)?)),
+            "TO" | "DF" => {
+                notes.push(format!("{} is imported as AL, which doesn't disable other layers", name));
+                Ok(format!("AL({})", self.layer(arg(0)?)?))
+            }
+            "TT" => {
+                notes.push("TT is imported as TG, so it doesn't act like MO when held".to_string());
+                Ok(format!("TG({})", self.layer(arg(0)?)?))
+            }
+            "LT" => Ok(format!("LT({},{})", self.layer(arg(0)?)?, self.translate_tree(arg(1)?, notes, depth)?)),
+            "MT" => {
+                let mods: Vec<&str> = arg(0)?.identifier.split('|').collect();
+                let mut keys = Vec::new();
+                for m in mods.iter() {
+                    match lookup(QMK_MODIFIER_MASKS, m) {
+                        Some(v) => keys.extend(v.iter()),
+                        None => return Err(format!("unknown modifier {}", m))
+                    }
+                }
+                self.mod_tap(&keys, arg(1)?, notes, depth)
+            }
+            _ => {
+                if let Some(v) = lookup(QMK_MOD_TAPS, name) {
+                    let keys = lookup(QMK_MODIFIER_FUNCTIONS, v).unwrap();
+                    return self.mod_tap(keys, arg(0)?, notes, depth);
+                }
+                match lookup(QMK_MODIFIER_FUNCTIONS, name) {
+                    Some(keys) => {
+                        // LCS(kc) is WRAP(KC_LEFTCTRL,WRAP(KC_LEFTSHIFT,kc)).
+                        let mut ans = self.translate_tree(arg(0)?, notes, depth)?;
+                        for k in keys.iter().rev() {
+                            ans = format!("WRAP({},{})", k, ans);
+                        }
+                        Ok(ans)
+                    }
+                    None => Err(format!("{} isn't supported", name))
+                }
+            }
+        }
+    }
+
+    /// Translate a mod-tap key. Only the first modifier can be held.
+    fn mod_tap(&self, mods: &[&str], key: &ParsedKeyTree, notes: &mut Vec<String>, depth: usize) -> Result<String, String> {
+        if mods.len() > 1 {
+            notes.push(format!("MT can only hold one modifier, so only {} is held", mods[0]));
+        }
+        Ok(format!("MT({},{})", mods[0], self.translate_tree(key, notes, depth)?))
+    }
+
+    /// Translate a keycode without arguments (e.g. `KC_SPC`).
+    fn translate_keycode(&self, name: &str) -> Result<String, String> {
+        if name == "KC_TRNS" || name == "KC_TRANSPARENT" || (name.len() > 1 && name.chars().all(|x| x == '_')) {
+            return Ok("___".to_string());
+        }
+        if name == "KC_NO" || (name.len() > 1 && name.chars().all(|x| x == 'X')) {
+            return Ok("XXX".to_string());
+        }
+        if name == "QK_LLCK" || name == "QK_LAYER_LOCK" {
+            return Ok("LLOCK".to_string());
+        }
+        if let Some(v) = lookup(QMK_SHIFTED_KEYS, name) {
+            return Ok(format!("WRAP(KC_LEFTSHIFT,{})", v));
+        }
+        let name = lookup(QMK_KEY_NAMES, name).cloned().unwrap_or(name);
+        if !name.starts_with("KC_") {
+            return Err(format!("{} isn't supported", name));
+        }
+        let tree = ParsedKeyTree::create(name).map_err(|e| e.message())?;
+        match NormalKey::from_tokens(&tree) {
+            Ok(_) => Ok(name.to_string()),
+            Err(_) => Err(format!("{} isn't supported", name))
+        }
+    }
+
+    /// Find the name of a layer from its number or symbol.
+    fn layer(&self, arg: &ParsedKeyTree) -> Result<String, String> {
+        let mut v = arg.identifier.clone();
+        for _ in 0..16 {
+            if let Some(name) = self.symbols.get(&v) {
+                return Ok(format_argument(name));
+            }
+            if let Ok(idx) = v.parse::<usize>() {
+                return self.layers.get(&idx).map(|x| format_argument(x))
+                    .ok_or_else(|| format!("there isn't a layer {}", idx));
+            }
+            match self.defines.get(&v) {
+                Some(d) => v = d.trim().to_string(),
+                None => break
+            }
+        }
+        Err(format!("unknown layer {}", arg.identifier))
+    }
+
+    /// Translate the keycodes of a layer, replacing keys that can't be translated by `XXX`.
+    fn translate_layer(&self, name: &str, rows: &[Vec<String>], warnings: &mut Vec<String>) -> ImportedLayer {
+        let mut idx = 0;
+        let keys = rows.iter().map(|row| row.iter().map(|code| {
+            idx += 1;
+            let mut notes = Vec::new();
+            let ans = match self.translate(code, &mut notes) {
+                Ok(v) => v,
+                Err(e) => {
+                    notes.push(format!("{}, imported as XXX", e));
+                    "XXX".to_string()
+                }
+            };
+            for note in notes {
+                warnings.push(format!("layer {}, key {} ({}): {}", name, idx, code.trim(), note));
+            }
+            ans
+        }).collect()).collect();
+        ImportedLayer { name: name.to_string(), keys }
+    }
+}
+
+
+/// Import a QMK Configurator keymap (`keymap.json`).
+///
+/// The file doesn't describe where keys are, so each layer is imported as a single row
+/// named `layer0`, `layer1` and so on.
+pub fn import_qmk_json(contents: &str) -> Result<ImportedLayout, String> {
+    let data = json::parse(contents).map_err(|e| e.to_string())?;
+    if !data["layers"].is_array() {
+        return Err("Expected a \"layers\" list.".to_string());
+    }
+    let mut translator = QmkTranslator { layers: HashMap::new(), symbols: HashMap::new(), defines: HashMap::new() };
+    let count = data["layers"].len();
+    for idx in 0..count {
+        translator.layers.insert(idx, format!("layer{}", idx));
+    }
+
+    let mut ans = ImportedLayout::new();
+    for (idx, layer) in data["layers"].members().enumerate() {
+        let mut row = Vec::new();
+        for key in layer.members() {
+            match key.as_str() {
+                Some(v) => row.push(v.to_string()),
+                None => return Err(format!("Expected the keys of layer {} to be strings.", idx))
+            }
+        }
+        let name = format!("layer{}", idx);
+        let layer = translator.translate_layer(&name, &[row], &mut ans.warnings);
+        ans.layers.push(layer);
+    }
+    Ok(ans)
+}
+
+/// Import a QMK C keymap (`keymap.c`).
+///
+/// Layers are read from the `keymaps` array, and each line of a `LAYOUT(...)` is imported as a row.
+/// Layers are named after their symbol (e.g. `[_NAV]` is `nav`, `[2]` is `layer2`). Single-line
+/// `#define`s and `enum`s are used to resolve keycodes and layer numbers.
+pub fn import_qmk_c(contents: &str) -> Result<ImportedLayout, String> {
+    let source = strip_c_comments(contents);
+    let mut translator = QmkTranslator { layers: HashMap::new(), symbols: HashMap::new(), defines: HashMap::new() };
+    read_c_defines(&source, &mut translator.defines);
+    read_c_enums(&source, &mut translator.defines);
+
+    let entries = read_c_keymaps(&source)?;
+    let mut names = Vec::new();
+    for (idx, (symbol, _)) in entries.iter().enumerate() {
+        let name = match symbol {
+            Some(s) if s.chars().all(|x| x.is_ascii_digit()) => format!("layer{}", s),
+            Some(s) => s.trim_matches('_').to_lowercase(),
+            None => format!("layer{}", idx),
+        };
+        // Use the symbol's value if it has one, otherwise its position in the array.
+        let number = symbol.as_ref()
+            .map(|s| translator.defines.get(s).cloned().unwrap_or_else(|| s.clone()))
+            .and_then(|s| s.trim().parse::<usize>().ok())
+            .unwrap_or(idx);
+        translator.layers.insert(number, name.clone());
+        if let Some(s) = symbol {
+            translator.symbols.insert(s.clone(), name.clone());
+        }
+        names.push(name);
+    }
+
+    let mut ans = ImportedLayout::new();
+    for (name, (_, rows)) in names.iter().zip(entries.iter()) {
+        let layer = translator.translate_layer(name, rows, &mut ans.warnings);
+        ans.layers.push(layer);
+    }
+    Ok(ans)
+}
+
+/// Remove `//` and `/* */` comments, keeping line breaks (so lines still match the file).
+fn strip_c_comments(contents: &str) -> String {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut ans = String::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let next = chars.get(idx + 1).cloned();
+        if chars[idx] == '"' || chars[idx] == '\'' {
+            // Copy string and character literals as they are.
+            let quote = chars[idx];
+            ans.push(quote);
+            idx += 1;
+            while idx < chars.len() && chars[idx] != quote && chars[idx] != '\n' {
+                if chars[idx] == '\\' && idx + 1 < chars.len() {
+                    ans.push(chars[idx]);
+                    idx += 1;
+                }
+                ans.push(chars[idx]);
+                idx += 1;
+            }
+            if idx < chars.len() {
+                ans.push(chars[idx]);
+                idx += 1;
+            }
+        } else if chars[idx] == '/' && next == Some('/') {
+            while idx < chars.len() && chars[idx] != '\n' {
+                idx += 1;
+            }
+        } else if chars[idx] == '/' && next == Some('*') {
+            idx += 2;
+            while idx < chars.len() && !(chars[idx] == '*' && chars.get(idx + 1) == Some(&'/')) {
+                if chars[idx] == '\n' {
+                    ans.push('\n');
+                }
+                idx += 1;
+            }
+            idx += 2;
+        } else {
+            ans.push(chars[idx]);
+            idx += 1;
+        }
+    }
+    ans
+}
+
+/// Read simple single-line defines (e.g. `#define HOME_A LGUI_T(KC_A)`).
+fn read_c_defines(source: &str, defines: &mut HashMap<String, String>) {
+    for line in source.lines() {
+        let line = line.trim();
+        if !line.starts_with("#define") {
+            continue;
+        }
+        let rest = line["#define".len()..].trim_start();
+        let end = rest.find(|x: char| !(x.is_alphanumeric() || x == '_')).unwrap_or(rest.len());
+        let (name, value) = rest.split_at(end);
+        // Skip function-like macros (e.g. `#define LAYOUT(k00, k01)`) and empty defines.
+        if name.is_empty() || value.starts_with('(') || value.trim().is_empty() || value.trim().ends_with('\\') {
+            continue;
+        }
+        defines.insert(name.to_string(), value.trim().to_string());
+    }
+}
+
+/// Read the values of `enum` entries (e.g. `enum layers { _BASE, _NAV }`).
+/// Entries after one with a value that isn't a number (e.g. `= SAFE_RANGE`) are skipped.
+fn read_c_enums(source: &str, defines: &mut HashMap<String, String>) {
+    let mut rest = source;
+    while let Some(start) = rest.find("enum") {
+        let after = &rest[start + 4..];
+        let is_keyword = after.starts_with(|x: char| x.is_whitespace() || x == '{')
+            && !rest[..start].ends_with(|x: char| x.is_alphanumeric() || x == '_');
+        let body = match (after.find('{'), after.find('}')) {
+            (Some(a), Some(b)) if is_keyword && a < b => &after[a + 1..b],
+            _ => { rest = after; continue; }
+        };
+        let mut value = Some(0);
+        for entry in body.split(',') {
+            let mut parts = entry.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            if name.is_empty() {
+                continue;
+            }
+            if let Some(v) = parts.next() {
+                value = v.trim().parse::<usize>().ok();
+            }
+            if let Some(v) = value {
+                defines.entry(name.to_string()).or_insert_with(|| v.to_string());
+                value = Some(v + 1);
+            }
+        }
+        rest = after;
+    }
+}
+
+/// An entry of the `keymaps` array: the layer's symbol (if it has one) and rows of keycodes.
+type KeymapEntry = (Option<String>, Vec<Vec<String>>);
+
+/// Read the entries of the `keymaps` array.
+fn read_c_keymaps(source: &str) -> Result<Vec<KeymapEntry>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let start = source.find("keymaps").ok_or_else(|| "Couldn't find the keymaps array.".to_string())?;
+    let mut idx = source[..start].chars().count();
+    // Skip the declaration (e.g. `keymaps[][MATRIX_ROWS][MATRIX_COLS] = {`).
+    while idx < chars.len() && chars[idx] != '=' {
+        idx += 1;
+    }
+    while idx < chars.len() && chars[idx] != '{' {
+        idx += 1;
+    }
+    idx += 1;
+
+    let mut ans = Vec::new();
+    loop {
+        while idx < chars.len() && (chars[idx].is_whitespace() || chars[idx] == ',') {
+            idx += 1;
+        }
+        if idx >= chars.len() {
+            return Err("The keymaps array isn't closed.".to_string());
+        }
+        if chars[idx] == '}' {
+            break;
+        }
+
+        let mut symbol = None;
+        if chars[idx] == '[' {
+            let end = (idx..chars.len()).find(|x| chars[*x] == ']')
+                .ok_or_else(|| "A keymaps entry isn't closed.".to_string())?;
+            symbol = Some(chars[idx + 1..end].iter().collect::<String>().trim().to_string());
+            idx = end + 1;
+            while idx < chars.len() && (chars[idx].is_whitespace() || chars[idx] == '=') {
+                idx += 1;
+            }
+        }
+
+        // The layout macro (e.g. `LAYOUT_split_3x6_3(`).
+        while idx < chars.len() && chars[idx] != '(' {
+            idx += 1;
+        }
+        let (rows, end) = read_c_arguments(&chars, idx)?;
+        ans.push((symbol, rows));
+        idx = end;
+    }
+    if ans.is_empty() {
+        return Err("The keymaps array doesn't have any layers.".to_string());
+    }
+    Ok(ans)
+}
+
+/// Read the arguments of a macro call starting at `(`, grouping them into rows by line.
+/// Returns the rows and the index after the closing `)`.
+fn read_c_arguments(chars: &[char], start: usize) -> Result<(Vec<Vec<String>>, usize), String> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut current = String::new();
+    let mut line = 0;
+    let mut current_line = None;
+    let mut depth = 0;
+    let mut last_line = None;
+    let mut idx = start;
+    while idx < chars.len() {
+        let c = chars[idx];
+        idx += 1;
+        match c {
+            '(' => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            }
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    push_c_argument(&mut rows, &mut current, current_line, &mut last_line);
+                    return Ok((rows, idx));
+                }
+            }
+            ',' if depth == 1 => {
+                push_c_argument(&mut rows, &mut current, current_line, &mut last_line);
+                current_line = None;
+                continue;
+            }
+            '\n' => line += 1,
+            _ => ()
+        }
+        if !c.is_whitespace() && current_line.is_none() {
+            current_line = Some(line);
+        }
+        current.push(c);
+    }
+    Err("A layout isn't closed.".to_string())
+}
+
+fn push_c_argument(rows: &mut Vec<Vec<String>>, current: &mut String, line: Option<usize>, last_line: &mut Option<usize>) {
+    let arg = current.trim().to_string();
+    current.clear();
+    if arg.is_empty() {
+        return;
+    }
+    if rows.is_empty() || line != *last_line {
+        rows.push(Vec::new());
+        *last_line = line;
+    }
+    rows.last_mut().unwrap().push(arg);
+}
+
+
+
+/// Export a driver's layers as a QMK Configurator keymap (`keymap.json`) for a keyboard and layout
+/// (e.g. `crkbd/rev1` and `LAYOUT_split_3x6_3`).
+///
+/// Each layer is written row by row, skipping positions without a key in the matrix. QMK sets
+/// timing (e.g. tapping terms) in `config.h`, so timing arguments aren't exported. Keys that QMK
+/// can't represent are exported as `KC_NO`, and each is described in `warnings`.
+pub fn export_qmk_json<I, O>(driver: &KeyboardDriver<I, O>, keyboard: &str, layout: &str, warnings: &mut Vec<String>) -> json::JsonValue
+    where I: InputKeyboard, O: OutputKeyboard {
+    let mut ans = json::JsonValue::new_object();
+    ans["version"] = 1.into();
+    ans["keyboard"] = keyboard.into();
+    ans["keymap"] = "spacecadet".into();
+    ans["layout"] = layout.into();
+    ans["layers"] = json::JsonValue::new_array();
+
+    let layers = &driver.layer_attributes.name_to_idx;
+    for (attr, codes) in driver.layer_attributes.attributes.iter().zip(driver.layered_codes.iter()) {
+        let mut keys = Vec::new();
+        for (r, row) in codes.codes.iter().enumerate() {
+            for (c, code) in row.iter().enumerate() {
+                if driver.matrix.key_at((r, c)).is_none() {
+                    continue;
+                }
+                let syntax = describe_key_code(code.as_ref());
+                let mut notes = Vec::new();
+                let key = match code.to_key_syntax().ok_or_else(|| "can't be written in key syntax".to_string())
+                    .and_then(|x| ParsedKeyTree::create(&x).map_err(|e| e.message()))
+                    .and_then(|x| export_qmk_keycode(&x, layers, &mut notes)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        notes.push(format!("{}, exported as KC_NO", e));
+                        "KC_NO".to_string()
+                    }
+                };
+                for note in notes {
+                    warnings.push(format!("layer {}, key {}x{} ({}): {}", attr.name, r, c, syntax, note));
+                }
+                keys.push(key);
+            }
+        }
+        ans["layers"].push(keys).unwrap();
+    }
+    ans
+}
+
+/// Translate key syntax into a QMK keycode (e.g. `LT(nav,KC_SPACE,200)` into `LT(1,KC_SPC)`).
+fn export_qmk_keycode(tree: &ParsedKeyTree, layers: &HashMap<String, usize>, notes: &mut Vec<String>) -> Result<String, String> {
+    let name = tree.identifier.as_str();
+    let layer = |idx: usize| -> Result<usize, String> {
+        let arg = tree.args.get(idx).ok_or_else(|| format!("{} is missing an argument", name))?;
+        layers.get(&arg.identifier).cloned().ok_or_else(|| format!("unknown layer {}", arg.identifier))
+    };
+    let key = |idx: usize, notes: &mut Vec<String>| -> Result<String, String> {
+        let arg = tree.args.get(idx).ok_or_else(|| format!("{} is missing an argument", name))?;
+        export_qmk_keycode(arg, layers, notes)
+    };
+    // QMK's mod-tap and layer-tap keys can only tap basic keycodes.
+    let basic_key = |idx: usize, notes: &mut Vec<String>| -> Result<String, String> {
+        let ans = key(idx, notes)?;
+        if ans.contains('(') {
+            Err(format!("QMK can't tap {} from {}", ans, name))
+        } else {
+            Ok(ans)
+        }
+    };
+    match name {
+        _ if tree.args.is_empty() && name.len() > 1 && name.chars().all(|x| x == '_') => Ok("KC_TRNS".to_string()),
+        _ if tree.args.is_empty() && name.len() > 1 && name.chars().all(|x| x == 'X') => Ok("KC_NO".to_string()),
+        "TRANSPARENT" => Ok("KC_TRNS".to_string()),
+        "OPAQUE" => Ok("KC_NO".to_string()),
+        "LLOCK" => Ok("QK_LLCK".to_string()),
+        "MO" | "TG" | "OSL" => Ok(format!("{}({})", name, layer(0)?)),
+        "AL" => {
+            notes.push("AL is exported as TO, which disables other layers".to_string());
+            Ok(format!("TO({})", layer(0)?))
+        }
+        "LT" => Ok(format!("LT({},{})", layer(0)?, basic_key(1, notes)?)),
+        "MT" => {
+            let function = modifier_function(&key(0, notes)?)?;
+            let mod_tap = reverse_lookup(QMK_MOD_TAPS, &function).unwrap();
+            Ok(format!("{}({})", mod_tap, basic_key(1, notes)?))
+        }
+        "WRAP" => {
+            let outside = key(0, notes)?;
+            let inside = key(1, notes)?;
+            if outside == "KC_LSFT" || outside == "KC_RSFT" {
+                let shifted = lookup(QMK_KEY_NAMES, inside.as_str()).cloned().unwrap_or(&inside);
+                if let Some(v) = reverse_lookup(QMK_SHIFTED_KEYS, &shifted) {
+                    return Ok(v.to_string());
+                }
+            }
+            Ok(format!("{}({})", modifier_function(&outside)?, inside))
+        }
+        "SPACECADET" => {
+            match (key(0, notes)?.as_str(), key(1, notes)?.as_str()) {
+                ("KC_LPRN", "KC_LSFT") => Ok("SC_LSPO".to_string()),
+                ("KC_RPRN", "KC_RSFT") => Ok("SC_RSPC".to_string()),
+                ("KC_LPRN", "KC_LCTL") => Ok("SC_LCPO".to_string()),
+                ("KC_RPRN", "KC_RCTL") => Ok("SC_RCPC".to_string()),
+                ("KC_LPRN", "KC_LALT") => Ok("SC_LAPO".to_string()),
+                ("KC_RPRN", "KC_RALT") => Ok("SC_RAPC".to_string()),
+                ("KC_ENT", "KC_RSFT") => Ok("SC_SENT".to_string()),
+                _ => Err("QMK only has Space Cadet keys for parentheses and enter".to_string())
+            }
+        }
+        _ if tree.args.is_empty() && name.starts_with("KC_") => {
+            Ok(reverse_lookup(QMK_KEY_NAMES, &name).unwrap_or(name).to_string())
+        }
+        _ => Err(format!("QMK doesn't have {}", name))
+    }
+}
+
+/// Find the QMK modifier function (e.g. `LCTL`) for a modifier keycode (e.g. `KC_LCTL`).
+fn modifier_function(key: &str) -> Result<&'static str, String> {
+    let evdev_name = lookup(QMK_KEY_NAMES, key).cloned().unwrap_or(key);
+    QMK_MODIFIER_FUNCTIONS.iter()
+        .find(|x| x.1.len() == 1 && x.1[0] == evdev_name)
+        .map(|x| x.0)
+        .ok_or_else(|| format!("{} isn't a modifier", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_io_keyboard::*;
+    use crate::{KeyCodeMatrix, KeyRegistry, LayerAttributes, SimpleKey, VirtualKeyboardMatrix};
+
+    /// Check that every imported key can be loaded.
+    fn assert_keys_load(layout: &ImportedLayout) {
+        let registry = KeyRegistry::new();
+        for layer in layout.layers.iter() {
+            for key in layer.keys.iter().flat_map(|x| x.iter()) {
+                let tree = ParsedKeyTree::create(key).unwrap();
+                assert!(registry.convert(&tree).is_ok(), "{} doesn't load", key);
+            }
+        }
+    }
+
+    #[test]
+    fn import_qmk_json_keymap() {
+        let layout = import_qmk_json(r#"{
+            "keyboard": "crkbd/rev1",
+            "layout": "LAYOUT_split_3x6_3",
+            "layers": [
+                [ "KC_ESC", "LT(1,KC_SPC)", "MT(MOD_LCTL,KC_A)", "KC_EXLM", "LSFT(KC_TAB)", "RGB_TOG" ],
+                [ "KC_TRNS", "_______", "TG(0)", "OSL(1)", "LGUI_T(KC_ENT)", "KC_NO" ]
+            ]
+        }"#).unwrap();
+
+        let names: Vec<&str> = layout.layers.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["layer0", "layer1"]);
+        assert_eq!(layout.layers[0].keys, vec![vec![
+            "KC_ESC", "LT(layer1,KC_SPACE)", "MT(KC_LEFTCTRL,KC_A)", "WRAP(KC_LEFTSHIFT,KC_1)",
+            "WRAP(KC_LEFTSHIFT,KC_TAB)", "XXX"
+        ]]);
+        assert_eq!(layout.layers[1].keys, vec![vec![
+            "___", "___", "TG(layer0)", "OSL(layer1)", "MT(KC_LEFTMETA,KC_ENTER)", "XXX"
+        ]]);
+        assert_eq!(layout.warnings, vec![
+            "layer layer0, key 6 (RGB_TOG): RGB_TOG isn't supported, imported as XXX".to_string()
+        ]);
+        assert_keys_load(&layout);
+
+        assert!(import_qmk_json(r#"{ "layout": "LAYOUT" }"#).is_err());
+    }
+
+    #[test]
+    fn import_qmk_c_keymap() {
+        let layout = import_qmk_c(r#"
+            #include QMK_KEYBOARD_H
+
+            enum layers { _BASE, _NAV, _SYM };
+            enum custom_keycodes { MY_MACRO = SAFE_RANGE };
+
+            #define HOME_A LGUI_T(KC_A)
+            #define NAV_SPC LT(_NAV, KC_SPC)
+
+            const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+                /* Base layer
+                 * (with a comment) */
+                [_BASE] = LAYOUT(
+                    HOME_A,  KC_B,    KC_C,   // first row
+                    NAV_SPC, MO(2),   MEH_T(KC_D)
+                ),
+                [_NAV] = LAYOUT(
+                    KC_LEFT, KC_RGHT, TO(_SYM),
+                    _______, XXXXXXX, MY_MACRO
+                ),
+                [_SYM] = LAYOUT(
+                    KC_LPRN, LCTL(LSFT(KC_Z)), MT(MOD_LCTL | MOD_LSFT, KC_E),
+                    TG(_NAV), ___, KC_TRNS
+                )
+            };
+        "#).unwrap();
+
+        let names: Vec<&str> = layout.layers.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["base", "nav", "sym"]);
+        assert_eq!(layout.layers[0].keys, vec![
+            vec!["MT(KC_LEFTMETA,KC_A)", "KC_B", "KC_C"],
+            vec!["LT(nav,KC_SPACE)", "MO(sym)", "MT(KC_LEFTCTRL,KC_D)"],
+        ]);
+        assert_eq!(layout.layers[1].keys, vec![
+            vec!["KC_LEFT", "KC_RIGHT", "AL(sym)"],
+            vec!["___", "XXX", "XXX"],
+        ]);
+        assert_eq!(layout.layers[2].keys, vec![
+            vec!["WRAP(KC_LEFTSHIFT,KC_9)", "WRAP(KC_LEFTCTRL,WRAP(KC_LEFTSHIFT,KC_Z))", "MT(KC_LEFTCTRL,KC_E)"],
+            vec!["TG(nav)", "___", "___"],
+        ]);
+        assert_eq!(layout.warnings, vec![
+            "layer base, key 6 (MEH_T(KC_D)): MT can only hold one modifier, so only KC_LEFTCTRL is held".to_string(),
+            "layer nav, key 3 (TO(_SYM)): TO is imported as AL, which doesn't disable other layers".to_string(),
+            "layer nav, key 6 (MY_MACRO): MY_MACRO isn't supported, imported as XXX".to_string(),
+            "layer sym, key 3 (MT(MOD_LCTL | MOD_LSFT, KC_E)): MT can only hold one modifier, so only KC_LEFTCTRL is held".to_string(),
+        ]);
+        assert_keys_load(&layout);
+
+        assert!(import_qmk_c("int main() {}").is_err());
+    }
+
+    #[test]
+    fn export_qmk_json_keymap() {
+        let registry = KeyRegistry::new();
+        let mut driver = KeyboardDriver::new(TestInputKeyboard::new(), TestOutputKeyboard::new(),
+            VirtualKeyboardMatrix::new(vec![
+                vec![Some(SimpleKey::KEY_1), None, Some(SimpleKey::KEY_2)],
+                vec![Some(SimpleKey::KEY_3), Some(SimpleKey::KEY_4), Some(SimpleKey::KEY_5)],
+            ], None));
+        let layer = |keys: [&str; 6]| {
+            let mut ans = KeyCodeMatrix::new((2, 3));
+            for (idx, key) in keys.iter().enumerate() {
+                ans.codes[idx / 3][idx % 3] = registry.parse(key).unwrap();
+            }
+            ans
+        };
+        driver.add_layer(LayerAttributes { name: "base".to_string(), enabled: true, timeout: None }, layer([
+            "KC_ESC", "XXX", "LT(nav,KC_SPACE,150)",
+            "MT(KC_LEFTCTRL,KC_A)", "WRAP(KC_LEFTSHIFT,KC_9)", "SPACECADET(WRAP(KC_LEFTSHIFT,KC_9),KC_LEFTSHIFT)",
+        ]));
+        driver.add_layer(LayerAttributes { name: "nav".to_string(), enabled: false, timeout: None }, layer([
+            "___", "___", "AL(base)",
+            "WRAP(KC_LEFTCTRL,KC_BACKSPACE)", "MACRO(KC_A,KC_B)", "MT(KC_A,KC_B)",
+        ]));
+
+        let mut warnings = Vec::new();
+        let keymap = export_qmk_json(&driver, "crkbd/rev1", "LAYOUT", &mut warnings);
+        let expected = json::parse(r#"{
+            "version": 1, "keyboard": "crkbd/rev1", "keymap": "spacecadet", "layout": "LAYOUT",
+            "layers": [
+                [ "KC_ESCAPE", "LT(1,KC_SPC)", "LCTL_T(KC_A)", "KC_LPRN", "SC_LSPO" ],
+                [ "KC_TRNS", "TO(0)", "LCTL(KC_BSPC)", "KC_NO", "KC_NO" ]
+            ]
+        }"#).unwrap();
+        assert_eq!(keymap, expected);
+        assert_eq!(warnings, vec![
+            "layer nav, key 0x2 (AL(base)): AL is exported as TO, which disables other layers".to_string(),
+            "layer nav, key 1x1 (MACRO(KC_A,KC_B)): QMK doesn't have MACRO, exported as KC_NO".to_string(),
+            "layer nav, key 1x2 (MT(KC_A,KC_B,200,false)): KC_A isn't a modifier, exported as KC_NO".to_string(),
+        ]);
+
+        // Importing the keymap gives the same keys (other than timing).
+        let imported = import_qmk_json(&keymap.dump()).unwrap();
+        assert_eq!(imported.layers[0].keys[0], vec![
+            "KC_ESC", "LT(layer1,KC_SPACE)", "MT(KC_LEFTCTRL,KC_A)", "WRAP(KC_LEFTSHIFT,KC_9)", "XXX"
+        ]);
+    }
+}
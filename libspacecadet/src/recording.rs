@@ -0,0 +1,122 @@
+use evdev_rs as evdev;
+use std::time::Duration;
+use crate::keys::NormalKey;
+use crate::parser::ParsedKeyTree;
+use crate::simulate::{ScriptAction, ScriptEvent, SimulatedEvent, SimulatedEventKind, key_state_name};
+
+/// The first line of a recording.
+pub const RECORDING_HEADER: &str = "# spacecadet recording: kernel time, key, value (0 release, 1 press, 2 repeat)";
+
+/// Write an input event as a line of a recording (e.g. `1697040000.123456 KC_A 1`).
+/// Only key events are recorded - anything else is `None`.
+pub fn format_recorded_event(e: &evdev::InputEvent) -> Option<String> {
+    match &e.event_code {
+        evdev::enums::EventCode::EV_KEY(key) => Some(format!("{}.{:06} {} {}",
+            e.time.tv_sec, e.time.tv_usec, NormalKey { value: key.clone() }.name(), e.value)),
+        _ => None
+    }
+}
+
+/// Read the events in a recording.
+pub fn parse_recording(contents: &str) -> Result<Vec<evdev::InputEvent>, String> {
+    let mut ans = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("line {}: {}", idx + 1, message);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() != 3 {
+            return Err(error("expected a time, a key and a value (e.g. \"1697040000.123456 KC_A 1\")."));
+        }
+        let mut time = words[0].splitn(2, '.');
+        let tv_sec = time.next().and_then(|x| x.parse::<i64>().ok());
+        let tv_usec = time.next().and_then(|x| x.parse::<i64>().ok());
+        let time = match (tv_sec, tv_usec) {
+            (Some(tv_sec), Some(tv_usec)) if tv_usec < 1_000_000 => evdev::TimeVal::new(tv_sec, tv_usec),
+            _ => return Err(error(&format!("\"{}\" isn't a time (seconds.microseconds).", words[0])))
+        };
+        let key = ParsedKeyTree::create(words[1]).map_err(|e| e.message())
+            .and_then(|x| NormalKey::from_tokens(&x))
+            .map_err(|e| error(&e))?;
+        let value = match words[2].parse::<i32>() {
+            Ok(v) if (0..=2).contains(&v) => v,
+            _ => return Err(error(&format!("\"{}\" isn't a key value (0, 1 or 2).", words[2])))
+        };
+        ans.push(evdev::InputEvent::new(&time, &evdev::enums::EventCode::EV_KEY(key.value), value));
+    }
+    Ok(ans)
+}
+
+/// Turn a recording into a simulation script, timed from the first event.
+pub fn recording_to_script(events: &[evdev::InputEvent]) -> Vec<ScriptEvent> {
+    let micros = |e: &evdev::InputEvent| e.time.tv_sec * 1_000_000 + e.time.tv_usec;
+    let start = events.first().map(micros).unwrap_or(0);
+    events.iter().map(|e| ScriptEvent {
+        // Kernel timestamps should never go backwards, but a hand-edited log could.
+        time: Duration::from_micros(std::cmp::max(micros(e) - start, 0) as u64),
+        action: ScriptAction::Event(e.clone()),
+    }).collect()
+}
+
+/// Find the first difference between the key events two simulations sent (e.g. the same
+/// recording replayed through two layouts). Returns `None` if they sent the same events.
+pub fn compare_outputs(a: &[SimulatedEvent], b: &[SimulatedEvent]) -> Option<String> {
+    let outputs = |events: &[SimulatedEvent]| -> Vec<String> {
+        events.iter().filter_map(|x| match &x.kind {
+            SimulatedEventKind::Output(key, state) =>
+                Some(format!("{} {} at {}ms", key_state_name(*state), NormalKey { value: key.clone() }.name(), x.time.as_millis())),
+            _ => None
+        }).collect()
+    };
+    let (a, b) = (outputs(a), outputs(b));
+    let describe = |v: Option<&String>| v.cloned().unwrap_or_else(|| "nothing".to_string());
+    (0..std::cmp::max(a.len(), b.len()))
+        .find(|x| a.get(*x) != b.get(*x))
+        .map(|x| format!("output #{}: {} vs {}", x + 1, describe(a.get(x)), describe(b.get(x))))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::SimpleKey;
+    use crate::virtual_keyboard_matrix::KeyStateChange;
+
+    #[test]
+    fn read_and_write_recordings() {
+        let event = |sec, usec, key: SimpleKey, value| {
+            evdev::InputEvent::new(&evdev::TimeVal::new(sec, usec), &evdev::enums::EventCode::EV_KEY(key), value)
+        };
+        let events = vec![
+            event(1_697_040_000, 999_500, SimpleKey::KEY_A, 1),
+            event(1_697_040_001, 49_500, SimpleKey::KEY_A, 0),
+        ];
+        let lines: Vec<String> = events.iter().filter_map(format_recorded_event).collect();
+        assert_eq!(lines, vec!["1697040000.999500 KC_A 1", "1697040001.049500 KC_A 0"]);
+
+        let contents = format!("{}\n{}\n", RECORDING_HEADER, lines.join("\n"));
+        assert_eq!(parse_recording(&contents).unwrap(), events);
+        let script = recording_to_script(&events);
+        assert_eq!(script[0].time, Duration::from_millis(0));
+        assert_eq!(script[1].time, Duration::from_millis(50));
+
+        assert_eq!(parse_recording("1.5 KC_A").unwrap_err(), "line 1: expected a time, a key and a value (e.g. \"1697040000.123456 KC_A 1\").");
+        assert_eq!(parse_recording("\n1 KC_A 1").unwrap_err(), "line 2: \"1\" isn't a time (seconds.microseconds).");
+        assert_eq!(parse_recording("1.0 KC_A 7").unwrap_err(), "line 1: \"7\" isn't a key value (0, 1 or 2).");
+    }
+
+    #[test]
+    fn compare_simulated_outputs() {
+        let output = |ms, key, state| SimulatedEvent { time: Duration::from_millis(ms), kind: SimulatedEventKind::Output(key, state) };
+        let a = vec![output(0, SimpleKey::KEY_A, KeyStateChange::Pressed), output(50, SimpleKey::KEY_A, KeyStateChange::Released)];
+        let mut b = a.clone();
+        b.push(SimulatedEvent { time: Duration::from_millis(60), kind: SimulatedEventKind::Layer("nav".to_string(), true) });
+        assert_eq!(compare_outputs(&a, &b), None);
+
+        b[1] = output(200, SimpleKey::KEY_A, KeyStateChange::Released);
+        assert_eq!(compare_outputs(&a, &b).unwrap(), "output #2: release KC_A at 50ms vs release KC_A at 200ms");
+        assert_eq!(compare_outputs(&a, &a[..1]).unwrap(), "output #2: release KC_A at 50ms vs nothing");
+    }
+}
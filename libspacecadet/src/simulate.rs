@@ -0,0 +1,256 @@
+use evdev_rs as evdev;
+use std::fmt;
+use std::time::{Duration, Instant};
+use crate::keyboard_driver::KeyboardDriver;
+use crate::keys::{KeyState, NormalKey, SimpleKey};
+use crate::parser::ParsedKeyTree;
+use crate::test_io_keyboard::{TestInputKeyboard, TestOutputKeyboard};
+use crate::virtual_keyboard_matrix::{KeyStateChange, VirtualKeyboardMatrix};
+
+/// A driver that reads and writes test keyboards, so it can run without any devices.
+pub type SimulatedDriver = KeyboardDriver<TestInputKeyboard, TestOutputKeyboard>;
+
+/// What happens at a point in a simulation script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptAction {
+    Press(SimpleKey),
+    Release(SimpleKey),
+    /// Nothing happens, but the simulation keeps running until then (e.g. to let a key be held).
+    Wait,
+    /// A raw event from the input device (e.g. from a recording).
+    Event(evdev::InputEvent),
+}
+
+/// A line in a simulation script: an action, and when it happens (since the start of the script).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptEvent {
+    pub time: Duration,
+    pub action: ScriptAction,
+}
+
+/// Read a simulation script. Every line is a time, an action and (except for `wait`) a key:
+///
+/// ```text
+/// # Hold space for a moment, then tap H.
+/// 0     press   KC_SPACE
+/// 200ms press   KC_H
+/// 250ms release KC_H
+/// 300ms release KC_SPACE
+/// 1s    wait
+/// ```
+///
+/// Times are milliseconds (or durations like `1s`) since the start of the script. Keys are
+/// physical keys (e.g. `KC_H`), or `row,col` positions in the matrix.
+pub fn parse_script(contents: &str, matrix: &VirtualKeyboardMatrix) -> Result<Vec<ScriptEvent>, String> {
+    let mut ans: Vec<ScriptEvent> = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {}", idx + 1, message);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let time = ParsedKeyTree::create(words[0]).ok().and_then(|x| x.as_duration_ms())
+            .ok_or_else(|| error(format!("\"{}\" isn't a time (e.g. 150 or 150ms).", words[0])))?;
+        if let Some(last) = ans.last() {
+            if time < last.time {
+                return Err(error("events must be in order.".to_string()));
+            }
+        }
+        let action = match (words.get(1).cloned(), words.len()) {
+            (Some("wait"), 2) => ScriptAction::Wait,
+            (Some("press"), 3) => ScriptAction::Press(parse_script_key(words[2], matrix).map_err(error)?),
+            (Some("release"), 3) => ScriptAction::Release(parse_script_key(words[2], matrix).map_err(error)?),
+            _ => return Err(error("expected a time, then \"press KEY\", \"release KEY\" or \"wait\".".to_string()))
+        };
+        ans.push(ScriptEvent { time, action });
+    }
+    Ok(ans)
+}
+
+/// Read a physical key (e.g. `KC_H`), or the key at a `row,col` position in the matrix.
+fn parse_script_key(v: &str, matrix: &VirtualKeyboardMatrix) -> Result<SimpleKey, String> {
+    if let Ok(key) = ParsedKeyTree::create(v).map_err(|e| e.message()).and_then(|x| NormalKey::from_tokens(&x)) {
+        return Ok(key.value);
+    }
+    let idx = matrix.parse_position(v)?;
+    matrix.key_at(idx).ok_or_else(|| format!("the matrix doesn't have a key at {},{}", idx.0, idx.1))
+}
+
+/// Something that happened during a simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimulatedEventKind {
+    /// A key event from the script.
+    Input(SimpleKey, KeyStateChange),
+    /// A key event the driver sent to the output keyboard.
+    Output(SimpleKey, KeyStateChange),
+    /// A layer was turned on (`true`) or off (`false`).
+    Layer(String, bool),
+}
+
+/// Something that happened during a simulation, and when (since the start of the script).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedEvent {
+    pub time: Duration,
+    pub kind: SimulatedEventKind,
+}
+
+impl fmt::Display for SimulatedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = |v: &KeyStateChange| key_state_name(*v);
+        let name = |v: &SimpleKey| NormalKey { value: v.clone() }.name();
+        let time = format!("{}ms", self.time.as_millis());
+        match &self.kind {
+            SimulatedEventKind::Input(key, v) => write!(f, "{:>8}  in     {:<7}  {}", time, state(v), name(key)),
+            SimulatedEventKind::Output(key, v) => write!(f, "{:>8}  out    {:<7}  {}", time, state(v), name(key)),
+            SimulatedEventKind::Layer(layer, on) => write!(f, "{:>8}  layer  {:<7}  {}", time, if *on { "on" } else { "off" }, layer),
+        }
+    }
+}
+
+/// The word for a key state change in scripts and simulations (e.g. `press`).
+pub fn key_state_name(v: KeyStateChange) -> &'static str {
+    match v {
+        KeyStateChange::Pressed => "press",
+        KeyStateChange::Released => "release",
+        KeyStateChange::Held => "hold",
+    }
+}
+
+/// Run a script through a driver on a virtual clock, and record the key events it sends and
+/// the layers it turns on and off.
+///
+/// The clock ticks every `tick` (like the driver's poll rate, but at least a millisecond apart),
+/// and at every event in the script. Events at the same time are read in the same tick. The
+/// simulation stops at the last event.
+pub fn simulate(driver: &mut SimulatedDriver, script: &[ScriptEvent], tick: Duration) -> Vec<SimulatedEvent> {
+    let mut ans = Vec::new();
+    simulate_with(driver, script, tick, |_, events| ans.extend_from_slice(events));
+    ans
+}
+
+/// Run a script like `simulate`, but pass the time and what happened to a callback after every
+/// tick, instead of collecting it (e.g. to print events as they happen, or to pace a replay).
+pub fn simulate_with<F>(driver: &mut SimulatedDriver, script: &[ScriptEvent], tick: Duration, mut on_tick: F)
+    where F: FnMut(Duration, &[SimulatedEvent]) {
+    // A zero tick would never move the clock.
+    let tick = std::cmp::max(tick, Duration::from_millis(1));
+    let start = Instant::now();
+    let end = script.last().map(|x| x.time).unwrap_or_default();
+    let mut enabled: Vec<bool> = (0..driver.layer_attributes.len()).map(|x| driver.layer_attributes.is_enabled(x)).collect();
+    let mut next = 0;
+    let mut time = Duration::from_millis(0);
+    loop {
+        let mut ans = Vec::new();
+
+        // Read every event that's due.
+        while next < script.len() && script[next].time <= time {
+            let event: Option<evdev::InputEvent> = match &script[next].action {
+                ScriptAction::Press(key) => Some(KeyState(key.clone(), KeyStateChange::Pressed).into()),
+                ScriptAction::Release(key) => Some(KeyState(key.clone(), KeyStateChange::Released).into()),
+                ScriptAction::Wait => None,
+                ScriptAction::Event(e) => Some(e.clone())
+            };
+            if let Some(e) = event {
+                if let evdev::enums::EventCode::EV_KEY(key) = &e.event_code {
+                    ans.push(SimulatedEvent { time, kind: SimulatedEventKind::Input(key.clone(), e.value.into()) });
+                }
+                driver.input.events.push(e);
+            }
+            next += 1;
+        }
+
+        driver.clock_tick(start + time);
+
+        // Record what the tick sent, and any layers that changed.
+        for e in driver.output.events.drain(..) {
+            if let evdev::enums::EventCode::EV_KEY(key) = e.event_code {
+                ans.push(SimulatedEvent { time, kind: SimulatedEventKind::Output(key, e.value.into()) });
+            }
+        }
+        for (idx, was_enabled) in enabled.iter_mut().enumerate() {
+            let is_enabled = driver.layer_attributes.is_enabled(idx);
+            if is_enabled != *was_enabled {
+                let name = driver.layer_attributes.attributes[idx].name.clone();
+                ans.push(SimulatedEvent { time, kind: SimulatedEventKind::Layer(name, is_enabled) });
+                *was_enabled = is_enabled;
+            }
+        }
+
+        on_tick(time, &ans);
+        if time >= end {
+            return;
+        }
+        // Tick again at the next poll, or the next event (whichever is first).
+        time = std::cmp::min(time + tick, script[next].time);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_registry::KeyRegistry;
+    use crate::layer::{KeyCodeMatrix, LayerAttributes};
+
+    #[test]
+    fn simulate_script() {
+        let new_driver = || {
+            let registry = KeyRegistry::new();
+            let mut driver = KeyboardDriver::new(TestInputKeyboard::new(), TestOutputKeyboard::new(),
+                VirtualKeyboardMatrix::new(vec![vec![Some(SimpleKey::KEY_H), Some(SimpleKey::KEY_SPACE)]], None));
+            for (name, enabled, keys) in [("base", true, ["KC_H", "LT(cursor,KC_SPACE,150)"]), ("cursor", false, ["KC_LEFT", "___"])].iter() {
+                let mut codes = KeyCodeMatrix::new((1, 2));
+                for (idx, key) in keys.iter().enumerate() {
+                    codes.codes[0][idx] = registry.parse(key).unwrap();
+                }
+                driver.add_layer(LayerAttributes { name: name.to_string(), enabled: *enabled, timeout: None }, codes);
+            }
+            driver
+        };
+        let mut driver = new_driver();
+
+        // Tap space, then hold it and tap H.
+        let script = parse_script("
+            # A tap.
+            0      press    KC_SPACE
+            50ms   release  0,1
+            # A hold.
+            1s     press    KC_SPACE
+            1400   press    KC_H
+            1450   release  KC_H
+            1500   release  KC_SPACE
+            2s     wait
+        ", &driver.matrix).unwrap();
+        assert_eq!(script[1], ScriptEvent { time: Duration::from_millis(50), action: ScriptAction::Release(SimpleKey::KEY_SPACE) });
+
+        let events: Vec<String> = simulate(&mut driver, &script, Duration::from_millis(5)).iter().map(|x| x.to_string()).collect();
+
+        // A zero tick still moves the clock (a millisecond at a time).
+        let fast: Vec<String> = simulate(&mut new_driver(), &script, Duration::from_millis(0)).iter().map(|x| x.to_string()).collect();
+        assert_eq!(fast, events);
+        assert_eq!(events, vec![
+            "     0ms  in     press    KC_SPACE",
+            "    50ms  in     release  KC_SPACE",
+            "    50ms  out    press    KC_SPACE",
+            "    50ms  out    release  KC_SPACE",
+            "  1000ms  in     press    KC_SPACE",
+            "  1200ms  layer  on       cursor",
+            "  1400ms  in     press    KC_H",
+            "  1400ms  out    press    KC_LEFT",
+            "  1450ms  in     release  KC_H",
+            "  1450ms  out    release  KC_LEFT",
+            "  1500ms  in     release  KC_SPACE",
+        ]);
+    }
+
+    #[test]
+    fn simulation_script_errors() {
+        let matrix = VirtualKeyboardMatrix::new(vec![vec![Some(SimpleKey::KEY_H), None]], None);
+        let error = |script: &str| parse_script(script, &matrix).unwrap_err();
+        assert_eq!(error("soon press KC_H"), "line 1: \"soon\" isn't a time (e.g. 150 or 150ms).");
+        assert_eq!(error("10 press KC_H\n5 release KC_H"), "line 2: events must be in order.");
+        assert_eq!(error("0 push KC_H"), "line 1: expected a time, then \"press KEY\", \"release KEY\" or \"wait\".");
+        assert_eq!(error("0 press 0,1"), "line 1: the matrix doesn't have a key at 0,1");
+    }
+}
@@ -0,0 +1,874 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use crate::error::SpaceCadetError;
+use crate::keys::{KeyState, SimpleKey};
+use crate::output_keyboard::OutputKeyboard;
+use crate::virtual_keyboard_matrix::KeyStateChange;
+
+
+/// The keys on a stenotype machine, listed in steno order.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum StenoKey {
+    Number,
+    LeftS,
+    LeftT,
+    LeftK,
+    LeftP,
+    LeftW,
+    LeftH,
+    LeftR,
+    A,
+    O,
+    Star,
+    E,
+    U,
+    RightF,
+    RightR,
+    RightP,
+    RightB,
+    RightL,
+    RightG,
+    RightT,
+    RightS,
+    RightD,
+    RightZ,
+}
+
+/// Every steno key in steno order.
+const STENO_ORDER: [StenoKey; 23] = [
+    StenoKey::Number,
+    StenoKey::LeftS, StenoKey::LeftT, StenoKey::LeftK, StenoKey::LeftP,
+    StenoKey::LeftW, StenoKey::LeftH, StenoKey::LeftR,
+    StenoKey::A, StenoKey::O, StenoKey::Star, StenoKey::E, StenoKey::U,
+    StenoKey::RightF, StenoKey::RightR, StenoKey::RightP, StenoKey::RightB,
+    StenoKey::RightL, StenoKey::RightG, StenoKey::RightT, StenoKey::RightS,
+    StenoKey::RightD, StenoKey::RightZ,
+];
+
+impl StenoKey {
+    /// The Plover-style name of the key (e.g. `S-` or `-Z`).
+    pub fn name(self) -> &'static str {
+        match self {
+            StenoKey::Number => "#",
+            StenoKey::LeftS => "S-",
+            StenoKey::LeftT => "T-",
+            StenoKey::LeftK => "K-",
+            StenoKey::LeftP => "P-",
+            StenoKey::LeftW => "W-",
+            StenoKey::LeftH => "H-",
+            StenoKey::LeftR => "R-",
+            StenoKey::A => "A-",
+            StenoKey::O => "O-",
+            StenoKey::Star => "*",
+            StenoKey::E => "-E",
+            StenoKey::U => "-U",
+            StenoKey::RightF => "-F",
+            StenoKey::RightR => "-R",
+            StenoKey::RightP => "-P",
+            StenoKey::RightB => "-B",
+            StenoKey::RightL => "-L",
+            StenoKey::RightG => "-G",
+            StenoKey::RightT => "-T",
+            StenoKey::RightS => "-S",
+            StenoKey::RightD => "-D",
+            StenoKey::RightZ => "-Z",
+        }
+    }
+
+    /// Find a key by its Plover-style name. Vowels can omit the hyphen.
+    pub fn from_name(name: &str) -> Option<StenoKey> {
+        match name {
+            "A" => Some(StenoKey::A),
+            "O" => Some(StenoKey::O),
+            "E" => Some(StenoKey::E),
+            "U" => Some(StenoKey::U),
+            _ => STENO_ORDER.iter().find(|x| x.name() == name).cloned()
+        }
+    }
+
+    /// The letter used when writing a stroke (e.g. `S` for both `S-` and `-S`).
+    fn letter(self) -> char {
+        match self {
+            StenoKey::Number => '#',
+            StenoKey::Star => '*',
+            _ => self.name().chars().find(|x| *x != '-').unwrap()
+        }
+    }
+
+    /// The digit written when the key is combined with the number bar.
+    fn digit(self) -> Option<char> {
+        match self {
+            StenoKey::LeftS => Some('1'),
+            StenoKey::LeftT => Some('2'),
+            StenoKey::LeftP => Some('3'),
+            StenoKey::LeftH => Some('4'),
+            StenoKey::A => Some('5'),
+            StenoKey::O => Some('0'),
+            StenoKey::RightF => Some('6'),
+            StenoKey::RightP => Some('7'),
+            StenoKey::RightL => Some('8'),
+            StenoKey::RightT => Some('9'),
+            _ => None
+        }
+    }
+
+    fn is_vowel_or_star(self) -> bool {
+        matches!(self, StenoKey::A | StenoKey::O | StenoKey::Star | StenoKey::E | StenoKey::U)
+    }
+
+    fn is_right_bank(self) -> bool {
+        (self as usize) > (StenoKey::U as usize)
+    }
+}
+
+
+/// A chord of steno keys that were pressed together.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Stroke {
+    keys: u32
+}
+
+impl Stroke {
+    /// Create an empty stroke.
+    pub fn new() -> Stroke {
+        Stroke { keys: 0 }
+    }
+
+    /// Create a stroke from a collection of keys.
+    pub fn from_keys(keys: &[StenoKey]) -> Stroke {
+        let mut ans = Stroke::new();
+        for i in keys {
+            ans.add(*i);
+        }
+        ans
+    }
+
+    /// Add a key to the stroke.
+    pub fn add(&mut self, key: StenoKey) {
+        self.keys |= 1 << (key as u32);
+    }
+
+    /// Remove a key from the stroke.
+    pub fn remove(&mut self, key: StenoKey) {
+        self.keys &= !(1 << (key as u32));
+    }
+
+    /// Check if a key is part of the stroke.
+    pub fn contains(&self, key: StenoKey) -> bool {
+        self.keys & (1 << (key as u32)) != 0
+    }
+
+    /// Check if the stroke doesn't contain any keys.
+    pub fn is_empty(&self) -> bool {
+        self.keys == 0
+    }
+
+    /// Get the keys in the stroke (in steno order).
+    pub fn keys(&self) -> Vec<StenoKey> {
+        STENO_ORDER.iter().filter(|x| self.contains(**x)).cloned().collect()
+    }
+
+    /// Parse a stroke written in Plover's notation (e.g. `STKPW-FPL`, `KAT`, or `1-9`).
+    pub fn parse(v: &str) -> Result<Stroke, String> {
+        let mut ans = Stroke::new();
+        let mut idx = 1;
+        for c in v.chars() {
+            match c {
+                '#' => { ans.add(StenoKey::Number); continue; }
+                '-' => { idx = std::cmp::max(idx, StenoKey::E as usize); continue; }
+                _ => ()
+            }
+
+            // Keys must appear in steno order, so only search past the last key.
+            let found = STENO_ORDER[idx..].iter().position(|x| x.letter() == c || x.digit() == Some(c));
+            match found {
+                Some(offset) => {
+                    let key = STENO_ORDER[idx + offset];
+                    if key.digit() == Some(c) {
+                        ans.add(StenoKey::Number);
+                    }
+                    ans.add(key);
+                    idx += offset + 1;
+                }
+                None => return Err(format!("Invalid steno stroke \"{}\".", v))
+            }
+        }
+
+        if ans.is_empty() {
+            Err(format!("Invalid steno stroke \"{}\".", v))
+        } else {
+            Ok(ans)
+        }
+    }
+
+    /// Write the stroke in Plover's notation. A hyphen separates the banks
+    /// when the right bank is used without any vowels.
+    pub fn to_steno_string(&self) -> String {
+        let keys = self.keys();
+        let use_digits = self.contains(StenoKey::Number) && keys.iter().any(|x| x.digit().is_some());
+        let has_middle = keys.iter().any(|x| x.is_vowel_or_star());
+
+        let mut ans = String::new();
+        let mut wrote_hyphen = false;
+        for key in keys {
+            if key == StenoKey::Number {
+                if !use_digits { ans.push('#'); }
+                continue;
+            }
+            if key.is_right_bank() && !has_middle && !wrote_hyphen {
+                ans.push('-');
+                wrote_hyphen = true;
+            }
+            match key.digit() {
+                Some(d) if use_digits => ans.push(d),
+                _ => ans.push(key.letter())
+            }
+        }
+        ans
+    }
+}
+
+impl Default for Stroke {
+    fn default() -> Stroke {
+        Stroke::new()
+    }
+}
+
+
+/// Wire protocols that send raw strokes to steno software (e.g. Plover).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StenoProtocol {
+    /// The six byte Gemini PR protocol.
+    GeminiPR,
+    /// The variable length TX Bolt protocol.
+    TxBolt,
+}
+
+impl StenoProtocol {
+    /// Encode a stroke as a packet.
+    pub fn encode(self, stroke: Stroke) -> Vec<u8> {
+        match self {
+            StenoProtocol::GeminiPR => {
+                // Each key has a fixed position in the 42 bit chart, which is packed
+                // seven bits per byte. The high bit of the first byte marks the packet start.
+                let mut ans = vec![0u8; 6];
+                ans[0] = 0x80;
+                for key in stroke.keys() {
+                    let idx = match key {
+                        StenoKey::Number => 1,
+                        StenoKey::LeftS => 7,
+                        StenoKey::LeftT => 9,
+                        StenoKey::LeftK => 10,
+                        StenoKey::LeftP => 11,
+                        StenoKey::LeftW => 12,
+                        StenoKey::LeftH => 13,
+                        StenoKey::LeftR => 14,
+                        StenoKey::A => 15,
+                        StenoKey::O => 16,
+                        StenoKey::Star => 17,
+                        StenoKey::E => 24,
+                        StenoKey::U => 25,
+                        StenoKey::RightF => 26,
+                        StenoKey::RightR => 27,
+                        StenoKey::RightP => 28,
+                        StenoKey::RightB => 29,
+                        StenoKey::RightL => 30,
+                        StenoKey::RightG => 31,
+                        StenoKey::RightT => 32,
+                        StenoKey::RightS => 33,
+                        StenoKey::RightD => 34,
+                        StenoKey::RightZ => 41,
+                    };
+                    ans[idx / 7] |= 0x40 >> (idx % 7);
+                }
+                ans
+            }
+            StenoProtocol::TxBolt => {
+                // Keys are split into four sets of six. Each set is sent as one byte
+                // (set number in the top two bits), and a zero byte ends the stroke.
+                let sets: [&[StenoKey]; 4] = [
+                    &[StenoKey::LeftS, StenoKey::LeftT, StenoKey::LeftK, StenoKey::LeftP, StenoKey::LeftW, StenoKey::LeftH],
+                    &[StenoKey::LeftR, StenoKey::A, StenoKey::O, StenoKey::Star, StenoKey::E, StenoKey::U],
+                    &[StenoKey::RightF, StenoKey::RightR, StenoKey::RightP, StenoKey::RightB, StenoKey::RightL, StenoKey::RightG],
+                    &[StenoKey::RightT, StenoKey::RightS, StenoKey::RightD, StenoKey::RightZ, StenoKey::Number],
+                ];
+                let mut ans = Vec::new();
+                for set in sets.iter().enumerate() {
+                    let mut byte = 0u8;
+                    for key in set.1.iter().enumerate() {
+                        if stroke.contains(*key.1) {
+                            byte |= 1 << key.0;
+                        }
+                    }
+                    if byte != 0 {
+                        ans.push(((set.0 as u8) << 6) | byte);
+                    }
+                }
+                ans.push(0);
+                ans
+            }
+        }
+    }
+}
+
+impl FromStr for StenoProtocol {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gemini" | "geminipr" | "gemini_pr" => Ok(StenoProtocol::GeminiPR),
+            "txbolt" | "tx_bolt" => Ok(StenoProtocol::TxBolt),
+            _ => Err(format!("Unknown steno protocol \"{}\" (expected \"gemini\" or \"txbolt\").", s))
+        }
+    }
+}
+
+
+/// A Plover-style dictionary that maps outlines (e.g. `KAT` or `A/PHOUPBT`) to text.
+pub struct StenoDictionary {
+    entries: HashMap<String, String>,
+    longest_outline: usize,
+}
+
+impl StenoDictionary {
+    /// Create an empty dictionary.
+    pub fn new() -> StenoDictionary {
+        StenoDictionary {
+            entries: HashMap::new(),
+            longest_outline: 0
+        }
+    }
+
+    /// Add a translation for an outline. Strokes in the outline are separated by `/`.
+    pub fn add(&mut self, outline: &str, text: &str) -> Result<(), String> {
+        let strokes = outline.split('/')
+            .map(Stroke::parse)
+            .collect::<Result<Vec<Stroke>, String>>()?;
+        self.longest_outline = std::cmp::max(self.longest_outline, strokes.len());
+        self.entries.insert(StenoDictionary::outline_key(&strokes), text.to_string());
+        Ok(())
+    }
+
+    /// Load a Plover JSON dictionary from a file.
+    pub fn load(path: &str) -> Result<StenoDictionary, String> {
+        let mut file = File::open(path).map_err(|e| format!("{} on {}", e, path))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).map_err(|e| format!("{} on {}", e, path))?;
+        let document = json::parse(&contents).map_err(|e| format!("{} in {}", e, path))?;
+
+        let mut ans = StenoDictionary::new();
+        for (outline, text) in document.entries() {
+            match text.as_str() {
+                Some(t) => ans.add(outline, t)?,
+                None => return Err(format!("The translation for \"{}\" isn't a string.", outline))
+            }
+        }
+        Ok(ans)
+    }
+
+    /// Look up the translation for a series of strokes.
+    pub fn lookup(&self, strokes: &[Stroke]) -> Option<&str> {
+        self.entries.get(&StenoDictionary::outline_key(strokes)).map(|x| x.as_str())
+    }
+
+    fn outline_key(strokes: &[Stroke]) -> String {
+        strokes.iter().map(|x| x.to_steno_string()).collect::<Vec<String>>().join("/")
+    }
+}
+
+impl Default for StenoDictionary {
+    fn default() -> StenoDictionary {
+        StenoDictionary::new()
+    }
+}
+
+
+/// Where completed strokes are sent.
+pub enum StenoOutput {
+    /// Translate strokes through a dictionary and type the text on the output keyboard.
+    Dictionary(StenoDictionary),
+    /// Send raw strokes to another program (e.g. a pipe that Plover reads).
+    Protocol(StenoProtocol, Box<Write>),
+}
+
+/// A file, pipe or pseudo-terminal that raw strokes are written to. It must already exist
+/// (so a missing pipe isn't replaced by a regular file), and it's opened without blocking
+/// when a stroke is sent, so a pipe that nothing reads yet doesn't stall the driver.
+/// If opening or writing fails, the file is opened again for the next stroke.
+pub struct StenoOutputFile {
+    path: String,
+    file: Option<File>,
+}
+
+impl StenoOutputFile {
+    pub fn new(path: &str) -> Result<StenoOutputFile, String> {
+        if !Path::new(path).exists() {
+            return Err(format!("The steno output {} doesn't exist.", path));
+        }
+        Ok(StenoOutputFile { path: path.to_string(), file: None })
+    }
+
+    fn open(&mut self) -> std::io::Result<&mut File> {
+        if self.file.is_none() {
+            // Opening a pipe without a reader fails (rather than waiting for one).
+            let file = OpenOptions::new().append(true).custom_flags(libc::O_NONBLOCK).open(&self.path)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{} on {}", e, self.path)))?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+impl Write for StenoOutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let ans = self.open()?.write(buf);
+        if ans.is_err() {
+            self.file = None;
+        }
+        ans
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.file {
+            Some(ref mut file) => file.flush(),
+            None => Ok(())
+        }
+    }
+}
+
+/// Text that was typed for one or more strokes. Kept so that later
+/// strokes can replace or undo it.
+struct Translation {
+    strokes: Vec<Stroke>,
+    typed: String,
+    attach_next: bool,
+}
+
+/// How many translations can be undone with the asterisk. More are kept if the dictionary
+/// has longer outlines, so that they can still replace earlier translations.
+const UNDO_DEPTH: usize = 100;
+
+/// Collects steno keys into strokes. A stroke is complete once every key
+/// in the chord has been released.
+pub struct StenoEngine {
+    pressed: Stroke,
+    chord: Stroke,
+    output: StenoOutput,
+    history: Vec<Translation>,
+    config: json::JsonValue,
+}
+
+impl StenoEngine {
+    /// Create an engine that types untranslated strokes.
+    pub fn new() -> StenoEngine {
+        StenoEngine::with_output(StenoOutput::Dictionary(StenoDictionary::new()))
+    }
+
+    /// Create an engine that sends strokes to the given output.
+    pub fn with_output(output: StenoOutput) -> StenoEngine {
+        StenoEngine {
+            pressed: Stroke::new(),
+            chord: Stroke::new(),
+            output,
+            history: Vec::new(),
+            config: json::JsonValue::Null
+        }
+    }
+
+    /// Create an engine from the `"steno"` section of a layers document.
+    /// Paths are relative to the directory that holds the layers document.
+    pub fn from_json(config: &json::JsonValue, base_dir: &Path) -> Result<StenoEngine, String> {
+        let mut ans = StenoEngine::from_json_without_config(config, base_dir)?;
+        ans.config = config.clone();
+        Ok(ans)
+    }
+
+    /// The `"steno"` section the engine was created from (null if it wasn't created from one).
+    pub fn config(&self) -> &json::JsonValue {
+        &self.config
+    }
+
+    fn from_json_without_config(config: &json::JsonValue, base_dir: &Path) -> Result<StenoEngine, String> {
+        let resolve = |v: &str| base_dir.join(v).to_string_lossy().into_owned();
+        if let Some(protocol) = config["protocol"].as_str() {
+            let protocol: StenoProtocol = protocol.parse()?;
+            let path = config["output"].as_str().ok_or("A steno protocol requires an \"output\" path.")?;
+            let file = StenoOutputFile::new(&resolve(path))?;
+            Ok(StenoEngine::with_output(StenoOutput::Protocol(protocol, Box::new(file))))
+        } else if let Some(path) = config["dictionary"].as_str() {
+            let dictionary = StenoDictionary::load(&resolve(path))?;
+            Ok(StenoEngine::with_output(StenoOutput::Dictionary(dictionary)))
+        } else {
+            Ok(StenoEngine::new())
+        }
+    }
+
+    /// Record that a steno key was pressed.
+    pub fn press(&mut self, key: StenoKey) {
+        self.pressed.add(key);
+        self.chord.add(key);
+    }
+
+    /// Record that a steno key was released. When the last key in the chord is
+    /// released, the stroke is sent to the output and returned.
+    pub fn release(&mut self, key: StenoKey, output: &mut OutputKeyboard) -> Option<Stroke> {
+        self.pressed.remove(key);
+        if !self.pressed.is_empty() || self.chord.is_empty() {
+            return None;
+        }
+
+        let stroke = self.chord;
+        self.chord = Stroke::new();
+        self.send_stroke(stroke, output);
+        Some(stroke)
+    }
+
+    /// Send a completed stroke to the configured output.
+    pub fn send_stroke(&mut self, stroke: Stroke, output: &mut OutputKeyboard) {
+        let typed = match self.output {
+            StenoOutput::Protocol(ref protocol, ref mut writer) => {
+                let packet = protocol.encode(stroke);
+                if let Err(e) = writer.write_all(&packet).and_then(|_| writer.flush()) {
+                    output.report_error(SpaceCadetError::Device(format!("Couldn't send a steno stroke: {}", e)));
+                }
+                return;
+            }
+            StenoOutput::Dictionary(_) => self.translate(stroke)
+        };
+
+        // Erase any text that the new translation replaces, then type the new text.
+        for _ in 0..typed.0 {
+            tap(output, SimpleKey::KEY_BACKSPACE, false);
+        }
+        type_text(output, &typed.1);
+    }
+
+    /// Translate a stroke. Returns the number of characters to erase and the text to type.
+    fn translate(&mut self, stroke: Stroke) -> (usize, String) {
+        let dictionary = match self.output {
+            StenoOutput::Dictionary(ref d) => d,
+            _ => unreachable!()
+        };
+
+        // A lone asterisk undoes the last translation.
+        if stroke == Stroke::from_keys(&[StenoKey::Star]) && dictionary.lookup(&[stroke]).is_none() {
+            return match self.history.pop() {
+                Some(t) => (t.typed.chars().count(), String::new()),
+                None => (0, String::new())
+            };
+        }
+
+        // Find the longest outline that ends with this stroke. Outlines may
+        // replace previous translations, but only whole translations.
+        let mut replaced = 0;
+        let mut strokes = vec![stroke];
+        let mut text = None;
+        let mut candidate = vec![stroke];
+        for count in 0..=std::cmp::min(self.history.len(), dictionary.longest_outline) {
+            if candidate.len() > dictionary.longest_outline {
+                break;
+            }
+            if let Some(t) = dictionary.lookup(&candidate) {
+                replaced = count;
+                strokes = candidate.clone();
+                text = Some(t.to_string());
+            }
+            if count < self.history.len() {
+                let previous = &self.history[self.history.len() - 1 - count];
+                candidate = previous.strokes.iter().chain(candidate.iter()).cloned().collect();
+            }
+        }
+
+        // Untranslated strokes are typed as-is.
+        let text = text.unwrap_or_else(|| stroke.to_steno_string());
+
+        // Remove the translations that are being replaced.
+        let mut erase = 0;
+        for _ in 0..replaced {
+            erase += self.history.pop().unwrap().typed.chars().count();
+        }
+
+        // Format the text relative to the previous translation.
+        let attach_previous = match self.history.last() {
+            Some(t) => t.attach_next,
+            None => true
+        };
+        let (text, attach_left, attach_next) = format_translation(&text);
+        let typed = if attach_previous || attach_left { text } else { format!(" {}", text) };
+        self.history.push(Translation {
+            strokes,
+            typed: typed.clone(),
+            attach_next
+        });
+
+        // Forget the oldest translations once they can't be undone or replaced.
+        let limit = std::cmp::max(UNDO_DEPTH, dictionary.longest_outline);
+        if self.history.len() > limit {
+            let excess = self.history.len() - limit;
+            self.history.drain(..excess);
+        }
+        (erase, typed)
+    }
+}
+
+impl Default for StenoEngine {
+    fn default() -> StenoEngine {
+        StenoEngine::new()
+    }
+}
+
+/// Apply the basic Plover formatting operators to a translation.
+///
+/// Returns the text to type, whether the text attaches to the previous
+/// translation, and whether the next translation attaches to this one.
+fn format_translation(v: &str) -> (String, bool, bool) {
+    let mut text = String::new();
+    let mut attach_left = false;
+    let mut attach_next = false;
+    let mut rest = v;
+    while !rest.is_empty() {
+        let start = match rest.find('{') {
+            Some(i) => i,
+            None => { text.push_str(rest); break; }
+        };
+        let end = match rest[start..].find('}') {
+            Some(i) => start + i,
+            None => { text.push_str(rest); break; }
+        };
+        text.push_str(&rest[..start]);
+
+        let mut command = &rest[start + 1..end];
+        let at_start = text.is_empty() && start == 0;
+        let at_end = end == rest.len() - 1;
+        if command.starts_with('^') {
+            if at_start { attach_left = true; }
+            command = &command[1..];
+        }
+        if command.ends_with('^') {
+            if at_end { attach_next = true; }
+            command = &command[..command.len() - 1];
+        }
+        match command {
+            "," | "." | "?" | "!" | ":" | ";" => {
+                if at_start { attach_left = true; }
+                text.push_str(command);
+            }
+            // Other commands (e.g. capitalization) aren't supported; only literal text is typed.
+            c if c.starts_with('#') || c.starts_with('-') || c.starts_with('>') || c.starts_with('<') => (),
+            c => text.push_str(c)
+        }
+        rest = &rest[end + 1..];
+    }
+    (text, attach_left, attach_next)
+}
+
+/// Press and release a key on the output keyboard, optionally wrapped with shift.
+fn tap(output: &mut OutputKeyboard, key: SimpleKey, shifted: bool) {
+    if shifted { output.send(KeyState(SimpleKey::KEY_LEFTSHIFT, KeyStateChange::Pressed).into()); }
+    output.send(KeyState(key.clone(), KeyStateChange::Pressed).into());
+    output.send(KeyState(key, KeyStateChange::Released).into());
+    if shifted { output.send(KeyState(SimpleKey::KEY_LEFTSHIFT, KeyStateChange::Released).into()); }
+}
+
+/// Type text on the output keyboard using a US QWERTY layout.
+/// Characters that can't be typed are skipped.
+fn type_text(output: &mut OutputKeyboard, text: &str) {
+    for c in text.chars() {
+        if let Some((key, shifted)) = char_to_key(c) {
+            tap(output, key, shifted);
+        }
+    }
+}
+
+/// Convert a character into the key (and shift state) that types it on a US QWERTY layout.
+fn char_to_key(c: char) -> Option<(SimpleKey, bool)> {
+    use evdev_rs::enums::{EventCode, EventType};
+    let (name, shifted) = match c {
+        'a'..='z' | '0'..='9' => (c.to_uppercase().to_string(), false),
+        'A'..='Z' => (c.to_string(), true),
+        ' ' => ("SPACE".to_string(), false),
+        '\n' => ("ENTER".to_string(), false),
+        '\t' => ("TAB".to_string(), false),
+        _ => {
+            let (name, shifted) = match c {
+                '-' => ("MINUS", false), '_' => ("MINUS", true),
+                '=' => ("EQUAL", false), '+' => ("EQUAL", true),
+                '[' => ("LEFTBRACE", false), '{' => ("LEFTBRACE", true),
+                ']' => ("RIGHTBRACE", false), '}' => ("RIGHTBRACE", true),
+                '\\' => ("BACKSLASH", false), '|' => ("BACKSLASH", true),
+                ';' => ("SEMICOLON", false), ':' => ("SEMICOLON", true),
+                '\'' => ("APOSTROPHE", false), '"' => ("APOSTROPHE", true),
+                '`' => ("GRAVE", false), '~' => ("GRAVE", true),
+                ',' => ("COMMA", false), '<' => ("COMMA", true),
+                '.' => ("DOT", false), '>' => ("DOT", true),
+                '/' => ("SLASH", false), '?' => ("SLASH", true),
+                '!' => ("1", true), '@' => ("2", true), '#' => ("3", true),
+                '$' => ("4", true), '%' => ("5", true), '^' => ("6", true),
+                '&' => ("7", true), '*' => ("8", true), '(' => ("9", true),
+                ')' => ("0", true),
+                _ => return None
+            };
+            (name.to_string(), shifted)
+        }
+    };
+    match EventCode::from_str(&EventType::EV_KEY, &format!("KEY_{}", name)) {
+        Some(EventCode::EV_KEY(k)) => Some((k, shifted)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_io_keyboard::TestOutputKeyboard;
+
+    fn stroke(v: &str) -> Stroke { Stroke::parse(v).unwrap() }
+
+    #[test]
+    fn stroke_round_trip() {
+        // Strokes written in canonical notation should survive parse -> write.
+        for i in ["STKPW", "KAT", "-T", "STKPW-FPL", "A", "*", "#", "1-9", "12", "TPHO*ET", "SKWR-Z"].iter() {
+            assert_eq!(stroke(i).to_steno_string(), *i);
+        }
+
+        // Keys have to be in steno order.
+        assert!(Stroke::parse("ZS").is_err());
+        assert!(Stroke::parse("").is_err());
+        assert!(Stroke::parse("Q").is_err());
+    }
+
+    #[test]
+    fn stroke_right_bank() {
+        // Letters after the vowels (or a hyphen) belong to the right bank.
+        assert!(stroke("KAT").contains(StenoKey::RightT));
+        assert!(!stroke("KAT").contains(StenoKey::LeftT));
+        assert!(stroke("-S").contains(StenoKey::RightS));
+        assert_eq!(Stroke::from_keys(&[StenoKey::LeftS, StenoKey::RightS]).to_steno_string(), "S-S");
+    }
+
+    #[test]
+    fn gemini_pr_packet() {
+        assert_eq!(StenoProtocol::GeminiPR.encode(Stroke::new()), vec![0x80, 0, 0, 0, 0, 0]);
+        assert_eq!(StenoProtocol::GeminiPR.encode(stroke("S")), vec![0x80, 0x40, 0, 0, 0, 0]);
+        assert_eq!(StenoProtocol::GeminiPR.encode(stroke("-Z")), vec![0x80, 0, 0, 0, 0, 0x01]);
+        assert_eq!(StenoProtocol::GeminiPR.encode(stroke("#")), vec![0xA0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn tx_bolt_packet() {
+        assert_eq!(StenoProtocol::TxBolt.encode(stroke("S")), vec![0x01, 0x00]);
+        assert_eq!(StenoProtocol::TxBolt.encode(stroke("KAT")), vec![0x04, 0x42, 0xC1, 0x00]);
+        assert_eq!(StenoProtocol::TxBolt.encode(stroke("#")), vec![0xD0, 0x00]);
+    }
+
+    fn typed_text(out: &TestOutputKeyboard) -> usize {
+        out.events.iter().filter(|x| x.value == KeyStateChange::Pressed as i32).count()
+    }
+
+    #[test]
+    fn dictionary_translation() {
+        let mut dictionary = StenoDictionary::new();
+        dictionary.add("KAT", "cat").unwrap();
+        dictionary.add("KAT/-S", "catalogues").unwrap();
+        dictionary.add("-S", "{^s}").unwrap();
+        let mut engine = StenoEngine::with_output(StenoOutput::Dictionary(dictionary));
+
+        // The first word doesn't get a leading space.
+        assert_eq!(engine.translate(stroke("KAT")), (0, "cat".to_string()));
+        // A multi-stroke outline replaces the previous translation.
+        assert_eq!(engine.translate(stroke("-S")), (3, "catalogues".to_string()));
+        // Suffixes attach to the previous word.
+        assert_eq!(engine.translate(stroke("-S")), (0, "s".to_string()));
+        // Untranslated strokes are typed raw.
+        assert_eq!(engine.translate(stroke("TPH")), (0, " TPH".to_string()));
+        // The asterisk undoes the last translation.
+        assert_eq!(engine.translate(stroke("*")), (4, "".to_string()));
+    }
+
+    #[test]
+    fn engine_waits_for_chord_release() {
+        let mut dictionary = StenoDictionary::new();
+        dictionary.add("KAT", "Cat").unwrap();
+        let mut engine = StenoEngine::with_output(StenoOutput::Dictionary(dictionary));
+        let mut out = TestOutputKeyboard::new();
+
+        engine.press(StenoKey::LeftK);
+        engine.press(StenoKey::A);
+        assert!(engine.release(StenoKey::LeftK, &mut out).is_none());
+        engine.press(StenoKey::RightT);
+        assert!(engine.release(StenoKey::A, &mut out).is_none());
+        assert!(out.events.is_empty());
+        assert_eq!(engine.release(StenoKey::RightT, &mut out), Some(stroke("KAT")));
+
+        // "Cat" -> shift + C, A, T.
+        assert_eq!(typed_text(&out), 4);
+    }
+
+    #[test]
+    fn history_is_limited() {
+        let mut dictionary = StenoDictionary::new();
+        dictionary.add("KAT", "cat").unwrap();
+        let mut engine = StenoEngine::with_output(StenoOutput::Dictionary(dictionary));
+        for _ in 0..UNDO_DEPTH + 50 {
+            engine.translate(stroke("KAT"));
+        }
+        assert_eq!(engine.history.len(), UNDO_DEPTH);
+
+        // Undo still works back to the oldest translation that's kept.
+        for _ in 0..UNDO_DEPTH {
+            assert_eq!(engine.translate(stroke("*")), (4, "".to_string()));
+        }
+        assert_eq!(engine.translate(stroke("*")), (0, "".to_string()));
+    }
+
+    /// A pipe that the steno software has stopped reading.
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "broken pipe"))
+        }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn protocol_output_to_a_pipe() {
+        let dir = std::env::temp_dir();
+        let config = |name: &str| json::parse(&format!(r#"{{ "protocol": "txbolt", "output": "{}" }}"#, name)).unwrap();
+        let name = format!("spacecadet_steno_{}.fifo", std::process::id());
+        let path = dir.join(&name);
+
+        // A missing pipe is an error, rather than a new regular file.
+        assert_eq!(StenoEngine::from_json(&config(&name), &dir).err().unwrap(),
+            format!("The steno output {} doesn't exist.", path.to_str().unwrap()));
+        assert!(!path.exists());
+
+        // Sending to a pipe without a reader doesn't block; the stroke is reported as lost.
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let mut engine = StenoEngine::from_json(&config(&name), &dir).unwrap();
+        let mut out = TestOutputKeyboard::new();
+        engine.send_stroke(stroke("S"), &mut out);
+        assert_eq!(out.errors.len(), 1);
+
+        // Once a reader opens the pipe, strokes reach it.
+        let mut reader = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&path).unwrap();
+        engine.send_stroke(stroke("S"), &mut out);
+        assert_eq!(out.errors.len(), 1);
+        let mut packet = Vec::new();
+        reader.read_to_end(&mut packet).ok();
+        assert_eq!(packet, StenoProtocol::TxBolt.encode(stroke("S")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn protocol_errors_are_reported() {
+        let mut engine = StenoEngine::with_output(StenoOutput::Protocol(StenoProtocol::TxBolt, Box::new(ClosedPipe)));
+        let mut out = TestOutputKeyboard::new();
+        engine.send_stroke(stroke("KAT"), &mut out);
+        assert!(out.events.is_empty());
+        assert_eq!(out.errors.iter().map(|x| x.to_string()).collect::<Vec<String>>(), vec!["Couldn't send a steno stroke: broken pipe"]);
+    }
+}
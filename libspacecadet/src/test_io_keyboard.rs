@@ -1,4 +1,5 @@
 use evdev_rs as evdev;
+use crate::error::SpaceCadetError;
 use crate::input_keyboard::InputKeyboard;
 use crate::output_keyboard::{EventBuffer, OutputKeyboard};
 use crate::virtual_keyboard_matrix::KeyStats;
@@ -37,7 +38,9 @@ impl InputKeyboard for TestInputKeyboard {
 pub struct TestOutputKeyboard {
     pub events: Vec<evdev::InputEvent>,
     pub stats: KeyStats,
-    pub buffer: EventBuffer
+    pub buffer: EventBuffer,
+    /// The errors that were reported while sending output.
+    pub errors: Vec<SpaceCadetError>,
 }
 
 
@@ -47,6 +50,7 @@ impl TestOutputKeyboard {
             events: Vec::new(),
             stats: KeyStats::new(),
             buffer: EventBuffer::new(),
+            errors: Vec::new(),
         }
     }
 
@@ -68,4 +72,5 @@ impl OutputKeyboard for TestOutputKeyboard {
     }
     fn set_buffer(&mut self, buffer: EventBuffer) { self.buffer = buffer; }
     fn get_stats(&self) -> KeyStats { self.stats }
+    fn report_error(&mut self, error: SpaceCadetError) { self.errors.push(error); }
 }
@@ -1,11 +1,10 @@
 use evdev_rs as evdev;
-use json;
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
-use std::fs::File;
-use std::io::Read;
 use crate::keys;
 use crate::parser::ParsedKeyTree;
+use crate::error::SpaceCadetError;
+use crate::document::LayoutDocument;
 
 
 /// A MxN matrix of key codes. None can be used to encode matrix
@@ -86,11 +85,22 @@ impl BlockedKeyStates {
         ans
     }
 
+    /// Create a new block for key holds. The block ends when the key is released.
+    pub fn new_block_hold() -> BlockedKeyStates {
+        let mut ans = BlockedKeyStates::new();
+        ans.blocked[KeyStateChange::Held as usize] = true;
+        ans
+    }
+
     /// Returns `true` if a key state change is blocked.
     fn check_if_blocked(&mut self, s: KeyStateChange) -> bool {
         if s == KeyStateChange::Held && self.blocked[s as usize] {
             // Multiple holds are blocked.
             true
+        } else if s == KeyStateChange::Released && !self.blocked[s as usize] {
+            // An unblocked release still ends any hold blocks.
+            self.unblock();
+            false
         } else if self.blocked[s as usize] {
             // Press or releases are only blocked once
             // before all blocks are turned off.
@@ -128,6 +138,7 @@ pub enum MatrixUpdateResult {
 /// representation of the keyboard state -- an MxN matrix.
 pub struct VirtualKeyboardMatrix {
     key_to_index: HashMap<evdev::enums::EV_KEY, Index2D>,
+    duplicates: Vec<(evdev::enums::EV_KEY, Index2D)>,
     dim: Index2D,
     state: StateMatrix,
     blocked: Vec<Vec<BlockedKeyStates>>,
@@ -146,15 +157,18 @@ impl VirtualKeyboardMatrix {
         let col_count = keys.iter().map(|x| x.len()).max().unwrap_or(0);
         let dim = (row_count, col_count);
         let mut hash = HashMap::new();
+        let mut duplicates = Vec::new();
         for r in 0..dim.0 {
             for c in 0..keys[r].len() {
-                match &keys[r][c] {
-                    Some(t) => hash.insert(t.clone(), (r, c)),
+                // If a key is in the matrix twice, the last position wins.
+                let replaced = match &keys[r][c] {
+                    Some(t) => hash.insert(t.clone(), (r, c)).map(|idx| (t.clone(), idx)),
                     None => None
                     // This (row,col) in the virtual keyboard matrix doesn't have
                     // a key assigned. It's therefore impossible for this matrix
                     // location to ever be pressed (true) or released (false).
                 };
+                duplicates.extend(replaced);
             }
         }
 
@@ -165,6 +179,7 @@ impl VirtualKeyboardMatrix {
 
         VirtualKeyboardMatrix {
             key_to_index: hash,
+            duplicates,
             dim,
             state: StateMatrix::new(dim),
             blocked: vec![vec![BlockedKeyStates::new(); dim.1]; dim.0],
@@ -173,22 +188,24 @@ impl VirtualKeyboardMatrix {
     }
 
     /// Load a keyboard matrix from a file.
-    pub fn load(path: &str) -> VirtualKeyboardMatrix {
-
-        // Read the file to a string.
-        let mut file = File::open(path).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
+    pub fn load(path: &str) -> Result<VirtualKeyboardMatrix, SpaceCadetError> {
 
-        // Deserialize and print Rust data structure.
-        let data = json::parse(&contents).unwrap();
+        // Read the file (merged with any files it includes).
+        let data = LayoutDocument::load(path)?.value;
+        VirtualKeyboardMatrix::from_json(&data["matrix"], path)
+    }
 
+    /// Create a keyboard matrix from a list of rows of key names (the `"matrix"` of a matrix file).
+    /// The path is only used to describe errors.
+    pub fn from_json(matrix: &json::JsonValue, path: &str) -> Result<VirtualKeyboardMatrix, SpaceCadetError> {
         let mut mat = KeyMatrix::new();
-        for rows in data["matrix"].members() {
+        for (r, rows) in matrix.members().enumerate() {
             mat.push(Vec::new());
-            for col in rows.members() {
-                let key_code = col.as_str().unwrap();
-                let tokenized = ParsedKeyTree::create(key_code).unwrap();
+            for (c, col) in rows.members().enumerate() {
+                let key_error = |message: String| SpaceCadetError::json(path, format!("The key at {}x{}: {}", r, c, message));
+                let key_code = col.as_str()
+                    .ok_or_else(|| key_error(format!("{} isn't a string.", col)))?;
+                let tokenized = ParsedKeyTree::create(key_code).map_err(|e| key_error(e.to_string()))?;
 
                 // Try to parse it as a NormalKey.
                 // If that fails, try a transparent key.
@@ -201,14 +218,56 @@ impl VirtualKeyboardMatrix {
                     } else if as_transparent.is_ok() {
                         None
                     } else {
-                        panic!(format!("Couldn't convert {} into a code.", key_code));
+                        return Err(key_error(format!("couldn't convert {} into a code.", key_code)));
                     };
 
                 mat.last_mut().unwrap().push(code);
             }
         }
 
-        VirtualKeyboardMatrix::new(mat, None)
+        Ok(VirtualKeyboardMatrix::new(mat, None))
+    }
+
+    /// Find the position of a physical key (e.g. `KEY_H`) in the matrix.
+    pub fn index_of(&self, key: &evdev::enums::EV_KEY) -> Option<Index2D> {
+        self.key_to_index.get(key).cloned()
+    }
+
+    /// Parse a key position, written as a physical key name (e.g. `KC_H`) or as `row,col` (e.g. `2,3`).
+    pub fn parse_position(&self, position: &str) -> Result<Index2D, String> {
+        let parts: Vec<&str> = position.split(',').map(|x| x.trim()).collect();
+        if parts.len() == 2 {
+            let idx = match (parts[0].parse::<usize>(), parts[1].parse::<usize>()) {
+                (Ok(r), Ok(c)) => (r, c),
+                _ => return Err(format!("\"{}\" should be a key name or two numbers (row,col)", position))
+            };
+            if idx.0 >= self.dim.0 || idx.1 >= self.dim.1 {
+                return Err(format!("{},{} is outside the {}x{} matrix", idx.0, idx.1, self.dim.0, self.dim.1));
+            }
+            Ok(idx)
+        } else {
+            let key = ParsedKeyTree::create(position)
+                .map_err(|e| e.message())
+                .and_then(|x| keys::NormalKey::from_tokens(&x))?;
+            self.index_of(&key.value)
+                .ok_or_else(|| format!("{} isn't in the matrix", position))
+        }
+    }
+
+    /// Get the key at a position in the matrix (if there is one).
+    pub fn key_at(&self, idx: Index2D) -> Option<keys::SimpleKey> {
+        self.key_to_index.iter().find(|x| *x.1 == idx).map(|x| x.0.clone())
+    }
+
+    /// Get the keys that are in the matrix more than once, and the positions that were ignored
+    /// because of it (only the last position of a key can be pressed).
+    pub fn duplicate_keys(&self) -> &[(keys::SimpleKey, Index2D)] {
+        &self.duplicates
+    }
+
+    /// Set how long a key must be held to generate a HOLD event.
+    pub fn set_hold_duration(&mut self, hold: Duration) {
+        self.hold_down_threshold = hold;
     }
 
     /// Get the default duration that a key must be held to generate a HOLD event.
@@ -219,6 +278,11 @@ impl VirtualKeyboardMatrix {
         self.dim
     }
 
+    /// Check if any key in the matrix is currently pressed.
+    pub fn is_any_key_pressed(&self) -> bool {
+        self.state.is_any_key_pressed()
+    }
+
     /// Block key events at the given index.
     pub fn set_block(&mut self, block: BlockedKeyStates, idx: Index2D) {
         self.blocked[idx.0][idx.1] = block;
@@ -345,6 +409,11 @@ impl StateMatrix {
         }
     }
 
+    /// Check if any key is pressed.
+    pub fn is_any_key_pressed(&self) -> bool {
+        self.state.iter().any(|row| row.iter().any(|x| *x))
+    }
+
     /// Check if a key at the given index has been held longer than a specified duration.
     pub fn is_held(&self, idx: Index2D, hold_threshold: Duration, now: Instant) -> bool {
         let is_pressed = self.state[idx.0][idx.1];
@@ -408,6 +477,17 @@ mod tests {
         assert_eq!(KeyStateChange::Held, 2.into());
     }
 
+    #[test]
+    fn block_key_holds() {
+        let mut block = BlockedKeyStates::new_block_hold();
+
+        // Holds are blocked until the key is released.
+        assert!(block.check_if_blocked(KeyStateChange::Held));
+        assert!(block.check_if_blocked(KeyStateChange::Held));
+        assert!(!block.check_if_blocked(KeyStateChange::Released));
+        assert!(!block.check_if_blocked(KeyStateChange::Held));
+    }
+
     #[test]
     fn block_key_states() {
         let mut block = BlockedKeyStates::new_block_release_and_hold();
@@ -433,6 +513,18 @@ mod tests {
         ], None)
     }
 
+    #[test]
+    fn virtual_keyboard_matrix_positions() {
+        let mat = get_simple_matrix();
+        assert_eq!(mat.parse_position("KC_2"), Ok((1, 1)));
+        assert_eq!(mat.parse_position("KEY_4"), Ok((0, 0)));
+        assert_eq!(mat.parse_position("0, 2"), Ok((0, 2)));
+        assert_eq!(mat.parse_position("KC_9").unwrap_err(), "KC_9 isn't in the matrix");
+        assert_eq!(mat.parse_position("2,0").unwrap_err(), "2,0 is outside the 2x3 matrix");
+        assert!(mat.parse_position("1,x").is_err());
+        assert!(mat.parse_position("KC_NOPE").is_err());
+    }
+
     // Short-hand for checking if v is a specific enum variant.
     macro_rules! is_enum_variant {
         ($v:expr, $p:pat) => (
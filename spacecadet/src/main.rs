@@ -1,61 +1,303 @@
 use std::time;
 use libspacecadet::*;
-use clap::{Arg, App, value_t};
+use clap::{Arg, App, AppSettings, SubCommand, value_t};
 
-struct ParsedArgs {
-    device_path: String,
-    matrix_path: String,
-    layer_path: String,
-    event_hz_rate: u32,
+/// The exit status when layout tests fail or replays differ (as opposed to an error running them).
+const EXIT_TEST_FAILED: i32 = 1;
+
+enum Command {
+    Run(ParsedArgs),
+    Import(ImportArgs),
+    Export(ExportArgs),
+    Check(LayoutArgs),
+    Explain(ExplainArgs),
+    Simulate(SimulateArgs),
+    Test(Vec<String>),
+    Replay(ReplayArgs),
 }
 
-impl ParsedArgs {
-    fn create() -> ParsedArgs {
-        let matches = App::new("Space Cadet Driver")
-            .version("1.0")
-            .arg(Arg::with_name("device")
-                .short("d")
-                .long("device")
-                .value_name("DEV")
-                .required(true)
-                .help("The path of a keyboard device.")
-                .takes_value(true   ))
-            .arg(Arg::with_name("matrix")
+/// Where to find a layout: a config file, or separate matrix and layer files.
+struct LayoutArgs {
+    config_path: Option<String>,
+    matrix_path: Option<String>,
+    layer_path: Option<String>,
+}
+
+impl LayoutArgs {
+    fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .conflicts_with_all(&["matrix", "layer"])
+                .help("The path to a config file with the matrix, layers, and settings.")
+                .takes_value(true),
+            Arg::with_name("matrix")
                 .short("m")
                 .long("matrix")
                 .value_name("FILE")
-                .required(true)
+                .required_unless("config")
                 .help("The path to the matrix file.")
-                .takes_value(true))
-            .arg(Arg::with_name("layer")
+                .takes_value(true),
+            Arg::with_name("layer")
                 .short("l")
                 .long("layer")
                 .value_name("FILE")
-                .required(true)
+                .required_unless("config")
                 .help("The path to the layer file.")
-                .takes_value(true))
+                .takes_value(true),
+        ]
+    }
+
+    fn from_matches(matches: &clap::ArgMatches) -> LayoutArgs {
+        LayoutArgs {
+            config_path: matches.value_of("config").map(|x| x.to_string()),
+            matrix_path: matches.value_of("matrix").map(|x| x.to_string()),
+            layer_path: matches.value_of("layer").map(|x| x.to_string()),
+        }
+    }
+
+    fn load(&self) -> Result<Config, SpaceCadetError> {
+        match &self.config_path {
+            Some(path) => Config::load(path),
+            None => Config::from_files(self.matrix_path.as_ref().unwrap(), self.layer_path.as_ref().unwrap())
+        }
+    }
+}
+
+struct ExportArgs {
+    layout: LayoutArgs,
+    qmk_path: Option<String>,
+    keyboard: String,
+    qmk_layout: String,
+    diagram_path: Option<String>,
+}
+
+struct ExplainArgs {
+    layout: LayoutArgs,
+    position: String,
+    layers: Option<Vec<String>>,
+}
+
+struct SimulateArgs {
+    layout: LayoutArgs,
+    script_path: String,
+}
+
+struct ReplayArgs {
+    layout: LayoutArgs,
+    recording_path: String,
+    against_path: Option<String>,
+    expect_path: Option<String>,
+    speed: Option<f64>,
+}
+
+struct ImportArgs {
+    keymap_path: String,
+    layer_path: String,
+    matrix_path: Option<String>,
+}
+
+struct ParsedArgs {
+    device_path: Option<String>,
+    layout: LayoutArgs,
+    event_hz_rate: Option<u32>,
+    record_path: Option<String>,
+}
+
+impl Command {
+    fn create() -> Command {
+        let matches = App::new("Space Cadet Driver")
+            .version("1.0")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .arg(Arg::with_name("device")
+                .short("d")
+                .long("device")
+                .value_name("DEV")
+                .required_unless("config")
+                .help("The path of a keyboard device (overrides the config's device).")
+                .takes_value(true   ))
+            .args(&LayoutArgs::args())
             .arg(Arg::with_name("hz-rate")
                 .long("hz-rate")
                 .value_name("U32")
                 .required(false)
-                .help("Frequency rate of the primary event loop.")
+                .help("Frequency rate of the primary event loop (overrides the poll_hz setting).")
+                .takes_value(true))
+            .arg(Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Record every key event from the keyboard to a file (see the replay command).")
                 .takes_value(true))
+            .subcommand(SubCommand::with_name("import")
+                .about("Convert a QMK keymap (keymap.c or keymap.json) or a KMonad/Kanata config (.kbd) into a layer file.")
+                .arg(Arg::with_name("keymap")
+                    .value_name("KEYMAP")
+                    .required(true)
+                    .help("The path to the keymap or config."))
+                .arg(Arg::with_name("layer")
+                    .short("l")
+                    .long("layer")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Where to write the layer file.")
+                    .takes_value(true))
+                .arg(Arg::with_name("matrix")
+                    .short("m")
+                    .long("matrix")
+                    .value_name("FILE")
+                    .help("Where to write a matrix file (from defsrc, or guessed from the first QMK layer).")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("export")
+                .about("Write a layout as a QMK keymap.json, or draw it as a keymap diagram.")
+                .args(&LayoutArgs::args())
+                .arg(Arg::with_name("qmk")
+                    .long("qmk")
+                    .value_name("FILE")
+                    .help("Where to write a QMK keymap.json.")
+                    .takes_value(true))
+                .arg(Arg::with_name("keyboard")
+                    .long("keyboard")
+                    .value_name("NAME")
+                    .default_value("")
+                    .help("The QMK keyboard (e.g. crkbd/rev1).")
+                    .takes_value(true))
+                .arg(Arg::with_name("qmk-layout")
+                    .long("qmk-layout")
+                    .value_name("NAME")
+                    .default_value("LAYOUT")
+                    .help("The QMK layout macro (e.g. LAYOUT_split_3x6_3).")
+                    .takes_value(true))
+                .arg(Arg::with_name("diagram")
+                    .long("diagram")
+                    .value_name("FILE")
+                    .help("Where to write a keymap diagram (SVG for .svg files, otherwise ASCII art).")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("check")
+                .about("Check a layout for mistakes, like layers you can't get out of or keys that can't be pressed.")
+                .arg(Arg::with_name("config")
+                    .value_name("CONFIG")
+                    .index(1)
+                    .conflicts_with_all(&["matrix", "layer"])
+                    .help("The path to a config file with the matrix, layers, and settings."))
+                .args(&LayoutArgs::args()[1..]))
+            .subcommand(SubCommand::with_name("explain")
+                .about("Show how a key press at a position goes through the layers, and which key handles it.")
+                .args(&LayoutArgs::args())
+                .arg(Arg::with_name("position")
+                    .value_name("POSITION")
+                    .required(true)
+                    .help("A physical key (e.g. KC_H) or a matrix position (e.g. 2,3)."))
+                .arg(Arg::with_name("layers")
+                    .long("layers")
+                    .value_name("NAMES")
+                    .use_delimiter(true)
+                    .min_values(0)
+                    .help("The layers to treat as enabled, separated by commas (the startup layers by default).")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("simulate")
+                .about("Run a layout against a script of timed key events, and print the keys it sends and the layers it changes.")
+                .args(&LayoutArgs::args())
+                .arg(Arg::with_name("script")
+                    .short("s")
+                    .long("script")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("The path to the script (lines like \"150ms press KC_SPACE\").")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("test")
+                .about("Run layout test files: timed key events, and the keys and layers they should produce.")
+                .arg(Arg::with_name("tests")
+                    .value_name("FILE")
+                    .required(true)
+                    .multiple(true)
+                    .help("The paths to the test files.")))
+            .subcommand(SubCommand::with_name("replay")
+                .about("Replay a recording (from --record) through a layout, and print or compare what it sends.")
+                .args(&LayoutArgs::args())
+                .arg(Arg::with_name("recording")
+                    .value_name("RECORDING")
+                    .required(true)
+                    .help("The path to the recording."))
+                .arg(Arg::with_name("against")
+                    .long("against")
+                    .value_name("CONFIG")
+                    .conflicts_with("expect")
+                    .help("Replay through another config file too, and compare the keys they send.")
+                    .takes_value(true))
+                .arg(Arg::with_name("expect")
+                    .long("expect")
+                    .value_name("FILE")
+                    .help("Compare with the output of an earlier replay (e.g. from another version).")
+                    .takes_value(true))
+                .arg(Arg::with_name("speed")
+                    .long("speed")
+                    .value_name("FACTOR")
+                    .help("Replay in real time (1), or faster or slower (e.g. 10 or 0.5). By default, as fast as possible.")
+                    .takes_value(true)))
             .get_matches();
-        ParsedArgs {
-            device_path: matches.value_of("device").unwrap().to_string(),
-            matrix_path: matches.value_of("matrix").unwrap().to_string(),
-            layer_path: matches.value_of("layer").unwrap().to_string(),
-            event_hz_rate: value_t!(matches, "hz-rate", u32).unwrap_or(200) // 5ms.
+        if let Some(matches) = matches.subcommand_matches("import") {
+            return Command::Import(ImportArgs {
+                keymap_path: matches.value_of("keymap").unwrap().to_string(),
+                layer_path: matches.value_of("layer").unwrap().to_string(),
+                matrix_path: matches.value_of("matrix").map(|x| x.to_string()),
+            });
+        }
+        if let Some(matches) = matches.subcommand_matches("export") {
+            return Command::Export(ExportArgs {
+                layout: LayoutArgs::from_matches(matches),
+                qmk_path: matches.value_of("qmk").map(|x| x.to_string()),
+                keyboard: matches.value_of("keyboard").unwrap().to_string(),
+                qmk_layout: matches.value_of("qmk-layout").unwrap().to_string(),
+                diagram_path: matches.value_of("diagram").map(|x| x.to_string()),
+            });
+        }
+        if let Some(matches) = matches.subcommand_matches("check") {
+            return Command::Check(LayoutArgs::from_matches(matches));
+        }
+        if let Some(matches) = matches.subcommand_matches("explain") {
+            return Command::Explain(ExplainArgs {
+                layout: LayoutArgs::from_matches(matches),
+                position: matches.value_of("position").unwrap().to_string(),
+                layers: matches.values_of("layers").map(|x| x.map(|x| x.to_string()).collect())
+                    .or_else(|| if matches.is_present("layers") { Some(Vec::new()) } else { None }),
+            });
         }
+        if let Some(matches) = matches.subcommand_matches("simulate") {
+            return Command::Simulate(SimulateArgs {
+                layout: LayoutArgs::from_matches(matches),
+                script_path: matches.value_of("script").unwrap().to_string(),
+            });
+        }
+        if let Some(matches) = matches.subcommand_matches("test") {
+            return Command::Test(matches.values_of("tests").unwrap().map(|x| x.to_string()).collect());
+        }
+        if let Some(matches) = matches.subcommand_matches("replay") {
+            return Command::Replay(ReplayArgs {
+                layout: LayoutArgs::from_matches(matches),
+                recording_path: matches.value_of("recording").unwrap().to_string(),
+                against_path: matches.value_of("against").map(|x| x.to_string()),
+                expect_path: matches.value_of("expect").map(|x| x.to_string()),
+                speed: value_t!(matches, "speed", f64).ok().filter(|x| *x > 0.0),
+            });
+        }
+        Command::Run(ParsedArgs {
+            device_path: matches.value_of("device").map(|x| x.to_string()),
+            layout: LayoutArgs::from_matches(&matches),
+            event_hz_rate: value_t!(matches, "hz-rate", u32).ok(),
+            record_path: matches.value_of("record").map(|x| x.to_string()),
+        })
     }
 }
 
-fn cyclic_executor<F>(action: &mut F, hz_rate: u32) where F: FnMut() {
-    let event_loop_rate = time::Duration::from_millis(1000 / u64::from(hz_rate));
+fn cyclic_executor<F>(action: &mut F, hz_rate: u32) -> Result<(), SpaceCadetError>
+    where F: FnMut() -> Result<(), SpaceCadetError> {
+    let event_loop_rate = poll_interval(hz_rate);
     let mut warned = false;
     loop {
         let start = time::Instant::now();
-        action();
+        action()?;
         let end = time::Instant::now();
         let elapsed = end - start;
         if elapsed < event_loop_rate {
@@ -68,21 +310,217 @@ fn cyclic_executor<F>(action: &mut F, hz_rate: u32) where F: FnMut() {
     }
 }
 
-fn main() {
-    let args = ParsedArgs::create();
-
-    let mut driver = KeyboardDriver {
-        input: EvdevKeyboard::open(&args.device_path).unwrap(),
-        output: UInputKeyboard::new(None).unwrap(),
-        matrix: VirtualKeyboardMatrix::load(&args.matrix_path),
-        layered_codes: Vec::new(),
-        layer_attributes: LayerCollection::new(),
+fn run(args: &ParsedArgs) -> Result<(), SpaceCadetError> {
+    let config = args.layout.load()?;
+    let device_path = args.device_path.clone().or_else(|| config.device.clone())
+        .ok_or_else(|| SpaceCadetError::Device("No keyboard device - pass --device or set \"device\" in the config.".to_string()))?;
+    let hz_rate = args.event_hz_rate.unwrap_or(config.settings.poll_hz);
+    if hz_rate == 0 || hz_rate > MAX_POLL_HZ {
+        return Err(SpaceCadetError::Usage(format!("The hz rate must be a number from 1 to {}.", MAX_POLL_HZ)));
+    }
+
+    let mut input = EvdevKeyboard::open(&device_path).map_err(SpaceCadetError::Device)?;
+    if let Some(path) = &args.record_path {
+        input.record(path)?;
+    }
+    let output = UInputKeyboard::from_settings(&config.settings.output)
+        .map_err(|e| SpaceCadetError::Device(format!("Couldn't create the output device: {}", e)))?;
+    let mut driver = config.into_driver(input, output, &KeyRegistry::new())?;
+
+    let mut update = || {
+        let now = time::Instant::now();
+        driver.clock_tick(now);
+        driver.output.send_repeats(now);
+        match driver.output.take_error().or_else(|| driver.input.take_error()) {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
     };
+    cyclic_executor(&mut update, hz_rate)
+}
+
+fn write_file(path: &str, contents: String) -> Result<(), SpaceCadetError> {
+    std::fs::write(path, contents + "\n").map_err(|e| SpaceCadetError::io(path, e))
+}
+
+fn import(args: &ImportArgs) -> Result<(), SpaceCadetError> {
+    let layout = import_layout(&args.keymap_path)?;
+    for warning in layout.warnings.iter() {
+        eprintln!("spacecadet: warning: {}", warning);
+    }
+    write_file(&args.layer_path, layout.layers_to_json().pretty(2))?;
+    if let Some(path) = &args.matrix_path {
+        write_file(path, layout.matrix_to_json().pretty(2))?;
+    }
+    Ok(())
+}
+
+fn export(args: &ExportArgs) -> Result<(), SpaceCadetError> {
+    let config = args.layout.load()?;
+    let driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new())?;
+    if let Some(path) = &args.qmk_path {
+        let mut warnings = Vec::new();
+        let keymap = export_qmk_json(&driver, &args.keyboard, &args.qmk_layout, &mut warnings);
+        for warning in warnings.iter() {
+            eprintln!("spacecadet: warning: {}", warning);
+        }
+        write_file(path, keymap.pretty(2))?;
+    }
+    if let Some(path) = &args.diagram_path {
+        let diagram = if path.ends_with(".svg") { keymap_svg(&driver) } else { keymap_ascii(&driver) };
+        write_file(path, diagram.trim_end().to_string())?;
+    }
+    Ok(())
+}
+
+fn check(args: &LayoutArgs) -> Result<(), SpaceCadetError> {
+    let config = args.load()?;
+    let driver = config.into_unverified_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new())?;
+    let findings = driver.lint();
+    for finding in findings.iter() {
+        eprintln!("spacecadet: {}", finding);
+    }
+    let errors = findings.iter().filter(|x| x.level == LintLevel::Error).count();
+    if errors > 0 {
+        return Err(SpaceCadetError::Constraint(format!("{} error(s) in the layout.", errors)));
+    }
+    Ok(())
+}
 
-    driver.load_layers(&args.layer_path);
+fn explain(args: &ExplainArgs) -> Result<(), SpaceCadetError> {
+    let config = args.layout.load()?;
+    let driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new())?;
+    let explanation = explain_position(&driver, &args.position, args.layers.as_deref())
+        .map_err(SpaceCadetError::Usage)?;
+    println!("{}", explanation);
+    Ok(())
+}
 
-    driver.verify().unwrap();
+fn simulate(args: &SimulateArgs) -> Result<(), SpaceCadetError> {
+    let config = args.layout.load()?;
+    let tick = poll_interval(config.settings.poll_hz);
+    let mut driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new())?;
+    let contents = std::fs::read_to_string(&args.script_path).map_err(|e| SpaceCadetError::io(&args.script_path, e))?;
+    let script = parse_script(&contents, &driver.matrix).map_err(|e| SpaceCadetError::json(&args.script_path, e))?;
+    for event in libspacecadet::simulate(&mut driver, &script, tick) {
+        println!("{}", event);
+    }
+    Ok(())
+}
 
-    let mut update = || driver.clock_tick(time::Instant::now());
-    cyclic_executor(&mut update, args.event_hz_rate);
-}
\ No newline at end of file
+/// Run the layout tests, returning whether they all passed.
+fn test(paths: &[String]) -> Result<bool, SpaceCadetError> {
+    let (mut count, mut failed) = (0, 0);
+    for path in paths {
+        let file = LayoutTestFile::load(path)?;
+        for test in file.tests.iter() {
+            // Every test starts with a new driver.
+            let config = file.load_config()?
+                .ok_or_else(|| SpaceCadetError::json(path, "The file doesn't name a layout (a \"config\", or a \"matrix\" and \"layers\")."))?;
+            let tick = poll_interval(config.settings.poll_hz);
+            let mut driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new())?;
+            let failures = test.run(&mut driver, tick);
+            count += 1;
+            if failures.is_empty() {
+                println!("test {} ... ok", test.name);
+            } else {
+                failed += 1;
+                println!("test {} ... FAILED", test.name);
+                for failure in failures.iter() {
+                    println!("    {}", failure);
+                }
+            }
+        }
+    }
+    println!("\n{} tests, {} passed, {} failed", count, count - failed, failed);
+    if failed > 0 {
+        eprintln!("spacecadet: {} of {} layout tests failed.", failed, count);
+    }
+    Ok(failed == 0)
+}
+
+fn replay_through<F>(config: Config, script: &[ScriptEvent], mut on_tick: F) -> Result<Vec<SimulatedEvent>, SpaceCadetError>
+    where F: FnMut(time::Duration, &[SimulatedEvent]) {
+    let tick = poll_interval(config.settings.poll_hz);
+    let mut driver = config.into_driver(TestInputKeyboard::new(), TestOutputKeyboard::new(), &KeyRegistry::new())?;
+    let mut ans = Vec::new();
+    simulate_with(&mut driver, script, tick, |t, events| {
+        on_tick(t, events);
+        ans.extend_from_slice(events);
+    });
+    Ok(ans)
+}
+
+/// Replay a recording, returning whether the replays match (if there's anything to compare).
+fn replay(args: &ReplayArgs) -> Result<bool, SpaceCadetError> {
+    let contents = std::fs::read_to_string(&args.recording_path).map_err(|e| SpaceCadetError::io(&args.recording_path, e))?;
+    let recording = parse_recording(&contents).map_err(|e| SpaceCadetError::json(&args.recording_path, e))?;
+    let script = recording_to_script(&recording);
+
+    // Print events as they happen, at the requested speed.
+    let start = time::Instant::now();
+    let speed = args.speed;
+    let print = args.against_path.is_none() && args.expect_path.is_none();
+    let events = replay_through(args.layout.load()?, &script, |t, events| {
+        if let Some(speed) = speed {
+            let due = start + t.div_f64(speed);
+            let now = time::Instant::now();
+            if due > now {
+                std::thread::sleep(due - now);
+            }
+        }
+        if print {
+            for event in events {
+                println!("{}", event);
+            }
+        }
+    })?;
+
+    let difference = if let Some(path) = &args.against_path {
+        let other = replay_through(Config::load(path)?, &script, |_, _| ())?;
+        compare_outputs(&events, &other)
+    } else if let Some(path) = &args.expect_path {
+        let expected = std::fs::read_to_string(path).map_err(|e| SpaceCadetError::io(path, e))?;
+        let actual: Vec<String> = events.iter().map(|x| x.to_string()).collect();
+        let expected: Vec<&str> = expected.lines().collect();
+        (0..std::cmp::max(actual.len(), expected.len()))
+            .find(|x| actual.get(*x).map(|x| x.as_str()) != expected.get(*x).cloned())
+            .map(|x| format!("line {}: expected \"{}\", got \"{}\"", x + 1,
+                expected.get(x).map(|x| x.trim()).unwrap_or("nothing"), actual.get(x).map(|x| x.trim()).unwrap_or("nothing")))
+    } else {
+        None
+    };
+    match difference {
+        Some(v) => {
+            eprintln!("spacecadet: The replays differ at {}", v);
+            Ok(false)
+        }
+        None if !print => {
+            let count = events.iter().filter(|x| matches!(x.kind, SimulatedEventKind::Output(..))).count();
+            println!("The replays match ({} key events sent).", count);
+            Ok(true)
+        }
+        None => Ok(true)
+    }
+}
+
+fn main() {
+    let result = match Command::create() {
+        Command::Run(args) => run(&args).map(|_| true),
+        Command::Import(args) => import(&args).map(|_| true),
+        Command::Export(args) => export(&args).map(|_| true),
+        Command::Check(args) => check(&args).map(|_| true),
+        Command::Explain(args) => explain(&args).map(|_| true),
+        Command::Simulate(args) => simulate(&args).map(|_| true),
+        Command::Test(paths) => test(&paths),
+        Command::Replay(args) => replay(&args),
+    };
+    match result {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(EXIT_TEST_FAILED),
+        Err(e) => {
+            eprintln!("spacecadet: {}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}